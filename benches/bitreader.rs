@@ -0,0 +1,51 @@
+// Throughput of BitReader reading from a file, and of SliceBitReader.
+//
+// Copyright 2021 Robbert Haarman
+//
+// SPDX-License-Identifier: MIT
+//
+// Run with: cargo bench --bench bitreader
+
+use std::io::Write;
+use std::time::Instant;
+
+use compression_toolkit::io::{BitReader, ReadBits, SliceBitReader};
+
+/// Size of the file read by the benchmark.
+const FILE_SIZE: usize = 16 << 20;
+
+fn main() {
+    let path = std::env::temp_dir().join(format!("bitreader-bench-{}", std::process::id()));
+    let data: Vec<u8> = (0..FILE_SIZE as u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+        .collect();
+    std::fs::File::create(&path).unwrap().write_all(&data).unwrap();
+
+    for &nbits in &[1, 7, 13, 32] {
+        let mut file = std::fs::File::open(&path).unwrap();
+        let mut reader = BitReader::new(&mut file);
+        let reads = FILE_SIZE * 8 / nbits as usize;
+        let start = Instant::now();
+        let mut sum = 0u64;
+        for _ in 0..reads {
+            sum = sum.wrapping_add(reader.read_bits(nbits).unwrap() as u64);
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        println!("read_bits({:2}): {:8.1} MB/s (checksum {:x})",
+                 nbits, FILE_SIZE as f64 / elapsed / 1e6, sum);
+    }
+
+    for &nbits in &[1, 7, 13, 32] {
+        let mut reader = SliceBitReader::new(&data);
+        let reads = FILE_SIZE * 8 / nbits as usize;
+        let start = Instant::now();
+        let mut sum = 0u64;
+        for _ in 0..reads {
+            sum = sum.wrapping_add(reader.read_bits(nbits).unwrap() as u64);
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        println!("slice read_bits({:2}): {:8.1} MB/s (checksum {:x})",
+                 nbits, FILE_SIZE as f64 / elapsed / 1e6, sum);
+    }
+
+    std::fs::remove_file(&path).unwrap();
+}
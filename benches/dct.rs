@@ -0,0 +1,75 @@
+// Speed of the butterfly DCT in dct::transform and dct::reverse,
+// compared with the matrix multiplication versions, of a round trip
+// through the in-place versions, and of dct::transform_quantize compared
+// with separate transform and quantization.
+//
+// Copyright 2023 Robbert Haarman
+//
+// SPDX-License-Identifier: MIT
+//
+// Run with: cargo bench --bench dct
+
+use std::time::Instant;
+
+use compression_toolkit::dct;
+
+/// Number of different blocks, few enough to stay in the cache.
+const BLOCKS: usize = 1000;
+
+/// Number of times each measurement transforms every block.
+const ROUNDS: usize = 200;
+
+/// Returns the number of blocks per second f transforms.
+fn measure(f: fn(&[f32; 64]) -> [f32; 64], blocks: &[[f32; 64]]) -> f64 {
+    let start = Instant::now();
+    let mut sum = 0.0;
+    for _ in 0..ROUNDS {
+        for block in blocks {
+            sum += f(block)[1];
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    // Use the results, so that the transforms are not optimized away.
+    assert!(sum.is_finite());
+    (ROUNDS * blocks.len()) as f64 / elapsed
+}
+
+/// Returns the number of blocks per second transform_in_place and
+/// reverse_in_place transform and restore.
+fn measure_in_place(blocks: &[[f32; 64]]) -> f64 {
+    let mut blocks = blocks.to_vec();
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        for block in blocks.iter_mut() {
+            dct::transform_in_place(block);
+            dct::reverse_in_place(block);
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    assert!(blocks.iter().all(|block| block[1].is_finite()));
+    (ROUNDS * blocks.len()) as f64 / elapsed
+}
+
+fn main() {
+    let blocks: Vec<[f32; 64]> = (0..BLOCKS as u32).map(|b| {
+        let mut block = [0.0; 64];
+        for (i, x) in block.iter_mut().enumerate() {
+            *x = ((b.wrapping_mul(2654435761) >> 16) as usize + i * 7) as f32 % 256.0 - 128.0;
+        }
+        block
+    }).collect();
+    println!("transform: {:10.0} blocks/s (matrix {:10.0} blocks/s)",
+             measure(dct::transform, &blocks), measure(dct::transform_matrix, &blocks));
+    println!("reverse:   {:10.0} blocks/s (matrix {:10.0} blocks/s)",
+             measure(dct::reverse, &blocks), measure(dct::reverse_matrix, &blocks));
+    println!("both:      {:10.0} blocks/s (in place {:10.0} blocks/s)",
+             measure(|block| dct::reverse(&dct::transform(block)), &blocks),
+             measure_in_place(&blocks));
+    println!("quantize:  {:10.0} blocks/s (fused {:10.0} blocks/s)",
+             measure(|block| {
+                 dct::quantize(&dct::transform(block), &dct::LUMINANCE_QTABLE).map(|q| q as f32)
+             }, &blocks),
+             measure(|block| {
+                 dct::transform_quantize(block, &dct::LUMINANCE_QTABLE).map(|q| q as f32)
+             }, &blocks));
+}
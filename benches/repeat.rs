@@ -0,0 +1,54 @@
+// Throughput of RepeatOutput::repeat_bytes on a Vec, the hottest loop of
+// decompression, compared with copying one byte at a time.
+//
+// Copyright 2021 Robbert Haarman
+//
+// SPDX-License-Identifier: MIT
+//
+// Run with: cargo bench --bench repeat
+
+use std::time::Instant;
+
+use compression_toolkit::io::{RepeatOutput, SliceToVecIO};
+
+/// Number of bytes produced by each measurement.
+const OUTPUT_SIZE: usize = 64 << 20;
+
+/// Appends count bytes to output, starting dist bytes before the last
+/// one, one byte at a time.
+fn repeat_bytewise(output: &mut Vec<u8>, count: usize, dist: usize) {
+    let start = output.len() - 1 - dist;
+    for outpos in start..start + count {
+        output.push(output[outpos]);
+    }
+}
+
+fn main() {
+    let seed: Vec<u8> = (0..0x10000u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+        .collect();
+    for &(count, dist) in &[(4, 0), (32, 0), (258, 0), (32, 2), (258, 7), (8, 100),
+                            (32, 1000), (258, 30000)] {
+        let repeats = OUTPUT_SIZE / count;
+
+        let mut output = Vec::with_capacity(seed.len() + OUTPUT_SIZE);
+        output.extend_from_slice(&seed);
+        let start = Instant::now();
+        for _ in 0..repeats {
+            repeat_bytewise(&mut output, count, dist);
+        }
+        let bytewise = start.elapsed().as_secs_f64();
+
+        let mut output = Vec::with_capacity(seed.len() + OUTPUT_SIZE);
+        output.extend_from_slice(&seed);
+        let mut io = SliceToVecIO::new(&[], &mut output);
+        let start = Instant::now();
+        for _ in 0..repeats {
+            io.repeat_bytes(count, dist).unwrap();
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+
+        println!("repeat_bytes({:3}, {:5}): {:8.1} MB/s (bytewise {:8.1} MB/s)",
+                 count, dist, (repeats * count) as f64 / elapsed / 1e6,
+                 (repeats * count) as f64 / bytewise / 1e6);
+    }
+}
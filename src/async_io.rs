@@ -0,0 +1,416 @@
+// Asynchronous counterparts of the traits in io, with adapters over
+// tokio's AsyncRead and AsyncWrite. Enabled by the "async" feature.
+//
+// Copyright 2021 Robbert Haarman
+//
+// SPDX-License-Identifier: MIT
+
+// The futures returned by these traits are Send whenever the
+// implementing type is, which is all callers need so far.
+#![allow(async_fn_in_trait)]
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::io::{BitReadError, BitResult};
+use crate::result::BoxResult;
+
+/// Number of bytes the adapters in this module read or write at a time.
+const ASYNC_BUFFER: usize = 0x2000;
+
+/// Asynchronous counterpart of io::IOTrait.
+pub trait AsyncIOTrait {
+    /// Copies count bytes from the input to the output.
+    async fn copy_bytes(&mut self, count: usize) -> BoxResult<()>;
+
+    /// Returns the number of bytes of input that have been read so far.
+    fn inpos(&self) -> u64;
+
+    /// Reads the next byte of input. Returns Ok(None) at the end of the
+    /// input.
+    async fn next_byte(&mut self) -> BoxResult<Option<u8>>;
+
+    /// Writes a single byte to the output.
+    async fn write_byte(&mut self, b: u8) -> BoxResult<()>;
+}
+
+/// Asynchronous counterpart of io::ReadBits.
+pub trait AsyncReadBits {
+    /// Reads nbits bits (at most 32), least significant bit first.
+    async fn read_bits(&mut self, nbits: u32) -> BitResult<u32>;
+
+    /// Like read_bits, but returns Ok(None) instead of an error if the
+    /// input ends at the current position, which is then always a byte
+    /// boundary.
+    async fn try_read_bits(&mut self, nbits: u32) -> BitResult<Option<u32>>;
+
+    /// Returns the next nbits bits (at most 32) without consuming them.
+    /// Bits past the end of the input are returned as 0.
+    async fn peek_bits(&mut self, nbits: u32) -> BitResult<u32>;
+
+    /// Returns the number of bits that have been consumed so far.
+    fn bit_position(&self) -> u64;
+
+    /// Reads nbits bits (at most 64), least significant bit first.
+    async fn read_bits64(&mut self, nbits: u32) -> BitResult<u64> {
+        assert!(nbits <= 64, "cannot read more than 64 bits at once");
+        if nbits <= 32 {
+            return Ok(self.read_bits(nbits).await? as u64);
+        }
+        let low = self.read_bits(32).await? as u64;
+        let high = self.read_bits(nbits - 32).await? as u64;
+        Ok(low | (high << 32))
+    }
+
+    /// Skips the remaining bits of the current byte, if any, so that the
+    /// next read starts at a byte boundary.
+    async fn align_to_byte(&mut self) -> BitResult<()> {
+        let pad = (8 - self.bit_position() % 8) % 8;
+        self.read_bits(pad as u32).await?;
+        Ok(())
+    }
+}
+
+/// Asynchronous counterpart of io::WriteBits.
+pub trait AsyncWriteBits {
+    async fn flush(&mut self) -> BoxResult<()>;
+
+    /// Writes the nbits (at most 32) least significant bits of bits,
+    /// least significant bit first. The other bits of bits are ignored.
+    async fn write_bits(&mut self, bits: u32, nbits: u8) -> BoxResult<()>;
+
+    /// Writes the nbits (at most 64) least significant bits of bits,
+    /// least significant bit first. The other bits of bits are ignored.
+    async fn write_bits64(&mut self, bits: u64, nbits: u8) -> BoxResult<()> {
+        assert!(nbits <= 64, "cannot write more than 64 bits at once");
+        if nbits <= 32 {
+            return self.write_bits(bits as u32, nbits).await;
+        }
+        self.write_bits(bits as u32, 32).await?;
+        self.write_bits((bits >> 32) as u32, nbits - 32).await
+    }
+
+    /// Pads the current byte, if any, with zero bits, so that the next
+    /// write starts at a byte boundary.
+    async fn align_to_byte(&mut self) -> BoxResult<()>;
+}
+
+/// Reads input from an AsyncRead and writes output to an AsyncWrite,
+/// buffering both. Call flush or finish when done.
+pub struct AsyncStreamIO<R, W> {
+    input: R,
+    inbuf: Vec<u8>,
+    inbuf_pos: usize,
+    inbuf_len: usize,
+    inpos: u64,
+    output: W,
+    outbuf: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> AsyncStreamIO<R, W> {
+    pub fn new(input: R, output: W) -> AsyncStreamIO<R, W> {
+        AsyncStreamIO {
+            input,
+            inbuf: vec![0; ASYNC_BUFFER],
+            inbuf_pos: 0,
+            inbuf_len: 0,
+            inpos: 0,
+            output,
+            outbuf: Vec::with_capacity(ASYNC_BUFFER),
+        }
+    }
+
+    /// Writes all buffered output to the writer and flushes it.
+    pub async fn flush(&mut self) -> BoxResult<()> {
+        self.output.write_all(&self.outbuf).await?;
+        self.outbuf.clear();
+        self.output.flush().await?;
+        Ok(())
+    }
+
+    /// Flushes the output and returns the reader and the writer.
+    pub async fn finish(mut self) -> BoxResult<(R, W)> {
+        self.flush().await?;
+        Ok((self.input, self.output))
+    }
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> AsyncIOTrait for AsyncStreamIO<R, W> {
+    async fn copy_bytes(&mut self, count: usize) -> BoxResult<()> {
+        for _ in 0..count {
+            match self.next_byte().await? {
+                Some(b) => self.write_byte(b).await?,
+                None => return Err("end of input while copying bytes".into()),
+            }
+        }
+        Ok(())
+    }
+
+    fn inpos(&self) -> u64 { self.inpos }
+
+    async fn next_byte(&mut self) -> BoxResult<Option<u8>> {
+        if self.inbuf_pos == self.inbuf_len {
+            self.inbuf_len = self.input.read(&mut self.inbuf).await?;
+            self.inbuf_pos = 0;
+            if self.inbuf_len == 0 {
+                return Ok(None);
+            }
+        }
+        let b = self.inbuf[self.inbuf_pos];
+        self.inbuf_pos += 1;
+        self.inpos += 1;
+        Ok(Some(b))
+    }
+
+    async fn write_byte(&mut self, b: u8) -> BoxResult<()> {
+        self.outbuf.push(b);
+        if self.outbuf.len() == ASYNC_BUFFER {
+            self.output.write_all(&self.outbuf).await?;
+            self.outbuf.clear();
+        }
+        Ok(())
+    }
+}
+
+/// Reads bits from an AsyncRead, least significant bit of each byte
+/// first, like io::BitReader.
+pub struct AsyncBitReader<R> {
+    input: R,
+    buf: Vec<u8>,
+    buf_pos: usize,
+    buf_len: usize,
+    /// Bits that have been read from input but not consumed yet.
+    bits: u64,
+    have_bits: u32,
+    position: u64,
+}
+
+impl<R: AsyncRead + Unpin> AsyncBitReader<R> {
+    pub fn new(input: R) -> AsyncBitReader<R> {
+        AsyncBitReader {
+            input,
+            buf: vec![0; ASYNC_BUFFER],
+            buf_pos: 0,
+            buf_len: 0,
+            bits: 0,
+            have_bits: 0,
+            position: 0,
+        }
+    }
+
+    /// Reads input until at least nbits bits are available or the input
+    /// ends. Returns whether nbits bits are available.
+    async fn fill(&mut self, nbits: u32) -> BitResult<bool> {
+        assert!(nbits <= 32, "cannot read more than 32 bits at once");
+        while self.have_bits < nbits {
+            if self.buf_pos == self.buf_len {
+                self.buf_len = self.input.read(&mut self.buf).await?;
+                self.buf_pos = 0;
+                if self.buf_len == 0 {
+                    return Ok(false);
+                }
+            }
+            self.bits |= (self.buf[self.buf_pos] as u64) << self.have_bits;
+            self.buf_pos += 1;
+            self.have_bits += 8;
+        }
+        Ok(true)
+    }
+
+    /// Consumes nbits of the available bits.
+    fn take(&mut self, nbits: u32) -> u32 {
+        let bits = self.bits & ((1u64 << nbits) - 1);
+        self.bits >>= nbits;
+        self.have_bits -= nbits;
+        self.position += nbits as u64;
+        bits as u32
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncReadBits for AsyncBitReader<R> {
+    async fn read_bits(&mut self, nbits: u32) -> BitResult<u32> {
+        if !self.fill(nbits).await? {
+            return Err(BitReadError::UnexpectedEof { bits_needed: nbits - self.have_bits });
+        }
+        Ok(self.take(nbits))
+    }
+
+    async fn try_read_bits(&mut self, nbits: u32) -> BitResult<Option<u32>> {
+        if !self.fill(nbits).await? && self.have_bits == 0 {
+            return Ok(None);
+        }
+        self.read_bits(nbits).await.map(Some)
+    }
+
+    async fn peek_bits(&mut self, nbits: u32) -> BitResult<u32> {
+        self.fill(nbits).await?;
+        // Bits above have_bits are always 0.
+        Ok((self.bits & ((1u64 << nbits) - 1)) as u32)
+    }
+
+    fn bit_position(&self) -> u64 { self.position }
+}
+
+/// Writes bits to an AsyncWrite, least significant bit of each byte
+/// first, like io::BitWriter. Call flush when done.
+pub struct AsyncBitWriter<W> {
+    output: W,
+    buf: Vec<u8>,
+    bits: u8,
+    have_bits: u8,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncBitWriter<W> {
+    pub fn new(output: W) -> AsyncBitWriter<W> {
+        AsyncBitWriter { output, buf: Vec::with_capacity(ASYNC_BUFFER), bits: 0, have_bits: 0 }
+    }
+
+    /// Returns the writer.
+    pub fn into_inner(self) -> W { self.output }
+
+    /// Appends a byte to the buffer, writing the buffer out when it is
+    /// full.
+    async fn push_byte(&mut self, b: u8) -> BoxResult<()> {
+        self.buf.push(b);
+        if self.buf.len() == ASYNC_BUFFER {
+            self.output.write_all(&self.buf).await?;
+            self.buf.clear();
+        }
+        Ok(())
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWriteBits for AsyncBitWriter<W> {
+    /// Writes any partial byte and all buffered output to the writer,
+    /// and flushes it.
+    async fn flush(&mut self) -> BoxResult<()> {
+        self.align_to_byte().await?;
+        self.output.write_all(&self.buf).await?;
+        self.buf.clear();
+        self.output.flush().await?;
+        Ok(())
+    }
+
+    async fn write_bits(&mut self, bits: u32, nbits: u8) -> BoxResult<()> {
+        let mut bits = bits;
+        let mut nbits = nbits;
+        while nbits > 0 {
+            self.bits |= (((bits as u64) << self.have_bits) & 0xff) as u8;
+            let need_bits = 8 - self.have_bits;
+            if nbits < need_bits {
+                self.have_bits += nbits;
+                break;
+            }
+            self.push_byte(self.bits).await?;
+            self.bits = 0;
+            self.have_bits = 0;
+            bits = ((bits as u64) >> need_bits) as u32;
+            nbits -= need_bits;
+        }
+        Ok(())
+    }
+
+    async fn align_to_byte(&mut self) -> BoxResult<()> {
+        if self.have_bits > 0 {
+            self.push_byte(self.bits).await?;
+            self.bits = 0;
+            self.have_bits = 0;
+        }
+        Ok(())
+    }
+}
+
+/// Runs a future that never has to wait, such as one that only does IO
+/// on in-memory buffers, to completion.
+#[cfg(test)]
+pub(crate) fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let mut context = std::task::Context::from_waker(std::task::Waker::noop());
+    loop {
+        if let std::task::Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_io() {
+        let data: Vec<u8> = (0..20000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let (_, output) = block_on(async {
+            let mut io = AsyncStreamIO::new(&data[..], Vec::new());
+            io.copy_bytes(10000).await.unwrap();
+            assert_eq!(io.next_byte().await.unwrap(), Some(data[10000]));
+            io.write_byte(b'x').await.unwrap();
+            assert!(io.copy_bytes(10000).await.is_err());
+            assert_eq!(io.next_byte().await.unwrap(), None);
+            assert_eq!(io.inpos(), 20000);
+            io.finish().await.unwrap()
+        });
+        assert_eq!(output.len(), 20000);
+        assert_eq!(&output[..10000], &data[..10000]);
+        assert_eq!(&output[10001..], &data[10001..]);
+    }
+
+    #[test]
+    fn bits() {
+        let output = block_on(async {
+            let mut writer = AsyncBitWriter::new(Vec::new());
+            writer.write_bits(2, 2).await.unwrap();
+            writer.write_bits(7, 3).await.unwrap();
+            writer.write_bits(9, 4).await.unwrap();
+            writer.write_bits(0xdeadbeef, 32).await.unwrap();
+            writer.flush().await.unwrap();
+            writer.into_inner()
+        });
+        assert_eq!(&output[..2], [0x3e, 0xdf]);
+        block_on(async {
+            let mut reader = AsyncBitReader::new(&output[..]);
+            assert_eq!(reader.read_bits(2).await.unwrap(), 2);
+            assert_eq!(reader.read_bits(3).await.unwrap(), 7);
+            assert_eq!(reader.read_bits(4).await.unwrap(), 9);
+            assert_eq!(reader.read_bits(32).await.unwrap(), 0xdeadbeef);
+            assert_eq!(reader.bit_position(), 41);
+            assert_eq!(reader.read_bits(7).await.unwrap(), 0);
+            assert!(reader.read_bits(1).await.is_err());
+        });
+    }
+
+    #[test]
+    fn bits_surface() {
+        let output = block_on(async {
+            let mut writer = AsyncBitWriter::new(Vec::new());
+            writer.write_bits(5, 3).await.unwrap();
+            writer.align_to_byte().await.unwrap();
+            writer.write_bits64(0x0123_4567_89ab_cdef, 64).await.unwrap();
+            writer.write_bits(1, 1).await.unwrap();
+            writer.flush().await.unwrap();
+            writer.into_inner()
+        });
+        assert_eq!(output.len(), 10);
+        block_on(async {
+            let mut reader = AsyncBitReader::new(&output[..]);
+            assert_eq!(reader.peek_bits(3).await.unwrap(), 5);
+            assert_eq!(reader.read_bits(3).await.unwrap(), 5);
+            reader.align_to_byte().await.unwrap();
+            assert_eq!(reader.bit_position(), 8);
+            assert_eq!(reader.read_bits64(64).await.unwrap(), 0x0123_4567_89ab_cdef);
+            // Bits past the end of the input peek as 0.
+            assert_eq!(reader.peek_bits(32).await.unwrap(), 1);
+            assert_eq!(reader.try_read_bits(8).await.unwrap(), Some(1));
+            assert_eq!(reader.try_read_bits(8).await.unwrap(), None);
+            assert_eq!(reader.bit_position(), 80);
+        });
+        // Running out in the middle of a read is still an error.
+        block_on(async {
+            let mut reader = AsyncBitReader::new(&output[..]);
+            reader.read_bits(4).await.unwrap();
+            for _ in 0..9 {
+                reader.read_bits(8).await.unwrap();
+            }
+            assert!(reader.try_read_bits(8).await.is_err());
+        });
+    }
+}
@@ -44,250 +44,3716 @@
 // Result in copying 6 (134 - 128) bytes, starting from 4 (1 + 3) bytes
 // ago. If the last 4 bytes we had decoded were "1234", then the
 // text resulting from the backreference would be "123412".
+//
+// Version 2 of the format (decode_v2 and encode_v2) keeps the same
+// structure, but splits backreferences into several token types.
+// The lead byte is interpreted as follows:
+//
+//   +--------+
+//   |0xxxxxxx|  literal run of xxxxxxx bytes (1 to 127)
+//   +--------+
+//   |00000000|  stored block
+//   +--------+
+//   |1kkkllll|  backreference
+//   +--------+
+//
+// For backreferences, llll is the length minus 3. If llll is 15, the
+// lead byte is followed by a byte e and the length is 18 + e. The
+// kind bits kkk determine where the distance comes from:
+//
+//   000  The distance follows in two bytes, as in version 1.
+//   001  rep0: the distance of the most recent backreference.
+//   010  rep1: the second most recent distinct distance.
+//   011  rep2: the third most recent distinct distance.
+//   100  The distance follows in a single byte. Most backreferences
+//        in typical data are short, and this saves a byte for them.
+//
+// Other values of kkk are reserved. The decoder keeps the three most
+// recently used distances, most recent first, all initialized to 0.
+// A backreference with an explicit distance, in one or two bytes,
+// pushes that distance to the front. Using rep1 or rep2 moves that
+// distance to the front. Structured data (records, log lines, tables)
+// tends to repeat the same few distances, which these tokens encode in
+// a single byte.
+//
+// A stored block consists of the lead byte 0, two bytes giving the
+// length n of the block (least significant byte first), and n bytes
+// that are copied to the output as-is. The encoder emits stored blocks
+// for parts of the input that would otherwise expand, such as random
+// or already compressed data. Stored blocks do not affect the recently
+// used distances.
+//
+// Streams produced by encode start with a version byte, 0x80 plus the
+// version number, unless they target version 1. A stream in version 1
+// of the format cannot start with a byte of 0x80 or more, since that
+// would be a backreference without any earlier output, so decode can
+// tell the difference and accepts both. Streams without a version byte
+// are produced by encode_v2 and decoded by decode_v2.
+//
+// Framed streams (encode_framed and decode_framed) wrap a stream in
+// either version of the format with a header and an end marker. The
+// header consists of:
+//
+//   4 bytes   magic: "BREF"
+//   1 byte    version of the format used for the stream (1 or 2)
+//   1 byte    flags
+//   8 bytes   length of the uncompressed data, least significant
+//             byte first
+//
+// The end marker is a lead byte of 0 in version 1 (a literal run of
+// length 0), and a stored block of length 0 in version 2. Neither is
+// ever produced by the encoders, and both are no-ops when decoding
+// unframed streams. The decoder checks that the stream ends with the
+// end marker and that the uncompressed length matches the header.
+//
+// The only flag defined so far is 0x01, which indicates that the end
+// marker is followed by the CRC-32 of the uncompressed data, least
+// significant byte first. The decoder checks it against the CRC-32 of
+// the data it produced, so that corruption that still decodes is
+// detected. Other flags are reserved and must be 0.
+
+use std::convert::{TryFrom, TryInto};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::crc32::Crc32;
+use crate::io::{InputLimitExceeded, IOTrait, LookbackInput, NeedsInput, OutputFull,
+                OutputLimitExceeded, PushDecode, PushIO, PushStatus, RepeatOutput, SliceIO,
+                SliceToVecIO, StreamIO, TeeIO, WindowedLookback};
+use crate::result::BoxResult;
+use crate::tans;
+
+/// Version of the format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FormatVersion {
+    /// The original format, which can be decoded by every version of
+    /// this library.
+    V1,
+    /// Adds repeat-distance codes, longer backreferences and stored blocks.
+    V2,
+}
+
+impl FormatVersion {
+    /// Returns the version number stored in version bytes and frame
+    /// headers.
+    pub fn number(self) -> u8 {
+        match self {
+            FormatVersion::V1 => 1,
+            FormatVersion::V2 => 2,
+        }
+    }
+
+    /// Returns the version with the given number, if it is supported.
+    pub fn from_number(number: u8) -> Option<FormatVersion> {
+        match number {
+            1 => Some(FormatVersion::V1),
+            2 => Some(FormatVersion::V2),
+            _ => None,
+        }
+    }
+}
+
+/// Bit set in the version byte at the start of a stream.
+const VERSION_MARKER: u8 = 0x80;
+
+/// Part of a stream that was being decoded when an error occurred.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    /// The version byte at the start of a stream.
+    Version,
+    /// The header of a framed stream.
+    FrameHeader,
+    /// A literal run.
+    Literals,
+    /// A backreference.
+    Backreference,
+    /// A version 2 stored block.
+    Stored,
+    /// The end marker of a framed stream.
+    EndMarker,
+    /// The checksum at the end of a framed stream.
+    Checksum,
+}
+
+impl std::fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            TokenKind::Version => "version byte",
+            TokenKind::FrameHeader => "frame header",
+            TokenKind::Literals => "literal run",
+            TokenKind::Backreference => "backreference",
+            TokenKind::Stored => "stored block",
+            TokenKind::EndMarker => "end marker",
+            TokenKind::Checksum => "checksum",
+        })
+    }
+}
+
+/// Error returned when decoding malformed input.
+#[derive(Debug)]
+pub struct DecodeError {
+    /// Input offset of the first byte of the token.
+    pub offset: u64,
+    /// Kind of token that could not be decoded.
+    pub token: TokenKind,
+    /// What is wrong with the token.
+    pub message: String,
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} in {} at input offset {}", self.message, self.token, self.offset)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Returns a DecodeError for the token of the given kind at offset.
+fn decode_error(offset: u64, token: TokenKind, message: String) -> Box<dyn std::error::Error> {
+    Box::new(DecodeError { offset, token, message })
+}
+
+/// Turns an error from decoding the token of the given kind at offset
+/// into a DecodeError. NeedsInput is passed through, so that PushIO can
+/// retry the token, and so are errors about exceeding input or output
+/// limits, which do not mean the input is malformed.
+fn at<T>(result: BoxResult<T>, offset: u64, token: TokenKind) -> BoxResult<T> {
+    result.map_err(|e| {
+        if e.is::<NeedsInput>() || e.is::<OutputFull>() || e.is::<OutputLimitExceeded>()
+            || e.is::<InputLimitExceeded>() {
+            e
+        } else {
+            decode_error(offset, token, e.to_string())
+        }
+    })
+}
+
+/// Decodes a stream produced by encode, in either version of the format.
+pub fn decode<IO: IOTrait + RepeatOutput>(io: &mut IO) -> BoxResult<()> {
+    let first = match io.next_byte()? {
+        Some(b) => b,
+        None => return Ok(()),
+    };
+    if first < VERSION_MARKER {
+        decode_v1_tokens(io, false, Some(first))?;
+        return Ok(());
+    }
+    match FormatVersion::from_number(first & !VERSION_MARKER) {
+        Some(FormatVersion::V1) => decode_v1_tokens(io, false, None)?,
+        Some(FormatVersion::V2) => decode_v2_tokens(io, false, None)?,
+        None => return Err(decode_error(io.inpos() - 1, TokenKind::Version,
+                                        format!("unsupported format version {}",
+                                                first & !VERSION_MARKER))),
+    };
+    Ok(())
+}
+
+/// Returns first if it is Some, and otherwise reads the next byte.
+fn first_or_next<IO: IOTrait>(io: &mut IO, first: &mut Option<u8>) -> BoxResult<Option<u8>> {
+    match first.take() {
+        Some(b) => Ok(Some(b)),
+        None => io.next_byte(),
+    }
+}
+
+/// Decodes a version 1 stream, starting with the lead byte first if it
+/// has already been read. If framed is true, stops at the end marker
+/// and returns an error if there is none. Returns the number of bytes of
+/// output produced.
+fn decode_v1_tokens<IO: IOTrait + RepeatOutput>(io: &mut IO, framed: bool, first: Option<u8>)
+                                                -> BoxResult<u64> {
+    let mut first = first;
+    let mut outlen = 0;
+    while let Some(b) = first_or_next(io, &mut first)? {
+        if b == 0 && framed {
+            return Ok(outlen);
+        }
+        outlen += decode_v1_token(io, b)?;
+    }
+    if framed {
+        return Err(decode_error(io.inpos(), TokenKind::EndMarker,
+                                "missing end marker".to_string()));
+    }
+    Ok(outlen)
+}
+
+/// Decodes the rest of a version 1 token with lead byte b. Returns the
+/// number of bytes of output produced.
+fn decode_v1_token<IO: IOTrait + RepeatOutput>(io: &mut IO, b: u8) -> BoxResult<u64> {
+    let offset = io.inpos() - 1;
+    if b < 128 {
+        at(io.copy_bytes(b as usize), offset, TokenKind::Literals)?;
+        Ok(b as u64)
+    } else {
+        at(decode_v1_backref(io, b), offset, TokenKind::Backreference)?;
+        Ok((b & 0x7f) as u64)
+    }
+}
+
+/// Decodes the rest of a version 1 backreference with lead byte b.
+fn decode_v1_backref<IO: IOTrait + RepeatOutput>(io: &mut IO, b: u8) -> BoxResult<()> {
+    let lo = next_required(io)?;
+    let hi = next_required(io)?;
+    let dist = ((hi as usize) << 8) | lo as usize;
+    io.repeat_bytes((b & 0x7f) as usize, dist)
+}
+
+/// Lead byte of a version 2 stored block.
+const V2_STORED: u8 = 0;
+
+/// Lowest lead byte of a version 2 backreference. The kind and length
+/// bits are or-ed into this.
+const V2_MATCH: u8 = 0x80;
+
+/// Kind bits for a version 2 backreference with an explicit distance.
+const V2_KIND_DIST: u8 = 0;
+
+/// Kind bits for a version 2 rep0 backreference. rep1 and rep2 follow.
+const V2_KIND_REP0: u8 = 1;
+
+/// Kind bits for a version 2 backreference with a one-byte distance.
+const V2_KIND_SHORT: u8 = 4;
+
+/// Value of the length bits indicating that an extra length byte follows.
+const V2_LEN_EXTENDED: u8 = 15;
+
+/// Shortest backreference that can be encoded.
+const V2_MIN_MATCH: u64 = 3;
+
+/// Longest backreference that can be encoded in version 2 of the format.
+const V2_MAX_MATCH: u64 = 18 + 255;
+
+/// Largest distance that can be encoded.
+const MAX_DIST: u64 = 0xffff;
+
+/// Largest number of bytes of output a byte of input can produce: a
+/// rep backreference of the longest length takes 2 bytes.
+const MAX_EXPANSION: u64 = V2_MAX_MATCH.div_ceil(2);
+
+/// The three most recently used distances, most recent first.
+#[derive(Clone, Copy)]
+struct RecentDistances([u64; 3]);
+
+impl RecentDistances {
+    fn new() -> RecentDistances {
+        RecentDistances([0; 3])
+    }
+
+    /// Returns the index of dist in the recent distances, if present.
+    fn find(&self, dist: u64) -> Option<usize> {
+        self.0.iter().position(|&d| d == dist)
+    }
+
+    /// Moves the distance at index rep to the front and returns it.
+    fn use_rep(&mut self, rep: usize) -> u64 {
+        let dist = self.0[rep];
+        self.0.copy_within(0..rep, 1);
+        self.0[0] = dist;
+        dist
+    }
+
+    /// Records a newly used distance.
+    fn push(&mut self, dist: u64) {
+        self.0.copy_within(0..2, 1);
+        self.0[0] = dist;
+    }
+}
+
+/// Reads a byte that is required to be present.
+fn next_required<IO: IOTrait>(io: &mut IO) -> BoxResult<u8> {
+    io.next_byte()?.ok_or_else(|| "unexpected end of input".into())
+}
+
+/// Decodes a version 2 stream without a version byte, as produced by
+/// encode_v2.
+pub fn decode_v2<IO: IOTrait + RepeatOutput>(io: &mut IO) -> BoxResult<()> {
+    decode_v2_tokens(io, false, None)?;
+    Ok(())
+}
+
+/// Decodes a version 2 stream, starting with the lead byte first if it
+/// has already been read. If framed is true, stops at the end marker
+/// and returns an error if there is none. Returns the number of bytes of
+/// output produced.
+fn decode_v2_tokens<IO: IOTrait + RepeatOutput>(io: &mut IO, framed: bool, first: Option<u8>)
+                                                -> BoxResult<u64> {
+    let mut first = first;
+    let mut recent = RecentDistances::new();
+    let mut outlen = 0;
+    while let Some(b) = first_or_next(io, &mut first)? {
+        let len = decode_v2_token(io, b, &mut recent)?;
+        if b == V2_STORED && len == 0 && framed {
+            return Ok(outlen);
+        }
+        outlen += len;
+    }
+    if framed {
+        return Err(decode_error(io.inpos(), TokenKind::EndMarker,
+                                "missing end marker".to_string()));
+    }
+    Ok(outlen)
+}
+
+/// Decodes the rest of a version 2 token with lead byte b. Returns the
+/// number of bytes of output produced.
+fn decode_v2_token<IO: IOTrait + RepeatOutput>(io: &mut IO, b: u8,
+                                               recent: &mut RecentDistances)
+                                               -> BoxResult<u64> {
+    let offset = io.inpos() - 1;
+    let len = if b == V2_STORED {
+        at(decode_v2_stored(io), offset, TokenKind::Stored)?
+    } else if b < V2_MATCH {
+        at(io.copy_bytes(b as usize), offset, TokenKind::Literals)?;
+        b as usize
+    } else {
+        at(decode_v2_backref(io, b, recent), offset, TokenKind::Backreference)?
+    };
+    Ok(len as u64)
+}
+
+/// Decodes the rest of a version 2 stored block. Returns its length.
+fn decode_v2_stored<IO: IOTrait>(io: &mut IO) -> BoxResult<usize> {
+    let lo = next_required(io)?;
+    let hi = next_required(io)?;
+    let len = ((hi as usize) << 8) | lo as usize;
+    io.copy_bytes(len)?;
+    Ok(len)
+}
+
+/// Decodes the rest of a version 2 backreference with lead byte b.
+/// Returns its length.
+fn decode_v2_backref<IO: IOTrait + RepeatOutput>(io: &mut IO, b: u8,
+                                                 recent: &mut RecentDistances)
+                                                 -> BoxResult<usize> {
+    let kind = (b >> 4) & 7;
+    let mut len = (b & 0xf) as usize + V2_MIN_MATCH as usize;
+    if b & 0xf == V2_LEN_EXTENDED {
+        len += next_required(io)? as usize;
+    }
+    let dist = match kind {
+        V2_KIND_DIST => {
+            let lo = next_required(io)?;
+            let hi = next_required(io)?;
+            let dist = ((hi as u64) << 8) | lo as u64;
+            recent.push(dist);
+            dist
+        },
+        1..=3 => recent.use_rep((kind - V2_KIND_REP0) as usize),
+        V2_KIND_SHORT => {
+            let dist = next_required(io)? as u64;
+            recent.push(dist);
+            dist
+        },
+        _ => return Err(format!("reserved backreference kind {}", kind).into()),
+    };
+    io.repeat_bytes(len, dist as usize)?;
+    Ok(len)
+}
+
+/// Magic bytes at the start of a framed stream.
+const FRAME_MAGIC: &[u8; 4] = b"BREF";
+
+/// Frame flag indicating that the stream ends in a CRC-32 of the
+/// uncompressed data.
+const FRAME_CHECKSUM: u8 = 0x01;
+
+/// Reads and validates a frame header. Returns the version of the
+/// format, the flags, and the uncompressed length.
+fn read_frame_header<IO: IOTrait>(io: &mut IO) -> BoxResult<(FormatVersion, u8, u64)> {
+    let offset = io.inpos();
+    at(read_frame_header_fields(io), offset, TokenKind::FrameHeader)
+}
+
+/// Reads the fields of a frame header. See read_frame_header.
+fn read_frame_header_fields<IO: IOTrait>(io: &mut IO) -> BoxResult<(FormatVersion, u8, u64)> {
+    let mut header = [0u8; 14];
+    for b in header.iter_mut() {
+        *b = io.next_byte()?.ok_or("end of input inside frame header")?;
+    }
+    if &header[..4] != FRAME_MAGIC {
+        return Err("not a framed backref stream".into());
+    }
+    let version = FormatVersion::from_number(header[4])
+        .ok_or_else(|| format!("unsupported format version {}", header[4]))?;
+    if header[5] & !FRAME_CHECKSUM != 0 {
+        return Err(format!("unsupported frame flags {:#x}", header[5]).into());
+    }
+    Ok((version, header[5], u64::from_le_bytes(header[6..].try_into().unwrap())))
+}
+
+/// Decodes the tokens of a framed stream up to and including the end
+/// marker. Returns the number of bytes of output produced.
+fn decode_frame_tokens<IO: IOTrait + RepeatOutput>(io: &mut IO, version: FormatVersion)
+                                                   -> BoxResult<u64> {
+    match version {
+        FormatVersion::V1 => decode_v1_tokens(io, true, None),
+        FormatVersion::V2 => decode_v2_tokens(io, true, None),
+    }
+}
+
+/// Decodes a framed stream produced by encode_framed, verifying the
+/// checksum if the stream has one.
+pub fn decode_framed<IO: IOTrait + RepeatOutput>(io: &mut IO) -> BoxResult<()> {
+    let (version, flags, len) = read_frame_header(io)?;
+    // The length comes from the input, so only trust it as far as the
+    // rest of the input can produce that much output.
+    if let Some(remaining) = io.remaining_input() {
+        let len = std::cmp::min(len, remaining.saturating_mul(MAX_EXPANSION));
+        io.reserve_output(len.try_into().unwrap_or(usize::MAX));
+    }
+    let (outlen, crc) = if flags & FRAME_CHECKSUM != 0 {
+        let mut checksum_io = TeeIO::new(&mut *io, Crc32::new(), WINDOW_SIZE);
+        let outlen = decode_frame_tokens(&mut checksum_io, version)?;
+        (outlen, Some(checksum_io.sink().value()))
+    } else {
+        (decode_frame_tokens(io, version)?, None)
+    };
+    if outlen != len {
+        return Err(decode_error(io.inpos() - 1, TokenKind::EndMarker,
+                                format!("decoded {} bytes, but frame header says {}",
+                                        outlen, len)));
+    }
+    if let Some(crc) = crc {
+        let offset = io.inpos();
+        let mut stored = [0u8; 4];
+        for b in stored.iter_mut() {
+            *b = at(next_required(io), offset, TokenKind::Checksum)?;
+        }
+        let stored = u32::from_le_bytes(stored);
+        if stored != crc {
+            return Err(decode_error(offset, TokenKind::Checksum,
+                                    format!("checksum {:#010x} does not match decoded data \
+                                             ({:#010x})", stored, crc)));
+        }
+    }
+    Ok(())
+}
+
+/// Number of bytes of output a decoder must keep to resolve every
+/// backreference the format can express.
+pub const WINDOW_SIZE: usize = MAX_DIST as usize + 1;
+
+/// Decodes a stream produced by encode from input, writing the result to
+/// output. Only the last WINDOW_SIZE bytes of output are kept in memory.
+pub fn decode_windowed(input: &mut dyn std::io::Read, output: &mut dyn std::io::Write)
+                       -> BoxResult<()> {
+    let mut io = StreamIO::with_output_window(input, output, WINDOW_SIZE);
+    decode(&mut io)?;
+    io.flush()
+}
+
+/// Decodes a framed stream produced by encode_framed from input, writing
+/// the result to output. Only the last WINDOW_SIZE bytes of output are
+/// kept in memory.
+pub fn decode_framed_windowed(input: &mut dyn std::io::Read, output: &mut dyn std::io::Write)
+                              -> BoxResult<()> {
+    let mut io = StreamIO::with_output_window(input, output, WINDOW_SIZE);
+    decode_framed(&mut io)?;
+    io.flush()
+}
+
+/// What a PushDecoder expects next.
+enum PushState {
+    /// The version byte or, for a version 1 stream without one, the
+    /// first token.
+    Start,
+    V1,
+    V2(RecentDistances),
+}
+
+/// Decodes a stream produced by encode from input that is pushed to it
+/// in chunks of any size, for use with non-blocking and event-driven
+/// sources.
+pub struct PushDecoder {
+    io: PushIO,
+    state: PushState,
+}
+
+impl PushDecoder {
+    pub fn new() -> PushDecoder {
+        PushDecoder { io: PushIO::new(WINDOW_SIZE), state: PushState::Start }
+    }
+
+    /// Decodes as much of the input pushed so far, plus data, as
+    /// possible. Returns PushStatus::NeedsInput, since the decoder cannot
+    /// tell whether the stream has ended until finish is called.
+    pub fn push_input(&mut self, data: &[u8]) -> BoxResult<PushStatus> {
+        self.io.push_input(data);
+        let state = &mut self.state;
+        self.io.run(|io| push_step(io, state))
+    }
+
+    /// Signals the end of the input and decodes the rest of it. Returns
+    /// an error if the stream is incomplete.
+    pub fn finish(&mut self) -> BoxResult<()> {
+        self.io.finish_input();
+        let state = &mut self.state;
+        self.io.run(|io| push_step(io, state))?;
+        Ok(())
+    }
+
+    /// Returns the output produced since the last call.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.io.take_output()
+    }
+}
+
+impl Default for PushDecoder {
+    fn default() -> PushDecoder {
+        PushDecoder::new()
+    }
+}
+
+impl PushDecode for PushDecoder {
+    fn push_input(&mut self, data: &[u8]) -> BoxResult<PushStatus> {
+        PushDecoder::push_input(self, data)
+    }
+
+    fn finish(&mut self) -> BoxResult<()> {
+        PushDecoder::finish(self)
+    }
+
+    fn take_output(&mut self) -> Vec<u8> {
+        PushDecoder::take_output(self)
+    }
+}
+
+/// Wraps a stream produced by encode and implements std::io::Read,
+/// decoding on demand. Create one with DecodeReader::new(input).
+pub type DecodeReader<R> = crate::io::DecodeReader<R, PushDecoder>;
+
+/// Decodes a single token for PushDecoder. Returns false at the end of
+/// the input.
+fn push_step(io: &mut PushIO, state: &mut PushState) -> BoxResult<bool> {
+    let b = match io.next_byte()? {
+        Some(b) => b,
+        None => return Ok(false),
+    };
+    match state {
+        PushState::Start if b < VERSION_MARKER => {
+            decode_v1_token(io, b)?;
+            *state = PushState::V1;
+        },
+        PushState::Start => {
+            *state = match FormatVersion::from_number(b & !VERSION_MARKER) {
+                Some(FormatVersion::V1) => PushState::V1,
+                Some(FormatVersion::V2) => PushState::V2(RecentDistances::new()),
+                None => return Err(decode_error(io.inpos() - 1, TokenKind::Version,
+                                                format!("unsupported format version {}",
+                                                        b & !VERSION_MARKER))),
+            };
+        },
+        PushState::V1 => {
+            decode_v1_token(io, b)?;
+        },
+        PushState::V2(recent) => {
+            // Work on a copy, so that the recent distances are unchanged
+            // if the token is retried.
+            let mut next = *recent;
+            decode_v2_token(io, b, &mut next)?;
+            *recent = next;
+        },
+    }
+    Ok(true)
+}
+
+/// Longest backreference that can be encoded in version 1 of the format.
+const V1_MAX_MATCH: u64 = 127;
+
+/// Longest literal run that can be encoded.
+const MAX_LITERALS: u64 = 127;
+
+/// Strategy used to choose between literals and backreferences.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseStrategy {
+    /// Take the most profitable backreference at the current position.
+    Greedy,
+    /// Like Greedy, but emit a literal instead if the next position
+    /// starts a more profitable backreference.
+    Lazy,
+    /// Choose the cheapest sequence of literals and backreferences for
+    /// each block of input using dynamic programming.
+    Optimal,
+}
+
+/// A candidate backreference offered to a match filter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Candidate {
+    /// Input position at which the backreference starts.
+    pub pos: u64,
+    /// Number of bytes covered by the backreference.
+    pub len: u64,
+    /// How far back the repeated bytes start; 1 is the previous byte.
+    pub distance: u64,
+}
+
+/// Callback consulted for every candidate backreference. Returning None
+/// vetoes the candidate. Returning Some(bonus) accepts it, and bonus is
+/// added to the number of bytes the parser expects the backreference to
+/// save, so that a positive bonus favours the candidate and a negative
+/// one penalizes it.
+///
+/// The greedy and lazy parsers offer each candidate at the longest
+/// length found. The optimal parser also offers shorter lengths.
+pub type MatchFilter = Arc<dyn Fn(&Candidate) -> Option<i64> + Send + Sync>;
+
+/// Number of cost units per bit. Costs are fixed-point numbers so that
+/// cost models based on entropy coding can express fractions of bits.
+pub const COST_SCALE: u32 = 16;
+
+/// Cost of a byte that is output as is.
+const BYTE_COST: u32 = 8 * COST_SCALE;
+
+/// Estimates the cost of tokens after any further encoding of the output,
+/// in units of 1 / COST_SCALE bits. The parser minimizes the total cost
+/// of the tokens it writes.
+pub trait TokenCost: Send + Sync {
+    /// Returns the cost of literal byte b in a literal run.
+    fn literal(&self, b: u8) -> u32;
+
+    /// Returns the cost of the byte that starts a literal run.
+    fn literal_run(&self) -> u32;
+
+    /// Returns the cost of a backreference, given its encoded bytes.
+    fn backreference(&self, token: &[u8]) -> u32;
+}
+
+/// Counts every byte of output as 8 bits. This is the default cost model.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ByteCost;
+
+impl TokenCost for ByteCost {
+    fn literal(&self, _b: u8) -> u32 { BYTE_COST }
+
+    fn literal_run(&self) -> u32 { BYTE_COST }
+
+    fn backreference(&self, token: &[u8]) -> u32 { token.len() as u32 * BYTE_COST }
+}
+
+/// Cost of a byte that cannot be encoded by the tANS table.
+const MAX_BYTE_COST: u32 = 64 * COST_SCALE;
+
+/// Cost model for output that will be entropy-coded byte by byte using
+/// tANS with a known frequency table.
+#[derive(Clone, Debug)]
+pub struct TansCost {
+    /// Cost of each byte value.
+    costs: Vec<u32>,
+    /// Expected cost of the byte that starts a literal run.
+    run: u32,
+}
+
+impl TansCost {
+    /// Creates a cost model for a tANS table with 2**sbits states and
+    /// freqs[b] occurrences of each byte value b.
+    pub fn new(sbits: u32, freqs: &[u32]) -> TansCost {
+        assert_eq!(freqs.len(), 256, "freqs must have an entry for every byte value");
+        let costs: Vec<u32> = tans::symbol_costs(sbits, freqs).iter()
+            .map(|&bits| (bits * COST_SCALE as f64).round().min(MAX_BYTE_COST as f64) as u32)
+            .collect();
+        // The length of a literal run is not known when the parser needs
+        // the cost of starting one, so use the average over all lengths.
+        let runs = 1..=MAX_LITERALS as usize;
+        let total: u64 = freqs[runs.clone()].iter().map(|&f| f as u64).sum();
+        let weighted: u64 = freqs[runs.clone()].iter().zip(&costs[runs])
+            .map(|(&f, &c)| f as u64 * c as u64).sum();
+        let run = weighted.checked_div(total).map_or(MAX_BYTE_COST, |run| run as u32);
+        TansCost { costs, run }
+    }
+}
+
+impl TokenCost for TansCost {
+    fn literal(&self, b: u8) -> u32 { self.costs[b as usize] }
+
+    fn literal_run(&self) -> u32 { self.run }
+
+    fn backreference(&self, token: &[u8]) -> u32 {
+        token.iter().map(|&b| self.costs[b as usize]).sum()
+    }
+}
+
+/// Hashes a short sequence of input bytes to an index into a hash table
+/// with 2**bits entries.
+pub type HashFunction = fn(bytes: &[u8], bits: u32) -> usize;
+
+/// Number of bytes hashed to find backreferences.
+const MIN_HASH_LEN: usize = 3;
+
+/// Maximum number of bytes hashed for the secondary hash table.
+const MAX_LONG_HASH_LEN: usize = 8;
+
+/// Default for EncodeOptions::bucket_size.
+const DEFAULT_BUCKET_SIZE: usize = 4;
+
+/// Maximum for EncodeOptions::bucket_size.
+const MAX_BUCKET_SIZE: usize = 64;
+
+/// Default for EncodeOptions::skip_trigger.
+const DEFAULT_SKIP_TRIGGER: u32 = 6;
+
+/// Multiplicative (Fibonacci) hash. This is the default hash function.
+pub fn multiplicative_hash(bytes: &[u8], bits: u32) -> usize {
+    let mut x: u64 = 0;
+    for &b in bytes {
+        x = (x << 8) | b as u64;
+    }
+    (x.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (64 - bits)) as usize
+}
+
+/// Hash that shifts each byte by 5 bits and combines them using xor.
+/// This is what the encoder used originally.
+pub fn shift_xor_hash(bytes: &[u8], bits: u32) -> usize {
+    let mut x: usize = 0;
+    for &b in bytes {
+        x = (x << 5) ^ b as usize;
+    }
+    x & ((1 << bits) - 1)
+}
+
+/// Options for encode and encode_v2.
+///
+/// ```
+/// use compression_toolkit::backref::{EncodeOptions, ParseStrategy};
+///
+/// let options = EncodeOptions::new()
+///     .strategy(ParseStrategy::Lazy)
+///     .window_size(4096);
+/// ```
+#[derive(Clone)]
+pub struct EncodeOptions {
+    version: FormatVersion,
+    strategy: ParseStrategy,
+    window_size: u64,
+    min_match: u64,
+    hash_bits: u32,
+    hash: HashFunction,
+    long_hash_len: usize,
+    bucket_size: usize,
+    skip_trigger: u32,
+    checksum: bool,
+    filter: Option<MatchFilter>,
+    cost: Arc<dyn TokenCost>,
+}
+
+impl EncodeOptions {
+    /// Returns the default options: version 2 of the format, greedy
+    /// parsing, the largest window the format supports, a minimum match
+    /// length of 3, a single hash table with 2**14 buckets of 4 positions
+    /// using multiplicative_hash, skipping ahead after 2**6 failed match
+    /// attempts, and the ByteCost cost model.
+    pub fn new() -> EncodeOptions {
+        EncodeOptions {
+            version: FormatVersion::V2,
+            strategy: ParseStrategy::Greedy,
+            window_size: MAX_DIST + 1,
+            min_match: V2_MIN_MATCH,
+            hash_bits: 14,
+            hash: multiplicative_hash,
+            long_hash_len: 0,
+            bucket_size: DEFAULT_BUCKET_SIZE,
+            skip_trigger: DEFAULT_SKIP_TRIGGER,
+            checksum: false,
+            filter: None,
+            cost: Arc::new(ByteCost),
+        }
+    }
+
+    /// Sets the version of the format encode and encode_framed produce.
+    /// Use FormatVersion::V1 to produce streams that older decoders can
+    /// read.
+    pub fn version(mut self, version: FormatVersion) -> EncodeOptions {
+        self.version = version;
+        self
+    }
+
+    /// Sets the parse strategy.
+    pub fn strategy(mut self, strategy: ParseStrategy) -> EncodeOptions {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Sets the number of most recent bytes backreferences can refer to.
+    /// This is clamped to the range 1 to 65536.
+    pub fn window_size(mut self, window_size: u64) -> EncodeOptions {
+        self.window_size = window_size.clamp(1, MAX_DIST + 1);
+        self
+    }
+
+    /// Sets the shortest backreference the encoder will emit. Values
+    /// below 3 are raised to 3.
+    pub fn min_match(mut self, min_match: u64) -> EncodeOptions {
+        self.min_match = std::cmp::max(min_match, V2_MIN_MATCH);
+        self
+    }
+
+    /// Sets the size of the hash table used to find repetitions to
+    /// 2**hash_bits buckets. This is clamped to the range 8 to 24.
+    pub fn hash_bits(mut self, hash_bits: u32) -> EncodeOptions {
+        self.hash_bits = hash_bits.clamp(8, 24);
+        self
+    }
+
+    /// Sets the function used to hash input bytes.
+    pub fn hash_function(mut self, hash: HashFunction) -> EncodeOptions {
+        self.hash = hash;
+        self
+    }
+
+    /// Enables a secondary hash table that hashes sequences of len bytes,
+    /// so that longer matches are found first and hash collisions are
+    /// less frequent. len is clamped to the range 4 to 8. A len of 0
+    /// disables the secondary table, which is the default.
+    pub fn long_hash_len(mut self, len: usize) -> EncodeOptions {
+        self.long_hash_len = if len == 0 {
+            0
+        } else {
+            len.clamp(MIN_HASH_LEN + 1, MAX_LONG_HASH_LEN)
+        };
+        self
+    }
+
+    /// Sets the number of positions kept for each hash, clamped to the
+    /// range 1 to 64. All of them are checked for matches, so larger
+    /// buckets find longer backreferences at the cost of speed and
+    /// memory.
+    pub fn bucket_size(mut self, size: usize) -> EncodeOptions {
+        self.bucket_size = size.clamp(1, MAX_BUCKET_SIZE);
+        self
+    }
+
+    /// Makes the greedy and lazy parsers skip ahead in regions where no
+    /// backreferences are found. After 2**bits consecutive positions
+    /// without a backreference, the parser moves ahead 2 bytes at a time,
+    /// after another 2**bits 3 bytes at a time, and so on. This makes
+    /// incompressible input much faster to encode, at the cost of missing
+    /// some backreferences. bits is clamped to the range 1 to 31. A value
+    /// of 0 disables skipping.
+    pub fn skip_trigger(mut self, bits: u32) -> EncodeOptions {
+        self.skip_trigger = if bits == 0 { 0 } else { bits.clamp(1, 31) };
+        self
+    }
+
+    /// Sets a callback that can veto or re-rank candidate backreferences.
+    /// See MatchFilter.
+    pub fn match_filter<F>(mut self, filter: F) -> EncodeOptions
+        where F: Fn(&Candidate) -> Option<i64> + Send + Sync + 'static
+    {
+        self.filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Sets whether encode_framed appends a CRC-32 of the input, which
+    /// decode_framed verifies. Off by default.
+    pub fn checksum(mut self, checksum: bool) -> EncodeOptions {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Sets the cost model the parser uses to choose between literals
+    /// and backreferences. Use this when the output will be entropy-coded
+    /// afterwards.
+    pub fn token_cost<C: TokenCost + 'static>(mut self, cost: C) -> EncodeOptions {
+        self.cost = Arc::new(cost);
+        self
+    }
+
+    /// Returns the number of bytes of input the encoder needs to be able
+    /// to look back at with these options, relative to the furthest
+    /// position it has read: a window, or a block if that is longer,
+    /// since stored blocks are copied from the input, plus the input the
+    /// optimal parser reads ahead. IOs passed to encode must have a
+    /// lookback_window at least this large.
+    pub fn lookback_needed(&self) -> u64 {
+        std::cmp::max(self.window_size, BLOCK_SIZE + OPTIMAL_BLOCK) + 2 * OPTIMAL_BLOCK
+    }
+}
+
+impl std::fmt::Debug for EncodeOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("EncodeOptions")
+            .field("version", &self.version)
+            .field("strategy", &self.strategy)
+            .field("window_size", &self.window_size)
+            .field("min_match", &self.min_match)
+            .field("hash_bits", &self.hash_bits)
+            .field("long_hash_len", &self.long_hash_len)
+            .field("bucket_size", &self.bucket_size)
+            .field("skip_trigger", &self.skip_trigger)
+            .field("checksum", &self.checksum)
+            .field("filter", &self.filter.as_ref().map(|_| "Fn"))
+            .field("cost", &"TokenCost")
+            .finish()
+    }
+}
+
+impl Default for EncodeOptions {
+    fn default() -> EncodeOptions {
+        EncodeOptions::new()
+    }
+}
+
+/// Number of buckets in EncodeStats::match_distances.
+pub const DISTANCE_BUCKETS: usize = 17;
+
+/// Statistics collected while encoding, for tuning options such as the
+/// window size and minimum match length, and for designing any further
+/// encoding of the output.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EncodeStats {
+    /// Number of bytes encoded as literals.
+    pub literal_bytes: u64,
+    /// Number of literal runs.
+    pub literal_runs: u64,
+    /// Number of bytes encoded as backreferences.
+    pub match_bytes: u64,
+    /// Number of backreferences.
+    pub matches: u64,
+    /// Number of bytes in stored blocks.
+    pub stored_bytes: u64,
+    /// Number of stored blocks.
+    pub stored_blocks: u64,
+    /// match_lengths[n] is the number of backreferences of length n.
+    pub match_lengths: Vec<u64>,
+    /// match_distances[i] is the number of backreferences with a
+    /// distance d such that 2**i <= d < 2**(i + 1). A distance of 1
+    /// refers to the previous byte.
+    pub match_distances: [u64; DISTANCE_BUCKETS],
+    /// Number of earlier positions found in the hash tables and checked
+    /// for a match.
+    pub hash_lookups: u64,
+    /// Number of those positions that did not start with the same bytes
+    /// as the current position, because their hashes collided.
+    pub hash_collisions: u64,
+}
+
+impl EncodeStats {
+    /// Returns the fraction of hash lookups that were collisions.
+    pub fn collision_rate(&self) -> f64 {
+        if self.hash_lookups == 0 {
+            0.0
+        } else {
+            self.hash_collisions as f64 / self.hash_lookups as f64
+        }
+    }
+
+    fn add_match(&mut self, m: Match) {
+        self.matches += 1;
+        self.match_bytes += m.len;
+        if self.match_lengths.len() <= m.len as usize {
+            self.match_lengths.resize(m.len as usize + 1, 0);
+        }
+        self.match_lengths[m.len as usize] += 1;
+        self.match_distances[distance_bucket(m.dist + 1)] += 1;
+    }
+
+    fn remove_match(&mut self, m: Match) {
+        self.matches -= 1;
+        self.match_bytes -= m.len;
+        self.match_lengths[m.len as usize] -= 1;
+        self.match_distances[distance_bucket(m.dist + 1)] -= 1;
+    }
+
+    /// Adds the token counts in other to self.
+    fn add_tokens(&mut self, other: &EncodeStats) {
+        self.literal_bytes += other.literal_bytes;
+        self.literal_runs += other.literal_runs;
+        self.match_bytes += other.match_bytes;
+        self.matches += other.matches;
+        if self.match_lengths.len() < other.match_lengths.len() {
+            self.match_lengths.resize(other.match_lengths.len(), 0);
+        }
+        for (n, count) in other.match_lengths.iter().enumerate() {
+            self.match_lengths[n] += count;
+        }
+        for (i, count) in other.match_distances.iter().enumerate() {
+            self.match_distances[i] += count;
+        }
+    }
+}
+
+/// Returns the index in EncodeStats::match_distances for distance.
+fn distance_bucket(distance: u64) -> usize {
+    63 - distance.leading_zeros() as usize
+}
+
+/// Returns the byte at position pos, reading input up to that position
+/// if necessary. Returns Ok(None) if pos is past the end of the input.
+fn fetch<IO: IOTrait + LookbackInput>(io: &mut IO, pos: u64) -> BoxResult<Option<u8>> {
+    while io.inpos() <= pos {
+        if io.next_byte()?.is_none() { return Ok(None) }
+    }
+    Ok(Some(io.lookback(pos)))
+}
+
+/// Returns the number of bytes, up to max, that match between the
+/// input starting at pos and the input starting at the earlier
+/// position prev.
+fn match_len<IO: IOTrait + LookbackInput>(io: &mut IO, pos: u64, prev: u64, max: u64)
+                                          -> BoxResult<u64> {
+    let mut len = 0;
+    while len < max && fetch(io, pos + len)? == Some(io.lookback(prev + len)) {
+        len += 1;
+    }
+    Ok(len)
+}
+
+fn write_lit<IO: LookbackInput>(io: &IO, out: &mut Vec<u8>, litlen: u8, start: u64) {
+    out.push(litlen);
+    for pos in start..start + litlen as u64 {
+        out.push(io.lookback(pos));
+    }
+}
+
+/// A backreference: len bytes, starting dist + 1 bytes back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Match {
+    len: u64,
+    dist: u64,
+}
+
+/// Maximum number of bytes in an encoded backreference.
+const MAX_TOKEN_LEN: usize = 4;
+
+/// An encoded backreference.
+struct Token {
+    bytes: [u8; MAX_TOKEN_LEN],
+    len: usize,
+}
+
+impl Token {
+    fn new() -> Token {
+        Token { bytes: [0; MAX_TOKEN_LEN], len: 0 }
+    }
+
+    fn push(&mut self, b: u8) {
+        self.bytes[self.len] = b;
+        self.len += 1;
+    }
+
+    fn as_slice(&self) -> &[u8] { &self.bytes[..self.len] }
+}
+
+/// Serializes backreferences in one of the versions of the format.
+/// Cloning a writer saves its state, so that it can be restored if a
+/// block is emitted as a stored block after all.
+trait TokenWriter: Clone {
+    /// Returns whether the format supports stored blocks.
+    fn stored_blocks(&self) -> bool;
+
+    /// Returns the longest backreference the format can express.
+    fn max_match(&self) -> u64;
+
+    /// Returns distances that are likely to be cheap to encode.
+    fn recent(&self) -> &[u64];
+
+    /// Returns the encoding of m in the current state.
+    fn encode_match(&self, m: Match) -> Token;
+
+    /// Updates the state after m has been written.
+    fn record_match(&mut self, m: Match);
+
+    fn write_match(&mut self, out: &mut Vec<u8>, m: Match) {
+        out.extend_from_slice(self.encode_match(m).as_slice());
+        self.record_match(m);
+    }
+}
+
+#[derive(Clone)]
+struct V1Writer {
+    /// Distance of the most recent backreference. Version 1 has no
+    /// cheaper encoding for it, but trying it lets long repetitions
+    /// continue at the same distance.
+    last: Option<u64>,
+}
+
+impl TokenWriter for V1Writer {
+    fn stored_blocks(&self) -> bool { false }
+
+    fn max_match(&self) -> u64 { V1_MAX_MATCH }
+
+    fn recent(&self) -> &[u64] { self.last.as_slice() }
+
+    fn encode_match(&self, m: Match) -> Token {
+        let mut token = Token::new();
+        token.push(0x80 + m.len as u8);
+        token.push((m.dist & 0xff) as u8);
+        token.push((m.dist >> 8) as u8);
+        token
+    }
+
+    fn record_match(&mut self, m: Match) {
+        self.last = Some(m.dist);
+    }
+}
+
+#[derive(Clone)]
+struct V2Writer {
+    /// Recently used distances, kept in sync with the decoder.
+    recent: RecentDistances,
+}
+
+impl V2Writer {
+    fn new() -> V2Writer {
+        V2Writer { recent: RecentDistances::new() }
+    }
+}
+
+impl TokenWriter for V2Writer {
+    fn stored_blocks(&self) -> bool { true }
+
+    fn max_match(&self) -> u64 { V2_MAX_MATCH }
+
+    fn recent(&self) -> &[u64] { &self.recent.0 }
+
+    fn encode_match(&self, m: Match) -> Token {
+        let kind = match self.recent.find(m.dist) {
+            Some(rep) => V2_KIND_REP0 + rep as u8,
+            None if m.dist <= 0xff => V2_KIND_SHORT,
+            None => V2_KIND_DIST,
+        };
+        let lenbits = std::cmp::min(m.len - V2_MIN_MATCH, V2_LEN_EXTENDED as u64) as u8;
+        let mut token = Token::new();
+        token.push(V2_MATCH | (kind << 4) | lenbits);
+        if lenbits == V2_LEN_EXTENDED {
+            token.push((m.len - V2_MIN_MATCH - V2_LEN_EXTENDED as u64) as u8);
+        }
+        if kind == V2_KIND_SHORT {
+            token.push(m.dist as u8);
+        } else if kind == V2_KIND_DIST {
+            token.push((m.dist & 0xff) as u8);
+            token.push((m.dist >> 8) as u8);
+        }
+        token
+    }
+
+    fn record_match(&mut self, m: Match) {
+        match self.recent.find(m.dist) {
+            Some(rep) => {
+                self.recent.use_rep(rep);
+            },
+            None => self.recent.push(m.dist),
+        }
+    }
+}
+
+/// Finds repetitions in the input.
+struct MatchFinder {
+    /// For each hash of 3 bytes, a bucket with the bucket_size most
+    /// recent positions at which we encountered it, most recent first.
+    /// Positions are stored relative to base, plus one, so that 0 means
+    /// no position.
+    head: Vec<u32>,
+
+    /// Like head, but for hashes of long_len bytes. Empty if the
+    /// secondary table is disabled.
+    head_long: Vec<u32>,
+
+    /// Position that entries in the hash tables are relative to.
+    base: u64,
+
+    /// Distance from base at which the hash tables are rebased.
+    rebase_at: u64,
+
+    /// Number of positions kept per hash.
+    bucket_size: usize,
+
+    /// Positions returned by the most recent call to insert.
+    prevs: Vec<u64>,
+
+    /// Number of positions from the hash tables checked for matches.
+    lookups: u64,
+
+    /// Number of those that did not match at least MIN_HASH_LEN bytes.
+    collisions: u64,
+
+    /// Number of bytes hashed for head_long.
+    long_len: usize,
+
+    hash: HashFunction,
+    hash_bits: u32,
+
+    /// Largest distance to consider.
+    max_dist: u64,
+
+    /// Shortest match to consider.
+    min_match: u64,
+}
+
+impl MatchFinder {
+    fn new(options: &EncodeOptions) -> MatchFinder {
+        let size = options.bucket_size << options.hash_bits;
+        let long_size = if options.long_hash_len > 0 { size } else { 0 };
+        MatchFinder {
+            head: vec![0; size],
+            head_long: vec![0; long_size],
+            base: 0,
+            rebase_at: REBASE_AT,
+            bucket_size: options.bucket_size,
+            prevs: Vec::with_capacity(2 * options.bucket_size),
+            lookups: 0,
+            collisions: 0,
+            long_len: options.long_hash_len,
+            hash: options.hash,
+            hash_bits: options.hash_bits,
+            max_dist: options.window_size - 1,
+            min_match: options.min_match,
+        }
+    }
+
+    /// Adds pos to the bucket for hash in table, and appends the
+    /// positions that were in the bucket to prevs.
+    fn record(&mut self, long: bool, hash: usize, pos: u64) {
+        let table = if long { &mut self.head_long } else { &mut self.head };
+        let bucket = &mut table[hash * self.bucket_size..(hash + 1) * self.bucket_size];
+        let base = self.base;
+        self.prevs.extend(bucket.iter().filter(|&&p| p > 0).map(|&p| base + p as u64 - 1));
+        bucket.copy_within(..self.bucket_size - 1, 1);
+        bucket[0] = (pos - base + 1) as u32;
+    }
+
+    /// Makes the hash tables relative to a base close to pos, so that
+    /// positions fit in 32 bits however long the input is. Positions
+    /// too far back to be used for backreferences are removed.
+    fn rebase(&mut self, pos: u64) {
+        let new_base = pos.saturating_sub(self.max_dist + 1);
+        let shift = new_base - self.base;
+        for entry in self.head.iter_mut().chain(self.head_long.iter_mut()) {
+            *entry = (*entry as u64).saturating_sub(shift) as u32;
+        }
+        self.base = new_base;
+    }
+
+    /// Records pos in the hash tables and sets prevs to the previous
+    /// positions with the same hashes: first from the secondary table,
+    /// then from the primary table.
+    fn insert<IO: IOTrait + LookbackInput>(&mut self, io: &mut IO, pos: u64) -> BoxResult<()> {
+        let mut bytes = [0u8; MAX_LONG_HASH_LEN];
+        let mut n = 0;
+        while n < std::cmp::max(self.long_len, MIN_HASH_LEN) {
+            match fetch(io, pos + n as u64)? {
+                Some(b) => bytes[n] = b,
+                None => break,
+            }
+            n += 1;
+        }
+        self.prevs.clear();
+        if n < MIN_HASH_LEN {
+            return Ok(());
+        }
+        if pos - self.base >= self.rebase_at {
+            self.rebase(pos);
+        }
+        if !self.head_long.is_empty() && n == self.long_len {
+            let hash = (self.hash)(&bytes[..n], self.hash_bits);
+            self.record(true, hash, pos);
+        }
+        let hash = (self.hash)(&bytes[..MIN_HASH_LEN], self.hash_bits);
+        self.record(false, hash, pos);
+        Ok(())
+    }
+
+    /// Records pos in the hash tables and appends to out every candidate
+    /// backreference at pos of at least the minimum length and at most
+    /// max_len bytes. Candidates are the recently used distances and the
+    /// previous positions with the same hashes.
+    fn candidates<IO: IOTrait + LookbackInput>(&mut self, io: &mut IO, pos: u64,
+                                               recent: &[u64], max_len: u64,
+                                               out: &mut Vec<Match>) -> BoxResult<()> {
+        self.insert(io, pos)?;
+        let dists = recent.iter().copied()
+            .chain(self.prevs.iter().map(|prev| pos - prev - 1));
+        for (i, dist) in dists.enumerate() {
+            if dist < pos && dist <= self.max_dist && !out.iter().any(|m| m.dist == dist) {
+                let len = match_len(io, pos, pos - dist - 1, max_len)?;
+                if i >= recent.len() {
+                    self.lookups += 1;
+                    if len < std::cmp::min(MIN_HASH_LEN as u64, max_len) {
+                        self.collisions += 1;
+                    }
+                }
+                if len >= self.min_match {
+                    out.push(Match { len, dist });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Distance from the base position at which MatchFinder rebases its
+/// hash tables. Entries in the tables are 32 bits.
+const REBASE_AT: u64 = 1 << 31;
+
+/// Maximum number of bytes the optimal parser considers at once.
+const OPTIMAL_BLOCK: u64 = 0x1000;
+
+/// Number of input bytes after which the parsers end the current block.
+/// Blocks can be longer by up to one backreference or OPTIMAL_BLOCK,
+/// which keeps them within the maximum length of a stored block.
+const BLOCK_SIZE: u64 = 0x8000;
+
+struct Parser<W: TokenWriter> {
+    strategy: ParseStrategy,
+    skip_trigger: u32,
+    filter: Option<MatchFilter>,
+    finder: MatchFinder,
+    cost: Arc<dyn TokenCost>,
+    writer: W,
+    candidates: Vec<Match>,
+
+    /// Encoded output for the current block.
+    out: Vec<u8>,
+
+    /// Input position at which the current block starts.
+    block_start: u64,
+
+    /// Writer state at the start of the current block.
+    saved: W,
+
+    /// The most recent backreference in the current block, if nothing
+    /// has been written after it: its offset in out, the writer state
+    /// before it was written, its position, and the backreference itself.
+    last: Option<(usize, W, u64, Match)>,
+
+    /// Offset in out of the lead byte of the last literal run, if
+    /// nothing has been written after it.
+    open_run: Option<usize>,
+
+    /// Statistics for the blocks written so far.
+    stats: EncodeStats,
+
+    /// Token statistics for the current block.
+    block_stats: EncodeStats,
+}
+
+impl<W: TokenWriter> Parser<W> {
+    fn new(options: &EncodeOptions, writer: W) -> Parser<W> {
+        Parser {
+            strategy: options.strategy,
+            skip_trigger: options.skip_trigger,
+            filter: options.filter.clone(),
+            finder: MatchFinder::new(options),
+            cost: options.cost.clone(),
+            saved: writer.clone(),
+            writer,
+            candidates: Vec::new(),
+            out: Vec::new(),
+            block_start: 0,
+            last: None,
+            open_run: None,
+            stats: EncodeStats::default(),
+            block_stats: EncodeStats::default(),
+        }
+    }
+
+    /// Appends the input from start to end to the output for the current
+    /// block as literal runs. If the output ends in a literal run that is
+    /// not full yet, that run is extended first, so that literal runs are
+    /// as long as possible.
+    fn write_literals<IO: LookbackInput>(&mut self, io: &IO, start: u64, end: u64) {
+        let mut pos = start;
+        if let Some(run) = self.open_run {
+            let n = std::cmp::min(MAX_LITERALS - self.out[run] as u64, end - pos);
+            for p in pos..pos + n {
+                self.out.push(io.lookback(p));
+            }
+            self.out[run] += n as u8;
+            pos += n;
+        }
+        while pos < end {
+            let litlen = std::cmp::min(end - pos, MAX_LITERALS);
+            self.open_run = Some(self.out.len());
+            write_lit(io, &mut self.out, litlen as u8, pos);
+            self.block_stats.literal_runs += 1;
+            pos += litlen;
+        }
+        self.block_stats.literal_bytes += end - start;
+    }
+
+    /// Writes as many of the literals from start to end as can be written
+    /// in full literal runs. Returns the position of the first literal
+    /// that was not written.
+    fn write_full_runs<IO: LookbackInput>(&mut self, io: &IO, start: u64, end: u64) -> u64 {
+        let room = match self.open_run {
+            Some(run) => MAX_LITERALS - self.out[run] as u64,
+            None => 0,
+        };
+        if end - start < room {
+            return start;
+        }
+        let stop = end - (end - start - room) % MAX_LITERALS;
+        self.write_literals(io, start, stop);
+        stop
+    }
+
+    /// Returns whether the output for the current block ends in a literal
+    /// run that can be extended.
+    fn in_open_run(&self) -> bool {
+        self.open_run.is_some_and(|run| (self.out[run] as u64) < MAX_LITERALS)
+    }
+
+    /// Appends the literals from lit_start to pos, followed by m, to the
+    /// output for the current block. If there are no literals and m
+    /// continues the previous backreference at the same distance, the
+    /// two are merged, so that as few backreferences as possible are
+    /// used. If the result is still too long for a single backreference,
+    /// the first one is made as long as possible.
+    fn emit<IO: LookbackInput>(&mut self, io: &IO, lit_start: u64, pos: u64, m: Match) {
+        let mut pos = pos;
+        let mut m = m;
+        if lit_start < pos {
+            self.write_literals(io, lit_start, pos);
+            self.last = None;
+        }
+        self.open_run = None;
+        if let Some((offset, writer, prev_pos, prev)) = self.last.take() {
+            let total = prev.len + m.len;
+            let first = Match { len: std::cmp::min(total, self.writer.max_match()), dist: m.dist };
+            let rest = Match { len: total - first.len, dist: m.dist };
+            if prev.dist == m.dist && first.len > prev.len
+                && (rest.len == 0 || rest.len >= self.finder.min_match)
+                && self.score(io, prev_pos, first).is_some()
+                && (rest.len == 0 || self.score(io, prev_pos + first.len, rest).is_some())
+            {
+                self.out.truncate(offset);
+                self.writer = writer;
+                self.block_stats.remove_match(prev);
+                if rest.len == 0 {
+                    pos = prev_pos;
+                    m = first;
+                } else {
+                    self.writer.write_match(&mut self.out, first);
+                    self.block_stats.add_match(first);
+                    pos = prev_pos + first.len;
+                    m = rest;
+                }
+            }
+        }
+        let offset = self.out.len();
+        let before = self.writer.clone();
+        self.writer.write_match(&mut self.out, m);
+        self.block_stats.add_match(m);
+        self.last = Some((offset, before, pos, m));
+    }
+
+    /// Writes the encoded output for the input up to end. If the
+    /// format supports it and it is smaller, a stored block is written
+    /// instead.
+    fn finish_block<IO: IOTrait + LookbackInput>(&mut self, io: &mut IO, end: u64)
+                                                 -> BoxResult<()> {
+        let stored_len = end - self.block_start;
+        if self.writer.stored_blocks() && self.out.len() as u64 > stored_len + 3 {
+            self.writer = self.saved.clone();
+            io.write_byte(V2_STORED)?;
+            io.write_byte((stored_len & 0xff) as u8)?;
+            io.write_byte((stored_len >> 8) as u8)?;
+            for pos in self.block_start..end {
+                io.write_byte(io.lookback(pos))?;
+            }
+            self.stats.stored_blocks += 1;
+            self.stats.stored_bytes += stored_len;
+        } else {
+            for &b in self.out.iter() {
+                io.write_byte(b)?;
+            }
+            self.stats.add_tokens(&self.block_stats);
+        }
+        self.block_stats = EncodeStats::default();
+        self.out.clear();
+        self.block_start = end;
+        self.saved = self.writer.clone();
+        self.last = None;
+        self.open_run = None;
+        Ok(())
+    }
+
+    /// Returns the cost of the input from start to end as literals, not
+    /// counting literal run headers.
+    fn literal_cost<IO: LookbackInput>(&self, io: &IO, start: u64, end: u64) -> i64 {
+        (start..end).map(|pos| self.cost.literal(io.lookback(pos)) as i64).sum()
+    }
+
+    /// Returns how profitable m at pos is, given the cost of the same
+    /// input as literals, taking the match filter into account. Returns
+    /// None if the filter vetoes m.
+    fn score_with(&self, pos: u64, m: Match, literal_cost: i64) -> Option<i64> {
+        let token = self.writer.encode_match(m);
+        let gain = literal_cost - self.cost.backreference(token.as_slice()) as i64;
+        match &self.filter {
+            None => Some(gain),
+            Some(filter) => {
+                let candidate = Candidate { pos, len: m.len, distance: m.dist + 1 };
+                filter(&candidate).map(|bonus| gain + bonus * BYTE_COST as i64)
+            },
+        }
+    }
+
+    /// Returns how profitable m at pos is, taking the match filter into
+    /// account. Returns None if the filter vetoes m.
+    fn score<IO: LookbackInput>(&self, io: &IO, pos: u64, m: Match) -> Option<i64> {
+        self.score_with(pos, m, self.literal_cost(io, pos, pos + m.len))
+    }
+
+    /// Records pos in the hash table and returns the most profitable
+    /// backreference at pos, if any, along with its score. Backreferences
+    /// with a negative score are not considered.
+    fn best_at<IO: IOTrait + LookbackInput>(&mut self, io: &mut IO, pos: u64)
+                                            -> BoxResult<Option<(Match, i64)>> {
+        self.candidates.clear();
+        self.finder.candidates(io, pos, self.writer.recent(), self.writer.max_match(),
+                               &mut self.candidates)?;
+        Ok(self.candidates.iter()
+           .filter_map(|&m| self.score(io, pos, m).map(|score| (m, score)))
+           .filter(|&(_, score)| score >= 0)
+           .max_by_key(|&(m, score)| (score, m.len)))
+    }
+
+    /// Records the positions covered by a backreference in the hash table.
+    fn skip<IO: IOTrait + LookbackInput>(&mut self, io: &mut IO, from: u64, to: u64)
+                                         -> BoxResult<()> {
+        for p in from..to {
+            self.finder.insert(io, p)?;
+        }
+        Ok(())
+    }
+
+    /// Parses the input using the greedy or lazy strategy.
+    fn parse_greedy<IO: IOTrait + LookbackInput>(&mut self, io: &mut IO, start: u64)
+                                                 -> BoxResult<()> {
+        let lazy = self.strategy == ParseStrategy::Lazy;
+        let mut pos = start;
+        let mut lit_start = start;
+        // Number of consecutive positions at which no backreference was
+        // found, used to skip ahead.
+        let mut misses: u64 = 0;
+        let mut cur = self.best_at(io, pos)?;
+        while fetch(io, pos)?.is_some() {
+            if pos - self.block_start >= BLOCK_SIZE {
+                // Formats without stored blocks can keep the current
+                // literal run going. Its full runs are written out, so
+                // that only the last few literals need to be kept.
+                if self.writer.stored_blocks() {
+                    self.write_literals(io, lit_start, pos);
+                    lit_start = pos;
+                } else {
+                    lit_start = self.write_full_runs(io, lit_start, pos);
+                }
+                self.finish_block(io, pos)?;
+            }
+            let (m, score) = match cur {
+                Some(cur) => cur,
+                None => {
+                    let step = match self.skip_trigger {
+                        0 => 1,
+                        bits => 1 + (misses >> bits),
+                    };
+                    misses += 1;
+                    pos += step;
+                    if fetch(io, pos)?.is_none() {
+                        pos = std::cmp::min(pos, io.inpos());
+                    }
+                    cur = self.best_at(io, pos)?;
+                    continue;
+                },
+            };
+            misses = 0;
+            let mut covered = pos + 1;
+            if lazy {
+                let next = self.best_at(io, pos + 1)?;
+                covered += 1;
+                if let Some((_, next_score)) = next {
+                    if next_score > score {
+                        pos += 1;
+                        cur = next;
+                        continue;
+                    }
+                }
+            }
+            self.skip(io, covered, pos + m.len)?;
+            self.emit(io, lit_start, pos, m);
+            pos += m.len;
+            lit_start = pos;
+            cur = self.best_at(io, pos)?;
+        }
+        self.write_literals(io, lit_start, pos);
+        self.finish_block(io, pos)
+    }
+
+    /// Parses the input in blocks, choosing the cheapest sequence of
+    /// tokens for each block. Within a block, the cost of backreferences
+    /// is computed using the recently used distances from the start of
+    /// the block, so the result is not always strictly optimal for
+    /// version 2 of the format.
+    fn parse_optimal<IO: IOTrait + LookbackInput>(&mut self, io: &mut IO, start: u64)
+                                                  -> BoxResult<()> {
+        let mut pos = start;
+        while fetch(io, pos)?.is_some() {
+            let end = match fetch(io, pos + OPTIMAL_BLOCK - 1)? {
+                Some(_) => pos + OPTIMAL_BLOCK,
+                None => io.inpos(),
+            };
+            let n = (end - pos) as usize;
+
+            // Collect candidate backreferences for every position.
+            let mut candidates = Vec::with_capacity(n);
+            for i in 0..n {
+                let p = pos + i as u64;
+                let max_len = std::cmp::min(self.writer.max_match(), end - p);
+                self.candidates.clear();
+                self.finder.candidates(io, p, self.writer.recent(), max_len,
+                                       &mut self.candidates)?;
+                candidates.push(self.candidates.clone());
+            }
+
+            // lit_prefix[i] is the cost of the literals from the start of
+            // the block up to i.
+            let mut lit_prefix = vec![0i64; n + 1];
+            for i in 0..n {
+                lit_prefix[i + 1] = lit_prefix[i] + self.literal_cost(io, pos + i as u64,
+                                                                     pos + i as u64 + 1);
+            }
+
+            // cost_start[i] is the cost of encoding the block from i onward
+            // if a new token starts at i, cost_lit[i] is the cost if i
+            // continues a literal run. choice_* record the first token:
+            // None for a literal, Some(m) for a backreference.
+            let run_cost = self.cost.literal_run() as i64;
+            let mut cost_start = vec![0i64; n + 1];
+            let mut cost_lit = vec![0i64; n + 1];
+            let mut choice_start = vec![None; n];
+            let mut choice_lit = vec![None; n];
+            for i in (0..n).rev() {
+                let lit = lit_prefix[i + 1] - lit_prefix[i];
+                cost_start[i] = run_cost + lit + cost_lit[i + 1];
+                cost_lit[i] = lit + cost_lit[i + 1];
+                for &m in candidates[i].iter() {
+                    for len in self.finder.min_match..=m.len {
+                        let c = Match { len, dist: m.dist };
+                        // A backreference costs what it does not save.
+                        let literal = lit_prefix[i + len as usize] - lit_prefix[i];
+                        let score = match self.score_with(pos + i as u64, c, literal) {
+                            Some(score) => score,
+                            None => continue,
+                        };
+                        let cost = literal - score + cost_start[i + len as usize];
+                        if cost < cost_start[i] {
+                            cost_start[i] = cost;
+                            choice_start[i] = Some(c);
+                        }
+                        if cost < cost_lit[i] {
+                            cost_lit[i] = cost;
+                            choice_lit[i] = Some(c);
+                        }
+                    }
+                }
+            }
+
+            // Emit the chosen tokens.
+            let mut i = 0;
+            let mut lit_start = pos;
+            let mut in_literal = self.in_open_run();
+            while i < n {
+                let choice = if in_literal { choice_lit[i] } else { choice_start[i] };
+                match choice {
+                    Some(m) => {
+                        let p = pos + i as u64;
+                        self.emit(io, lit_start, p, m);
+                        i += m.len as usize;
+                        lit_start = pos + i as u64;
+                        in_literal = false;
+                    },
+                    None => {
+                        i += 1;
+                        in_literal = true;
+                    },
+                }
+            }
+            self.write_literals(io, lit_start, end);
+            if end - self.block_start >= BLOCK_SIZE {
+                self.finish_block(io, end)?;
+            }
+            pos = end;
+        }
+        self.finish_block(io, pos)
+    }
+
+    /// Encodes the input from position start onward. The input before
+    /// start is not encoded, but can be referred to by backreferences.
+    fn parse<IO: IOTrait + LookbackInput>(&mut self, io: &mut IO, start: u64) -> BoxResult<()> {
+        self.skip(io, 0, start)?;
+        self.block_start = start;
+        match self.strategy {
+            ParseStrategy::Greedy | ParseStrategy::Lazy => self.parse_greedy(io, start),
+            ParseStrategy::Optimal => self.parse_optimal(io, start),
+        }
+    }
+
+    /// Returns the statistics for the input parsed so far.
+    fn stats(&self) -> EncodeStats {
+        let mut stats = self.stats.clone();
+        stats.hash_lookups = self.finder.lookups;
+        stats.hash_collisions = self.finder.collisions;
+        stats
+    }
+}
+
+/// Returns an error if io cannot look back as far as the encoder needs
+/// to with options.
+fn check_lookback<IO: WindowedLookback>(io: &IO, options: &EncodeOptions) -> BoxResult<()> {
+    if io.lookback_window() < options.lookback_needed() {
+        return Err(format!("encoder needs to look back {} bytes, but input only keeps {}",
+                           options.lookback_needed(), io.lookback_window()).into());
+    }
+    Ok(())
+}
+
+/// Encodes the input from position start onward in the version of the
+/// format selected by options, without a version byte.
+/// Returns statistics about the encoding.
+fn encode_tokens<IO: IOTrait + WindowedLookback>(io: &mut IO, options: &EncodeOptions,
+                                                 start: u64) -> BoxResult<EncodeStats> {
+    fn run<W: TokenWriter, IO: IOTrait + LookbackInput>(mut parser: Parser<W>, io: &mut IO,
+                                                        start: u64) -> BoxResult<EncodeStats> {
+        parser.parse(io, start)?;
+        Ok(parser.stats())
+    }
+    match options.version {
+        FormatVersion::V1 => run(Parser::new(options, V1Writer { last: None }), io, start),
+        FormatVersion::V2 => run(Parser::new(options, V2Writer::new()), io, start),
+    }
+}
+
+/// Encodes the input in the version of the format selected by options.
+/// Streams in version 1 are written without a version byte, so that
+/// older decoders can read them.
+pub fn encode<IO: IOTrait + WindowedLookback>(io: &mut IO, options: &EncodeOptions)
+                                              -> BoxResult<()> {
+    encode_with_stats(io, options)?;
+    Ok(())
+}
+
+/// Encodes the input like encode, and returns statistics about the
+/// encoding.
+pub fn encode_with_stats<IO: IOTrait + WindowedLookback>(io: &mut IO, options: &EncodeOptions)
+                                                         -> BoxResult<EncodeStats> {
+    check_lookback(io, options)?;
+    if options.version != FormatVersion::V1 {
+        io.write_byte(VERSION_MARKER | options.version.number())?;
+    }
+    encode_tokens(io, options, 0)
+}
+
+/// Encodes input from a reader, writing the result to output, like
+/// encode. Only a window of the input is kept in memory, so input of any
+/// length can be encoded.
+pub fn encode_stream(input: &mut dyn std::io::Read, output: &mut dyn std::io::Write,
+                     options: &EncodeOptions) -> BoxResult<()> {
+    let mut io = StreamIO::with_input_history(input, output, options.lookback_needed() as usize);
+    encode(&mut io, options)?;
+    io.flush()
+}
+
+/// Compresses bytes written to it and writes the result to an inner
+/// writer, for passing to anything that takes a std::io::Write. The
+/// encoder needs to look ahead in its input, so input is buffered until
+/// a block of BLOCK_SIZE bytes is complete, or until flush or finish is
+/// called. Then it is encoded and written to the inner writer. Only the
+/// input that later input can refer back to and one block of pending
+/// input are kept in memory. Matches end at the end of each block, and
+/// every flush ends a block, so flushing often makes the output larger.
+/// Input of at most one block without flushes is encoded the same as by
+/// encode. Dropping an EncodeWriter finishes it, ignoring errors; call
+/// finish to see them.
+pub struct EncodeWriter<W: std::io::Write> {
+    output: Option<W>,
+    options: EncodeOptions,
+    /// The last bytes of the input that has been encoded, which
+    /// backreferences can refer to, followed by the input that has not.
+    input: Vec<u8>,
+    /// Number of bytes at the start of input that have been encoded.
+    encoded: usize,
+    /// Whether the version byte has been written.
+    started: bool,
+    tokens: SegmentWriter,
+}
+
+/// State of the token writer, kept from one segment of input to the next
+/// so that it stays in sync with the decoder.
+enum SegmentWriter {
+    V1(V1Writer),
+    V2(V2Writer),
+}
+
+/// Encodes input from position start onward, continuing with the state
+/// in writer, and appends the result to output.
+fn encode_segment<T: TokenWriter>(options: &EncodeOptions, writer: &mut T, input: &[u8],
+                                  start: usize, output: &mut Vec<u8>) -> BoxResult<()> {
+    let mut parser = Parser::new(options, writer.clone());
+    parser.parse(&mut SliceToVecIO::new(input, output), start as u64)?;
+    *writer = parser.writer;
+    Ok(())
+}
+
+impl<W: std::io::Write> EncodeWriter<W> {
+    pub fn new(output: W, options: EncodeOptions) -> EncodeWriter<W> {
+        let tokens = match options.version {
+            FormatVersion::V1 => SegmentWriter::V1(V1Writer { last: None }),
+            FormatVersion::V2 => SegmentWriter::V2(V2Writer::new()),
+        };
+        EncodeWriter { output: Some(output), options, input: Vec::new(), encoded: 0,
+                       started: false, tokens }
+    }
+
+    /// Encodes everything written so far, writes it to the inner writer,
+    /// and returns the inner writer.
+    pub fn finish(mut self) -> BoxResult<W> {
+        // Take the writer first, so that Drop does not write again if
+        // this fails.
+        let mut output = self.output.take().unwrap();
+        let encoded = self.encode_pending(self.input.len())?;
+        output.write_all(&encoded)?;
+        output.flush()?;
+        Ok(output)
+    }
+
+    /// Encodes the input that has not been encoded yet up to position end
+    /// in input, and keeps the last bytes of it for later input to refer
+    /// to.
+    fn encode_pending(&mut self, end: usize) -> BoxResult<Vec<u8>> {
+        let mut encoded = Vec::with_capacity(compress_bound(end - self.encoded));
+        if !self.started {
+            if self.options.version != FormatVersion::V1 {
+                encoded.push(VERSION_MARKER | self.options.version.number());
+            }
+            self.started = true;
+        }
+        if end > self.encoded {
+            let input = &self.input[..end];
+            match &mut self.tokens {
+                SegmentWriter::V1(writer) => encode_segment(&self.options, writer, input,
+                                                            self.encoded, &mut encoded)?,
+                SegmentWriter::V2(writer) => encode_segment(&self.options, writer, input,
+                                                            self.encoded, &mut encoded)?,
+            }
+            let keep = std::cmp::min(end, self.options.lookback_needed() as usize);
+            self.input.drain(..end - keep);
+            self.encoded = keep;
+        }
+        Ok(encoded)
+    }
+
+    /// Encodes the input up to position end in input and writes it to
+    /// the inner writer.
+    fn write_pending(&mut self, end: usize) -> std::io::Result<()> {
+        let encoded = self.encode_pending(end).map_err(|e| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+        })?;
+        self.output.as_mut().unwrap().write_all(&encoded)
+    }
+}
+
+impl<W: std::io::Write> std::io::Write for EncodeWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let block = BLOCK_SIZE as usize;
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let n = std::cmp::min(rest.len(), self.encoded + block - self.input.len());
+            self.input.extend_from_slice(&rest[..n]);
+            rest = &rest[n..];
+            if self.input.len() == self.encoded + block {
+                self.write_pending(self.input.len())?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.write_pending(self.input.len())?;
+        self.output.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: std::io::Write> Drop for EncodeWriter<W> {
+    fn drop(&mut self) {
+        if let Some(mut output) = self.output.take() {
+            if let Ok(encoded) = self.encode_pending(self.input.len()) {
+                let _ = output.write_all(&encoded);
+                let _ = output.flush();
+            }
+        }
+    }
+}
+
+/// Encodes the input in version 2 of the format, without a version
+/// byte. The version set in options is ignored.
+pub fn encode_v2<IO: IOTrait + WindowedLookback>(io: &mut IO, options: &EncodeOptions)
+                                                 -> BoxResult<()> {
+    check_lookback(io, options)?;
+    Parser::new(options, V2Writer::new()).parse(io, 0)
+}
+
+/// Encodes the input as a framed stream in the version of the format
+/// selected by options. Since the header contains the length of the
+/// input, all input is read before any output is written, so io must
+/// be able to look back at all of it.
+pub fn encode_framed<IO: IOTrait + WindowedLookback>(io: &mut IO, options: &EncodeOptions)
+                                                     -> BoxResult<()> {
+    while io.next_byte()?.is_some() {}
+    let len = io.inpos();
+    if io.lookback_window() < len {
+        return Err(format!("framed encoding needs all {} bytes of input, but input only keeps {}",
+                           len, io.lookback_window()).into());
+    }
+    for &b in FRAME_MAGIC {
+        io.write_byte(b)?;
+    }
+    io.write_byte(options.version.number())?;
+    io.write_byte(if options.checksum { FRAME_CHECKSUM } else { 0 })?;
+    for &b in len.to_le_bytes().iter() {
+        io.write_byte(b)?;
+    }
+    encode_tokens(io, options, 0)?;
+    match options.version {
+        FormatVersion::V1 => io.write_byte(0)?,
+        FormatVersion::V2 => {
+            io.write_byte(V2_STORED)?;
+            io.write_byte(0)?;
+            io.write_byte(0)?;
+        },
+    }
+    if options.checksum {
+        let mut crc = Crc32::new();
+        for pos in 0..len {
+            crc.update_byte(io.lookback(pos));
+        }
+        for &b in crc.value().to_le_bytes().iter() {
+            io.write_byte(b)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the largest number of bytes that encode can produce for
+/// input_len bytes of input in version 2 of the format, with any other
+/// options. The same bound applies to compress and to
+/// compress_with_reference. The encoder guarantees this by falling back
+/// to stored blocks, which version 1 of the format does not have.
+pub fn compress_bound(input_len: usize) -> usize {
+    // The version byte, plus at most a stored block header for every
+    // block of input.
+    1 + input_len + 3 * input_len.div_ceil(BLOCK_SIZE as usize)
+}
+
+/// Compresses input using encode with default options.
+///
+/// ```
+/// use compression_toolkit::backref;
+///
+/// let compressed = backref::compress(b"abcabcabcabc");
+/// assert_eq!(backref::decompress(&compressed).unwrap(), b"abcabcabcabc");
+/// ```
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(compress_bound(input.len()));
+    let mut io = SliceToVecIO::new(input, &mut output);
+    encode(&mut io, &EncodeOptions::new()).expect("encoding to memory cannot fail");
+    output
+}
+
+/// Decodes a stream produced by encode from an AsyncRead, writing the
+/// result to an AsyncWrite. Input is decoded as it arrives, in bounded
+/// memory.
+#[cfg(feature = "async")]
+pub async fn decode_async<R, W>(input: &mut R, output: &mut W) -> BoxResult<()>
+    where R: tokio::io::AsyncRead + Unpin, W: tokio::io::AsyncWrite + Unpin {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut decoder = PushDecoder::new();
+    let mut buf = vec![0; 0x2000];
+    loop {
+        let n = input.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        decoder.push_input(&buf[..n])?;
+        output.write_all(&decoder.take_output()).await?;
+    }
+    decoder.finish()?;
+    output.write_all(&decoder.take_output()).await?;
+    output.flush().await?;
+    Ok(())
+}
+
+/// Encodes input from an AsyncRead, writing the result to an AsyncWrite,
+/// like encode. The encoder pulls its input synchronously, so all input
+/// is read into memory before encoding starts, and the encoded stream
+/// is kept in memory until it is written. Encoding runs on tokio's pool
+/// of blocking threads, so that it does not hold up the executor. This
+/// must be called from within a tokio runtime.
+#[cfg(feature = "async")]
+pub async fn encode_async<R, W>(input: &mut R, output: &mut W, options: &EncodeOptions)
+                                -> BoxResult<()>
+    where R: tokio::io::AsyncRead + Unpin, W: tokio::io::AsyncWrite + Unpin {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut data = Vec::new();
+    input.read_to_end(&mut data).await?;
+    let options = options.clone();
+    let encoded = tokio::task::spawn_blocking(move || {
+        let mut encoded = Vec::with_capacity(compress_bound(data.len()));
+        // Errors are not Send, so pass them back as strings.
+        encode(&mut SliceToVecIO::new(&data, &mut encoded), &options)
+            .map(|_| encoded).map_err(|e| e.to_string())
+    }).await??;
+    output.write_all(&encoded).await?;
+    output.flush().await?;
+    Ok(())
+}
+
+/// Decompresses data produced by compress, or by encode with any options.
+pub fn decompress(input: &[u8]) -> BoxResult<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut io = SliceToVecIO::new(input, &mut output);
+    decode(&mut io)?;
+    Ok(output)
+}
+
+/// Decompresses data produced by compress into output, without
+/// allocating. Returns the number of bytes written, or an
+/// io::OutputFull error if the decompressed data does not fit.
+pub fn decompress_into(input: &[u8], output: &mut [u8]) -> BoxResult<usize> {
+    let mut io = SliceIO::new(input, output);
+    decode(&mut io)?;
+    Ok(io.outpos())
+}
+
+/// Returns the part of reference that backreferences can reach: its
+/// last WINDOW_SIZE bytes.
+fn reference_window(reference: &[u8]) -> &[u8] {
+    &reference[reference.len().saturating_sub(WINDOW_SIZE)..]
+}
+
+/// Compresses input as a delta against reference: the encoder behaves
+/// as if reference preceded input, so parts of input that also occur in
+/// reference are encoded as backreferences into it. Only the last
+/// WINDOW_SIZE bytes of reference are used. The result can only be
+/// decompressed by decompress_with_reference, given the same reference.
+pub fn compress_with_reference(reference: &[u8], input: &[u8], options: &EncodeOptions)
+                               -> Vec<u8> {
+    let reference = reference_window(reference);
+    let mut seeded = Vec::with_capacity(reference.len() + input.len());
+    seeded.extend_from_slice(reference);
+    seeded.extend_from_slice(input);
+    let mut output = Vec::new();
+    let mut io = SliceToVecIO::new(&seeded, &mut output);
+    // Unlike encode, always write the version byte: the first token
+    // may be a backreference into the reference, which would otherwise
+    // be mistaken for a version byte.
+    io.write_byte(VERSION_MARKER | options.version.number())
+        .expect("encoding to memory cannot fail");
+    encode_tokens(&mut io, options, reference.len() as u64)
+        .expect("encoding to memory cannot fail");
+    output
+}
+
+/// Decompresses data produced by compress_with_reference. reference
+/// must be the same as the one used for compression.
+pub fn decompress_with_reference(reference: &[u8], input: &[u8]) -> BoxResult<Vec<u8>> {
+    let reference = reference_window(reference);
+    let mut output = reference.to_vec();
+    let mut io = SliceToVecIO::new(input, &mut output);
+    decode(&mut io)?;
+    Ok(output.split_off(reference.len()))
+}
+
+/// Options for compress_parallel.
+#[derive(Clone, Debug)]
+pub struct ParallelOptions {
+    block_size: usize,
+    overlap: usize,
+    threads: usize,
+}
+
+impl ParallelOptions {
+    /// Returns the default options: blocks of 1 MiB, no overlap, and one
+    /// thread for every available CPU.
+    pub fn new() -> ParallelOptions {
+        ParallelOptions {
+            block_size: 1 << 20,
+            overlap: 0,
+            threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
+        }
+    }
+
+    /// Sets the number of input bytes per block. This is clamped to the
+    /// range 1 to 2**30.
+    pub fn block_size(mut self, block_size: usize) -> ParallelOptions {
+        self.block_size = block_size.clamp(1, 1 << 30);
+        self
+    }
+
+    /// Sets the number of bytes before each block that backreferences in
+    /// the block can refer to. Larger overlaps improve compression, but
+    /// blocks must then be decompressed in order. This is clamped to the
+    /// window size of the format, 65536.
+    pub fn overlap(mut self, overlap: usize) -> ParallelOptions {
+        self.overlap = std::cmp::min(overlap, (MAX_DIST + 1) as usize);
+        self
+    }
+
+    /// Sets the number of threads used to compress blocks.
+    pub fn threads(mut self, threads: usize) -> ParallelOptions {
+        self.threads = std::cmp::max(threads, 1);
+        self
+    }
+}
+
+impl Default for ParallelOptions {
+    fn default() -> ParallelOptions {
+        ParallelOptions::new()
+    }
+}
+
+/// Compresses one block for compress_parallel, using the overlap bytes
+/// before it as a dictionary.
+fn compress_block(input: &[u8], start: usize, end: usize, overlap: usize,
+                  options: &EncodeOptions) -> Vec<u8> {
+    let dict_start = start.saturating_sub(overlap);
+    let mut output = Vec::new();
+    let mut io = SliceToVecIO::new(&input[dict_start..end], &mut output);
+    Parser::new(options, V2Writer::new()).parse(&mut io, (start - dict_start) as u64)
+        .expect("encoding to memory cannot fail");
+    output
+}
+
+/// Compresses input by splitting it into blocks that are compressed
+/// independently on several threads. Each block is compressed using
+/// version 2 of the format. The result has the following layout, with
+/// all numbers stored as 4 bytes, least significant byte first:
+///
+///   number of blocks n
+///   overlap
+///   n times: compressed size, uncompressed size
+///   n compressed blocks
+///
+/// Use decompress_parallel to decompress the result. Returns an error
+/// if the number of blocks does not fit in 4 bytes.
+pub fn compress_parallel(input: &[u8], options: &EncodeOptions,
+                         parallel: &ParallelOptions) -> BoxResult<Vec<u8>> {
+    let nblocks = input.len().div_ceil(parallel.block_size);
+    let mut output = Vec::new();
+    write_u32(&mut output, nblocks)?;
+    write_u32(&mut output, parallel.overlap)?;
+    let next = AtomicUsize::new(0);
+    let mut blocks = vec![Vec::new(); nblocks];
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..std::cmp::min(parallel.threads, nblocks)).map(|_| {
+            scope.spawn(|| {
+                let mut done = Vec::new();
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i >= nblocks { break }
+                    let start = i * parallel.block_size;
+                    let end = std::cmp::min(start + parallel.block_size, input.len());
+                    done.push((i, compress_block(input, start, end, parallel.overlap, options)));
+                }
+                done
+            })
+        }).collect();
+        for worker in workers {
+            for (i, block) in worker.join().expect("compression thread panicked") {
+                blocks[i] = block;
+            }
+        }
+    });
+
+    for (i, block) in blocks.iter().enumerate() {
+        let len = std::cmp::min(parallel.block_size, input.len() - i * parallel.block_size);
+        write_u32(&mut output, block.len())?;
+        write_u32(&mut output, len)?;
+    }
+    for block in blocks {
+        output.extend_from_slice(&block);
+    }
+    Ok(output)
+}
+
+/// Appends n to output as a 4-byte little-endian number, or returns an
+/// error if it does not fit.
+fn write_u32(output: &mut Vec<u8>, n: usize) -> BoxResult<()> {
+    let n = u32::try_from(n).map_err(|_| format!("{} is too large for block index", n))?;
+    output.extend_from_slice(&n.to_le_bytes());
+    Ok(())
+}
+
+/// Reads a 4-byte little-endian number from input at pos.
+fn read_u32_at(input: &[u8], pos: usize) -> BoxResult<usize> {
+    let bytes = input.get(pos..pos + 4).ok_or("truncated block index")?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+}
+
+/// Decompresses data produced by compress_parallel.
+pub fn decompress_parallel(input: &[u8]) -> BoxResult<Vec<u8>> {
+    let nblocks = read_u32_at(input, 0)?;
+    let overlap = read_u32_at(input, 4)?;
+    let mut pos = 8 + 8 * nblocks;
+    let mut output = Vec::new();
+    for i in 0..nblocks {
+        let clen = read_u32_at(input, 8 + 8 * i)?;
+        let ulen = read_u32_at(input, 12 + 8 * i)?;
+        let block = input.get(pos..pos + clen).ok_or("truncated block")?;
+        pos += clen;
+        // Decode with the overlap bytes preceding the block as a prefix
+        // of the output, so that backreferences into them work.
+        let dict_start = output.len().saturating_sub(overlap);
+        let mut decoded = output[dict_start..].to_vec();
+        let dict_len = decoded.len();
+        let mut io = SliceToVecIO::new(block, &mut decoded);
+        decode_v2(&mut io)?;
+        if decoded.len() - dict_len != ulen {
+            return Err("block size does not match block index".into());
+        }
+        output.extend_from_slice(&decoded[dict_len..]);
+    }
+    if pos != input.len() {
+        return Err("trailing data after last block".into());
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::{SliceToVecIO, StreamIO};
+    use crate::testutil::{lcg, lcg_bytes};
+
+    #[test]
+    fn decode_empty() {
+        let input = b"";
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(input, &mut output);
+        decode(&mut io).unwrap();
+        assert_eq!(output, b"");
+    }
+
+    #[test]
+    fn decode_litlit() {
+        let input = b"\x03xyz\x02zy";
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(input, &mut output);
+        decode(&mut io).unwrap();
+        assert_eq!(output, b"xyzzy");
+    }
+
+    #[test]
+    fn decode_rep0() {
+        let input = b"\x01a\x84\x00\x00";
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(input, &mut output);
+        decode(&mut io).unwrap();
+        assert_eq!(output, b"aaaaa");
+    }
+
+    #[test]
+    fn decode_rep2() {
+        let input = b"\x03abc\x83\x02\x00";
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(input, &mut output);
+        decode(&mut io).unwrap();
+        assert_eq!(output, b"abcabc");
+    }
+
+    #[test]
+    fn decode_rep3() {
+        let input = b"\x04abcd\x83\x03\x00";
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(input, &mut output);
+        decode(&mut io).unwrap();
+        assert_eq!(output, b"abcdabc");
+    }
+
+    #[test]
+    fn encode_empty() {
+        let input = b"";
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(input, &mut output);
+        encode(&mut io, &EncodeOptions::new().version(FormatVersion::V1)).unwrap();
+        assert_eq!(output, b"");
+    }
+
+    #[test]
+    fn encode_lit() {
+        let input = b"a";
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(input, &mut output);
+        encode(&mut io, &EncodeOptions::new().version(FormatVersion::V1)).unwrap();
+        assert_eq!(output, b"\x01a");
+    }
+
+    #[test]
+    fn encode_litlit() {
+        let input : Vec::<u8> = (0..=253).collect();
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(&input[..], &mut output);
+        encode(&mut io, &EncodeOptions::new().version(FormatVersion::V1)).unwrap();
+        let expected : Vec::<u8> = (0x7f..=0x7f).chain(0..=126)
+            .chain(0x7f..=0x7f).chain(127..=253).collect();
+        assert_eq!(output, expected);
+    }
+    
+    #[test]
+    fn encode_rep0() {
+        let input = b"aaaaa";
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(input, &mut output);
+        encode(&mut io, &EncodeOptions::new().version(FormatVersion::V1)).unwrap();
+        assert_eq!(output, b"\x01a\x84\x00\x00");
+    }
+
+    #[test]
+    fn encode_rep_lit() {
+        let input = b"aaaaab";
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(input, &mut output);
+        encode(&mut io, &EncodeOptions::new().version(FormatVersion::V1)).unwrap();
+        assert_eq!(output, b"\x01a\x84\x00\x00\x01b");
+    }
+    
+    #[test]
+    fn encode_rep2() {
+        let input = b"abcabc";
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(input, &mut output);
+        encode(&mut io, &EncodeOptions::new().version(FormatVersion::V1)).unwrap();
+        assert_eq!(output, b"\x03abc\x83\x02\x00");
+    }
+
+    #[test]
+    fn encode_rep3() {
+        let input = b"abcdabc";
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(input, &mut output);
+        encode(&mut io, &EncodeOptions::new().version(FormatVersion::V1)).unwrap();
+        assert_eq!(output, b"\x04abcd\x83\x03\x00");
+    }
+
+    #[test]
+    fn decode_v2_rep() {
+        // "ab", 3 bytes at distance 1, "xy", rep0 (distance 1) for 3 bytes.
+        let input = b"\x02ab\x80\x01\x00\x02xy\x90";
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(input, &mut output);
+        decode_v2(&mut io).unwrap();
+        assert_eq!(output, b"ababaxyxyx");
+    }
+
+    #[test]
+    fn decode_v2_short() {
+        // "ab", 3 bytes at one-byte distance 1, "xy", rep0 for 3 bytes.
+        let input = b"\x02ab\xc0\x01\x02xy\x90";
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(input, &mut output);
+        decode_v2(&mut io).unwrap();
+        assert_eq!(output, b"ababaxyxyx");
+    }
+
+    #[test]
+    fn decode_v2_rep_order() {
+        // Explicit distances 1 and 2, then rep1 (1), then rep0 (1 again).
+        let input = b"\x03abc\x80\x01\x00\x80\x02\x00\xa0\x90";
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(input, &mut output);
+        decode_v2(&mut io).unwrap();
+        assert_eq!(output, b"abcbcbbcbcbcbcb");
+    }
+
+    #[test]
+    fn decode_v2_extended_length() {
+        let input = b"\x01a\x9f\x02";
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(input, &mut output);
+        decode_v2(&mut io).unwrap();
+        assert_eq!(output, vec![b'a'; 21]);
+    }
+
+    #[test]
+    fn decode_v2_reserved() {
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(b"\x01a\xd0", &mut output);
+        assert!(decode_v2(&mut io).is_err());
+    }
+
+    #[test]
+    fn encode_v2_rep0() {
+        let input = b"aaaaa";
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(input, &mut output);
+        encode_v2(&mut io, &EncodeOptions::new()).unwrap();
+        assert_eq!(output, b"\x01a\x91");
+    }
+
+    #[test]
+    fn encode_v2_short() {
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(b"abcabcabc", &mut output);
+        encode_v2(&mut io, &EncodeOptions::new()).unwrap();
+        assert_eq!(output, b"\x03abc\xc3\x02");
+
+        // Distances over 255 still need two bytes.
+        let mut input = b"abcdefgh".to_vec();
+        input.extend(std::iter::repeat_n(b'-', 300));
+        input.extend(b"abcdefgh");
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(&input[..], &mut output);
+        encode_v2(&mut io, &EncodeOptions::new()).unwrap();
+        assert_eq!(&output[output.len() - 3..], b"\x85\x33\x01");
+    }
+
+    #[test]
+    fn encode_v2_records() {
+        // Fixed-size records with one changing field reuse the same
+        // distances over and over.
+        let input : Vec::<u8> = (0..200u32)
+            .flat_map(|i| format!("id={:03};kind=log;", i % 7).into_bytes())
+            .collect();
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(&input[..], &mut output);
+        encode_v2(&mut io, &EncodeOptions::new()).unwrap();
+        let mut v1 = Vec::new();
+        let mut io = SliceToVecIO::new(&input[..], &mut v1);
+        encode(&mut io, &EncodeOptions::new().version(FormatVersion::V1)).unwrap();
+        assert!(output.len() < v1.len());
+
+        let mut decoded = Vec::new();
+        let mut io = SliceToVecIO::new(&output[..], &mut decoded);
+        decode_v2(&mut io).unwrap();
+        assert_eq!(decoded, input);
+    }
+
+    fn sample_text() -> Vec<u8> {
+        let words = ["the ", "quick ", "brown ", "fox ", "jumps ", "over ", "lazy ", "dog ",
+                     "and ", "then ", "some ", "more "];
+        (0..2000u32).map(|i| words[((i * 7) ^ (i >> 3)) as usize % words.len()])
+            .flat_map(|w| w.bytes()).collect()
+    }
+
+    fn encoded_len(input: &[u8], options: &EncodeOptions, v2: bool) -> usize {
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(input, &mut output);
+        if v2 {
+            encode_v2(&mut io, options).unwrap();
+        } else {
+            encode(&mut io, &options.clone().version(FormatVersion::V1)).unwrap();
+        }
+        let mut decoded = Vec::new();
+        let mut io = SliceToVecIO::new(&output[..], &mut decoded);
+        if v2 {
+            decode_v2(&mut io).unwrap();
+        } else {
+            decode(&mut io).unwrap();
+        }
+        assert_eq!(decoded, input);
+        output.len()
+    }
+
+    #[test]
+    fn encode_strategies() {
+        let input = sample_text();
+        for &v2 in &[false, true] {
+            let greedy = encoded_len(&input, &EncodeOptions::new(), v2);
+            let lazy = encoded_len(
+                &input, &EncodeOptions::new().strategy(ParseStrategy::Lazy), v2);
+            let optimal = encoded_len(
+                &input, &EncodeOptions::new().strategy(ParseStrategy::Optimal), v2);
+            assert!(greedy < input.len());
+            assert!(optimal <= lazy, "optimal {} lazy {}", optimal, lazy);
+            assert!(optimal <= greedy, "optimal {} greedy {}", optimal, greedy);
+        }
+    }
+
+    #[test]
+    fn encode_window_size() {
+        let input = b"abcdefgh abcdefgh";
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(input, &mut output);
+        encode(&mut io, &EncodeOptions::new().version(FormatVersion::V1).window_size(8)).unwrap();
+        assert_eq!(output, b"\x11abcdefgh abcdefgh");
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(input, &mut output);
+        encode(&mut io, &EncodeOptions::new().version(FormatVersion::V1).window_size(9)).unwrap();
+        assert_eq!(output, b"\x09abcdefgh \x88\x08\x00");
+    }
+
+    #[test]
+    fn encode_min_match() {
+        let input = b"abcxabcdyabcd";
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(input, &mut output);
+        encode(&mut io, &EncodeOptions::new().version(FormatVersion::V1)).unwrap();
+        assert_eq!(output, b"\x04abcx\x83\x03\x00\x02dy\x84\x04\x00");
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(input, &mut output);
+        encode(&mut io, &EncodeOptions::new().version(FormatVersion::V1).min_match(4)).unwrap();
+        assert_eq!(output, b"\x09abcxabcdy\x84\x04\x00");
+    }
+
+    #[test]
+    fn encode_hash_bits() {
+        let input = sample_text();
+        for &bits in &[8, 12, 20] {
+            let options = EncodeOptions::new().hash_bits(bits);
+            assert!(encoded_len(&input, &options, true) < input.len() * 3 / 5);
+        }
+    }
+
+    /// Encodes input with options and returns the encoded data.
+    fn compress_with(input: &[u8], options: &EncodeOptions) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(input, &mut output);
+        encode(&mut io, options).unwrap();
+        output
+    }
+
+    /// Returns n pseudo-random bytes.
+    fn random_bytes(n: usize) -> Vec<u8> {
+        lcg_bytes(12345, n)
+    }
+
+    #[test]
+    fn decode_v2_stored() {
+        let input = b"\x00\x03\x00xyz\x01a\x00\x00\x00";
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(input, &mut output);
+        decode_v2(&mut io).unwrap();
+        assert_eq!(output, b"xyza");
+    }
+
+    #[test]
+    fn encode_bucket_size() {
+        // "abcd" was seen at 0, but "abc" was seen at 5 since. With more
+        // than one position per bucket, both are checked.
+        let input = b"abcdxabcyabcd";
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(input, &mut output);
+        encode(&mut io, &EncodeOptions::new().version(FormatVersion::V1).bucket_size(2))
+            .unwrap();
+        assert_eq!(output, b"\x05abcdx\x83\x04\x00\x01y\x84\x08\x00");
+
+        let input = sample_text();
+        let mut last = usize::MAX;
+        for &size in &[1, 4, 16] {
+            let len = encoded_len(&input, &EncodeOptions::new().bucket_size(size), true);
+            assert!(len < last, "{}: {} vs {}", size, len, last);
+            last = len;
+        }
+    }
+
+    #[test]
+    fn encode_skip_trigger() {
+        static HASHED: AtomicUsize = AtomicUsize::new(0);
+        fn counting_hash(bytes: &[u8], bits: u32) -> usize {
+            HASHED.fetch_add(1, Ordering::Relaxed);
+            multiplicative_hash(bytes, bits)
+        }
+        let mut input = random_bytes(50000);
+        input.extend(sample_text());
+        input.extend_from_within(10000..12000);
+        let mut hashed = Vec::new();
+        for &trigger in &[0, 6] {
+            let options = EncodeOptions::new().hash_function(counting_hash)
+                .skip_trigger(trigger);
+            HASHED.store(0, Ordering::Relaxed);
+            let len = encoded_len(&input, &options, true);
+            hashed.push(HASHED.load(Ordering::Relaxed));
+            assert!(len < input.len() - sample_text().len() / 2, "{}: {}", trigger, len);
+        }
+        // Without skipping, every position is hashed.
+        assert!(hashed[0] >= input.len() - 2);
+        assert!(hashed[1] < input.len() / 2, "{:?}", hashed);
+    }
+
+    #[test]
+    fn encode_v2_stored() {
+        let input = random_bytes(1000);
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(&input[..], &mut output);
+        encode_v2(&mut io, &EncodeOptions::new()).unwrap();
+        assert_eq!(&output[..3], b"\x00\xe8\x03");
+        assert_eq!(&output[3..], &input[..]);
+    }
+
+    #[test]
+    fn encode_v2_stored_mixed() {
+        // Random data followed by compressible data, with backreferences
+        // into the stored part.
+        let mut input = random_bytes(100000);
+        input.extend_from_within(90000..95000);
+        input.extend(sample_text());
+        for &strategy in &[ParseStrategy::Greedy, ParseStrategy::Optimal] {
+            let options = EncodeOptions::new().strategy(strategy);
+            let len = encoded_len(&input, &options, true);
+            let bound = 100000 + 3 * 4 + 100 + sample_text().len() / 2;
+            assert!(len < bound, "{:?}: {}", strategy, len);
+        }
+    }
+
+    /// Returns the (length, distance field) of every backreference in
+    /// a version 1 stream.
+    fn v1_matches(encoded: &[u8]) -> Vec<(u8, u16)> {
+        let mut matches = Vec::new();
+        let mut i = 0;
+        while i < encoded.len() {
+            let b = encoded[i];
+            if b < 128 {
+                i += 1 + b as usize;
+            } else {
+                let dist = encoded[i + 1] as u16 | (encoded[i + 2] as u16) << 8;
+                matches.push((b & 0x7f, dist));
+                i += 3;
+            }
+        }
+        matches
+    }
+
+    #[test]
+    fn encode_match_filter_veto() {
+        let input = sample_text();
+        let options = EncodeOptions::new().version(FormatVersion::V1).match_filter(|_| None);
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(&input[..], &mut output);
+        encode(&mut io, &options).unwrap();
+        assert!(v1_matches(&output).is_empty());
+        assert_eq!(output.len(), input.len() + input.len().div_ceil(127));
+    }
+
+    #[test]
+    fn encode_match_filter_record_boundary() {
+        // Backreferences must not extend past the end of a 16-byte record.
+        let input = sample_text();
+        for &strategy in &[ParseStrategy::Greedy, ParseStrategy::Lazy, ParseStrategy::Optimal] {
+            let options = EncodeOptions::new().version(FormatVersion::V1).strategy(strategy)
+                .match_filter(|c| {
+                if c.pos / 16 == (c.pos + c.len - 1) / 16 { Some(0) } else { None }
+            });
+            let mut output = Vec::new();
+            let mut io = SliceToVecIO::new(&input[..], &mut output);
+            encode(&mut io, &options).unwrap();
+            let mut decoded = Vec::new();
+            let mut io = SliceToVecIO::new(&output[..], &mut decoded);
+            decode(&mut io).unwrap();
+            assert_eq!(decoded, input);
+            assert!(v1_matches(&output).iter().all(|&(len, _)| len <= 16));
+        }
+    }
+
+    #[test]
+    fn encode_match_filter_bias() {
+        // A large penalty for distances over 64 leaves only short ones.
+        let input = sample_text();
+        let options = EncodeOptions::new().version(FormatVersion::V1).match_filter(|c| {
+            if c.distance > 64 { Some(-1000) } else { Some(0) }
+        });
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(&input[..], &mut output);
+        encode(&mut io, &options).unwrap();
+        let matches = v1_matches(&output);
+        assert!(!matches.is_empty());
+        assert!(matches.iter().all(|&(_, dist)| dist < 64));
+    }
+
+    #[test]
+    fn compress_decompress() {
+        assert_eq!(compress(b""), b"\x82");
+        assert_eq!(decompress(b"").unwrap(), b"");
+        let input = sample_text();
+        let compressed = compress(&input);
+        assert!(compressed.len() < input.len() / 2);
+        assert_eq!(decompress(&compressed).unwrap(), input);
+    }
+
+    #[test]
+    fn decompress_invalid() {
+        assert!(decompress(b"\x82\x01a\xd0").is_err());
+        assert!(decompress(b"\x82\x01a\x80\x00").is_err());
+        assert!(decompress(b"\x83\x01a").is_err());
+    }
+
+    #[test]
+    fn tans_cost() {
+        let mut freqs = [0u32; 256];
+        freqs[0xaa] = 512;
+        freqs[1] = 256;
+        let cost = TansCost::new(10, &freqs);
+        assert_eq!(cost.literal(0xaa), COST_SCALE);
+        assert_eq!(cost.literal(b'z'), MAX_BYTE_COST);
+        assert_eq!(cost.literal_run(), 2 * COST_SCALE);
+        assert_eq!(cost.backreference(&[0xaa, 1]), 3 * COST_SCALE);
+
+        // A flat table costs 8 bits per byte, like ByteCost.
+        let flat = TansCost::new(8, &[1; 256]);
+        let input = sample_text();
+        for &strategy in &[ParseStrategy::Greedy, ParseStrategy::Lazy, ParseStrategy::Optimal] {
+            let options = EncodeOptions::new().strategy(strategy);
+            assert_eq!(compress_with(&input, &options.clone().token_cost(flat.clone())),
+                       compress_with(&input, &options));
+        }
+    }
+
+    /// Backreferences cost more than any literals they could replace.
+    struct NoMatches;
+
+    impl TokenCost for NoMatches {
+        fn literal(&self, _b: u8) -> u32 { 1 }
+
+        fn literal_run(&self) -> u32 { 1 }
+
+        fn backreference(&self, _token: &[u8]) -> u32 { 1000 }
+    }
+
+    #[test]
+    fn token_cost_custom() {
+        let input = sample_text();
+        for &strategy in &[ParseStrategy::Greedy, ParseStrategy::Lazy, ParseStrategy::Optimal] {
+            let options = EncodeOptions::new().version(FormatVersion::V1).strategy(strategy)
+                .token_cost(NoMatches);
+            let compressed = compress_with(&input, &options);
+            assert_eq!(v1_matches(&compressed), []);
+            assert_eq!(decompress(&compressed).unwrap(), input);
+        }
+    }
 
-use crate::io::{IOTrait, LookbackInput, RepeatOutput};
-use crate::result::BoxResult;
+    #[test]
+    fn encode_literal_runs_coalesced() {
+        // Without backreferences, every literal run but the last is full,
+        // including across the blocks of the optimal parser.
+        let input = sample_text();
+        for &strategy in &[ParseStrategy::Greedy, ParseStrategy::Lazy, ParseStrategy::Optimal] {
+            let options = EncodeOptions::new().version(FormatVersion::V1).strategy(strategy)
+                .token_cost(NoMatches);
+            let compressed = compress_with(&input, &options);
+            assert_eq!(compressed.len(), input.len() + input.len().div_ceil(127), "{:?}",
+                       strategy);
+        }
+    }
 
-pub fn decode<IO: IOTrait + RepeatOutput>(io: &mut IO) -> BoxResult<()> {
-    while let Some(b) = io.next_byte()? {
-        if b < 128 {
-            io.copy_bytes(b as usize)?;
-        } else {
-            let lo = io.next_byte()?.expect("end of input inside backreference");
-            let hi = io.next_byte()?.expect("end of input inside backreference");
-            let dist = ((hi as usize) << 8) | lo as usize;
-            io.repeat_bytes((b & 0x7f) as usize, dist)?;
+    #[test]
+    fn compress_bound_holds() {
+        assert_eq!(compress(b"").len(), compress_bound(0));
+        let sizes = [1, 2, 127, 128, 1000, BLOCK_SIZE as usize, 3 * BLOCK_SIZE as usize + 5];
+        for &size in &sizes {
+            let mut inputs = vec![random_bytes(size)];
+            // Alternate short runs of random and repeated bytes.
+            let mut mixed = random_bytes(size);
+            for (i, b) in mixed.iter_mut().enumerate() {
+                if i % 8 < 3 { *b = 0 }
+            }
+            inputs.push(mixed);
+            for input in &inputs {
+                for &strategy in &[ParseStrategy::Greedy, ParseStrategy::Lazy,
+                                   ParseStrategy::Optimal] {
+                    let options = EncodeOptions::new().strategy(strategy);
+                    let compressed = compress_with(input, &options);
+                    assert!(compressed.len() <= compress_bound(size),
+                            "{:?} {}: {}", strategy, size, compressed.len());
+                }
+            }
         }
     }
-    Ok(())
-}
 
-struct EncoderState {
-    // We find repititions by computing a rolling hash of the most recently
-    // seen 3 bytes.
-    hash: u32,
-    
-    // We limit the magnitude of hash by bitwise anding with this mask.
-    hash_mask: u32,
-    
-    // For each hash value, we record the most recent position at which
-    // we have encountered it.
+    #[test]
+    fn reference_roundtrip() {
+        let reference = random_bytes(8000);
+        let mut input = reference[100..3000].to_vec();
+        input.extend(b"an edit in the middle");
+        input.extend_from_slice(&reference[3000..]);
+        for &version in &[FormatVersion::V1, FormatVersion::V2] {
+            let options = EncodeOptions::new().version(version);
+            let delta = compress_with_reference(&reference, &input, &options);
+            let plain = encoded_len(&input, &options, version == FormatVersion::V2);
+            assert!(delta.len() < plain / 4, "{} vs {}", delta.len(), plain);
+            assert_eq!(decompress_with_reference(&reference, &delta).unwrap(), input);
+        }
+        // Without the reference, backreferences point before the start.
+        let delta = compress_with_reference(&reference, &input, &EncodeOptions::new());
+        assert!(decompress(&delta).is_err());
+    }
 
-    pos: std::vec::Vec::<u64>,
-    // The literal length we have accumulated so far.
-    litlen: u8,
-}
+    #[test]
+    fn reference_long() {
+        // Only the end of a long reference is used.
+        let mut reference = sample_text();
+        reference.extend(random_bytes(WINDOW_SIZE));
+        let input = reference[reference.len() - 5000..].to_vec();
+        let delta = compress_with_reference(&reference, &input, &EncodeOptions::new());
+        assert!(delta.len() < 100);
+        assert_eq!(decompress_with_reference(&reference, &delta).unwrap(), input);
+        assert_eq!(decompress_with_reference(b"", &compress_with_reference(b"", &input,
+            &EncodeOptions::new())).unwrap(), input);
+    }
+
+    fn decode_error_of(input: &[u8]) -> (u64, TokenKind) {
+        let err = decompress(input).unwrap_err();
+        let err = err.downcast_ref::<DecodeError>().expect("not a DecodeError");
+        (err.offset, err.token)
+    }
+
+    #[test]
+    fn decode_error_details() {
+        // Distance beyond the start of the output.
+        assert_eq!(decode_error_of(b"\x82\x01a\x80\x05\x00"), (3, TokenKind::Backreference));
+        // Truncated backreferences.
+        assert_eq!(decode_error_of(b"\x01a\x80\x00"), (2, TokenKind::Backreference));
+        assert_eq!(decode_error_of(b"\x01a\xc0"), (2, TokenKind::Backreference));
+        assert_eq!(decode_error_of(b"\x82\x01a\x8f"), (3, TokenKind::Backreference));
+        // Literal run and stored block past the end of the input.
+        assert_eq!(decode_error_of(b"\x01a\x05ab"), (2, TokenKind::Literals));
+        assert_eq!(decode_error_of(b"\x82\x00\x05\x00abc"), (1, TokenKind::Stored));
+        assert_eq!(decode_error_of(b"\x82\x00\x05"), (1, TokenKind::Stored));
+        // Reserved backreference kind and unsupported version.
+        assert_eq!(decode_error_of(b"\x82\x01a\xd0"), (3, TokenKind::Backreference));
+        assert_eq!(decode_error_of(b"\x85"), (0, TokenKind::Version));
 
-impl EncoderState {
-    fn new() -> EncoderState {
-        let mask = (1 << 14) - 1;
-        let mut pos_table = Vec::new();
-        pos_table.resize(mask + 1, 0);
-        EncoderState {
-            hash: 0,
-            hash_mask: mask as u32,
-            pos: pos_table,
-            litlen: 0,
+        let err = decompress(b"\x01a\x05ab").unwrap_err();
+        assert_eq!(err.to_string(),
+                   "end of input while copying bytes in literal run at input offset 2");
+
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(b"BREF\x02\x00\x01\x00\x00\x00\x00\x00\x00\x00\x01a",
+                                       &mut output);
+        let err = decode_framed(&mut io).unwrap_err();
+        let err = err.downcast_ref::<DecodeError>().unwrap();
+        assert_eq!((err.offset, err.token), (16, TokenKind::EndMarker));
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(b"BREF\x07", &mut output);
+        let err = decode_framed(&mut io).unwrap_err();
+        assert_eq!(err.downcast_ref::<DecodeError>().unwrap().token, TokenKind::FrameHeader);
+    }
+
+    /// Returns a copy of input with a random corruption applied.
+    fn corrupt(input: &[u8], rng: &mut impl Iterator<Item = u32>) -> Vec<u8> {
+        let mut next = |n: usize| (rng.next().unwrap() >> 8) as usize % n.max(1);
+        let mut data = input.to_vec();
+        match next(5) {
+            0 => {
+                let i = next(data.len());
+                if let Some(b) = data.get_mut(i) { *b ^= 1 << next(8) }
+            },
+            1 => {
+                let i = next(data.len());
+                data[i..].iter_mut().take(4).for_each(|b| *b = next(256) as u8);
+            },
+            2 => data.truncate(next(data.len())),
+            3 => data.insert(next(data.len() + 1), next(256) as u8),
+            _ => {
+                let i = next(data.len());
+                let n = next(data.len() - i);
+                data.extend_from_within(i..i + n);
+            },
         }
+        data
     }
-    
-    fn find_rep<IO: IOTrait + LookbackInput>(&mut self, io: &mut IO)
-                                             -> BoxResult<(u8, u8, u64)> {
-        while let Some(b) = io.next_byte()? {
-            let pos = io.inpos();
-            let prev = self.update_hash(b, pos);
-            // Only return matches of length at least 3 that occur within
-            // 0x10000 bytes of the current position.
-            if prev >= 3 && (pos < 0x10000 || prev > pos - 0x10000) &&
-                io.lookback(prev - 3) == io.lookback(pos - 3) &&
-                io.lookback(prev - 2) == io.lookback(pos - 2) &&
-                io.lookback(prev - 1) == io.lookback(pos - 1)
-            {
-                return self.found_rep(io, pos, prev);
-            }
-            self.litlen += 1;
-            if self.litlen == 127 {
-                self.litlen = 0;
-                return Ok((127, 0, 0))
+
+    #[test]
+    fn decode_corrupted() {
+        // Every decoder must return an error rather than panic, and the
+        // windowed decoder must agree with the in-memory one.
+        let input = sample_text()[..3000].to_vec();
+        let mut streams = Vec::new();
+        for &version in &[FormatVersion::V1, FormatVersion::V2] {
+            let options = EncodeOptions::new().version(version);
+            streams.push((false, compress_with(&input, &options)));
+            let mut framed = Vec::new();
+            let mut io = SliceToVecIO::new(&input[..], &mut framed);
+            encode_framed(&mut io, &options).unwrap();
+            streams.push((true, framed));
+        }
+        let parallel = compress_parallel(&input, &EncodeOptions::new(),
+                                         &ParallelOptions::new().block_size(1000).overlap(500))
+            .unwrap();
+        let mut rng = lcg(1);
+        for _ in 0..500 {
+            for (framed, stream) in &streams {
+                let data = corrupt(stream, &mut rng);
+                let mut windowed = Vec::new();
+                if *framed {
+                    let mut output = Vec::new();
+                    let mut io = SliceToVecIO::new(&data, &mut output);
+                    let result = decode_framed(&mut io).map(|_| output);
+                    let windowed = decode_framed_windowed(&mut &data[..], &mut windowed)
+                        .map(|_| windowed);
+                    assert_eq!(result.ok(), windowed.ok());
+                } else {
+                    let result = decompress(&data);
+                    let windowed = decode_windowed(&mut &data[..], &mut windowed)
+                        .map(|_| windowed);
+                    assert_eq!(result.ok(), windowed.ok());
+                    let mut output = Vec::new();
+                    let mut io = SliceToVecIO::new(&data, &mut output);
+                    let _ = decode_v2(&mut io);
+                }
             }
+            let _ = decompress_parallel(&corrupt(&parallel, &mut rng));
         }
-
-        let litlen = self.litlen;
-        self.litlen = 0;
-        Ok((litlen, 0, 0))
     }
 
-    fn found_rep<IO: IOTrait + LookbackInput>(&mut self, io: &mut IO, pos: u64, prev: u64)
-                                              -> BoxResult<(u8, u8, u64)> {
-        let litlen_before = if self.litlen > 2 { self.litlen - 2 } else { 0 };
-        let dist = pos - prev - 1;
-        let mut matlen = 3;
-        let mut prevpos = prev;
-        self.litlen = 0;
-        while let Some(b) = io.next_byte()? {
-            self.update_hash(b, io.inpos() - 1);
-            if b == io.lookback(prevpos) {
-                matlen += 1;
-                prevpos += 1;
-            } else {
-                self.litlen = 1;
-                break;
-            }
-            if matlen == 127 { break };
+    #[test]
+    fn compress_parallel_roundtrip() {
+        let mut input = sample_text();
+        input.extend(random_bytes(3000));
+        input.extend(sample_text());
+        let options = EncodeOptions::new();
+        for &(overlap, threads) in &[(0, 1), (0, 4), (1000, 3)] {
+            let parallel = ParallelOptions::new().block_size(5000).overlap(overlap)
+                .threads(threads);
+            let compressed = compress_parallel(&input, &options, &parallel).unwrap();
+            let nblocks = input.len().div_ceil(5000);
+            assert_eq!(&compressed[..4], &(nblocks as u32).to_le_bytes());
+            assert_eq!(decompress_parallel(&compressed).unwrap(), input);
         }
-        return Ok((litlen_before, matlen, dist));
+        let empty = compress_parallel(b"", &options, &ParallelOptions::new()).unwrap();
+        assert_eq!(empty, b"\x00\x00\x00\x00\x00\x00\x00\x00");
+        assert_eq!(decompress_parallel(&empty).unwrap(), b"");
     }
 
-    /// Updates the hash value, records the position at which we encountered
-    /// it, and returns the previously most recent position for the same
-    /// hash value.
-    fn update_hash(&mut self, b: u8, pos: u64) -> u64 {
-        self.hash = ((self.hash << 5) ^ b as u32) & self.hash_mask;
-        let prev = self.pos[self.hash as usize];
-        self.pos[self.hash as usize] = pos;
-        prev
+    #[test]
+    fn compress_parallel_overlap() {
+        // Every block repeats the previous one, which only the overlap
+        // can take advantage of.
+        let block = random_bytes(1000);
+        let input: Vec<u8> = (0..8).flat_map(|_| block.iter().copied()).collect();
+        let options = EncodeOptions::new();
+        let parallel = ParallelOptions::new().block_size(1000);
+        let without = compress_parallel(&input, &options, &parallel).unwrap();
+        let with = compress_parallel(&input, &options, &parallel.overlap(1000)).unwrap();
+        assert!(with.len() < without.len() / 4);
+        assert_eq!(decompress_parallel(&with).unwrap(), input);
     }
-}
 
-fn write_lit<IO: IOTrait + LookbackInput>(io: &mut IO, litlen: u8, start: u64) -> BoxResult<()> {
-    io.write_byte(litlen)?;
-    let mut pos = start;
-    for _ in 0..litlen {
-        io.write_byte(io.lookback(pos))?;
-        pos += 1;
+    #[test]
+    fn decompress_parallel_invalid() {
+        let input = sample_text();
+        let parallel = ParallelOptions::new().block_size(1000);
+        let compressed = compress_parallel(&input, &EncodeOptions::new(), &parallel).unwrap();
+        assert!(decompress_parallel(&compressed[..compressed.len() - 1]).is_err());
+        assert!(decompress_parallel(&compressed[..20]).is_err());
+        let mut extra = compressed.clone();
+        extra.push(0);
+        assert!(decompress_parallel(&extra).is_err());
     }
-    Ok(())
-}
 
-pub fn encode<IO: IOTrait + LookbackInput>(io: &mut IO) -> BoxResult<()> {
-    let mut state = EncoderState::new();
-    loop {
-        let pos = io.inpos() - state.litlen as u64;
-        let (litlen, matlen, dist) = state.find_rep(io)?;
-        if litlen == 0 && matlen == 0 { return Ok(()) }
-        if litlen > 0 {
-            write_lit(io, litlen, pos)?;
+    #[test]
+    fn block_index_overflow() {
+        let mut output = Vec::new();
+        write_u32(&mut output, u32::MAX as usize).unwrap();
+        assert_eq!(output, [0xff; 4]);
+        if let Some(n) = (u32::MAX as usize).checked_add(1) {
+            assert!(write_u32(&mut output, n).is_err());
+            assert_eq!(output.len(), 4);
         }
-        if matlen > 0 {
-            io.write_byte(0x80 + matlen)?;
-            io.write_byte((dist & 0xff) as u8)?;
-            io.write_byte((dist >> 8) as u8)?;
+    }
+
+    #[test]
+    fn hash_functions() {
+        for &bits in &[8, 14, 24] {
+            for bytes in &[&b"abc"[..], b"\xff\xff\xff", b"abcdefgh"] {
+                assert!(multiplicative_hash(bytes, bits) < 1 << bits);
+                assert!(shift_xor_hash(bytes, bits) < 1 << bits);
+            }
         }
+        assert_ne!(multiplicative_hash(b"abc", 14), multiplicative_hash(b"abd", 14));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::io::SliceToVecIO;
+    #[test]
+    fn encode_hash_function() {
+        let input = sample_text();
+        for &hash in &[multiplicative_hash as HashFunction, shift_xor_hash] {
+            let options = EncodeOptions::new().hash_function(hash);
+            assert!(encoded_len(&input, &options, true) < input.len() * 3 / 5);
+        }
+        // A hash function that maps everything to the same slot only
+        // remembers the previous position.
+        let options = EncodeOptions::new().version(FormatVersion::V1).bucket_size(1)
+            .hash_function(|_, _| 0);
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(b"abcdabcdddddd", &mut output);
+        encode(&mut io, &options).unwrap();
+        assert_eq!(output, b"\x08abcdabcd\x85\x00\x00");
+    }
 
     #[test]
-    fn decode_empty() {
-        let input = b"";
+    fn encode_long_hash() {
+        // "abcd" was last seen at 0, but "abc" was last seen at 5. The
+        // secondary table finds the longer match.
+        let input = b"abcdxabcyabcd";
+        let options = EncodeOptions::new().version(FormatVersion::V1).bucket_size(1);
         let mut output = Vec::new();
         let mut io = SliceToVecIO::new(input, &mut output);
-        decode(&mut io).unwrap();
-        assert_eq!(output, b"");
+        encode(&mut io, &options).unwrap();
+        assert_eq!(output, b"\x05abcdx\x83\x04\x00\x01y\x83\x03\x00\x01d");
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(input, &mut output);
+        encode(&mut io, &options.long_hash_len(4)).unwrap();
+        assert_eq!(output, b"\x05abcdx\x83\x04\x00\x01y\x84\x08\x00");
+
+        let input = sample_text();
+        for &len in &[4, 5, 8] {
+            let options = EncodeOptions::new().long_hash_len(len).hash_bits(10);
+            assert!(encoded_len(&input, &options, true) < input.len() * 3 / 5);
+        }
     }
 
     #[test]
-    fn decode_litlit() {
-        let input = b"\x03xyz\x02zy";
+    fn encode_framed_empty() {
         let mut output = Vec::new();
-        let mut io = SliceToVecIO::new(input, &mut output);
-        decode(&mut io).unwrap();
-        assert_eq!(output, b"xyzzy");
+        let mut io = SliceToVecIO::new(b"", &mut output);
+        encode_framed(&mut io, &EncodeOptions::new()).unwrap();
+        assert_eq!(output, b"BREF\x02\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00");
     }
 
     #[test]
-    fn decode_rep0() {
-        let input = b"\x01a\x84\x00\x00";
+    fn framed_roundtrip() {
+        let input = sample_text();
         let mut output = Vec::new();
-        let mut io = SliceToVecIO::new(input, &mut output);
-        decode(&mut io).unwrap();
+        let mut io = SliceToVecIO::new(&input[..], &mut output);
+        encode_framed(&mut io, &EncodeOptions::new()).unwrap();
+        assert_eq!(&output[..6], b"BREF\x02\x00");
+        assert_eq!(&output[6..14], &(input.len() as u64).to_le_bytes());
+        assert_eq!(&output[output.len() - 3..], b"\x00\x00\x00");
+        let mut decoded = Vec::new();
+        let mut io = SliceToVecIO::new(&output[..], &mut decoded);
+        decode_framed(&mut io).unwrap();
+        assert_eq!(decoded, input);
+        // The output is allocated once, using the size in the header.
+        assert_eq!(decoded.capacity(), input.len());
+    }
+
+    #[test]
+    fn framed_checksum() {
+        let input = sample_text();
+        for &version in &[FormatVersion::V1, FormatVersion::V2] {
+            let options = EncodeOptions::new().version(version).checksum(true);
+            let mut output = Vec::new();
+            encode_framed(&mut SliceToVecIO::new(&input[..], &mut output), &options).unwrap();
+            assert_eq!(output[5], FRAME_CHECKSUM);
+            assert_eq!(&output[output.len() - 4..], &crate::crc32::crc32(&input).to_le_bytes());
+            let mut decoded = Vec::new();
+            decode_framed(&mut SliceToVecIO::new(&output[..], &mut decoded)).unwrap();
+            assert_eq!(decoded, input);
+            let mut decoded = Vec::new();
+            decode_framed_windowed(&mut &output[..], &mut decoded).unwrap();
+            assert_eq!(decoded, input);
+
+            // Changing a literal still decodes to the right length, but
+            // the checksum catches it.
+            let mut corrupted = output.clone();
+            corrupted[15] ^= 1;
+            let err = decode_framed(&mut SliceToVecIO::new(&corrupted[..], &mut Vec::new()))
+                .unwrap_err();
+            let err = err.downcast_ref::<DecodeError>().expect("not a DecodeError");
+            assert_eq!((err.offset, err.token), (output.len() as u64 - 4, TokenKind::Checksum));
+
+            // Truncated checksum.
+            let truncated = &output[..output.len() - 1];
+            let err = decode_framed(&mut SliceToVecIO::new(truncated, &mut Vec::new()))
+                .unwrap_err();
+            let err = err.downcast_ref::<DecodeError>().expect("not a DecodeError");
+            assert_eq!(err.token, TokenKind::Checksum);
+        }
+    }
+
+    #[test]
+    fn decode_framed_checksum() {
+        let input = b"BREF\x01\x01\x05\x00\x00\x00\x00\x00\x00\x00\x01a\x84\x00\x00\x00\
+                      \xb9\x93\xac\xee";
+        let mut output = Vec::new();
+        decode_framed(&mut SliceToVecIO::new(input, &mut output)).unwrap();
         assert_eq!(output, b"aaaaa");
     }
 
     #[test]
-    fn decode_rep2() {
-        let input = b"\x03abc\x83\x02\x00";
+    fn decode_framed_v1() {
+        let input = b"BREF\x01\x00\x05\x00\x00\x00\x00\x00\x00\x00\x01a\x84\x00\x00\x00";
         let mut output = Vec::new();
         let mut io = SliceToVecIO::new(input, &mut output);
-        decode(&mut io).unwrap();
-        assert_eq!(output, b"abcabc");
+        decode_framed(&mut io).unwrap();
+        assert_eq!(output, b"aaaaa");
     }
 
     #[test]
-    fn decode_rep3() {
-        let input = b"\x04abcd\x83\x03\x00";
+    fn encode_framed_v1() {
+        let input = sample_text();
         let mut output = Vec::new();
-        let mut io = SliceToVecIO::new(input, &mut output);
-        decode(&mut io).unwrap();
-        assert_eq!(output, b"abcdabc");
+        let mut io = SliceToVecIO::new(&input[..], &mut output);
+        encode_framed(&mut io, &EncodeOptions::new().version(FormatVersion::V1)).unwrap();
+        assert_eq!(&output[..6], b"BREF\x01\x00");
+        assert_eq!(output[output.len() - 1], 0);
+        let mut decoded = Vec::new();
+        let mut io = SliceToVecIO::new(&output[..], &mut decoded);
+        decode_framed(&mut io).unwrap();
+        assert_eq!(decoded, input);
     }
 
     #[test]
-    fn encode_empty() {
-        let input = b"";
+    fn encode_version_byte() {
+        let input = sample_text();
+        for &version in &[FormatVersion::V1, FormatVersion::V2] {
+            let mut output = Vec::new();
+            let mut io = SliceToVecIO::new(&input[..], &mut output);
+            encode(&mut io, &EncodeOptions::new().version(version)).unwrap();
+            match version {
+                FormatVersion::V1 => assert!(output[0] < 0x80),
+                FormatVersion::V2 => assert_eq!(output[0], 0x82),
+            }
+            assert_eq!(decompress(&output).unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn decode_detects_version() {
+        // Plain version 1, version 1 with a version byte, and version 2.
+        let cases: &[&[u8]] = &[b"\x01a\x84\x00\x00", b"\x81\x01a\x84\x00\x00",
+                                b"\x82\x01a\x81\x00\x00"];
+        for &case in cases {
+            assert_eq!(decompress(case).unwrap(), b"aaaaa");
+        }
+    }
+
+    #[test]
+    fn decode_windowed_roundtrip() {
+        // Longer than the window, with matches both near and far.
+        let mut input = sample_text();
+        input.extend(random_bytes(WINDOW_SIZE));
+        input.extend(sample_text());
+        input.extend_from_within(..WINDOW_SIZE / 2);
+        for &version in &[FormatVersion::V1, FormatVersion::V2] {
+            let options = EncodeOptions::new().version(version);
+            let mut compressed = Vec::new();
+            let mut io = SliceToVecIO::new(&input[..], &mut compressed);
+            encode(&mut io, &options).unwrap();
+            let mut output = Vec::new();
+            decode_windowed(&mut &compressed[..], &mut output).unwrap();
+            assert_eq!(output, input);
+
+            let mut framed = Vec::new();
+            let mut io = SliceToVecIO::new(&input[..], &mut framed);
+            encode_framed(&mut io, &options).unwrap();
+            let mut output = Vec::new();
+            decode_framed_windowed(&mut &framed[..], &mut output).unwrap();
+            assert_eq!(output, input);
+        }
+    }
+
+    /// Produces len bytes of mixed compressible and incompressible data
+    /// without keeping them in memory.
+    struct Generator {
+        pos: usize,
+        len: usize,
+    }
+
+    impl Generator {
+        fn new(len: usize) -> Generator {
+            Generator { pos: 0, len }
+        }
+
+        fn byte(&mut self) -> u8 {
+            let pos = self.pos;
+            self.pos += 1;
+            let noise = |region: usize| {
+                let x = ((region as u64) << 32) | (pos & 0x1fff) as u64;
+                (x.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 56) as u8
+            };
+            let region = pos >> 13;
+            match region % 4 {
+                0 => noise(region),
+                // A copy of a random region 40 KiB back.
+                1 if region > 4 => noise(region - 5),
+                // Random letters from a small alphabet.
+                _ => b"etaoin "[noise(region) as usize % 7],
+            }
+        }
+    }
+
+    impl std::io::Read for Generator {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = std::cmp::min(buf.len(), self.len - self.pos);
+            for b in buf[..n].iter_mut() {
+                *b = self.byte();
+            }
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn encode_stream_roundtrip() {
+        let len = EncodeOptions::new().lookback_needed() as usize + 50000;
+        let mut expected = vec![0; len];
+        std::io::Read::read_exact(&mut Generator::new(len), &mut expected).unwrap();
+        let cases = [(FormatVersion::V1, ParseStrategy::Greedy),
+                     (FormatVersion::V2, ParseStrategy::Lazy),
+                     (FormatVersion::V2, ParseStrategy::Optimal)];
+        for &(version, strategy) in &cases {
+            let options = EncodeOptions::new().version(version).strategy(strategy);
+            let mut compressed = Vec::new();
+            encode_stream(&mut Generator::new(len), &mut compressed, &options).unwrap();
+            assert_eq!(compressed, compress_with(&expected, &options));
+            let mut output = Vec::new();
+            decode_windowed(&mut &compressed[..], &mut output).unwrap();
+            assert!(output == expected, "{:?} {:?}", version, strategy);
+        }
+    }
+
+    #[test]
+    fn encode_lookback_window() {
+        use crate::io::LookbackRing;
+        let len = 200000;
+        let mut expected = vec![0; len];
+        std::io::Read::read_exact(&mut Generator::new(len), &mut expected).unwrap();
+        for &strategy in &[ParseStrategy::Greedy, ParseStrategy::Lazy, ParseStrategy::Optimal] {
+            // A small window needs less history, and the encoder never
+            // looks back further than it says it needs.
+            let options = EncodeOptions::new().strategy(strategy).window_size(4096);
+            let needed = options.lookback_needed();
+            assert!(needed < EncodeOptions::new().lookback_needed());
+            let mut input = Generator::new(len);
+            let mut compressed = Vec::new();
+            {
+                let inner = StreamIO::with_output_window(&mut input, &mut compressed, 1);
+                let mut io = LookbackRing::new(inner, needed as usize);
+                encode(&mut io, &options).unwrap();
+                io.get_mut().flush().unwrap();
+            }
+            assert!(compressed == compress_with(&expected, &options), "{:?}", strategy);
+        }
+
+        // Too little history is an error, not a panic.
+        let options = EncodeOptions::new();
         let mut output = Vec::new();
-        let mut io = SliceToVecIO::new(input, &mut output);
-        encode(&mut io).unwrap();
-        assert_eq!(output, b"");
+        let needed = options.lookback_needed() as usize;
+        let mut io = LookbackRing::new(SliceToVecIO::new(&expected, &mut output), needed - 1);
+        assert!(encode(&mut io, &options).is_err());
+        assert!(encode_framed(&mut io, &options).is_err());
+        assert!(output.is_empty());
     }
 
     #[test]
-    fn encode_lit() {
-        let input = b"a";
+    fn encode_rebase() {
+        // Rebasing the hash tables often gives the same result as never
+        // rebasing them.
+        let mut input = Vec::new();
+        std::io::Read::read_to_end(&mut Generator::new(200000), &mut input).unwrap();
+        for &window in &[1000, 65536] {
+            let options = EncodeOptions::new().window_size(window);
+            let mut output = Vec::new();
+            let mut io = SliceToVecIO::new(&input, &mut output);
+            let mut parser = Parser::new(&options, V2Writer::new());
+            parser.finder.rebase_at = 70000;
+            parser.parse(&mut io, 0).unwrap();
+            let mut expected = Vec::new();
+            let mut io = SliceToVecIO::new(&input, &mut expected);
+            encode_v2(&mut io, &options).unwrap();
+            assert!(output == expected, "{}", window);
+        }
+    }
+
+    fn stats_of(input: &[u8], options: &EncodeOptions) -> EncodeStats {
         let mut output = Vec::new();
-        let mut io = SliceToVecIO::new(input, &mut output);
-        encode(&mut io).unwrap();
-        assert_eq!(output, b"\x01a");
+        let stats = encode_with_stats(&mut SliceToVecIO::new(input, &mut output), options).unwrap();
+        assert!(output == compress_with(input, options));
+        assert_eq!(stats.literal_bytes + stats.match_bytes + stats.stored_bytes,
+                   input.len() as u64);
+        assert_eq!(stats.match_lengths.iter().sum::<u64>(), stats.matches);
+        assert_eq!(stats.match_distances.iter().sum::<u64>(), stats.matches);
+        assert!(stats.hash_collisions <= stats.hash_lookups);
+        stats
     }
 
     #[test]
-    fn encode_litlit() {
-        let input : Vec::<u8> = (0..=253).collect();
+    fn encode_stats() {
+        let stats = stats_of(b"abcabcabcabc", &EncodeOptions::new());
+        assert_eq!((stats.literal_runs, stats.literal_bytes), (1, 3));
+        assert_eq!((stats.matches, stats.match_bytes), (1, 9));
+        assert_eq!(stats.match_lengths[9], 1);
+        assert_eq!(stats.match_distances[1], 1);
+        assert_eq!(stats.stored_blocks, 0);
+
+        // Incompressible input is stored.
+        let input = random_bytes(1000);
+        let stats = stats_of(&input, &EncodeOptions::new());
+        assert_eq!((stats.stored_blocks, stats.stored_bytes), (1, 1000));
+        assert_eq!(stats.literal_bytes + stats.match_bytes, 0);
+
+        let input = sample_text();
+        for strategy in &[ParseStrategy::Greedy, ParseStrategy::Lazy, ParseStrategy::Optimal] {
+            for &version in &[FormatVersion::V1, FormatVersion::V2] {
+                let options = EncodeOptions::new().strategy(*strategy).version(version);
+                let stats = stats_of(&input, &options);
+                assert!(stats.matches > 0 && stats.hash_lookups > 0);
+                assert!(stats.collision_rate() < 1.0);
+            }
+        }
+    }
+
+    #[test]
+    fn encode_stats_collisions() {
+        // With a single hash bucket, collisions are easy to provoke by
+        // using the smallest hash table.
+        let input = sample_text();
+        let small = stats_of(&input, &EncodeOptions::new().hash_bits(8).bucket_size(1));
+        let large = stats_of(&input, &EncodeOptions::new().bucket_size(1));
+        assert!(small.collision_rate() > large.collision_rate());
+        assert_eq!(EncodeStats::default().collision_rate(), 0.0);
+    }
+
+    #[test]
+    fn stream_io_roundtrip() {
+        let input = sample_text();
+        let options = EncodeOptions::new().strategy(ParseStrategy::Optimal);
+        let mut io = StreamIO::new(&input[..], Vec::new());
+        encode(&mut io, &options).unwrap();
+        let (_, compressed) = io.finish().unwrap();
+        assert!(compressed == compress_with(&input, &options));
+        let mut io = StreamIO::new(&compressed[..], Vec::new());
+        decode(&mut io).unwrap();
+        let (_, decoded) = io.finish().unwrap();
+        assert!(decoded == input);
+    }
+
+    fn push_decode(compressed: &[u8], chunk: usize) -> BoxResult<Vec<u8>> {
+        let mut decoder = PushDecoder::new();
         let mut output = Vec::new();
-        let mut io = SliceToVecIO::new(&input[..], &mut output);
-        encode(&mut io).unwrap();
-        let expected : Vec::<u8> = (0x7f..=0x7f).chain(0..=126)
-            .chain(0x7f..=0x7f).chain(127..=253).collect();
-        assert_eq!(output, expected);
+        for data in compressed.chunks(chunk) {
+            assert_eq!(decoder.push_input(data)?, PushStatus::NeedsInput);
+            output.extend(decoder.take_output());
+        }
+        decoder.finish()?;
+        output.extend(decoder.take_output());
+        Ok(output)
     }
-    
+
     #[test]
-    fn encode_rep0() {
-        let input = b"aaaaa";
+    fn push_decoder() {
+        let input = sample_text();
+        for &version in &[FormatVersion::V1, FormatVersion::V2] {
+            let compressed = compress_with(&input, &EncodeOptions::new().version(version));
+            for &chunk in &[1, 2, 7, 1000, compressed.len()] {
+                assert!(push_decode(&compressed, chunk).unwrap() == input);
+            }
+            assert!(push_decode(&compressed[..compressed.len() - 1], 5).is_err());
+        }
+        assert_eq!(push_decode(b"", 1).unwrap(), b"");
+        assert_eq!(push_decode(b"\x82\x01a\x80\x00\x00", 1).unwrap(), b"aaaa");
+        let err = push_decode(b"\x82\x01a\x80\x05\x00", 1).unwrap_err();
+        let err = err.downcast_ref::<DecodeError>().expect("not a DecodeError");
+        assert_eq!((err.offset, err.token), (3, TokenKind::Backreference));
+
+        // Long enough that the decoder discards old input and output.
+        let mut input = Vec::new();
+        std::io::Read::read_to_end(&mut Generator::new(300000), &mut input).unwrap();
+        let compressed = compress(&input);
+        assert!(push_decode(&compressed, 4999).unwrap() == input);
+    }
+
+    #[test]
+    fn decompress_into_slice() {
+        let input = sample_text();
+        let compressed = compress(&input);
+        let mut output = vec![0u8; input.len() + 10];
+        assert_eq!(decompress_into(&compressed, &mut output).unwrap(), input.len());
+        assert!(output[..input.len()] == input[..]);
+        let err = decompress_into(&compressed, &mut output[..input.len() - 1]).unwrap_err();
+        assert!(err.is::<OutputFull>());
+    }
+
+    #[test]
+    fn decode_limited() {
+        use crate::io::{LimitedInput, LimitedOutput};
+        // A long run of zeros compresses to a few bytes.
+        let input = vec![0u8; 100000];
+        let compressed = compress(&input);
         let mut output = Vec::new();
-        let mut io = SliceToVecIO::new(input, &mut output);
-        encode(&mut io).unwrap();
-        assert_eq!(output, b"\x01a\x84\x00\x00");
+        let mut io = LimitedOutput::new(SliceToVecIO::new(&compressed, &mut output), 1000);
+        assert!(decode(&mut io).unwrap_err().is::<OutputLimitExceeded>());
+
+        let mut output = Vec::new();
+        let mut io = LimitedInput::new(SliceToVecIO::new(&compressed, &mut output), 5);
+        assert!(decode(&mut io).unwrap_err().is::<InputLimitExceeded>());
     }
 
+    #[cfg(feature = "bytes")]
     #[test]
-    fn encode_rep_lit() {
-        let input = b"aaaaab";
+    fn bytes_roundtrip() {
+        use crate::io::{ArcIO, BytesIO};
+        let input = sample_text();
+        let mut io = ArcIO::new(input.clone().into());
+        encode(&mut io, &EncodeOptions::new()).unwrap();
+        let compressed = bytes::Bytes::from(io.into_output());
+        let mut io = BytesIO::new(compressed.clone());
+        decode(&mut io).unwrap();
+        let (rest, output) = io.into_parts();
+        assert_eq!(rest, compressed);
+        assert!(output[..] == input[..]);
+    }
+
+    #[test]
+    fn encode_slices() {
+        use crate::io::SlicesToVecIO;
+        let input = sample_text();
+        let mut compressed = Vec::new();
+        let mut io = SlicesToVecIO::new(input.chunks(1000), &mut compressed);
+        encode(&mut io, &EncodeOptions::new()).unwrap();
+        assert!(compressed == compress(&input));
         let mut output = Vec::new();
-        let mut io = SliceToVecIO::new(input, &mut output);
-        encode(&mut io).unwrap();
-        assert_eq!(output, b"\x01a\x84\x00\x00\x01b");
+        let mut io = SlicesToVecIO::new(compressed.chunks(7), &mut output);
+        decode(&mut io).unwrap();
+        assert!(output == input);
     }
-    
+
     #[test]
-    fn encode_rep2() {
-        let input = b"abcabc";
+    fn boxed_io() {
+        use crate::io::{BoxDecodeIO, BoxEncodeIO, CountingIO, SliceIO};
+        let input = sample_text();
+        let mut compressed = Vec::new();
+        for counting in [false, true] {
+            compressed.clear();
+            let mut io: BoxEncodeIO = if counting {
+                Box::new(CountingIO::new(SliceToVecIO::new(&input, &mut compressed)))
+            } else {
+                Box::new(SliceToVecIO::new(&input, &mut compressed))
+            };
+            encode(&mut io, &EncodeOptions::new()).unwrap();
+        }
+        for fixed in [false, true] {
+            let mut output = Vec::new();
+            let mut buf = vec![0u8; input.len()];
+            {
+                let mut io: BoxDecodeIO = if fixed {
+                    Box::new(SliceIO::new(&compressed, &mut buf))
+                } else {
+                    Box::new(SliceToVecIO::new(&compressed, &mut output))
+                };
+                decode(&mut io).unwrap();
+            }
+            let decoded = if fixed { &buf } else { &output };
+            assert!(*decoded == input);
+        }
+    }
+
+    #[test]
+    fn decode_reader() {
+        use std::io::Read;
+        let input = sample_text();
+        let compressed = compress(&input);
+        let mut decoded = Vec::new();
+        DecodeReader::new(&compressed[..]).read_to_end(&mut decoded).unwrap();
+        assert!(decoded == input);
+
+        // Small reads return the same bytes.
+        let mut reader = DecodeReader::new(&compressed[..]);
+        let mut decoded = Vec::new();
+        let mut buf = [0u8; 3];
+        loop {
+            let n = reader.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            decoded.extend_from_slice(&buf[..n]);
+        }
+        assert!(decoded == input);
+
+        let mut decoded = Vec::new();
+        let err = DecodeReader::new(&compressed[..compressed.len() - 1])
+            .read_to_end(&mut decoded).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn encode_writer() {
+        use std::io::Write;
+        let input = sample_text();
+        let options = EncodeOptions::new().strategy(ParseStrategy::Lazy);
+        let mut writer = EncodeWriter::new(Vec::new(), options.clone());
+        for chunk in input.chunks(1000) {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.flush().unwrap();
+        let compressed = writer.finish().unwrap();
+        assert!(compressed == compress_with(&input, &options));
+
+        // Dropping the writer finishes it.
+        let mut compressed = Vec::new();
+        {
+            let mut writer = EncodeWriter::new(&mut compressed, EncodeOptions::new());
+            writer.write_all(&input).unwrap();
+        }
+        assert!(decompress(&compressed).unwrap() == input);
+    }
+
+    #[test]
+    fn encode_writer_flush() {
+        use std::io::Write;
+        let input = sample_text();
+        for &version in &[FormatVersion::V1, FormatVersion::V2] {
+            let options = EncodeOptions::new().version(version);
+            let mut compressed = Vec::new();
+            let mut writer = EncodeWriter::new(&mut compressed, options);
+            let mut sizes = Vec::new();
+            for chunk in input.chunks(3000) {
+                writer.write_all(chunk).unwrap();
+                writer.flush().unwrap();
+                sizes.push(writer.output.as_ref().unwrap().len());
+            }
+            writer.finish().unwrap();
+            // Every flush emits the input written before it, and later
+            // input still refers back to earlier input.
+            assert!(sizes.windows(2).all(|w| w[0] < w[1]));
+            assert_eq!(sizes.last(), Some(&compressed.len()));
+            assert!(compressed.len() < input.len() / 2);
+            assert!(decompress(&compressed).unwrap() == input);
+        }
+    }
+
+    #[test]
+    fn encode_writer_blocks() {
+        use std::io::Write;
+        let text = sample_text();
+        let input: Vec<u8> = random_bytes(5 * BLOCK_SIZE as usize + 123).into_iter()
+            .enumerate().map(|(i, b)| if b < 64 { b } else { text[i % text.len()] }).collect();
+        let options = EncodeOptions::new();
+        let mut writer = EncodeWriter::new(Vec::new(), options.clone());
+        let mut emitted = 0;
+        for chunk in input.chunks(7000) {
+            writer.write_all(chunk).unwrap();
+            // Complete blocks are written out without a flush, and only
+            // the lookback and one block of input are kept.
+            let len = writer.output.as_ref().unwrap().len();
+            assert!(len >= emitted);
+            emitted = len;
+            assert!(writer.input.len()
+                    <= options.lookback_needed() as usize + BLOCK_SIZE as usize);
+        }
+        assert!(emitted > input.len() / 4);
+        let compressed = writer.finish().unwrap();
+        assert!(decompress(&compressed).unwrap() == input);
+    }
+
+    #[test]
+    fn encode_writer_error() {
+        use std::io::Write;
+        /// Collects the bytes written to it, except that the first write
+        /// fails.
+        struct FailOnce {
+            failed: bool,
+            data: Vec<u8>,
+        }
+
+        impl Write for FailOnce {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                if !self.failed {
+                    self.failed = true;
+                    return Err(std::io::Error::other("full"));
+                }
+                self.data.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+        }
+
+        let mut sink = FailOnce { failed: false, data: Vec::new() };
+        let mut writer = EncodeWriter::new(&mut sink, EncodeOptions::new());
+        writer.write_all(&sample_text()).unwrap();
+        assert!(writer.finish().is_err());
+        // Dropping the writer did not write the stream again.
+        assert!(sink.data.is_empty());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_roundtrip() {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let input = sample_text();
+        let mut compressed = Vec::new();
+        runtime.block_on(encode_async(&mut &input[..], &mut compressed, &EncodeOptions::new()))
+            .unwrap();
+        assert!(compressed == compress(&input));
+        let mut decoded = Vec::new();
+        runtime.block_on(decode_async(&mut &compressed[..], &mut decoded)).unwrap();
+        assert!(decoded == input);
+        let truncated = &compressed[..compressed.len() - 1];
+        assert!(runtime.block_on(decode_async(&mut &truncated[..], &mut Vec::new())).is_err());
+    }
+
+    #[test]
+    fn decode_windowed_invalid() {
         let mut output = Vec::new();
-        let mut io = SliceToVecIO::new(input, &mut output);
-        encode(&mut io).unwrap();
-        assert_eq!(output, b"\x03abc\x83\x02\x00");
+        assert!(decode_windowed(&mut &b"\x82\x01a\x80\x00\x01"[..], &mut output).is_err());
     }
 
     #[test]
-    fn encode_rep3() {
-        let input = b"abcdabc";
+    fn decode_framed_invalid() {
+        let cases: &[&[u8]] = &[
+            // Truncated header.
+            b"BREF\x02\x00\x01\x00",
+            // Bad magic.
+            b"BREG\x02\x00\x01\x00\x00\x00\x00\x00\x00\x00\x01a\x00\x00\x00",
+            // Unsupported version.
+            b"BREF\x03\x00\x01\x00\x00\x00\x00\x00\x00\x00\x01a\x00\x00\x00",
+            // Unsupported flags.
+            b"BREF\x02\x80\x01\x00\x00\x00\x00\x00\x00\x00\x01a\x00\x00\x00",
+            // Missing end marker.
+            b"BREF\x02\x00\x01\x00\x00\x00\x00\x00\x00\x00\x01a",
+            // Wrong length.
+            b"BREF\x02\x00\x02\x00\x00\x00\x00\x00\x00\x00\x01a\x00\x00\x00",
+        ];
+        for input in cases {
+            let mut output = Vec::new();
+            let mut io = SliceToVecIO::new(input, &mut output);
+            assert!(decode_framed(&mut io).is_err(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn decode_framed_forged_length() {
+        // A header that claims far more output than the input can produce
+        // only reserves as much as the input can produce.
+        let input = b"BREF\x02\x00\x00\x00\x00\x00\x00\x00\x00\x40\x01a";
         let mut output = Vec::new();
-        let mut io = SliceToVecIO::new(input, &mut output);
-        encode(&mut io).unwrap();
-        assert_eq!(output, b"\x04abcd\x83\x03\x00");
+        assert!(decode_framed(&mut SliceToVecIO::new(input, &mut output)).is_err());
+        assert!(output.capacity() <= 2 * MAX_EXPANSION as usize);
+        #[cfg(feature = "bytes")]
+        {
+            use crate::io::BytesIO;
+            let mut io = BytesIO::new(bytes::Bytes::from_static(input));
+            assert!(decode_framed(&mut io).is_err());
+            assert!(io.into_output().capacity() <= 2 * MAX_EXPANSION as usize);
+        }
+    }
+
+    #[test]
+    fn encode_long_repeat() {
+        // A long repetition at a single distance is encoded as consecutive
+        // backreferences at that distance, without literals in between,
+        // even where the optimal parser splits it at a block boundary.
+        let mut input = random_bytes(3000);
+        input.extend_from_within(..);
+        for &strategy in &[ParseStrategy::Greedy, ParseStrategy::Optimal] {
+            let mut output = Vec::new();
+            let mut io = SliceToVecIO::new(&input[..], &mut output);
+            encode(&mut io, &EncodeOptions::new().version(FormatVersion::V1).strategy(strategy)).unwrap();
+            let matches = v1_matches(&output);
+            assert_eq!(matches.len(), 3000usize.div_ceil(127), "{:?}", strategy);
+            assert!(matches.iter().all(|&(_, dist)| dist == 2999));
+            assert_eq!(output.len(), 3000 + 3000usize.div_ceil(127) * 4);
+        }
+    }
+
+    #[test]
+    fn encode_v2_long_repeat() {
+        let mut input = random_bytes(3000);
+        input.extend_from_within(..);
+        for &strategy in &[ParseStrategy::Greedy, ParseStrategy::Optimal] {
+            let options = EncodeOptions::new().strategy(strategy);
+            // Literals, one explicit backreference, and rep0 for the rest.
+            let expected = 3000 + 3000usize.div_ceil(127) + 4
+                + (3000usize.div_ceil(273) - 1) * 2;
+            assert_eq!(encoded_len(&input, &options, true), expected, "{:?}", strategy);
+        }
     }
 }
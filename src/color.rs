@@ -0,0 +1,205 @@
+// Color space conversion and chroma subsampling.
+//
+// Copyright 2023 Robbert Haarman
+//
+// SPDX-License-Identifier: MIT
+
+//! Conversion between RGB and YCbCr, and subsampling of the chroma
+//! planes, as done before the DCT in image codecs such as JPEG.
+//!
+//! Values are full range, as in JFIF: Y, Cb and Cr range from about 0
+//! to 255, with Cb and Cr centered on 128.
+
+/// The coefficients used to convert between RGB and YCbCr.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Matrix {
+    /// ITU-R BT.601, as used by JPEG.
+    Bt601,
+    /// ITU-R BT.709, as used for HD video.
+    Bt709,
+}
+
+impl Matrix {
+    /// Returns the weights of red and blue in Y.
+    fn weights(self) -> (f32, f32) {
+        match self {
+            Matrix::Bt601 => (0.299, 0.114),
+            Matrix::Bt709 => (0.2126, 0.0722),
+        }
+    }
+}
+
+/// Converts an RGB pixel to YCbCr.
+pub fn rgb_to_ycbcr(rgb: [f32; 3], matrix: Matrix) -> [f32; 3] {
+    let (kr, kb) = matrix.weights();
+    let [r, g, b] = rgb;
+    let y = kr * r + (1.0 - kr - kb) * g + kb * b;
+    [y, 128.0 + 0.5 * (b - y) / (1.0 - kb), 128.0 + 0.5 * (r - y) / (1.0 - kr)]
+}
+
+/// Converts a YCbCr pixel to RGB. The result is not clamped.
+pub fn ycbcr_to_rgb(ycbcr: [f32; 3], matrix: Matrix) -> [f32; 3] {
+    let (kr, kb) = matrix.weights();
+    let [y, cb, cr] = ycbcr;
+    let r = y + 2.0 * (1.0 - kr) * (cr - 128.0);
+    let b = y + 2.0 * (1.0 - kb) * (cb - 128.0);
+    [r, (y - kr * r - kb * b) / (1.0 - kr - kb), b]
+}
+
+/// Converts interleaved 8-bit RGB pixels to separate Y, Cb and Cr
+/// planes.
+pub fn rgb_to_planes(rgb: &[u8], matrix: Matrix) -> [Vec<f32>; 3] {
+    assert!(rgb.len().is_multiple_of(3), "incomplete pixel");
+    let mut planes = [Vec::new(), Vec::new(), Vec::new()];
+    for plane in planes.iter_mut() {
+        plane.reserve(rgb.len() / 3);
+    }
+    for pixel in rgb.chunks_exact(3) {
+        let ycbcr = rgb_to_ycbcr([pixel[0] as f32, pixel[1] as f32, pixel[2] as f32], matrix);
+        for (plane, &v) in planes.iter_mut().zip(ycbcr.iter()) {
+            plane.push(v);
+        }
+    }
+    planes
+}
+
+/// Converts Y, Cb and Cr planes of equal size to interleaved 8-bit RGB
+/// pixels, rounding and clamping each value.
+pub fn planes_to_rgb(y: &[f32], cb: &[f32], cr: &[f32], matrix: Matrix) -> Vec<u8> {
+    assert!(y.len() == cb.len() && y.len() == cr.len(), "planes differ in size");
+    let mut rgb = Vec::with_capacity(y.len() * 3);
+    for i in 0..y.len() {
+        for &v in ycbcr_to_rgb([y[i], cb[i], cr[i]], matrix).iter() {
+            rgb.push(v.round().clamp(0.0, 255.0) as u8);
+        }
+    }
+    rgb
+}
+
+/// How much the chroma planes are subsampled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Subsampling {
+    /// 4:4:4, no subsampling.
+    None,
+    /// 4:2:2, half the horizontal resolution.
+    Half,
+    /// 4:2:0, half the horizontal and half the vertical resolution.
+    Quarter,
+}
+
+impl Subsampling {
+    /// Returns the number of pixels in each direction that are combined
+    /// into one sample.
+    fn factors(self) -> (usize, usize) {
+        match self {
+            Subsampling::None => (1, 1),
+            Subsampling::Half => (2, 1),
+            Subsampling::Quarter => (2, 2),
+        }
+    }
+
+    /// Returns the width and height of a subsampled width x height
+    /// plane.
+    pub fn size(self, width: usize, height: usize) -> (usize, usize) {
+        let (fx, fy) = self.factors();
+        (width.div_ceil(fx), height.div_ceil(fy))
+    }
+}
+
+/// Subsamples a width x height plane by averaging each group of pixels
+/// that becomes one sample. Groups at the right and bottom edges may be
+/// smaller.
+pub fn subsample(plane: &[f32], width: usize, height: usize, subsampling: Subsampling)
+                 -> Vec<f32> {
+    assert_eq!(plane.len(), width * height, "plane does not match size");
+    let (fx, fy) = subsampling.factors();
+    let (sw, sh) = subsampling.size(width, height);
+    let mut res = Vec::with_capacity(sw * sh);
+    for sy in 0..sh {
+        let rows = sy * fy..std::cmp::min(sy * fy + fy, height);
+        for sx in 0..sw {
+            let columns = sx * fx..std::cmp::min(sx * fx + fx, width);
+            let mut sum = 0.0;
+            for y in rows.clone() {
+                sum += plane[y * width + columns.start..y * width + columns.end]
+                    .iter().sum::<f32>();
+            }
+            res.push(sum / (rows.len() * columns.len()) as f32);
+        }
+    }
+    res
+}
+
+/// Reverses subsample, returning a width x height plane in which each
+/// sample is repeated over the pixels it was computed from.
+pub fn upsample(samples: &[f32], width: usize, height: usize, subsampling: Subsampling)
+                -> Vec<f32> {
+    let (fx, fy) = subsampling.factors();
+    let (sw, sh) = subsampling.size(width, height);
+    assert_eq!(samples.len(), sw * sh, "samples do not match size");
+    let mut res = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let row = &samples[y / fy * sw..y / fy * sw + sw];
+        res.extend((0..width).map(|x| row[x / fx]));
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: &[f32], b: &[f32], tolerance: f32) {
+        assert_eq!(a.len(), b.len());
+        for (x, y) in a.iter().zip(b.iter()) {
+            assert!((x - y).abs() <= tolerance, "{:?} differs from {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn ycbcr() {
+        for &matrix in &[Matrix::Bt601, Matrix::Bt709] {
+            assert_close(&rgb_to_ycbcr([255.0, 255.0, 255.0], matrix), &[255.0, 128.0, 128.0], 1e-3);
+            assert_close(&rgb_to_ycbcr([0.0, 0.0, 0.0], matrix), &[0.0, 128.0, 128.0], 1e-3);
+            assert_close(&rgb_to_ycbcr([0.0, 0.0, 255.0], matrix)[1..2], &[255.5], 1e-3);
+            for &rgb in &[[255.0, 0.0, 0.0], [12.0, 200.0, 77.0], [0.0, 1.0, 254.0]] {
+                assert_close(&ycbcr_to_rgb(rgb_to_ycbcr(rgb, matrix), matrix), &rgb, 1e-3);
+            }
+        }
+        assert_close(&rgb_to_ycbcr([255.0, 0.0, 0.0], Matrix::Bt601),
+                     &[76.245, 84.972, 255.5], 1e-3);
+        assert_close(&rgb_to_ycbcr([255.0, 0.0, 0.0], Matrix::Bt709)[..1], &[54.213], 1e-3);
+
+        let rgb: Vec<u8> = (0..300u32).map(|i| (i * 37 % 256) as u8).collect();
+        let [y, cb, cr] = rgb_to_planes(&rgb, Matrix::Bt601);
+        assert_eq!(y.len(), 100);
+        assert_eq!(planes_to_rgb(&y, &cb, &cr, Matrix::Bt601), rgb);
+    }
+
+    #[test]
+    fn subsampling() {
+        assert_eq!(Subsampling::None.size(5, 3), (5, 3));
+        assert_eq!(Subsampling::Half.size(5, 3), (3, 3));
+        assert_eq!(Subsampling::Quarter.size(5, 3), (3, 2));
+
+        let plane: Vec<f32> = (0..15).map(|i| i as f32).collect();
+        assert_eq!(subsample(&plane, 5, 3, Subsampling::None), plane);
+        assert_eq!(subsample(&plane, 5, 3, Subsampling::Half),
+                   [0.5, 2.5, 4.0, 5.5, 7.5, 9.0, 10.5, 12.5, 14.0]);
+        let quarter = subsample(&plane, 5, 3, Subsampling::Quarter);
+        assert_eq!(quarter, [3.0, 5.0, 6.5, 10.5, 12.5, 14.0]);
+        assert_eq!(upsample(&quarter, 5, 3, Subsampling::Quarter),
+                   [3.0, 3.0, 5.0, 5.0, 6.5,
+                    3.0, 3.0, 5.0, 5.0, 6.5,
+                    10.5, 10.5, 12.5, 12.5, 14.0]);
+
+        // Flat planes survive subsampling unchanged.
+        let flat = vec![42.0; 7 * 9];
+        for &subsampling in &[Subsampling::None, Subsampling::Half, Subsampling::Quarter] {
+            let (w, h) = subsampling.size(7, 9);
+            let samples = subsample(&flat, 7, 9, subsampling);
+            assert_eq!(samples, vec![42.0; w * h]);
+            assert_eq!(upsample(&samples, 7, 9, subsampling), flat);
+        }
+    }
+}
@@ -0,0 +1,105 @@
+// CRC-32 checksums, as used by zlib, gzip and PNG.
+//
+// Copyright 2021 Robbert Haarman
+//
+// SPDX-License-Identifier: MIT
+
+/// Reversed polynomial of CRC-32.
+const POLYNOMIAL: u32 = 0xedb88320;
+
+/// Lookup table with the CRC of every byte value.
+const TABLE: [u32; 256] = make_table();
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ POLYNOMIAL } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Incrementally computes the CRC-32 of a sequence of bytes.
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Crc32 {
+    /// Returns a Crc32 for the empty sequence.
+    pub fn new() -> Crc32 {
+        Crc32 { state: 0xffffffff }
+    }
+
+    /// Appends b to the sequence.
+    pub fn update_byte(&mut self, b: u8) {
+        self.state = TABLE[((self.state ^ b as u32) & 0xff) as usize] ^ (self.state >> 8);
+    }
+
+    /// Appends bytes to the sequence.
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.update_byte(b);
+        }
+    }
+
+    /// Returns the CRC-32 of the bytes seen so far.
+    pub fn value(&self) -> u32 {
+        !self.state
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Crc32 {
+        Crc32::new()
+    }
+}
+
+/// Writing to a Crc32 appends the bytes written to the sequence, so that
+/// it can be used as the sink of io::TeeIO.
+impl std::io::Write for Crc32 {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns the CRC-32 of bytes.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(bytes);
+    crc.value()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"a"), 0xe8b7be43);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414fa339);
+    }
+
+    #[test]
+    fn incremental() {
+        let mut crc = Crc32::new();
+        crc.update(b"12345");
+        crc.update_byte(b'6');
+        crc.update(b"789");
+        assert_eq!(crc.value(), crc32(b"123456789"));
+    }
+}
@@ -9,59 +9,1125 @@
 //!
 //! [Discrete Cosine Transform]: http://inglorion.net/documents/essays/data_compression/dct/
 
-use std::convert::TryInto;
+use std::ops::{Add, Mul, Sub};
+
+use crate::transform::BlockTransform;
 
 /// Everything in this module operates on square matrices of size N by N.
 const N : usize = 8;
 
+/// Returns cos(pi * num / den). This uses only basic arithmetic, so that
+/// it can be evaluated at compile time and gives the same results on
+/// every platform. The angle is reduced to at most pi / 4, where a short
+/// Taylor series of the cosine or sine is accurate to within rounding.
+const fn cos_pi(num: usize, den: usize) -> f64 {
+    // Reduce the angle to 0..=pi, then to 0..=pi/2.
+    let m = num % (2 * den);
+    let m = if m > den { 2 * den - m } else { m };
+    let (m, sign) = if 2 * m > den { (den - m, -1.0) } else { (m, 1.0) };
+    // Above pi/4, use cos(a) = sin(pi/2 - a).
+    let (x, sine) = if 4 * m > den {
+        (std::f64::consts::PI * (den - 2 * m) as f64 / (2 * den) as f64, true)
+    } else {
+        (std::f64::consts::PI * m as f64 / den as f64, false)
+    };
+    let mut term = if sine { x } else { 1.0 };
+    let mut sum = term;
+    let mut k = if sine { 2 } else { 1 };
+    while k < 24 {
+        term = -term * x * x / ((k * (k + 1)) as f64);
+        sum += term;
+        k += 2;
+    }
+    sign * sum
+}
+
+/// Returns the DCT matrix for blocks of M values, in which entry k, n is
+/// cos(pi * k * (2n + 1) / 2M).
+const fn dct_matrix<const M: usize>() -> [[f64; M]; M] {
+    let mut res = [[0.0; M]; M];
+    let mut k = 0;
+    while k < M {
+        let mut n = 0;
+        while n < M {
+            res[k][n] = cos_pi(k * (2 * n + 1), 2 * M);
+            n += 1;
+        }
+        k += 1;
+    }
+    res
+}
+
+/// Returns DCTLUT, or IDCTLUT if inverse is set.
+const fn lut(inverse: bool) -> [f32; N * N] {
+    let matrix = dct_matrix::<N>();
+    let mut res = [0.0; N * N];
+    let mut i = 0;
+    while i < N * N {
+        let (y, x) = (i / N, i % N);
+        res[i] = if !inverse {
+            matrix[y][x] as f32
+        } else if x == 0 {
+            0.5
+        } else {
+            matrix[x][y] as f32
+        };
+        i += 1;
+    }
+    res
+}
+
 /// Lookup table for the DCT. This is logically an 8x8 matrix, here
-/// represented as an array of 64 elements. The values shown here are
-/// as computed by compute_dctlut in the test dctlut_matches_computed.
-/// Some numbers have zeros added to the end to make the numbers line
-/// up in the source code.
-static DCTLUT : [f32; N * N] = [
-    1.00000000,  1.00000000,  1.00000000,  1.00000000,  1.00000000,  1.00000000,  1.00000000,  1.00000000,
-    0.98078525,  0.83146960,  0.55557020,  0.19509023, -0.19509032, -0.55557036, -0.83146966, -0.98078530,
-    0.92387950,  0.38268343, -0.38268352, -0.92387960, -0.92387950, -0.38268313,  0.38268360,  0.92387956,
-    0.83146960, -0.19509032, -0.98078530, -0.55557000,  0.55557007,  0.98078525,  0.19509053, -0.83146980,
-    0.70710677, -0.70710677, -0.70710665,  0.70710700,  0.70710677, -0.70710725, -0.70710653,  0.70710680,
-    0.55557020, -0.98078530,  0.19509041,  0.83146936, -0.83146980, -0.19509022,  0.98078513, -0.55557084,
-    0.38268343, -0.92387950,  0.92387956, -0.38268390, -0.38268384,  0.92387930, -0.92387940,  0.38268390,
-    0.19509023, -0.55557000,  0.83146936, -0.98078520, 0.980785400, -0.83147013,  0.55557114, -0.19509155
-];
+/// represented as an array of 64 elements, in which entry y, x is
+/// cos(pi * y * (2x + 1) / 16).
+pub(crate) static DCTLUT : [f32; N * N] = lut(false);
 
 /// Lookup table for the Inverse DCT. This is logically an 8x8 matrix,
-/// here represented as an array of 64 elements. This matrix has been
-/// created by transposing DCTLUT and replacing the values in the first
-/// column by 0.5
-static IDCTLUT : [f32; N * N] = [
-    0.5,  0.98078525,  0.92387950,  0.83146960,  0.70710677,  0.55557020,  0.38268343,  0.19509023,
-    0.5,  0.83146960,  0.38268343, -0.19509032, -0.70710677, -0.98078530, -0.92387950, -0.55557000,
-    0.5,  0.55557020, -0.38268352, -0.98078530, -0.70710665,  0.19509041,  0.92387956,  0.83146936,
-    0.5,  0.19509023, -0.92387960, -0.55557000,  0.70710700,  0.83146936, -0.38268390, -0.98078520,
-    0.5, -0.19509032, -0.92387950,  0.55557007,  0.70710677, -0.83146980, -0.38268384,  0.98078540,
-    0.5, -0.55557036, -0.38268313,  0.98078525, -0.70710725, -0.19509022,  0.92387930, -0.83147013,
-    0.5, -0.83146966,  0.38268360,  0.19509053, -0.70710653,  0.98078513, -0.92387940,  0.55557114,
-    0.5, -0.98078530,  0.92387956, -0.83146980,  0.7071068,  -0.55557084,  0.38268390, -0.19509155,
-];
+/// here represented as an array of 64 elements. This matrix is DCTLUT
+/// transposed, with the values in the first column replaced by 0.5.
+static IDCTLUT : [f32; N * N] = lut(true);
+
+/// Returns cos(k * pi / 16) for k from 0 to 7.
+const fn butterfly_cos() -> [f64; N] {
+    let matrix = dct_matrix::<N>();
+    let mut res = [0.0; N];
+    let mut k = 0;
+    while k < N {
+        res[k] = matrix[k][0];
+        k += 1;
+    }
+    res
+}
+
+/// Element types the butterfly transforms operate on.
+trait Element: Copy + Add<Output = Self> + Sub<Output = Self> + Mul<Output = Self> {
+    /// cos(k * pi / 16) for k from 0 to 7, as used by the butterflies
+    /// in dct_1d and idct_1d.
+    const COS: [Self; N];
+    const HALF: Self;
+}
+
+impl Element for f32 {
+    const COS: [f32; N] = {
+        let cos = butterfly_cos();
+        [cos[0] as f32, cos[1] as f32, cos[2] as f32, cos[3] as f32,
+         cos[4] as f32, cos[5] as f32, cos[6] as f32, cos[7] as f32]
+    };
+    const HALF: f32 = 0.5;
+}
+
+impl Element for f64 {
+    const COS: [f64; N] = butterfly_cos();
+    const HALF: f64 = 0.5;
+}
+
+/// Computes the unscaled one-dimensional DCT of x: DCTLUT times x. The
+/// inputs are split into sums and differences of mirrored pairs, which
+/// produce the even and odd outputs respectively, so that this takes 22
+/// multiplications instead of 64.
+#[inline(always)]
+fn dct_1d<T: Element>(x: [T; N]) -> [T; N] {
+    let cos = T::COS;
+    let (s0, s1, s2, s3) = (x[0] + x[7], x[1] + x[6], x[2] + x[5], x[3] + x[4]);
+    let (d0, d1, d2, d3) = (x[0] - x[7], x[1] - x[6], x[2] - x[5], x[3] - x[4]);
+    let (ss0, ss1, dd0, dd1) = (s0 + s3, s1 + s2, s0 - s3, s1 - s2);
+    [
+        ss0 + ss1,
+        d0 * cos[1] + d1 * cos[3] + d2 * cos[5] + d3 * cos[7],
+        dd0 * cos[2] + dd1 * cos[6],
+        d0 * cos[3] - d1 * cos[7] - d2 * cos[1] - d3 * cos[5],
+        (ss0 - ss1) * cos[4],
+        d0 * cos[5] - d1 * cos[1] + d2 * cos[7] + d3 * cos[3],
+        dd0 * cos[6] - dd1 * cos[2],
+        d0 * cos[7] - d1 * cos[5] + d2 * cos[3] - d3 * cos[1],
+    ]
+}
+
+/// Computes the unscaled one-dimensional inverse DCT of c: IDCTLUT times
+/// c. This reverses the butterflies of dct_1d.
+#[inline(always)]
+fn idct_1d<T: Element>(c: [T; N]) -> [T; N] {
+    let cos = T::COS;
+    let (a, b) = (T::HALF * c[0] + c[4] * cos[4], T::HALF * c[0] - c[4] * cos[4]);
+    let p = c[2] * cos[2] + c[6] * cos[6];
+    let q = c[2] * cos[6] - c[6] * cos[2];
+    let even = [a + p, b + q, b - q, a - p];
+    let odd = [
+        c[1] * cos[1] + c[3] * cos[3] + c[5] * cos[5] + c[7] * cos[7],
+        c[1] * cos[3] - c[3] * cos[7] - c[5] * cos[1] - c[7] * cos[5],
+        c[1] * cos[5] - c[3] * cos[1] + c[5] * cos[7] + c[7] * cos[3],
+        c[1] * cos[7] - c[3] * cos[5] + c[5] * cos[3] - c[7] * cos[1],
+    ];
+    [even[0] + odd[0], even[1] + odd[1], even[2] + odd[2], even[3] + odd[3],
+     even[3] - odd[3], even[2] - odd[2], even[1] - odd[1], even[0] - odd[0]]
+}
+
+/// Applies f to the rows and then to the columns of a, and scales the
+/// result.
+#[inline(always)]
+fn separable<T: Element, F: Fn([T; N]) -> [T; N]>(a: &[T; N * N], f: F, scale: T)
+                                                  -> [T; N * N] {
+    separable_rows(|y| {
+        [a[y * N], a[y * N + 1], a[y * N + 2], a[y * N + 3],
+         a[y * N + 4], a[y * N + 5], a[y * N + 6], a[y * N + 7]]
+    }, f, scale)
+}
+
+/// Like separable, but takes the rows of the matrix from row(y).
+#[inline(always)]
+fn separable_rows<T: Element, R: Fn(usize) -> [T; N], F: Fn([T; N]) -> [T; N]>(
+    row: R, f: F, scale: T) -> [T; N * N] {
+    let mut rows = [[scale; N]; N];
+    for (y, r) in rows.iter_mut().enumerate() {
+        *r = f(row(y));
+    }
+    let mut res = [scale; N * N];
+    for x in 0..N {
+        let column = f([rows[0][x], rows[1][x], rows[2][x], rows[3][x],
+                        rows[4][x], rows[5][x], rows[6][x], rows[7][x]]);
+        for (y, &v) in column.iter().enumerate() {
+            res[y * N + x] = v * scale;
+        }
+    }
+    res
+}
+
+/// Panics if image is too short to hold a block with the given stride.
+fn check_strided<T>(image: &[T], stride: usize) {
+    assert!(stride >= N, "stride is less than block width");
+    assert!(image.len() >= (N - 1) * stride + N, "image is too short for block");
+}
 
 /// Applies the forward DCT transform to an NxN matrix of image data.
+/// This applies dct_1d to the rows and then the columns, which gives
+/// the same result as transform_matrix with far fewer operations.
 pub fn transform(image: &[f32; N * N]) -> [f32; N * N] {
+    separable(image, dct_1d, 0.015625)
+}
+
+/// The DCT as a BlockTransform, using transform and reverse.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Dct;
+
+impl BlockTransform for Dct {
+    type Sample = f32;
+    type Coeff = f32;
+
+    fn forward(&self, block: &[f32; N * N]) -> [f32; N * N] {
+        transform(block)
+    }
+
+    fn inverse(&self, coeffs: &[f32; N * N]) -> [f32; N * N] {
+        reverse(coeffs)
+    }
+}
+
+/// Like transform, but replaces the image data with the result instead
+/// of returning a new matrix. Neither allocates memory.
+pub fn transform_in_place(block: &mut [f32; N * N]) {
+    *block = transform(block);
+}
+
+/// Applies the inverse DCT transform to an NxN matrix of image data,
+/// like transform.
+pub fn reverse(transformed: &[f32; N * N]) -> [f32; N * N] {
+    separable(transformed, idct_1d, 4.0)
+}
+
+/// Like reverse, but replaces the coefficients with the result instead
+/// of returning a new matrix.
+pub fn reverse_in_place(block: &mut [f32; N * N]) {
+    *block = reverse(block);
+}
+
+/// Like transform, but reads the block from a larger image: image starts
+/// at the top left value of the block, and its rows start stride values
+/// apart. This avoids copying the block into a matrix first.
+pub fn transform_strided(image: &[f32], stride: usize) -> [f32; N * N] {
+    check_strided(image, stride);
+    separable_rows(|y| {
+        let r = &image[y * stride..y * stride + N];
+        [r[0], r[1], r[2], r[3], r[4], r[5], r[6], r[7]]
+    }, dct_1d, 0.015625)
+}
+
+/// Like reverse, but stores the result in a larger image, laid out as
+/// for transform_strided.
+pub fn reverse_strided(transformed: &[f32; N * N], image: &mut [f32], stride: usize) {
+    check_strided(image, stride);
+    let block = reverse(transformed);
+    for (row, values) in image.chunks_mut(stride).zip(block.chunks(N)) {
+        row[..N].copy_from_slice(values);
+    }
+}
+
+/// Transforms every block in a batch, like transform.
+pub fn transform_batch(blocks: &[[f32; N * N]]) -> Vec<[f32; N * N]> {
+    blocks.iter().map(transform).collect()
+}
+
+/// Like transform_batch, but stores the results in output, which must
+/// have the same length as blocks, instead of allocating a vector.
+pub fn transform_batch_into(blocks: &[[f32; N * N]], output: &mut [[f32; N * N]]) {
+    assert_eq!(blocks.len(), output.len(), "output does not match number of blocks");
+    for (o, block) in output.iter_mut().zip(blocks.iter()) {
+        *o = transform(block);
+    }
+}
+
+/// Reverses every block in a batch, like reverse.
+pub fn reverse_batch(transformed: &[[f32; N * N]]) -> Vec<[f32; N * N]> {
+    transformed.iter().map(reverse).collect()
+}
+
+/// Like reverse_batch, but stores the results in output, which must have
+/// the same length as transformed.
+pub fn reverse_batch_into(transformed: &[[f32; N * N]], output: &mut [[f32; N * N]]) {
+    assert_eq!(transformed.len(), output.len(), "output does not match number of blocks");
+    for (o, block) in output.iter_mut().zip(transformed.iter()) {
+        *o = reverse(block);
+    }
+}
+
+/// Like transform_batch, but transforms blocks on the threads of rayon's
+/// global thread pool. Enabled by the "rayon" feature.
+#[cfg(feature = "rayon")]
+pub fn transform_batch_par(blocks: &[[f32; N * N]]) -> Vec<[f32; N * N]> {
+    use rayon::prelude::*;
+    blocks.par_iter().map(transform).collect()
+}
+
+/// Like reverse_batch, but transforms blocks on the threads of rayon's
+/// global thread pool. Enabled by the "rayon" feature.
+#[cfg(feature = "rayon")]
+pub fn reverse_batch_par(transformed: &[[f32; N * N]]) -> Vec<[f32; N * N]> {
+    use rayon::prelude::*;
+    transformed.par_iter().map(reverse).collect()
+}
+
+/// Applies the forward DCT transform to an NxN block of 8-bit pixels,
+/// after subtracting 128 from each, as JPEG does, so that the
+/// coefficients are centered on 0.
+pub fn transform_u8(pixels: &[u8; N * N]) -> [f32; N * N] {
+    transform(&pixels.map(|x| x as f32 - 128.0))
+}
+
+/// Reverses transform_u8, adding 128 to the result of the inverse
+/// transform and rounding and clamping it to 8-bit pixels.
+pub fn reverse_to_u8(transformed: &[f32; N * N]) -> [u8; N * N] {
+    reverse(transformed).map(|x| (x + 128.0).round().clamp(0.0, 255.0) as u8)
+}
+
+/// Panics if bits is not a supported sample depth.
+fn check_depth(bits: u32) {
+    assert!((8..=16).contains(&bits), "bit depth must be from 8 to 16");
+}
+
+/// Like transform_u8, but for samples of bits bits, from 8 to 16, such
+/// as 10- and 12-bit video or 16-bit images. The samples are centered
+/// on 0 by subtracting 2**(bits - 1). The coefficients are on the scale
+/// of the samples, so quantization tables should be scaled with
+/// qtable_for_depth.
+pub fn transform_u16(pixels: &[u16; N * N], bits: u32) -> [f32; N * N] {
+    check_depth(bits);
+    let mid = (1u32 << (bits - 1)) as f32;
+    transform(&pixels.map(|x| x as f32 - mid))
+}
+
+/// Reverses transform_u16, rounding and clamping the result to samples
+/// of bits bits.
+pub fn reverse_to_u16(transformed: &[f32; N * N], bits: u32) -> [u16; N * N] {
+    check_depth(bits);
+    let mid = (1u32 << (bits - 1)) as f32;
+    let max = ((1u32 << bits) - 1) as f32;
+    reverse(transformed).map(|x| (x + mid).round().clamp(0.0, max) as u16)
+}
+
+/// Scales a quantization table meant for 8-bit samples, such as the
+/// ones in this module, for the coefficients transform_u16 produces for
+/// samples of bits bits. Entries that would not fit are saturated.
+pub fn qtable_for_depth(table: &[u16; N * N], bits: u32) -> [u16; N * N] {
+    check_depth(bits);
+    table.map(|t| t.saturating_mul(1 << (bits - 8)))
+}
+
+/// Like transform_u8, but for floating point samples as used for high
+/// dynamic range images, where 0 is black and 1 is nominal white, but
+/// brighter values are allowed. The samples are centered on 0 and
+/// scaled to the range of 8-bit samples, so that the quantization
+/// tables in this module apply.
+pub fn transform_hdr(pixels: &[f32; N * N]) -> [f32; N * N] {
+    transform(&pixels.map(|x| (x - 0.5) * 255.0))
+}
+
+/// Reverses transform_hdr. Negative results are clamped to 0, but
+/// values above 1 are kept.
+pub fn reverse_to_hdr(transformed: &[f32; N * N]) -> [f32; N * N] {
+    reverse(transformed).map(|x| (x / 255.0 + 0.5).max(0.0))
+}
+
+/// Largest magnitude sanitize lets through. This is far beyond what
+/// transform produces for 16-bit samples, and small enough that reverse
+/// cannot overflow.
+const MAX_SANITIZED: f32 = (1 << 24) as f32;
+
+/// Makes coefficients from an untrusted source safe to pass to reverse:
+/// NaN becomes 0, and infinite and out of range values are clamped to
+/// plus or minus 2**24.
+pub fn sanitize(coeffs: &[f32; N * N]) -> [f32; N * N] {
+    coeffs.map(|x| if x.is_nan() { 0.0 } else { x.clamp(-MAX_SANITIZED, MAX_SANITIZED) })
+}
+
+/// Like reverse, but sanitizes the coefficients first, so that the
+/// result is always finite, whatever the input.
+pub fn reverse_sanitized(transformed: &[f32; N * N]) -> [f32; N * N] {
+    reverse(&sanitize(transformed))
+}
+
+/// Like reverse_to_u8, but sanitizes the coefficients first, so that
+/// NaN or infinite coefficients cannot affect the other pixels of the
+/// block.
+pub fn reverse_to_u8_sanitized(transformed: &[f32; N * N]) -> [u8; N * N] {
+    reverse_to_u8(&sanitize(transformed))
+}
+
+/// Applies the forward DCT transform to an NxN matrix of image data in
+/// double precision, for uses where the rounding of transform is too
+/// coarse.
+pub fn transform_f64(image: &[f64; N * N]) -> [f64; N * N] {
+    separable(image, dct_1d, 0.015625)
+}
+
+/// Applies the inverse DCT transform to an NxN matrix of image data in
+/// double precision, like transform_f64.
+pub fn reverse_f64(transformed: &[f64; N * N]) -> [f64; N * N] {
+    separable(transformed, idct_1d, 4.0)
+}
+
+/// Type of a one-dimensional DCT. Each type has a matching inverse, which
+/// Dct1d::reverse computes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DctType {
+    /// DCT-II, the transform JPEG and most image codecs use:
+    /// X[k] = 1/N * sum of x[n] * cos(pi * k * (2n + 1) / 2N).
+    II,
+    /// DCT-III, the inverse of DCT-II: X[k] = x[0] + 2 * sum over n > 0
+    /// of x[n] * cos(pi * n * (2k + 1) / 2N). Its inverse is DCT-II.
+    III,
+    /// DCT-IV, the basis of the MDCT:
+    /// X[k] = 1/N * sum of x[n] * cos(pi * (2n + 1) * (2k + 1) / 4N).
+    /// It is its own inverse, up to a factor of 2N.
+    IV,
+}
+
+/// One-dimensional DCT of a fixed length, for signals that are not
+/// images, such as frames of audio samples. The results of DCT-II are
+/// scaled like those of transform and reverse: the first coefficient is
+/// the mean of the input. This takes time proportional to the square of
+/// the length.
+pub struct Dct1d {
+    kind: DctType,
+    /// cos(i * pi / (4 * len)) for i from 0 to 8 * len - 1.
+    cos: Vec<f32>,
+}
+
+impl Dct1d {
+    /// Creates a DCT-II for frames of len samples.
+    pub fn new(len: usize) -> Dct1d {
+        Dct1d::with_type(len, DctType::II)
+    }
+
+    /// Creates a transform of the given type for frames of len samples.
+    pub fn with_type(len: usize, kind: DctType) -> Dct1d {
+        assert!(len > 0, "length must not be 0");
+        let cos = (0..8 * len).map(|i| {
+            (std::f64::consts::PI * i as f64 / (4 * len) as f64).cos() as f32
+        }).collect();
+        Dct1d { kind, cos }
+    }
+
+    /// Returns the number of samples in a frame.
+    pub fn frame_len(&self) -> usize {
+        self.cos.len() / 8
+    }
+
+    /// Returns the type of the transform.
+    pub fn dct_type(&self) -> DctType {
+        self.kind
+    }
+
+    /// Returns cos(k * pi * (2 * n + 1) / (2 * len)).
+    fn basis(&self, k: usize, n: usize) -> f32 {
+        self.cos[2 * k * (2 * n + 1) % self.cos.len()]
+    }
+
+    /// Returns cos(pi * (2 * k + 1) * (2 * n + 1) / (4 * len)).
+    fn basis_iv(&self, k: usize, n: usize) -> f32 {
+        self.cos[(2 * k + 1) * (2 * n + 1) % self.cos.len()]
+    }
+
+    /// Computes 1/len times the DCT-II of frame.
+    fn dct_ii(&self, frame: &[f32]) -> Vec<f32> {
+        let len = frame.len();
+        (0..len).map(|k| {
+            frame.iter().enumerate().map(|(n, &x)| x * self.basis(k, n)).sum::<f32>() / len as f32
+        }).collect()
+    }
+
+    /// Computes the DCT-III of frame, which reverses dct_ii.
+    fn dct_iii(&self, frame: &[f32]) -> Vec<f32> {
+        (0..frame.len()).map(|n| {
+            frame[0] + 2.0 * frame.iter().enumerate().skip(1)
+                .map(|(k, &c)| c * self.basis(k, n)).sum::<f32>()
+        }).collect()
+    }
+
+    /// Computes scale times the unscaled DCT-IV of frame.
+    fn dct_iv(&self, frame: &[f32], scale: f32) -> Vec<f32> {
+        (0..frame.len()).map(|k| {
+            scale * frame.iter().enumerate().map(|(n, &x)| x * self.basis_iv(k, n)).sum::<f32>()
+        }).collect()
+    }
+
+    /// Applies the forward transform to a frame.
+    pub fn transform(&self, frame: &[f32]) -> Vec<f32> {
+        let len = self.frame_len();
+        assert_eq!(frame.len(), len, "frame does not match length");
+        match self.kind {
+            DctType::II => self.dct_ii(frame),
+            DctType::III => self.dct_iii(frame),
+            DctType::IV => self.dct_iv(frame, 1.0 / len as f32),
+        }
+    }
+
+    /// Applies the inverse transform to a frame of coefficients.
+    pub fn reverse(&self, transformed: &[f32]) -> Vec<f32> {
+        let len = self.frame_len();
+        assert_eq!(transformed.len(), len, "frame does not match length");
+        match self.kind {
+            DctType::II => self.dct_iii(transformed),
+            DctType::III => self.dct_ii(transformed),
+            DctType::IV => self.dct_iv(transformed, 2.0),
+        }
+    }
+}
+
+/// How transform_image fills the parts of blocks that extend past the
+/// right or bottom edge of an image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Padding {
+    /// Repeat the last row or column.
+    Replicate,
+    /// Mirror the image around the last row or column, without repeating
+    /// it, so that the row or column after the last one is the same as
+    /// the one before it.
+    Reflect,
+}
+
+impl Padding {
+    /// Returns the index within 0..len of the row or column to use for
+    /// index i.
+    pub(crate) fn index(self, i: usize, len: usize) -> usize {
+        if i < len {
+            return i;
+        }
+        match self {
+            Padding::Replicate => len - 1,
+            Padding::Reflect if len == 1 => 0,
+            Padding::Reflect => {
+                let period = 2 * (len - 1);
+                let i = i % period;
+                if i < len { i } else { period - i }
+            },
+        }
+    }
+}
+
+/// The coefficients of the NxN blocks of an image, as produced by
+/// transform_image, or by haar::transform_image for integer
+/// coefficients.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImageCoefficients<T = f32> {
+    /// Width of the image in pixels.
+    pub width: usize,
+    /// Height of the image in pixels.
+    pub height: usize,
+    /// Number of blocks in each row of blocks.
+    pub blocks_wide: usize,
+    /// Number of rows of blocks.
+    pub blocks_high: usize,
+    /// The coefficients of each block, in row-major order of blocks.
+    pub blocks: Vec<[T; N * N]>,
+}
+
+impl<T: Copy> ImageCoefficients<T> {
+    /// Returns coefficient i of every block, in row-major order of
+    /// blocks. Plane 0 holds the DC coefficients.
+    pub fn plane(&self, i: usize) -> Vec<T> {
+        self.blocks.iter().map(|block| block[i]).collect()
+    }
+}
+
+/// Splits a row-major image into NxN blocks and transforms each of
+/// them. Row y of the image starts at image[y * stride]. Blocks that
+/// extend past the edges of the image are filled according to padding.
+///
+/// Panics if width or height is 0, if stride is less than width, or if
+/// image is too short.
+pub fn transform_image(image: &[f32], width: usize, height: usize, stride: usize,
+                       padding: Padding) -> ImageCoefficients {
+    transform_blocks(image, width, height, stride, padding, transform)
+}
+
+/// Reverses transform_image, returning the image with a stride equal
+/// to its width. The padding is discarded.
+pub fn reverse_image(coeffs: &ImageCoefficients) -> Vec<f32> {
+    reverse_blocks(coeffs, reverse)
+}
+
+/// Splits a row-major image into NxN blocks, like transform_image, and
+/// applies f to each of them.
+pub(crate) fn transform_blocks<T: Copy, U, F: Fn(&[T; N * N]) -> [U; N * N]>(
+    image: &[T], width: usize, height: usize, stride: usize, padding: Padding, f: F)
+    -> ImageCoefficients<U> {
+    check_image(image, width, height, stride);
+    let blocks_wide = width.div_ceil(N);
+    let blocks_high = height.div_ceil(N);
+    let blocks = (0..blocks_wide * blocks_high).map(|i| {
+        f(&image_block(image, width, height, stride, padding, i % blocks_wide, i / blocks_wide))
+    }).collect();
+    ImageCoefficients { width, height, blocks_wide, blocks_high, blocks }
+}
+
+/// Like transform_image, but transforms blocks on the threads of rayon's
+/// global thread pool. Enabled by the "rayon" feature.
+#[cfg(feature = "rayon")]
+pub fn transform_image_par(image: &[f32], width: usize, height: usize, stride: usize,
+                           padding: Padding) -> ImageCoefficients {
+    use rayon::prelude::*;
+    check_image(image, width, height, stride);
+    let blocks_wide = width.div_ceil(N);
+    let blocks_high = height.div_ceil(N);
+    let blocks = (0..blocks_wide * blocks_high).into_par_iter().map(|i| {
+        transform(&image_block(image, width, height, stride, padding,
+                               i % blocks_wide, i / blocks_wide))
+    }).collect();
+    ImageCoefficients { width, height, blocks_wide, blocks_high, blocks }
+}
+
+/// Panics unless image holds width x height pixels with the given
+/// stride.
+fn check_image<T>(image: &[T], width: usize, height: usize, stride: usize) {
+    assert!(width > 0 && height > 0, "image is empty");
+    assert!(stride >= width, "stride is less than width");
+    assert!(image.len() >= (height - 1) * stride + width, "image is too short");
+}
+
+/// Returns block bx, by of an image, padding it if it extends past the
+/// edges.
+fn image_block<T: Copy>(image: &[T], width: usize, height: usize, stride: usize,
+                        padding: Padding, bx: usize, by: usize) -> [T; N * N] {
+    let mut block = [image[0]; N * N];
+    for y in 0..N {
+        let row = padding.index(by * N + y, height) * stride;
+        for x in 0..N {
+            block[y * N + x] = image[row + padding.index(bx * N + x, width)];
+        }
+    }
+    block
+}
+
+/// Applies f to each block of coeffs and joins the results into an
+/// image, like reverse_image.
+pub(crate) fn reverse_blocks<T, U: Copy + Default, F: Fn(&[T; N * N]) -> [U; N * N]>(
+    coeffs: &ImageCoefficients<T>, f: F) -> Vec<U> {
+    assert_eq!(coeffs.blocks.len(), coeffs.blocks_wide * coeffs.blocks_high,
+               "wrong number of blocks");
+    let mut image = vec![U::default(); coeffs.width * coeffs.height];
+    for (rows, blocks) in image.chunks_mut(coeffs.width * N)
+        .zip(coeffs.blocks.chunks(coeffs.blocks_wide)) {
+        reverse_block_row(blocks, rows, coeffs.width, &f);
+    }
+    image
+}
+
+/// Like reverse_image, but transforms blocks on the threads of rayon's
+/// global thread pool. Enabled by the "rayon" feature.
+#[cfg(feature = "rayon")]
+pub fn reverse_image_par(coeffs: &ImageCoefficients) -> Vec<f32> {
+    use rayon::prelude::*;
+    assert_eq!(coeffs.blocks.len(), coeffs.blocks_wide * coeffs.blocks_high,
+               "wrong number of blocks");
+    let mut image = vec![0.0f32; coeffs.width * coeffs.height];
+    image.par_chunks_mut(coeffs.width * N)
+        .zip(coeffs.blocks.par_chunks(coeffs.blocks_wide))
+        .for_each(|(rows, blocks)| reverse_block_row(blocks, rows, coeffs.width, &reverse));
+    image
+}
+
+/// Applies f to a row of blocks, storing the results in rows, the rows
+/// of a width pixels wide image the blocks cover.
+fn reverse_block_row<T, U: Copy, F: Fn(&[T; N * N]) -> [U; N * N]>(
+    blocks: &[[T; N * N]], rows: &mut [U], width: usize, f: &F) {
+    for (bx, block) in blocks.iter().enumerate() {
+        let pixels = f(block);
+        let x = bx * N;
+        let w = std::cmp::min(N, width.saturating_sub(x));
+        for (y, row) in rows.chunks_mut(width).enumerate() {
+            row[x..x + w].copy_from_slice(&pixels[y * N..y * N + w]);
+        }
+    }
+}
+
+/// Applies the forward DCT transform to an NxN matrix of image data
+/// using matrix multiplication. This is slower than transform, but
+/// follows the definition directly, which makes it useful as a
+/// reference.
+pub fn transform_matrix(image: &[f32; N * N]) -> [f32; N * N] {
     matscale(
         &matmul_transposed(&matmul(&DCTLUT, image), &DCTLUT),
         0.015625)
 }
 
-/// Applies the inverse DCT transform to an NxN matrix of image data.
-pub fn reverse(transformed: &[f32; N * N]) -> [f32; N * N] {
+/// Applies the inverse DCT transform to an NxN matrix of image data
+/// using matrix multiplication, like transform_matrix.
+pub fn reverse_matrix(transformed: &[f32; N * N]) -> [f32; N * N] {
     matscale(
         &matmul_transposed(&matmul(&IDCTLUT, transformed), &IDCTLUT),
         4.0)
 }
 
+/// Number of fractional bits in the entries of DCTLUT_FIXED.
+const LUT_FRAC_BITS : u32 = 30;
+
+/// DCTLUT in fixed point, with LUT_FRAC_BITS fractional bits, rounded
+/// to the nearest integer. Since cos_pi gives the same results on every
+/// platform, so does this.
+static DCTLUT_FIXED : [i64; N * N] = {
+    let matrix = dct_matrix::<N>();
+    let mut res = [0i64; N * N];
+    let mut i = 0;
+    while i < N * N {
+        let x = matrix[i / N][i % N] * (1u64 << LUT_FRAC_BITS) as f64;
+        res[i] = if x >= 0.0 { (x + 0.5) as i64 } else { (x - 0.5) as i64 };
+        i += 1;
+    }
+    res
+};
+
+/// Number of fractional bits in the coefficients produced by
+/// transform_fixed and taken by reverse_fixed.
+pub const FIXED_FRAC_BITS : u32 = 12;
+
+/// Divides x by 2**shift, rounding to the nearest integer and rounding
+/// halves up.
+fn round_shift(x: i128, shift: u32) -> i128 {
+    (x + (1 << (shift - 1))) >> shift
+}
+
+/// Multiplies a by b, transposed if transpose_b is set, in fixed point,
+/// exactly.
+fn matmul_fixed(a: &[i128; N * N], b: &[i128; N * N], transpose_b: bool) -> [i128; N * N] {
+    let mut res = [0i128; N * N];
+    for y in 0..N {
+        for x in 0..N {
+            res[y * N + x] = (0..N).map(|i| {
+                a[y * N + i] * if transpose_b { b[x * N + i] } else { b[i * N + x] }
+            }).sum();
+        }
+    }
+    res
+}
+
+/// Applies the forward DCT transform to an NxN matrix of integer image
+/// data using only integer arithmetic, so that the result is the same
+/// on every platform. The result is the result of transform, multiplied
+/// by 2**FIXED_FRAC_BITS and rounded to integers.
+///
+/// Image values must be between -32768 and 32767. For such values, the
+/// coefficients are within 2**-12 of the exact ones, and
+/// reverse_fixed returns exactly the image that was transformed.
+pub fn transform_fixed(image: &[i32; N * N]) -> [i32; N * N] {
+    let lut = DCTLUT_FIXED.map(|x| x as i128);
+    let image = image.map(|x| x as i128);
+    let product = matmul_fixed(&matmul_fixed(&lut, &image, false), &lut, true);
+    // Undo the scaling of both LUTs and scale by 1/64, as transform does.
+    product.map(|x| round_shift(x, 2 * LUT_FRAC_BITS + 6 - FIXED_FRAC_BITS) as i32)
+}
+
+/// Applies the inverse DCT transform to coefficients produced by
+/// transform_fixed using only integer arithmetic, rounding the result to
+/// integers.
+pub fn reverse_fixed(transformed: &[i32; N * N]) -> [i32; N * N] {
+    // IDCTLUT is DCTLUT transposed, with 0.5 in the first column.
+    let mut lut = [0i128; N * N];
+    for y in 0..N {
+        lut[y * N] = 1 << (LUT_FRAC_BITS - 1);
+        for x in 1..N {
+            lut[y * N + x] = DCTLUT_FIXED[x * N + y] as i128;
+        }
+    }
+    let transformed = transformed.map(|x| x as i128);
+    let product = matmul_fixed(&matmul_fixed(&lut, &transformed, false), &lut, true);
+    // Undo the scaling of both LUTs and the coefficients, and scale by 4,
+    // as reverse does.
+    product.map(|x| round_shift(x, 2 * LUT_FRAC_BITS + FIXED_FRAC_BITS - 2) as i32)
+}
+
+/// The fixed point DCT as a BlockTransform, using transform_fixed and
+/// reverse_fixed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FixedDct;
+
+impl BlockTransform for FixedDct {
+    type Sample = i32;
+    type Coeff = i32;
+
+    fn forward(&self, block: &[i32; N * N]) -> [i32; N * N] {
+        transform_fixed(block)
+    }
+
+    fn inverse(&self, coeffs: &[i32; N * N]) -> [i32; N * N] {
+        reverse_fixed(coeffs)
+    }
+}
+
+/// Luminance quantization table from the JPEG standard (ITU T.81, annex
+/// K), which gives quality 50 when passed to scale_qtable.
+pub static LUMINANCE_QTABLE : [u16; N * N] = [
+    16,  11,  10,  16,  24,  40,  51,  61,
+    12,  12,  14,  19,  26,  58,  60,  55,
+    14,  13,  16,  24,  40,  57,  69,  56,
+    14,  17,  22,  29,  51,  87,  80,  62,
+    18,  22,  37,  56,  68, 109, 103,  77,
+    24,  35,  55,  64,  81, 104, 113,  92,
+    49,  64,  78,  87, 103, 121, 120, 101,
+    72,  92,  95,  98, 112, 100, 103,  99,
+];
+
+/// Chrominance quantization table from the JPEG standard, like
+/// LUMINANCE_QTABLE.
+pub static CHROMINANCE_QTABLE : [u16; N * N] = [
+    17,  18,  24,  47,  99,  99,  99,  99,
+    18,  21,  26,  66,  99,  99,  99,  99,
+    24,  26,  56,  99,  99,  99,  99,  99,
+    47,  66,  99,  99,  99,  99,  99,  99,
+    99,  99,  99,  99,  99,  99,  99,  99,
+    99,  99,  99,  99,  99,  99,  99,  99,
+    99,  99,  99,  99,  99,  99,  99,  99,
+    99,  99,  99,  99,  99,  99,  99,  99,
+];
+
+/// Scales a quantization table for a quality from 1 (smallest output)
+/// to 100 (best quality), the way the Independent JPEG Group's
+/// software does. Quality 50 returns the table unchanged. Entries are
+/// kept between 1 and 255.
+pub fn scale_qtable(table: &[u16; N * N], quality: u32) -> [u16; N * N] {
+    let quality = quality.clamp(1, 100);
+    let scale = if quality < 50 { 5000 / quality } else { 200 - 2 * quality };
+    let mut res = [0u16; N * N];
+    for (r, &t) in res.iter_mut().zip(table.iter()) {
+        *r = ((t as u32 * scale + 50) / 100).clamp(1, 255) as u16;
+    }
+    res
+}
+
+/// Returns the factor by which coefficient i of transform must be
+/// multiplied to get the coefficient the JPEG standard defines, which
+/// uses a differently scaled DCT.
+pub(crate) fn jpeg_scale(i: usize) -> f32 {
+    let c = |u: usize| if u == 0 { std::f32::consts::FRAC_1_SQRT_2 } else { 1.0 };
+    16.0 * c(i / N) * c(i % N)
+}
+
+/// Quantizes coefficients produced by transform by dividing them by the
+/// entries of qtable and rounding them to integers. Coefficients are
+/// scaled the way JPEG scales them first, so that the tables in this
+/// module can be used for image data with values from -128 to 127.
+pub fn quantize(coeffs: &[f32; N * N], qtable: &[u16; N * N]) -> [i32; N * N] {
+    let mut res = [0i32; N * N];
+    for i in 0..N * N {
+        res[i] = (coeffs[i] * jpeg_scale(i) / qtable[i] as f32).round() as i32;
+    }
+    res
+}
+
+/// Transforms and quantizes a block in one pass, giving the same result
+/// as quantize(&transform(image), qtable), except that results are
+/// clamped to the range of i16. The scaling of transform and the
+/// division by the quantization table are folded into one multiplier per
+/// coefficient, which is applied to the unscaled DCT.
+pub fn transform_quantize(image: &[f32; N * N], qtable: &[u16; N * N]) -> [i16; N * N] {
+    let unscaled = separable(image, dct_1d, 1.0);
+    let mut res = [0i16; N * N];
+    for i in 0..N * N {
+        let factor = jpeg_scale(i) * 0.015625 / qtable[i] as f32;
+        res[i] = (unscaled[i] * factor).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+    }
+    res
+}
+
+/// Quantizes every block of an image, like quantize.
+pub fn quantize_image(coeffs: &ImageCoefficients, qtable: &[u16; N * N])
+                      -> ImageCoefficients<i32> {
+    ImageCoefficients {
+        width: coeffs.width,
+        height: coeffs.height,
+        blocks_wide: coeffs.blocks_wide,
+        blocks_high: coeffs.blocks_high,
+        blocks: coeffs.blocks.iter().map(|block| quantize(block, qtable)).collect(),
+    }
+}
+
+/// Like quantize_image, but quantizes blocks on the threads of rayon's
+/// global thread pool. Enabled by the "rayon" feature.
+#[cfg(feature = "rayon")]
+pub fn quantize_image_par(coeffs: &ImageCoefficients, qtable: &[u16; N * N])
+                          -> ImageCoefficients<i32> {
+    use rayon::prelude::*;
+    ImageCoefficients {
+        width: coeffs.width,
+        height: coeffs.height,
+        blocks_wide: coeffs.blocks_wide,
+        blocks_high: coeffs.blocks_high,
+        blocks: coeffs.blocks.par_iter().map(|block| quantize(block, qtable)).collect(),
+    }
+}
+
+/// Reverses quantize, returning coefficients that can be passed to
+/// reverse.
+pub fn dequantize(quantized: &[i32; N * N], qtable: &[u16; N * N]) -> [f32; N * N] {
+    let mut res = [0.0f32; N * N];
+    for i in 0..N * N {
+        res[i] = quantized[i] as f32 * qtable[i] as f32 / jpeg_scale(i);
+    }
+    res
+}
+
+/// Options for quantize_with and dequantize_with. The defaults make them
+/// behave like quantize and dequantize.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QuantizeOptions {
+    dead_zone: f32,
+    weights: [f32; N * N],
+}
+
+impl QuantizeOptions {
+    /// Creates options for plain rounding with unweighted tables.
+    pub fn new() -> QuantizeOptions {
+        QuantizeOptions { dead_zone: 0.5, weights: [1.0; N * N] }
+    }
+
+    /// Sets the dead zone, as a fraction of the quantization step:
+    /// coefficients smaller than dead_zone steps become 0, and the rest
+    /// are rounded as usual, but to at least 1 step. The default of 0.5
+    /// is plain rounding. Larger values zero more small coefficients,
+    /// which are expensive to code and contribute little to quality.
+    pub fn dead_zone(mut self, dead_zone: f32) -> QuantizeOptions {
+        self.dead_zone = dead_zone;
+        self
+    }
+
+    /// Sets factors by which the quantization step of each coefficient
+    /// is multiplied, such as the ones frequency_weights returns.
+    pub fn weights(mut self, weights: [f32; N * N]) -> QuantizeOptions {
+        self.weights = weights;
+        self
+    }
+}
+
+impl Default for QuantizeOptions {
+    fn default() -> QuantizeOptions {
+        QuantizeOptions::new()
+    }
+}
+
+/// Returns weights for QuantizeOptions that grow with the spatial
+/// frequency of each coefficient, from 1 for the DC coefficient to
+/// 1 + strength for the highest frequency, following the falling
+/// sensitivity of the eye to detail. This moves bits from fine detail,
+/// where errors are hard to see, to coarser structure.
+pub fn frequency_weights(strength: f32) -> [f32; N * N] {
+    let max = ((2 * (N - 1) * (N - 1)) as f32).sqrt();
+    let mut res = [0.0f32; N * N];
+    for (i, w) in res.iter_mut().enumerate() {
+        let (u, v) = ((i % N) as f32, (i / N) as f32);
+        *w = 1.0 + strength * (u * u + v * v).sqrt() / max;
+    }
+    res
+}
+
+/// Quantizes coefficients like quantize, with the dead zone and weights
+/// from options.
+pub fn quantize_with(coeffs: &[f32; N * N], qtable: &[u16; N * N], options: &QuantizeOptions)
+                     -> [i32; N * N] {
+    let mut res = [0i32; N * N];
+    for i in 0..N * N {
+        let x = coeffs[i] * jpeg_scale(i) / (qtable[i] as f32 * options.weights[i]);
+        // Without a dead zone, 0 would otherwise be rounded up to 1 step.
+        if x != 0.0 && x.abs() >= options.dead_zone {
+            res[i] = (x.abs().round().max(1.0) as i32) * x.signum() as i32;
+        }
+    }
+    res
+}
+
+/// Reverses quantize_with, given the same options.
+pub fn dequantize_with(quantized: &[i32; N * N], qtable: &[u16; N * N],
+                       options: &QuantizeOptions) -> [f32; N * N] {
+    let mut res = [0.0f32; N * N];
+    for i in 0..N * N {
+        res[i] = quantized[i] as f32 * qtable[i] as f32 * options.weights[i] / jpeg_scale(i);
+    }
+    res
+}
+
+/// Returns a table of thresholds for threshold and soft_threshold that
+/// uses bands[u + v] for the coefficient in row v and column u, so that
+/// coefficients on the same anti-diagonal, which have similar spatial
+/// frequencies, share a threshold. bands[0] applies to the DC
+/// coefficient.
+pub fn band_thresholds(bands: &[f32; 2 * N - 1]) -> [f32; N * N] {
+    let mut res = [0.0f32; N * N];
+    for (i, t) in res.iter_mut().enumerate() {
+        *t = bands[i / N + i % N];
+    }
+    res
+}
+
+/// Sets coefficients whose magnitude is below the corresponding entry
+/// of thresholds to 0, leaving the others unchanged. This makes blocks
+/// sparser, so that they compress better, at the cost of detail.
+pub fn threshold(coeffs: &[f32; N * N], thresholds: &[f32; N * N]) -> [f32; N * N] {
+    let mut res = *coeffs;
+    for (c, &t) in res.iter_mut().zip(thresholds.iter()) {
+        if c.abs() < t {
+            *c = 0.0;
+        }
+    }
+    res
+}
+
+/// Moves coefficients toward 0 by the corresponding entry of
+/// thresholds, setting those whose magnitude is below it to 0. This
+/// removes noise with fewer artifacts than threshold.
+pub fn soft_threshold(coeffs: &[f32; N * N], thresholds: &[f32; N * N]) -> [f32; N * N] {
+    let mut res = *coeffs;
+    for (c, &t) in res.iter_mut().zip(thresholds.iter()) {
+        *c = c.signum() * (c.abs() - t).max(0.0);
+    }
+    res
+}
+
+/// Zigzag scan order for 8x8 blocks: ZIGZAG[k] is the row-major index
+/// of the k-th coefficient in order of increasing frequency, as computed
+/// by zigzag_order(8).
+pub static ZIGZAG : [usize; N * N] = [
+     0,  1,  8, 16,  9,  2,  3, 10,
+    17, 24, 32, 25, 18, 11,  4,  5,
+    12, 19, 26, 33, 40, 48, 41, 34,
+    27, 20, 13,  6,  7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36,
+    29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46,
+    53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// Returns the zigzag scan order for n by n blocks: the row-major index
+/// of each coefficient, starting at the top left and going back and
+/// forth along the anti-diagonals.
+pub fn zigzag_order(n: usize) -> Vec<usize> {
+    let mut order = Vec::with_capacity(n * n);
+    for diag in 0..(2 * n).saturating_sub(1) {
+        let rows = diag.saturating_sub(n - 1)..=std::cmp::min(diag, n - 1);
+        if diag % 2 == 0 {
+            order.extend(rows.rev().map(|y| y * n + diag - y));
+        } else {
+            order.extend(rows.map(|y| y * n + diag - y));
+        }
+    }
+    order
+}
+
+/// Reorders an 8x8 block from row-major to zigzag order.
+pub fn zigzag<T: Copy>(block: &[T; N * N]) -> [T; N * N] {
+    let mut res = *block;
+    for (r, &i) in res.iter_mut().zip(ZIGZAG.iter()) {
+        *r = block[i];
+    }
+    res
+}
+
+/// Reorders an 8x8 block from zigzag to row-major order.
+pub fn unzigzag<T: Copy>(scanned: &[T; N * N]) -> [T; N * N] {
+    let mut res = *scanned;
+    for (&x, &i) in scanned.iter().zip(ZIGZAG.iter()) {
+        res[i] = x;
+    }
+    res
+}
+
+/// Reorders a block from row-major order to the given order, as
+/// returned by zigzag_order, for blocks of any size.
+pub fn zigzag_with<T: Copy>(block: &[T], order: &[usize]) -> Vec<T> {
+    assert_eq!(block.len(), order.len(), "block does not match order");
+    order.iter().map(|&i| block[i]).collect()
+}
+
+/// Reverses zigzag_with.
+pub fn unzigzag_with<T: Copy>(scanned: &[T], order: &[usize]) -> Vec<T> {
+    assert_eq!(scanned.len(), order.len(), "block does not match order");
+    let mut res = scanned.to_vec();
+    for (&x, &i) in scanned.iter().zip(order.iter()) {
+        res[i] = x;
+    }
+    res
+}
+
+/// Where predict_dc restarts the prediction of DC coefficients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DcRestart {
+    /// Predict each block from the previous one, continuing from the
+    /// end of one row of blocks to the start of the next, as JPEG does.
+    /// Only the first block of the plane is predicted from 0.
+    Plane,
+    /// Predict the first block of each row of blocks from 0, so that
+    /// rows can be decoded independently.
+    Row,
+}
+
+/// Replaces the DC coefficient of each block with its difference from
+/// the DC coefficient of the previous block, in row-major order of
+/// blocks. Neighboring blocks tend to have similar means, so the
+/// differences are much smaller than the coefficients and cheaper to
+/// code.
+pub fn predict_dc(coeffs: &mut ImageCoefficients<i32>, restart: DcRestart) {
+    let mut previous = 0;
+    for (i, block) in coeffs.blocks.iter_mut().enumerate() {
+        if restart == DcRestart::Row && i % coeffs.blocks_wide == 0 {
+            previous = 0;
+        }
+        let dc = block[0];
+        block[0] = dc.wrapping_sub(previous);
+        previous = dc;
+    }
+}
+
+/// Reverses predict_dc, given the same restart.
+pub fn reconstruct_dc(coeffs: &mut ImageCoefficients<i32>, restart: DcRestart) {
+    let mut previous = 0;
+    for (i, block) in coeffs.blocks.iter_mut().enumerate() {
+        if restart == DcRestart::Row && i % coeffs.blocks_wide == 0 {
+            previous = 0;
+        }
+        block[0] = block[0].wrapping_add(previous);
+        previous = block[0];
+    }
+}
+
 /// Multiplies two matrices.
 fn matmul(a: &[f32; N * N], b: &[f32; N * N]) -> [f32; N * N] {
-    let mut res : [f32; N * N] = vec![0.0; N * N].try_into().unwrap();
+    let mut res = [0.0f32; N * N];
     for y in 0..N {
         for x in 0..N {
             res[y * N + x] =
@@ -75,7 +1141,7 @@ fn matmul(a: &[f32; N * N], b: &[f32; N * N]) -> [f32; N * N] {
 ///
 /// This is equivalent to the hypothetical `matmul(a, mattranspose(b))`.
 fn matmul_transposed(a: &[f32; N * N], b: &[f32; N * N]) -> [f32; N * N] {
-    let mut res : [f32; N * N] = vec![0.0; N * N].try_into().unwrap();
+    let mut res = [0.0f32; N * N];
     for y in 0..N {
         for x in 0..N {
             res[y * N + x] =
@@ -89,7 +1155,7 @@ fn matmul_transposed(a: &[f32; N * N], b: &[f32; N * N]) -> [f32; N * N] {
 ///
 /// This multiplies every element of the matrix by the scale factor.
 fn matscale(a: &[f32; N * N], scale: f32) -> [f32; N * N] {
-    let mut res : [f32; N * N] = vec![0.0; N * N].try_into().unwrap();
+    let mut res = [0.0f32; N * N];
     for y in 0..N {
         for x in 0..N {
             res[y * N + x] = a[y * N + x] * scale;
@@ -99,27 +1165,48 @@ fn matscale(a: &[f32; N * N], scale: f32) -> [f32; N * N] {
 }
 
 #[cfg(test)]
+#[allow(clippy::approx_constant)]
 mod tests {
     use super::*;
+    use crate::testutil::lcg;
 
     use std::convert::TryInto;
 
     /// Rounds the elements of a matrix to 3 decimal digits.
     fn round(a: &[f32; N * N]) -> [f32; N * N] {
-        a.into_iter().map(|x| (*x * 1000.0).round() / 1000.0)
+        a.iter().map(|x| (*x * 1000.0).round() / 1000.0)
             .collect::<Vec<f32>>().try_into().unwrap()
     }
 
     #[test]
     fn dctlut_matches_computed() {
-        fn compute_dctlut() -> Vec<f32> {
-            (0..64).map(|i| {
-                let x = (i % N) as f32;
-                let y = (i / N) as f32;
-                (std::f32::consts::PI * y / (N as f32) * (x + 0.5)).cos()
-            }).collect()
-        };
-        assert_eq!(Vec::from(DCTLUT), compute_dctlut());
+        for (i, &x) in DCTLUT.iter().enumerate() {
+            let angle = std::f64::consts::PI * (i / N) as f64 / (N as f64) * ((i % N) as f64 + 0.5);
+            assert_eq!(x, angle.cos() as f32, "entry {}", i);
+        }
+        for (i, &x) in IDCTLUT.iter().enumerate() {
+            let expected = if i % N == 0 { 0.5 } else { DCTLUT[i % N * N + i / N] };
+            assert_eq!(x, expected, "entry {}", i);
+        }
+    }
+
+    #[test]
+    fn cos_pi() {
+        for den in 1..40 {
+            for num in 0..5 * den {
+                // Reduce the angle, so that the reference is accurate.
+                let reduced = num % (2 * den);
+                let reduced = reduced.min(2 * den - reduced);
+                let exact = (std::f64::consts::PI * reduced as f64 / den as f64).cos();
+                let c = super::cos_pi(num, den);
+                assert!((c - exact).abs() < 1e-15, "cos(pi * {} / {}) is {}, not {}",
+                        num, den, c, exact);
+            }
+        }
+        // Other block sizes share the machinery.
+        let m = dct_matrix::<4>();
+        assert_eq!(m[0], [1.0; 4]);
+        assert!((m[2][1] + std::f64::consts::FRAC_1_SQRT_2).abs() < 1e-15);
     }
 
     #[test]
@@ -147,6 +1234,573 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn scale_qtable() {
+        assert_eq!(super::scale_qtable(&LUMINANCE_QTABLE, 50), LUMINANCE_QTABLE);
+        assert_eq!(super::scale_qtable(&LUMINANCE_QTABLE, 100), [1; N * N]);
+        assert_eq!(super::scale_qtable(&LUMINANCE_QTABLE, 0), [255; N * N]);
+        let q75 = super::scale_qtable(&CHROMINANCE_QTABLE, 75);
+        assert_eq!((q75[0], q75[63]), (9, 50));
+        let q10 = super::scale_qtable(&CHROMINANCE_QTABLE, 10);
+        assert_eq!((q10[0], q10[63]), (85, 255));
+    }
+
+    #[test]
+    fn quantize() {
+        // A flat block has only a DC coefficient, which JPEG defines as 8
+        // times the mean.
+        let flat = [100.0; N * N];
+        let quantized = super::quantize(&super::transform(&flat), &[1; N * N]);
+        assert_eq!(quantized[0], 800);
+        assert!(quantized[1..].iter().all(|&q| q == 0));
+
+        // A smooth gradient survives quantization at moderate quality,
+        // with most coefficients quantized to 0.
+        let img : [f32; N * N] = (0..N * N).map(|i| (i % N * 12 + i / N * 5) as f32 - 80.0)
+            .collect::<Vec<f32>>().try_into().unwrap();
+        let qtable = super::scale_qtable(&LUMINANCE_QTABLE, 75);
+        let quantized = super::quantize(&super::transform(&img), &qtable);
+        assert!(quantized.iter().filter(|&&q| q == 0).count() > 48);
+        let restored = reverse(&dequantize(&quantized, &qtable));
+        for (a, b) in restored.iter().zip(img.iter()) {
+            assert!((a - b).abs() < 8.0, "{} differs too much from {}", a, b);
+        }
+    }
+
+    #[test]
+    fn fused() {
+        for &quality in &[10, 50, 75, 95, 100] {
+            let qtable = super::scale_qtable(&LUMINANCE_QTABLE, quality);
+            for seed in 0..20 {
+                let block = random_block(seed, 128).map(|x| x as f32);
+                let fused = transform_quantize(&block, &qtable);
+                let two_step = super::quantize(&super::transform(&block), &qtable);
+                for (&f, &t) in fused.iter().zip(two_step.iter()) {
+                    assert_eq!(f as i32, t, "quality {}, seed {}", quality, seed);
+                }
+            }
+        }
+        // Results that do not fit are clamped.
+        let fused = transform_quantize(&[1e9; N * N], &[1; N * N]);
+        assert_eq!(fused[0], i16::MAX);
+    }
+
+    #[test]
+    fn quantize_options() {
+        let coeffs = random_block(9, 200).map(|x| x as f32 / 4.0);
+        let qtable = super::scale_qtable(&LUMINANCE_QTABLE, 90);
+        let plain = QuantizeOptions::default();
+        assert_eq!(quantize_with(&coeffs, &qtable, &plain), super::quantize(&coeffs, &qtable));
+        assert_eq!(dequantize_with(&[3; N * N], &qtable, &plain), dequantize(&[3; N * N], &qtable));
+
+        // A wider dead zone only zeroes coefficients that would otherwise
+        // be small.
+        let plain_quantized = quantize_with(&coeffs, &qtable, &plain);
+        let zoned = quantize_with(&coeffs, &qtable, &QuantizeOptions::new().dead_zone(1.5));
+        assert!(zoned.iter().filter(|&&q| q == 0).count()
+                > plain_quantized.iter().filter(|&&q| q == 0).count());
+        for (&z, &p) in zoned.iter().zip(plain_quantized.iter()) {
+            assert!(z == p || (z == 0 && p.abs() <= 2), "{} became {}", p, z);
+        }
+        let steps = [0.0, 0.3, 0.5, 0.9, -0.9, 1.2, -2.6, 0.0];
+        let mut block = [0.0; N * N];
+        for (i, &x) in steps.iter().enumerate() {
+            block[i + 1] = x * 16.0 / jpeg_scale(i + 1);
+        }
+        let q = quantize_with(&block, &[16; N * N], &QuantizeOptions::new().dead_zone(0.8));
+        assert_eq!(q[1..9], [0, 0, 0, 1, -1, 1, -3, 0]);
+        // Without a dead zone, only 0 stays 0, including -0.0.
+        block[9] = -0.0;
+        let q = quantize_with(&block, &[16; N * N], &QuantizeOptions::new().dead_zone(0.0));
+        assert_eq!(q[1..10], [0, 1, 1, 1, -1, 1, -3, 0, 0]);
+
+        // Weights give high frequencies coarser steps and reverse exactly.
+        let weights = frequency_weights(2.0);
+        assert_eq!((weights[0], weights[63]), (1.0, 3.0));
+        assert!(weights[1] < weights[2] && weights[9] < weights[18]);
+        let weighted = QuantizeOptions::new().weights(weights);
+        let quantized = quantize_with(&coeffs, &qtable, &weighted);
+        let restored = dequantize_with(&quantized, &qtable, &weighted);
+        for i in 0..N * N {
+            let step = qtable[i] as f32 * weights[i] / jpeg_scale(i);
+            assert!((restored[i] - coeffs[i]).abs() <= step / 2.0 + 1e-3);
+        }
+    }
+
+    #[test]
+    fn dc_prediction() {
+        let image: Vec<f32> = (0..24 * 16).map(|i| (i % 24 + i / 24 * 2) as f32 + 40.0)
+            .collect();
+        let qtable = super::scale_qtable(&LUMINANCE_QTABLE, 75);
+        let original = quantize_image(&super::transform_image(&image, 24, 16, 24, Padding::Replicate),
+                                      &qtable);
+        for &restart in &[DcRestart::Plane, DcRestart::Row] {
+            let mut coeffs = original.clone();
+            predict_dc(&mut coeffs, restart);
+            assert_eq!(coeffs.blocks[0][0], original.blocks[0][0]);
+            assert_eq!(coeffs.blocks[1][0], original.blocks[1][0] - original.blocks[0][0]);
+            assert_eq!(coeffs.blocks[1][1..], original.blocks[1][1..]);
+            // The differences are smaller than the coefficients.
+            let sum = |c: &ImageCoefficients<i32>| -> i32 {
+                c.plane(0).iter().map(|x| x.abs()).sum()
+            };
+            assert!(sum(&coeffs) * 2 < sum(&original), "{} vs {}", sum(&coeffs), sum(&original));
+            reconstruct_dc(&mut coeffs, restart);
+            assert_eq!(coeffs, original);
+        }
+
+        let mut plane = original.clone();
+        predict_dc(&mut plane, DcRestart::Plane);
+        let mut row = original.clone();
+        predict_dc(&mut row, DcRestart::Row);
+        assert_eq!(plane.blocks[3][0], original.blocks[3][0] - original.blocks[2][0]);
+        assert_eq!(row.blocks[3][0], original.blocks[3][0]);
+    }
+
+    #[test]
+    fn thresholds() {
+        let mut bands = [0.5; 2 * N - 1];
+        bands[0] = 0.0;
+        bands[14] = 2.0;
+        let thresholds = band_thresholds(&bands);
+        assert_eq!(thresholds[0], 0.0);
+        assert_eq!((thresholds[1], thresholds[N], thresholds[62]), (0.5, 0.5, 0.5));
+        assert_eq!(thresholds[63], 2.0);
+
+        let mut coeffs = [0.25; N * N];
+        coeffs[0] = 0.1;
+        coeffs[1] = -1.5;
+        coeffs[2] = 0.5;
+        coeffs[63] = 1.0;
+        let hard = threshold(&coeffs, &thresholds);
+        assert_eq!(hard[..4], [0.1, -1.5, 0.5, 0.0]);
+        assert_eq!(hard[63], 0.0);
+        let soft = soft_threshold(&coeffs, &thresholds);
+        assert_eq!(soft[..4], [0.1, -1.0, 0.0, 0.0]);
+        assert_eq!(soft[63], 0.0);
+
+        // Soft thresholding removes low amplitude noise from a smooth
+        // block.
+        let noise = random_block(9, 128).map(|x| x as f32 / 128.0);
+        let smooth: Vec<f32> = (0..N * N).map(|i| (i % N) as f32 * 10.0).collect();
+        let noisy: [f32; N * N] = smooth.iter().zip(noise.iter()).map(|(a, b)| a + b)
+            .collect::<Vec<f32>>().try_into().unwrap();
+        let mut bands = [0.15; 2 * N - 1];
+        bands[0] = 0.0;
+        let denoised = reverse(&soft_threshold(&super::transform(&noisy),
+                                               &band_thresholds(&bands)));
+        let error = |a: &[f32]| -> f32 {
+            a.iter().zip(smooth.iter()).map(|(x, y)| (x - y) * (x - y)).sum()
+        };
+        assert!(error(&denoised) < error(&noisy) / 2.0);
+    }
+
+    #[test]
+    fn zigzag_matches_computed() {
+        assert_eq!(zigzag_order(N), ZIGZAG);
+        assert_eq!(zigzag_order(1), [0]);
+        assert_eq!(zigzag_order(3), [0, 1, 3, 6, 4, 2, 5, 7, 8]);
+        assert!(zigzag_order(0).is_empty());
+    }
+
+    #[test]
+    fn zigzag() {
+        let block: [u8; N * N] = (0..(N * N) as u8).collect::<Vec<u8>>().try_into().unwrap();
+        let scanned = super::zigzag(&block);
+        assert_eq!(scanned[..6], [0, 1, 8, 16, 9, 2]);
+        assert_eq!(unzigzag(&scanned), block);
+
+        let order = zigzag_order(5);
+        let block: Vec<i32> = (0..25).collect();
+        let scanned = zigzag_with(&block, &order);
+        assert_eq!(scanned[..4], [0, 1, 5, 10]);
+        assert_eq!(scanned[24], 24);
+        assert_eq!(unzigzag_with(&scanned, &order), block);
+    }
+
+    #[test]
+    fn dctlut_fixed_matches_computed() {
+        for (i, &x) in DCTLUT_FIXED.iter().enumerate() {
+            let angle = std::f64::consts::PI * (i / N) as f64 / (N as f64) * ((i % N) as f64 + 0.5);
+            let exact = angle.cos() * (1u64 << LUT_FRAC_BITS) as f64;
+            assert!((x as f64 - exact).abs() <= 0.5, "entry {} is {}, not {}", i, x, exact);
+        }
+    }
+
+    /// Returns a matrix of pseudo-random values from -range to range - 1.
+    fn random_block(seed: u32, range: i32) -> [i32; N * N] {
+        let mut random = lcg(seed);
+        [0; N * N].map(|_| ((random.next().unwrap() >> 8) % (2 * range as u32)) as i32 - range)
+    }
+
+    #[test]
+    fn transform_fixed() {
+        // The fixed-point transform matches the floating-point one.
+        let img = random_block(1, 128);
+        let fixed = super::transform_fixed(&img);
+        let float = super::transform(&img.map(|x| x as f32));
+        for (&a, &b) in fixed.iter().zip(float.iter()) {
+            let a = a as f32 / (1 << FIXED_FRAC_BITS) as f32;
+            assert!((a - b).abs() < 0.001, "{} differs from {}", a, b);
+        }
+
+        // The results are the same on every platform.
+        let flat = super::transform_fixed(&[100; N * N]);
+        assert_eq!(flat[0], 100 << FIXED_FRAC_BITS);
+        assert!(flat[1..].iter().all(|&x| x == 0));
+        let mut ramp = [0; N * N];
+        for (i, x) in ramp.iter_mut().enumerate() {
+            *x = (i % N * 16) as i32 - 64;
+        }
+        assert_eq!(super::transform_fixed(&ramp)[..N],
+                   [-32768, -105551, 0, -11034, 0, -3292, 0, -831]);
+        assert!(super::transform_fixed(&ramp)[N..].iter().all(|&x| x == 0));
+    }
+
+    #[test]
+    fn reversible_fixed() {
+        for seed in 0..200 {
+            let range = if seed % 2 == 0 { 256 } else { 32768 };
+            let img = random_block(seed, range);
+            assert_eq!(reverse_fixed(&super::transform_fixed(&img)), img);
+        }
+        for img in [[-32768; N * N], [32767; N * N]] {
+            assert_eq!(reverse_fixed(&super::transform_fixed(&img)), img);
+        }
+        let mut checkerboard = [0; N * N];
+        for (i, x) in checkerboard.iter_mut().enumerate() {
+            *x = if (i / N + i % N).is_multiple_of(2) { 32767 } else { -32768 };
+        }
+        assert_eq!(reverse_fixed(&super::transform_fixed(&checkerboard)), checkerboard);
+    }
+
+    #[test]
+    fn in_place() {
+        let img = random_block(11, 128).map(|x| x as f32);
+        let mut block = img;
+        transform_in_place(&mut block);
+        assert_eq!(block, super::transform(&img));
+        reverse_in_place(&mut block);
+        assert_eq!(block, reverse(&super::transform(&img)));
+    }
+
+    #[test]
+    fn fast_matches_matrix() {
+        for seed in 0..20 {
+            let img = random_block(seed, 128).map(|x| x as f32 / 128.0);
+            let fast = super::transform(&img);
+            let matrix = transform_matrix(&img);
+            for (a, b) in fast.iter().zip(matrix.iter()) {
+                assert!((a - b).abs() < 1e-5, "{} differs from {}", a, b);
+            }
+            let fast = reverse(&matrix);
+            let matrix = reverse_matrix(&matrix);
+            for (a, b) in fast.iter().zip(matrix.iter()) {
+                assert!((a - b).abs() < 1e-5, "{} differs from {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn transform_f64() {
+        // Compare with the definition, computed in double precision.
+        let img = random_block(7, 32768).map(|x| x as f64 / 32768.0);
+        let basis = |k: usize, x: usize| {
+            (std::f64::consts::PI * k as f64 / N as f64 * (x as f64 + 0.5)).cos()
+        };
+        let result = super::transform_f64(&img);
+        for v in 0..N {
+            for u in 0..N {
+                let mut exact = 0.0;
+                for y in 0..N {
+                    for x in 0..N {
+                        exact += basis(v, y) * basis(u, x) * img[y * N + x];
+                    }
+                }
+                exact /= 64.0;
+                let r = result[v * N + u];
+                assert!((r - exact).abs() < 1e-14, "{} differs from {}", r, exact);
+            }
+        }
+
+        let reversed = reverse_f64(&result);
+        for (a, b) in reversed.iter().zip(img.iter()) {
+            assert!((a - b).abs() < 1e-14, "{} differs from {}", a, b);
+        }
+    }
+
+    #[test]
+    fn batch() {
+        let blocks: Vec<[f32; N * N]> = (0..10)
+            .map(|seed| random_block(seed, 128).map(|x| x as f32)).collect();
+        let transformed = transform_batch(&blocks);
+        assert_eq!(transformed.len(), blocks.len());
+        for (t, b) in transformed.iter().zip(blocks.iter()) {
+            assert_eq!(*t, super::transform(b));
+        }
+        let mut output = vec![[0.0; N * N]; blocks.len()];
+        transform_batch_into(&blocks, &mut output);
+        assert_eq!(output, transformed);
+
+        let reversed = reverse_batch(&transformed);
+        for (r, t) in reversed.iter().zip(transformed.iter()) {
+            assert_eq!(*r, reverse(t));
+        }
+        reverse_batch_into(&transformed, &mut output);
+        assert_eq!(output, reversed);
+        assert!(transform_batch(&[]).is_empty());
+    }
+
+    #[test]
+    fn strided() {
+        let stride = 21;
+        let image: Vec<f32> = (0..stride * 12).map(|i| ((i * 37) % 255) as f32 - 128.0).collect();
+        let offset = 3 * stride + 5;
+        let mut block = [0.0; N * N];
+        for y in 0..N {
+            block[y * N..(y + 1) * N].copy_from_slice(&image[offset + y * stride..][..N]);
+        }
+        let transformed = transform_strided(&image[offset..], stride);
+        assert_eq!(transformed, super::transform(&block));
+
+        let mut output = vec![1000.0; stride * 12];
+        reverse_strided(&transformed, &mut output[offset..], stride);
+        for (i, &x) in output.iter().enumerate() {
+            let (y, x0) = (i / stride, i % stride);
+            if (3..3 + N).contains(&y) && (5..5 + N).contains(&x0) {
+                assert!((x - image[i]).abs() < 1e-3, "{} differs from {}", x, image[i]);
+            } else {
+                assert_eq!(x, 1000.0);
+            }
+        }
+    }
+
+    #[test]
+    fn transform_u8() {
+        let flat = super::transform_u8(&[128; N * N]);
+        assert!(flat.iter().all(|&x| x == 0.0));
+        assert_eq!(super::transform_u8(&[0; N * N])[0], -128.0);
+
+        let pixels = random_block(3, 128).map(|x| (x + 128) as u8);
+        assert_eq!(reverse_to_u8(&super::transform_u8(&pixels)), pixels);
+        assert_eq!(reverse_to_u8(&super::transform_u8(&[255; N * N])), [255; N * N]);
+
+        // Reconstructed values are rounded and clamped.
+        let mut coeffs = [0.0; N * N];
+        coeffs[0] = 200.0;
+        assert_eq!(reverse_to_u8(&coeffs), [255; N * N]);
+        coeffs[0] = -200.0;
+        assert_eq!(reverse_to_u8(&coeffs), [0; N * N]);
+        coeffs[0] = 0.6;
+        assert_eq!(reverse_to_u8(&coeffs), [129; N * N]);
+    }
+
+    #[test]
+    fn high_depth() {
+        for &bits in &[10, 12, 16] {
+            let max = ((1u32 << bits) - 1) as u16;
+            let flat = transform_u16(&[1 << (bits - 1); N * N], bits);
+            assert!(flat.iter().all(|&x| x == 0.0));
+            let pixels = random_block(bits, 1 << (bits - 1))
+                .map(|x| (x + (1 << (bits - 1))) as u16);
+            assert_eq!(reverse_to_u16(&transform_u16(&pixels, bits), bits), pixels);
+            assert_eq!(reverse_to_u16(&transform_u16(&[max; N * N], bits), bits), [max; N * N]);
+
+            let mut coeffs = [0.0; N * N];
+            coeffs[0] = 1e6;
+            assert_eq!(reverse_to_u16(&coeffs, bits), [max; N * N]);
+            coeffs[0] = -1e6;
+            assert_eq!(reverse_to_u16(&coeffs, bits), [0; N * N]);
+        }
+        // At 8 bits, this is the same as transform_u8.
+        let pixels = random_block(4, 128).map(|x| (x + 128) as u8);
+        assert_eq!(transform_u16(&pixels.map(|x| x as u16), 8), super::transform_u8(&pixels));
+
+        let table = qtable_for_depth(&LUMINANCE_QTABLE, 12);
+        assert_eq!(table[0], LUMINANCE_QTABLE[0] * 16);
+        assert_eq!(qtable_for_depth(&[300; N * N], 16), [65535; N * N]);
+    }
+
+    #[test]
+    fn hdr() {
+        let flat = transform_hdr(&[0.5; N * N]);
+        assert!(flat.iter().all(|&x| x == 0.0));
+        let pixels = random_block(6, 100).map(|x| (x + 100) as f32 / 50.0);
+        assert!(pixels.iter().any(|&x| x > 1.0));
+        for (a, b) in reverse_to_hdr(&transform_hdr(&pixels)).iter().zip(pixels.iter()) {
+            assert!((a - b).abs() < 1e-5, "{} differs from {}", a, b);
+        }
+        let mut coeffs = [0.0; N * N];
+        coeffs[0] = -1000.0;
+        assert_eq!(reverse_to_hdr(&coeffs), [0.0; N * N]);
+    }
+
+    #[test]
+    fn sanitized() {
+        let mut coeffs = [0.0; N * N];
+        coeffs[0] = f32::NAN;
+        coeffs[1] = f32::INFINITY;
+        coeffs[2] = -1e30;
+        coeffs[3] = 12.5;
+        let clean = sanitize(&coeffs);
+        assert_eq!(clean[..4], [0.0, MAX_SANITIZED, -MAX_SANITIZED, 12.5]);
+        assert!(reverse(&coeffs).iter().all(|x| x.is_nan()));
+        assert!(reverse_sanitized(&coeffs).iter().all(|x| x.is_finite()));
+
+        // Valid coefficients are unchanged.
+        let pixels = random_block(8, 128).map(|x| (x + 128) as u8);
+        let transformed = super::transform_u8(&pixels);
+        assert_eq!(sanitize(&transformed), transformed);
+        assert_eq!(reverse_to_u8_sanitized(&transformed), pixels);
+
+        // Arbitrary bit patterns, including NaNs, infinities and
+        // subnormals, always give finite results.
+        let mut random = lcg(12345);
+        for round in 0..2000 {
+            let block = [0.0f32; N * N].map(|_| {
+                let state = random.next().unwrap();
+                // Mix arbitrary patterns with plausible values.
+                if round % 2 == 0 { f32::from_bits(state) } else { (state >> 8) as f32 - 8e6 }
+            });
+            let reversed = reverse_sanitized(&block);
+            assert!(reversed.iter().all(|x| x.is_finite()), "{:?} gives {:?}", block, reversed);
+            reverse_to_u8_sanitized(&block);
+        }
+    }
+
+    #[test]
+    fn dct_1d() {
+        // On blocks with identical rows, transform is a one-dimensional
+        // DCT of the rows.
+        let row = random_block(5, 128).map(|x| x as f32 / 128.0);
+        let mut block = [0.0; N * N];
+        for (i, x) in block.iter_mut().enumerate() {
+            *x = row[i % N];
+        }
+        let dct = Dct1d::new(N);
+        assert_eq!(dct.frame_len(), N);
+        let transformed = dct.transform(&row[..N]);
+        for (a, b) in transformed.iter().zip(super::transform(&block).iter()) {
+            assert!((a - b).abs() < 1e-5, "{} differs from {}", a, b);
+        }
+
+        for &len in &[256, 1024] {
+            let dct = Dct1d::new(len);
+            let frame: Vec<f32> = (0..len).map(|i| {
+                (std::f32::consts::PI * 7.0 * (i as f32 + 0.5) / len as f32).cos()
+            }).collect();
+            let transformed = dct.transform(&frame);
+            for (k, &c) in transformed.iter().enumerate() {
+                let expected = if k == 7 { 0.5 } else { 0.0 };
+                assert!((c - expected).abs() < 1e-3, "coefficient {} is {}", k, c);
+            }
+            for (a, b) in dct.reverse(&transformed).iter().zip(frame.iter()) {
+                assert!((a - b).abs() < 1e-3, "{} differs from {}", a, b);
+            }
+        }
+        let dct = Dct1d::new(1);
+        assert_eq!(dct.transform(&[3.0]), [3.0]);
+        assert_eq!(dct.reverse(&[3.0]), [3.0]);
+    }
+
+    #[test]
+    fn dct_types() {
+        let len = 24;
+        let frame: Vec<f32> = random_block(11, 128)[..len].iter().map(|&x| x as f32 / 128.0)
+            .collect();
+        let ii = Dct1d::new(len);
+        let iii = Dct1d::with_type(len, DctType::III);
+        let iv = Dct1d::with_type(len, DctType::IV);
+        assert_eq!(ii.dct_type(), DctType::II);
+        assert_eq!(iv.frame_len(), len);
+
+        // DCT-III and DCT-II are each other's inverses.
+        assert_eq!(iii.transform(&frame), ii.reverse(&frame));
+        assert_eq!(iii.reverse(&frame), ii.transform(&frame));
+
+        // DCT-IV against its definition.
+        let transformed = iv.transform(&frame);
+        for (k, &c) in transformed.iter().enumerate() {
+            let expected = frame.iter().enumerate().map(|(n, &x)| {
+                let angle = std::f64::consts::PI * ((2 * n + 1) * (2 * k + 1)) as f64
+                    / (4 * len) as f64;
+                x as f64 * angle.cos()
+            }).sum::<f64>() / len as f64;
+            assert!((c as f64 - expected).abs() < 1e-5, "{} differs from {}", c, expected);
+        }
+
+        for dct in &[ii, iii, iv] {
+            for (a, b) in dct.reverse(&dct.transform(&frame)).iter().zip(frame.iter()) {
+                assert!((a - b).abs() < 1e-4, "{:?}: {} differs from {}", dct.dct_type(), a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn padding() {
+        let replicate: Vec<usize> = (0..8).map(|i| Padding::Replicate.index(i, 3)).collect();
+        assert_eq!(replicate, [0, 1, 2, 2, 2, 2, 2, 2]);
+        let reflect: Vec<usize> = (0..8).map(|i| Padding::Reflect.index(i, 3)).collect();
+        assert_eq!(reflect, [0, 1, 2, 1, 0, 1, 2, 1]);
+        assert_eq!(Padding::Reflect.index(5, 1), 0);
+    }
+
+    #[test]
+    fn transform_image() {
+        // A 13x10 image in a buffer with a stride of 16.
+        let (width, height, stride) = (13, 10, 16);
+        let mut image = vec![-1000.0f32; stride * (height - 1) + width];
+        for y in 0..height {
+            for x in 0..width {
+                image[y * stride + x] = ((x * 31 + y * 17) % 64) as f32 / 32.0 - 1.0;
+            }
+        }
+        for &padding in &[Padding::Replicate, Padding::Reflect] {
+            let coeffs = super::transform_image(&image, width, height, stride, padding);
+            assert_eq!((coeffs.blocks_wide, coeffs.blocks_high), (2, 2));
+            assert_eq!(coeffs.blocks.len(), 4);
+            assert_eq!(coeffs.plane(0).len(), 4);
+
+            // The first block lies entirely within the image.
+            let mut first = [0.0f32; N * N];
+            for (i, x) in first.iter_mut().enumerate() {
+                *x = image[i / N * stride + i % N];
+            }
+            assert_eq!(coeffs.blocks[0], super::transform(&first));
+            // Padding never reads outside the image.
+            assert!(coeffs.plane(0).iter().all(|&dc| dc.abs() <= 1.0));
+
+            let reversed = reverse_image(&coeffs);
+            assert_eq!(reversed.len(), width * height);
+            for y in 0..height {
+                for x in 0..width {
+                    let (a, b) = (reversed[y * width + x], image[y * stride + x]);
+                    assert!((a - b).abs() < 1e-5, "{} differs from {}", a, b);
+                }
+            }
+        }
+
+        // Replicate padding turns a single pixel into a flat block.
+        let coeffs = super::transform_image(&[5.0], 1, 1, 1, Padding::Replicate);
+        assert_eq!(round(&coeffs.blocks[0])[..2], [5.0, 0.0]);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_image() {
+        let (width, height, stride) = (203, 77, 210);
+        let image: Vec<f32> = (0..stride * height).map(|i| ((i * 37) % 255) as f32 - 128.0)
+            .collect();
+        let coeffs = super::transform_image(&image, width, height, stride, Padding::Reflect);
+        let par = transform_image_par(&image, width, height, stride, Padding::Reflect);
+        assert_eq!(par, coeffs);
+        let qtable = super::scale_qtable(&LUMINANCE_QTABLE, 80);
+        assert_eq!(quantize_image_par(&par, &qtable), quantize_image(&coeffs, &qtable));
+        assert_eq!(reverse_image_par(&par), reverse_image(&coeffs));
+        assert_eq!(transform_batch_par(&par.blocks), transform_batch(&par.blocks));
+        assert_eq!(reverse_batch_par(&par.blocks), reverse_batch(&par.blocks));
+    }
+
     #[test]
     fn reversible() {
         let img : [f32; N * N] = [
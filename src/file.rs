@@ -0,0 +1,94 @@
+// Compressing and decompressing files in one call.
+//
+// Copyright 2021 Robbert Haarman
+//
+// SPDX-License-Identifier: MIT
+
+use std::fs::File;
+use std::path::Path;
+
+use crate::backref::{self, EncodeOptions};
+use crate::io::{PrefetchReader, SliceToVecIO};
+use crate::result::BoxResult;
+
+/// Codec and container format used by compress_file and decompress_file.
+#[derive(Clone)]
+pub enum Codec {
+    /// A backref stream, as produced by backref::encode. Files are
+    /// streamed through a window, so they can be of any size. Input is
+    /// read ahead on a background thread.
+    Backref(EncodeOptions),
+    /// A framed backref stream, as produced by backref::encode_framed.
+    /// Compressing reads the whole input into memory, since the frame
+    /// header holds its length; decompressing streams through a window.
+    BackrefFramed(EncodeOptions),
+}
+
+impl Default for Codec {
+    fn default() -> Codec {
+        Codec::Backref(EncodeOptions::new())
+    }
+}
+
+/// Compresses the file at in_path with codec, writing the result to
+/// out_path, which is created or truncated.
+pub fn compress_file(codec: &Codec, in_path: impl AsRef<Path>, out_path: impl AsRef<Path>)
+                     -> BoxResult<()> {
+    match codec {
+        Codec::Backref(options) => {
+            let mut input = PrefetchReader::new(File::open(in_path)?);
+            let mut output = File::create(out_path)?;
+            backref::encode_stream(&mut input, &mut output, options)
+        },
+        Codec::BackrefFramed(options) => {
+            let input = std::fs::read(in_path)?;
+            let mut output = Vec::new();
+            backref::encode_framed(&mut SliceToVecIO::new(&input, &mut output), options)?;
+            std::fs::write(out_path, output)?;
+            Ok(())
+        },
+    }
+}
+
+/// Decompresses the file at in_path, which must have been compressed
+/// with codec, writing the result to out_path, which is created or
+/// truncated. Only the encode options that affect the format matter.
+pub fn decompress_file(codec: &Codec, in_path: impl AsRef<Path>, out_path: impl AsRef<Path>)
+                       -> BoxResult<()> {
+    let mut input = PrefetchReader::new(File::open(in_path)?);
+    let mut output = File::create(out_path)?;
+    match codec {
+        Codec::Backref(_) => backref::decode_windowed(&mut input, &mut output),
+        Codec::BackrefFramed(_) => backref::decode_framed_windowed(&mut input, &mut output),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backref::FormatVersion;
+
+    #[test]
+    fn file_roundtrip() {
+        let dir = std::env::temp_dir();
+        let name = |ext: &str| dir.join(format!("file-test-{}.{}", std::process::id(), ext));
+        let (original, compressed, decompressed) = (name("in"), name("bref"), name("out"));
+        let data: Vec<u8> = (0..300000u32).map(|i| (i % 1000 * 7 % 251) as u8).collect();
+        std::fs::write(&original, &data).unwrap();
+        let codecs = [
+            Codec::default(),
+            Codec::Backref(EncodeOptions::new().version(FormatVersion::V2)),
+            Codec::BackrefFramed(EncodeOptions::new().checksum(true)),
+        ];
+        for codec in &codecs {
+            compress_file(codec, &original, &compressed).unwrap();
+            assert!(std::fs::metadata(&compressed).unwrap().len() < data.len() as u64);
+            decompress_file(codec, &compressed, &decompressed).unwrap();
+            assert!(std::fs::read(&decompressed).unwrap() == data);
+        }
+        assert!(decompress_file(&codecs[2], &original, &decompressed).is_err());
+        for path in [original, compressed, decompressed] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
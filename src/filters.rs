@@ -0,0 +1,206 @@
+// Reversible pre-filters.
+//
+// Copyright 2023 Robbert Haarman
+//
+// SPDX-License-Identifier: MIT
+
+//! This module implements reversible filters that make data easier to
+//! compress before it is entropy coded. Delta filters replace each byte
+//! by its difference from the byte stride bytes before it, which turns
+//! smoothly varying samples into small numbers. A stride of 1 suits 8-bit
+//! samples, 2 and 4 suit 16-bit and 32-bit samples, and 3 suits RGB
+//! pixels. The XOR filter replaces each byte by its exclusive or with
+//! the previous byte, which leaves zeros where bytes repeat.
+//!
+//! encode prefixes the filtered data with a 2-byte header identifying
+//! the filter: the filter number (0 for none, 1 for delta, 2 for XOR)
+//! and the stride of delta filters, or 0 for the others. choose picks
+//! the filter that minimizes the order-0 entropy of the result.
+
+use crate::result::BoxResult;
+
+/// Size of the header encode writes.
+pub const HEADER_SIZE: usize = 2;
+
+/// A reversible filter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    /// Leaves the data unchanged.
+    None,
+    /// Subtracts from each byte the byte the given number of bytes
+    /// before it, modulo 256. The stride must not be 0.
+    Delta(u8),
+    /// Exclusive ors each byte with the previous byte.
+    Xor,
+}
+
+/// Filters choose tries.
+const CANDIDATES: [Filter; 6] = [
+    Filter::None, Filter::Delta(1), Filter::Delta(2), Filter::Delta(3), Filter::Delta(4),
+    Filter::Xor,
+];
+
+/// Fraction by which a filter must lower the entropy for choose to
+/// prefer it.
+const MIN_GAIN: f64 = 0.01;
+
+/// Applies filter to data in place.
+pub fn apply(filter: Filter, data: &mut [u8]) {
+    match filter {
+        Filter::None => (),
+        Filter::Delta(stride) => {
+            assert!(stride > 0, "delta stride must not be 0");
+            for i in (stride as usize..data.len()).rev() {
+                data[i] = data[i].wrapping_sub(data[i - stride as usize]);
+            }
+        },
+        Filter::Xor => {
+            for i in (1..data.len()).rev() {
+                data[i] ^= data[i - 1];
+            }
+        },
+    }
+}
+
+/// Reverses apply in place.
+pub fn reverse(filter: Filter, data: &mut [u8]) {
+    match filter {
+        Filter::None => (),
+        Filter::Delta(stride) => {
+            assert!(stride > 0, "delta stride must not be 0");
+            for i in stride as usize..data.len() {
+                data[i] = data[i].wrapping_add(data[i - stride as usize]);
+            }
+        },
+        Filter::Xor => {
+            for i in 1..data.len() {
+                data[i] ^= data[i - 1];
+            }
+        },
+    }
+}
+
+/// Returns the order-0 entropy of data in bits.
+fn entropy(data: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for &b in data {
+        counts[b as usize] += 1;
+    }
+    let n = data.len() as f64;
+    counts.iter().filter(|&&c| c > 0).map(|&c| -(c as f64) * (c as f64 / n).log2()).sum()
+}
+
+/// Returns the filter after which data has the lowest order-0 entropy.
+/// A filter is only preferred over the ones before it in CANDIDATES if
+/// it lowers the entropy by more than MIN_GAIN, so that random variation
+/// does not pick a filter for data that no filter helps.
+pub fn choose(data: &[u8]) -> Filter {
+    let mut best = (Filter::None, entropy(data));
+    let mut filtered = vec![0; data.len()];
+    for &filter in &CANDIDATES[1..] {
+        filtered.copy_from_slice(data);
+        apply(filter, &mut filtered);
+        let e = entropy(&filtered);
+        if e < best.1 * (1.0 - MIN_GAIN) {
+            best = (filter, e);
+        }
+    }
+    best.0
+}
+
+/// Returns the header for filter.
+fn header(filter: Filter) -> [u8; HEADER_SIZE] {
+    match filter {
+        Filter::None => [0, 0],
+        Filter::Delta(stride) => [1, stride],
+        Filter::Xor => [2, 0],
+    }
+}
+
+/// Filters data with filter, returning the result with a header that
+/// identifies the filter.
+pub fn encode(filter: Filter, data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(HEADER_SIZE + data.len());
+    output.extend_from_slice(&header(filter));
+    output.extend_from_slice(data);
+    apply(filter, &mut output[HEADER_SIZE..]);
+    output
+}
+
+/// Reads the header written by encode and returns the filter it
+/// identifies.
+pub fn read_header(data: &[u8]) -> BoxResult<Filter> {
+    match data {
+        [0, 0, ..] => Ok(Filter::None),
+        [1, stride, ..] if *stride > 0 => Ok(Filter::Delta(*stride)),
+        [2, 0, ..] => Ok(Filter::Xor),
+        [_, _, ..] => Err("invalid filter header".into()),
+        _ => Err("filtered data is too short for header".into()),
+    }
+}
+
+/// Reverses encode.
+pub fn decode(data: &[u8]) -> BoxResult<Vec<u8>> {
+    let filter = read_header(data)?;
+    let mut output = data[HEADER_SIZE..].to_vec();
+    reverse(filter, &mut output);
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::lcg_bytes;
+
+    #[test]
+    fn filters() {
+        let mut data = [10, 13, 11, 200, 0];
+        apply(Filter::Delta(1), &mut data);
+        assert_eq!(data, [10, 3, 254, 189, 56]);
+        reverse(Filter::Delta(1), &mut data);
+        assert_eq!(data, [10, 13, 11, 200, 0]);
+
+        let mut data = [1, 2, 3, 5, 7, 9];
+        apply(Filter::Delta(3), &mut data);
+        assert_eq!(data, [1, 2, 3, 4, 5, 6]);
+
+        let mut data = [0x0f, 0x0f, 0xf0, 0xf1];
+        apply(Filter::Xor, &mut data);
+        assert_eq!(data, [0x0f, 0, 0xff, 1]);
+        reverse(Filter::Xor, &mut data);
+        assert_eq!(data, [0x0f, 0x0f, 0xf0, 0xf1]);
+
+        let mut short = [7];
+        for &filter in &CANDIDATES {
+            apply(filter, &mut short);
+            assert_eq!(short, [7]);
+        }
+    }
+
+    #[test]
+    fn choose_filter() {
+        // A ramp of 16-bit little endian samples.
+        let samples: Vec<u8> = (0..1000u16).flat_map(|i| (i * 37).to_le_bytes()).collect();
+        assert_eq!(choose(&samples), Filter::Delta(2));
+        // A gradient of RGB pixels.
+        let pixels: Vec<u8> = (0..500u32).flat_map(|i| [i as u8, (i * 3) as u8, 200]).collect();
+        assert_eq!(choose(&pixels), Filter::Delta(3));
+        assert_eq!(choose(&lcg_bytes(9, 1000)), Filter::None);
+        assert_eq!(choose(&[]), Filter::None);
+    }
+
+    #[test]
+    fn headers() {
+        let data: Vec<u8> = (0..300u32).map(|i| (i * i / 7) as u8).collect();
+        for &filter in &CANDIDATES {
+            let encoded = encode(filter, &data);
+            assert_eq!(read_header(&encoded).unwrap(), filter);
+            assert_eq!(decode(&encoded).unwrap(), data);
+        }
+        assert_eq!(decode(&encode(Filter::Delta(200), &data)).unwrap(), data);
+        assert!(decode(&[1]).is_err());
+        assert!(decode(&[1, 0, 5]).is_err());
+        assert!(decode(&[0, 1]).is_err());
+        assert!(decode(&[3, 0]).is_err());
+    }
+}
@@ -0,0 +1,321 @@
+// GPU block transforms.
+//
+// Copyright 2023 Robbert Haarman
+//
+// SPDX-License-Identifier: MIT
+
+//! This module runs the blockwise DCT of whole images, optionally
+//! followed by quantization, in a compute shader through wgpu. For large
+//! images, such as video frames, this frees the CPU for other work.
+//! Transformer selects the GPU at runtime if one is available, and
+//! falls back to dct::transform_image and dct::quantize_image otherwise,
+//! so callers do not need to handle the case where there is no GPU.
+//!
+//! The image is split into blocks and padded on the CPU. The blocks are
+//! uploaded in chunks of at most CHUNK_BLOCKS blocks, each of which is
+//! transformed by one dispatch of the shader, with one workgroup per
+//! block and one invocation per coefficient. The shader multiplies each
+//! coefficient by a factor that includes the division by the
+//! quantization table, and the coefficients are rounded after they have
+//! been read back. GPU results can differ from the CPU results by
+//! rounding errors. Enabled by the "gpu" feature.
+
+use std::sync::mpsc;
+
+use crate::dct::{self, ImageCoefficients, Padding};
+use crate::result::BoxResult;
+
+/// Blocks are N by N pixels.
+const N: usize = 8;
+
+/// Largest number of blocks transformed by one dispatch. This stays
+/// within the limit on the number of workgroups per dimension.
+const CHUNK_BLOCKS: usize = 65535;
+
+/// The compute shader. Each workgroup transforms the block with its
+/// index, computing DCTLUT * block * DCTLUT^T and multiplying the
+/// coefficients by scale.
+const SHADER: &str = r#"
+@group(0) @binding(0) var<storage, read> lut: array<f32, 64>;
+@group(0) @binding(1) var<storage, read> scale: array<f32, 64>;
+@group(0) @binding(2) var<storage, read> blocks: array<f32>;
+@group(0) @binding(3) var<storage, read_write> coeffs: array<f32>;
+
+@compute @workgroup_size(64)
+fn main(@builtin(workgroup_id) block: vec3<u32>,
+        @builtin(local_invocation_index) i: u32) {
+    let base = block.x * 64u;
+    let u = i % 8u;
+    let v = i / 8u;
+    var sum = 0.0;
+    for (var y = 0u; y < 8u; y++) {
+        var row = 0.0;
+        for (var x = 0u; x < 8u; x++) {
+            row += lut[u * 8u + x] * blocks[base + y * 8u + x];
+        }
+        sum += lut[v * 8u + y] * row;
+    }
+    coeffs[base + i] = sum * scale[i];
+}
+"#;
+
+/// Which device a Transformer uses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// A GPU, through wgpu.
+    Gpu,
+    /// The CPU, using the functions in the dct module.
+    Cpu,
+}
+
+/// The wgpu objects needed to run the shader.
+struct Gpu {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    pipeline: wgpu::ComputePipeline,
+    lut: wgpu::Buffer,
+}
+
+/// Returns the bytes of values, for uploading to the GPU.
+fn to_bytes(values: &[f32]) -> Vec<u8> {
+    values.iter().flat_map(|x| x.to_ne_bytes()).collect()
+}
+
+impl Gpu {
+    /// Opens the default GPU and compiles the shader.
+    fn new() -> BoxResult<Gpu> {
+        let instance = wgpu::Instance::default();
+        let adapter = pollster::block_on(
+            instance.request_adapter(&wgpu::RequestAdapterOptions::default()))?;
+        let (device, queue) = pollster::block_on(
+            adapter.request_device(&wgpu::DeviceDescriptor::default()))?;
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("dct"),
+            source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("dct"),
+            layout: None,
+            module: &module,
+            entry_point: Some("main"),
+            compilation_options: Default::default(),
+            cache: None,
+        });
+        let lut = Gpu::upload(&device, &queue, &to_bytes(&dct::DCTLUT));
+        Ok(Gpu { device, queue, pipeline, lut })
+    }
+
+    /// Creates a storage buffer holding data.
+    fn upload(device: &wgpu::Device, queue: &wgpu::Queue, data: &[u8]) -> wgpu::Buffer {
+        let buffer = Gpu::buffer(device, data.len(),
+                                 wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST);
+        queue.write_buffer(&buffer, 0, data);
+        buffer
+    }
+
+    /// Creates a buffer of size bytes.
+    fn buffer(device: &wgpu::Device, size: usize, usage: wgpu::BufferUsages) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: size as u64,
+            usage,
+            mapped_at_creation: false,
+        })
+    }
+
+    /// Returns an error if a validation error was raised since scope was
+    /// pushed, instead of leaving it to the uncaptured error handler.
+    fn check(scope: wgpu::ErrorScopeGuard) -> BoxResult<()> {
+        match pollster::block_on(scope.pop()) {
+            Some(e) => Err(format!("GPU error: {}", e).into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Transforms blocks, multiplying coefficient i of each block by
+    /// scale[i].
+    fn transform(&self, blocks: &[[f32; N * N]], scale: &[f32; N * N])
+                 -> BoxResult<Vec<[f32; N * N]>> {
+        let block_bytes = N * N * std::mem::size_of::<f32>();
+        let chunk_bytes = blocks.len().min(CHUNK_BLOCKS) * block_bytes;
+        let scope = self.device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let scale = Gpu::upload(&self.device, &self.queue, &to_bytes(scale));
+        let input = Gpu::buffer(&self.device, chunk_bytes,
+                                wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST);
+        let output = Gpu::buffer(&self.device, chunk_bytes,
+                                 wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC);
+        let staging = Gpu::buffer(&self.device, chunk_bytes,
+                                  wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST);
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &self.pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: self.lut.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 1, resource: scale.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 2, resource: input.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 3, resource: output.as_entire_binding() },
+            ],
+        });
+        Gpu::check(scope)?;
+
+        let mut res = Vec::with_capacity(blocks.len());
+        for chunk in blocks.chunks(CHUNK_BLOCKS) {
+            let size = chunk.len() * block_bytes;
+            let scope = self.device.push_error_scope(wgpu::ErrorFilter::Validation);
+            self.queue.write_buffer(&input, 0, &to_bytes(chunk.as_flattened()));
+            let mut encoder = self.device.create_command_encoder(&Default::default());
+            {
+                let mut pass = encoder.begin_compute_pass(&Default::default());
+                pass.set_pipeline(&self.pipeline);
+                pass.set_bind_group(0, &bind_group, &[]);
+                pass.dispatch_workgroups(chunk.len() as u32, 1, 1);
+            }
+            encoder.copy_buffer_to_buffer(&output, 0, &staging, 0, size as u64);
+            self.queue.submit([encoder.finish()]);
+            Gpu::check(scope)?;
+
+            let slice = staging.slice(..size as u64);
+            let (sender, receiver) = mpsc::channel();
+            slice.map_async(wgpu::MapMode::Read, move |result| {
+                // The receiver waits for this, so it cannot be gone.
+                sender.send(result).unwrap();
+            });
+            self.device.poll(wgpu::PollType::wait_indefinitely())?;
+            receiver.recv()??;
+            {
+                let data = slice.get_mapped_range()?;
+                res.extend(data.chunks_exact(block_bytes).map(|block| {
+                    let mut coeffs = [0.0; N * N];
+                    for (c, bytes) in coeffs.iter_mut().zip(block.chunks_exact(4)) {
+                        *c = f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                    }
+                    coeffs
+                }));
+            }
+            staging.unmap();
+        }
+        Ok(res)
+    }
+}
+
+/// Transforms whole images on a GPU if one is available, and on the CPU
+/// otherwise.
+pub struct Transformer {
+    gpu: Option<Gpu>,
+}
+
+impl Transformer {
+    /// Returns a Transformer that uses the default GPU, or the CPU if
+    /// there is no usable GPU.
+    pub fn new() -> Transformer {
+        Transformer { gpu: Gpu::new().ok() }
+    }
+
+    /// Returns a Transformer that uses the default GPU, or an error if
+    /// there is no usable GPU.
+    pub fn gpu() -> BoxResult<Transformer> {
+        Ok(Transformer { gpu: Some(Gpu::new()?) })
+    }
+
+    /// Returns a Transformer that uses the CPU.
+    pub fn cpu() -> Transformer {
+        Transformer { gpu: None }
+    }
+
+    /// Returns the device this Transformer uses.
+    pub fn backend(&self) -> Backend {
+        if self.gpu.is_some() { Backend::Gpu } else { Backend::Cpu }
+    }
+
+    /// Transforms blocks on the GPU, multiplying the coefficients by
+    /// scale, or returns None if there is no GPU or it fails.
+    fn transform_gpu(&self, image: &[f32], width: usize, height: usize, stride: usize,
+                     padding: Padding, scale: &[f32; N * N])
+                     -> Option<ImageCoefficients> {
+        let gpu = self.gpu.as_ref()?;
+        let mut blocks = dct::transform_blocks(image, width, height, stride, padding, |b| *b);
+        blocks.blocks = gpu.transform(&blocks.blocks, scale).ok()?;
+        Some(blocks)
+    }
+
+    /// Does the same as dct::transform_image.
+    pub fn transform_image(&self, image: &[f32], width: usize, height: usize, stride: usize,
+                           padding: Padding) -> ImageCoefficients {
+        self.transform_gpu(image, width, height, stride, padding, &[0.015625; N * N])
+            .unwrap_or_else(|| dct::transform_image(image, width, height, stride, padding))
+    }
+
+    /// Does the same as quantizing the result of transform_image with
+    /// dct::quantize_image.
+    pub fn transform_quantize_image(&self, image: &[f32], width: usize, height: usize,
+                                    stride: usize, padding: Padding, qtable: &[u16; N * N])
+                                    -> ImageCoefficients<i32> {
+        let mut scale = [0.0; N * N];
+        for (i, s) in scale.iter_mut().enumerate() {
+            *s = 0.015625 * dct::jpeg_scale(i) / qtable[i] as f32;
+        }
+        match self.transform_gpu(image, width, height, stride, padding, &scale) {
+            Some(coeffs) => ImageCoefficients {
+                width: coeffs.width,
+                height: coeffs.height,
+                blocks_wide: coeffs.blocks_wide,
+                blocks_high: coeffs.blocks_high,
+                blocks: coeffs.blocks.iter().map(|block| block.map(|x| x.round() as i32))
+                    .collect(),
+            },
+            None => dct::quantize_image(
+                &dct::transform_image(image, width, height, stride, padding), qtable),
+        }
+    }
+}
+
+impl Default for Transformer {
+    fn default() -> Transformer {
+        Transformer::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_image(width: usize, height: usize) -> Vec<f32> {
+        (0..width * height).map(|i| {
+            let (x, y) = ((i % width) as f32, (i / width) as f32);
+            (x * 0.3).sin() * 60.0 + (y * 0.2).cos() * 50.0 + (x * y * 0.7).sin() * 10.0
+        }).collect()
+    }
+
+    #[test]
+    fn shader() {
+        let module = wgpu::naga::front::wgsl::parse_str(SHADER).unwrap();
+        wgpu::naga::valid::Validator::new(Default::default(), Default::default())
+            .validate(&module).unwrap();
+    }
+
+    #[test]
+    fn transforms() {
+        let (width, height) = (45, 30);
+        let image = test_image(width, height);
+        let qtable = dct::scale_qtable(&dct::LUMINANCE_QTABLE, 75);
+        let expected = dct::transform_image(&image, width, height, width, Padding::Reflect);
+        let quantized = dct::quantize_image(&expected, &qtable);
+        // This runs on the GPU where there is one, and tests the fallback
+        // where there is not.
+        for transformer in [Transformer::new(), Transformer::cpu()] {
+            let coeffs = transformer.transform_image(&image, width, height, width,
+                                                     Padding::Reflect);
+            assert_eq!((coeffs.blocks_wide, coeffs.blocks_high), (6, 4));
+            for (a, b) in coeffs.blocks.iter().flatten().zip(expected.blocks.iter().flatten()) {
+                assert!((a - b).abs() < 1e-3, "{} differs from {}", a, b);
+            }
+            let q = transformer.transform_quantize_image(&image, width, height, width,
+                                                         Padding::Reflect, &qtable);
+            assert_eq!((q.width, q.height), (width, height));
+            for (a, b) in q.blocks.iter().flatten().zip(quantized.blocks.iter().flatten()) {
+                assert!((a - b).abs() <= 1, "{} differs from {}", a, b);
+            }
+        }
+        assert_eq!(Transformer::cpu().backend(), Backend::Cpu);
+    }
+}
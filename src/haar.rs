@@ -0,0 +1,176 @@
+// Haar wavelet transform.
+//
+// Copyright 2023 Robbert Haarman
+//
+// SPDX-License-Identifier: MIT
+
+//! This module implements the Haar wavelet transform on integers, in
+//! the reversible form known as the S transform: each pair of values a,
+//! b is replaced by floor((a + b) / 2) and b - a. It is the simplest
+//! wavelet transform and much cheaper than the DCT, which makes it
+//! useful for fast compression and as a baseline to compare against.
+//!
+//! Signals and planes are decomposed the same way as in the wavelet
+//! module. Blocks of 8x8 values can also be transformed, and images
+//! split into such blocks, as with the DCT.
+
+use crate::dct::{self, ImageCoefficients, Padding};
+use crate::transform::BlockTransform;
+use crate::wavelet::{self, Lifting};
+
+/// Blocks are N by N values.
+const N: usize = 8;
+
+/// Number of levels of decomposition transform applies to a block.
+const BLOCK_LEVELS: usize = 3;
+
+/// The lifting steps of the S transform. If there is an odd number of
+/// values, the last one is passed through as a low-pass coefficient.
+/// As a BlockTransform, it uses transform and reverse.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Haar;
+
+impl Lifting for Haar {
+    type T = i32;
+
+    fn forward(s: &mut [i32], d: &mut [i32]) {
+        for (a, b) in s.iter_mut().zip(d.iter_mut()) {
+            *b -= *a;
+            *a += *b >> 1;
+        }
+    }
+
+    fn inverse(s: &mut [i32], d: &mut [i32]) {
+        for (a, b) in s.iter_mut().zip(d.iter_mut()) {
+            *a -= *b >> 1;
+            *b += *a;
+        }
+    }
+}
+
+/// Applies levels levels of the Haar transform to a signal, in place.
+/// Each level leaves the low-pass coefficients in the first half of the
+/// part it transforms, which the next level transforms further.
+pub fn forward_1d(data: &mut [i32], levels: usize) {
+    wavelet::forward::<Haar>(data, data.len(), 1, levels)
+}
+
+/// Reverses forward_1d exactly.
+pub fn inverse_1d(data: &mut [i32], levels: usize) {
+    wavelet::inverse::<Haar>(data, data.len(), 1, levels)
+}
+
+/// Applies levels levels of the Haar transform to a width x height
+/// plane in row-major order, in place.
+pub fn forward_2d(data: &mut [i32], width: usize, height: usize, levels: usize) {
+    wavelet::forward::<Haar>(data, width, height, levels)
+}
+
+/// Reverses forward_2d exactly.
+pub fn inverse_2d(data: &mut [i32], width: usize, height: usize, levels: usize) {
+    wavelet::inverse::<Haar>(data, width, height, levels)
+}
+
+/// Transforms an 8x8 block fully, leaving the mean of the block (rounded
+/// down) in the first coefficient.
+pub fn transform(block: &[i32; N * N]) -> [i32; N * N] {
+    let mut res = *block;
+    forward_2d(&mut res, N, N, BLOCK_LEVELS);
+    res
+}
+
+/// Reverses transform exactly.
+pub fn reverse(transformed: &[i32; N * N]) -> [i32; N * N] {
+    let mut res = *transformed;
+    inverse_2d(&mut res, N, N, BLOCK_LEVELS);
+    res
+}
+
+impl BlockTransform for Haar {
+    type Sample = i32;
+    type Coeff = i32;
+
+    fn forward(&self, block: &[i32; N * N]) -> [i32; N * N] {
+        transform(block)
+    }
+
+    fn inverse(&self, coeffs: &[i32; N * N]) -> [i32; N * N] {
+        reverse(coeffs)
+    }
+}
+
+/// Splits a row-major image into 8x8 blocks and transforms each of
+/// them, like dct::transform_image.
+pub fn transform_image(image: &[i32], width: usize, height: usize, stride: usize,
+                       padding: Padding) -> ImageCoefficients<i32> {
+    dct::transform_blocks(image, width, height, stride, padding, transform)
+}
+
+/// Reverses transform_image exactly, returning the image with a stride
+/// equal to its width.
+pub fn reverse_image(coeffs: &ImageCoefficients<i32>) -> Vec<i32> {
+    dct::reverse_blocks(coeffs, reverse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::lcg;
+
+    #[test]
+    fn haar_1d() {
+        let mut data = [5, 9, -3, -4, 7];
+        forward_1d(&mut data, 1);
+        assert_eq!(data, [7, -4, 7, 4, -1]);
+        inverse_1d(&mut data, 1);
+        assert_eq!(data, [5, 9, -3, -4, 7]);
+
+        let original: Vec<i32> = (0..37).map(|i| (i * i * 31 % 255) - 128).collect();
+        let mut data = original.clone();
+        forward_1d(&mut data, 5);
+        inverse_1d(&mut data, 5);
+        assert_eq!(data, original);
+    }
+
+    #[test]
+    fn blocks() {
+        let flat = transform(&[-17; N * N]);
+        assert_eq!(flat[0], -17);
+        assert!(flat[1..].iter().all(|&x| x == 0));
+
+        let mut random = lcg(7);
+        let block = [0; N * N].map(|_| (random.next().unwrap() >> 16) as i32 - 32768);
+        assert_eq!(reverse(&transform(&block)), block);
+    }
+
+    #[test]
+    fn images() {
+        let (width, height, stride) = (19, 10, 24);
+        let image: Vec<i32> = (0..stride * height).map(|i| (i % stride * 9 + i / stride) as i32)
+            .collect();
+        for &padding in &[Padding::Replicate, Padding::Reflect] {
+            let coeffs = transform_image(&image, width, height, stride, padding);
+            assert_eq!((coeffs.blocks_wide, coeffs.blocks_high), (3, 2));
+            let reversed = reverse_image(&coeffs);
+            for y in 0..height {
+                assert_eq!(reversed[y * width..(y + 1) * width],
+                           image[y * stride..y * stride + width]);
+            }
+        }
+
+        // The high-pass coefficients of a smooth plane are small. After
+        // two levels, the low-pass coefficients are in the top left 5x3
+        // values.
+        let original: Vec<i32> = (0..width * height).map(|i| (i % width * 9 + i / width) as i32)
+            .collect();
+        let mut plane = original.clone();
+        forward_2d(&mut plane, width, height, 2);
+        for (i, &x) in plane.iter().enumerate() {
+            if i % width >= 5 || i / width >= 3 {
+                assert!(x.abs() <= 18, "coefficient {} is {}", i, x);
+            }
+        }
+        inverse_2d(&mut plane, width, height, 2);
+        assert_eq!(plane, original);
+    }
+}
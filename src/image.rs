@@ -0,0 +1,878 @@
+// Lossy image compression using the DCT.
+//
+// Copyright 2023 Robbert Haarman
+//
+// SPDX-License-Identifier: MIT
+//
+// This module combines the other parts of the crate into a simple lossy
+// image codec, along the lines of baseline JPEG. Images are converted to
+// YCbCr (color) and their chroma planes optionally subsampled. Every
+// plane is split into 8x8 blocks, which are transformed with the DCT,
+// quantized with the JPEG tables scaled for the quality, and put in
+// zigzag order. The quantized coefficients are coded with the binary
+// range coder from brc, using adaptive probabilities.
+//
+// A compressed image consists of a header, followed by the range coded
+// data of all planes. The header consists of:
+//
+//   4 bytes   magic: "CTIM"
+//   1 byte    version of the format: 1, or 2 if there is a quality map
+//   1 byte    number of channels: 1 (grayscale) or 3 (YCbCr)
+//   1 byte    subsampling of the chroma planes: 0 (none), 1 (half the
+//             horizontal resolution) or 2 (half the horizontal and half
+//             the vertical resolution)
+//   1 byte    quality, from 1 to 100
+//   4 bytes   width, least significant byte first
+//   4 bytes   height, least significant byte first
+//
+// In version 2, the header is followed by a quality map: a quality from
+// 1 to 100 for each 8x8 block of the image, in row-major order. Blocks
+// of the chroma planes use the quality of the block of the image that
+// holds their top left pixel. In version 1, all blocks use the quality
+// in the header.
+//
+// The quantization tables are not stored, but derived from the quality.
+// Each block is coded as the difference between its DC coefficient and
+// that of the previous block in the same plane, followed by the nonzero
+// AC coefficients in zigzag order. Each nonzero coefficient is preceded
+// by a bit indicating that the block does not end yet and the number of
+// zeros before it. If the last coefficient is zero, the block ends with
+// a bit indicating the end of the block.
+//
+// Numbers are coded as the number of bits after the most significant
+// bit of the number plus 1, in unary, followed by those bits, most
+// significant first. Signs are coded as a single bit. The probabilities
+// of all bits except signs adapt to the data, with separate models for
+// the luma and chroma planes.
+
+use crate::brc;
+use crate::color::{self, Matrix, Subsampling};
+use crate::dct::{self, Dct, ImageCoefficients, Padding};
+use crate::result::BoxResult;
+use crate::transform;
+
+const MAGIC: &[u8; 4] = b"CTIM";
+
+const VERSION: u8 = 1;
+
+/// Version of the format for images with a quality map.
+const VERSION_MAP: u8 = 2;
+
+const HEADER_SIZE: usize = 16;
+
+/// Width and height of a block.
+const BLOCK: usize = 8;
+
+/// Number of coefficients in a block.
+const COEFFS: usize = BLOCK * BLOCK;
+
+/// Largest number of pixels decode will allocate an image for. Runs of
+/// empty blocks take almost no space in a compressed image, so a small
+/// input can claim an image this large.
+const MAX_PIXELS: usize = 1 << 24;
+
+/// Largest number of bits after the most significant bit in a coded
+/// number.
+const MAX_NUMBER_BITS: usize = 24;
+
+/// Options for encode_gray and encode_rgb.
+///
+/// # Example
+///
+/// ```
+/// use compression_toolkit::color::Subsampling;
+/// use compression_toolkit::image::EncodeOptions;
+///
+/// let options = EncodeOptions::new()
+///     .quality(90)
+///     .subsampling(Subsampling::Half);
+/// ```
+#[derive(Clone)]
+pub struct EncodeOptions {
+    quality: u32,
+    subsampling: Subsampling,
+    quality_map: Option<Vec<u8>>,
+}
+
+impl EncodeOptions {
+    /// Returns the default options: quality 75 and chroma planes at half
+    /// the horizontal and half the vertical resolution.
+    pub fn new() -> EncodeOptions {
+        EncodeOptions { quality: 75, subsampling: Subsampling::Quarter, quality_map: None }
+    }
+
+    /// Sets the quality, from 1 (smallest output) to 100 (best
+    /// quality). Values outside that range are clamped.
+    pub fn quality(mut self, quality: u32) -> EncodeOptions {
+        self.quality = quality.clamp(1, 100);
+        self
+    }
+
+    /// Sets how much encode_rgb subsamples the chroma planes.
+    pub fn subsampling(mut self, subsampling: Subsampling) -> EncodeOptions {
+        self.subsampling = subsampling;
+        self
+    }
+
+    /// Sets a quality for each 8x8 block of the image, in row-major
+    /// order of blocks, to be used instead of the overall quality, for
+    /// example to keep faces or text sharper than the background. Blocks
+    /// of subsampled chroma planes use the quality of the block that
+    /// holds their top left pixel. The map is stored with the image.
+    /// Values outside the range from 1 to 100 are clamped.
+    pub fn quality_map(mut self, map: Vec<u8>) -> EncodeOptions {
+        self.quality_map = Some(map.into_iter().map(|q| q.clamp(1, 100)).collect());
+        self
+    }
+}
+
+impl Default for EncodeOptions {
+    fn default() -> EncodeOptions {
+        EncodeOptions::new()
+    }
+}
+
+/// An image returned by decode.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    /// Number of values per pixel: 1 for grayscale and 3 for RGB.
+    pub channels: usize,
+    /// The pixels, in row-major order, with the channels of each pixel
+    /// interleaved.
+    pub pixels: Vec<u8>,
+}
+
+/// Probability that a bit is 0, in units of 2**-16, which adapts to the
+/// bits coded with it.
+#[derive(Clone, Copy)]
+struct Bit(u16);
+
+impl Bit {
+    fn new() -> Bit {
+        Bit(0x8000)
+    }
+
+    /// Returns the probability in the form brc uses.
+    fn p0(self) -> u8 {
+        std::cmp::max(1, (self.0 >> 8) as u8)
+    }
+
+    fn update(&mut self, bit: bool) {
+        if bit {
+            self.0 -= self.0 >> 5;
+        } else {
+            self.0 += (0xffff - self.0) >> 5;
+        }
+    }
+}
+
+/// Adaptive model for non-negative numbers.
+#[derive(Clone, Copy)]
+struct NumberModel {
+    /// One model for each bit of the unary number of bits.
+    nbits: [Bit; MAX_NUMBER_BITS + 1],
+    /// One model for each position of the bits after that.
+    bits: [Bit; MAX_NUMBER_BITS],
+}
+
+impl NumberModel {
+    fn new() -> NumberModel {
+        NumberModel { nbits: [Bit::new(); MAX_NUMBER_BITS + 1], bits: [Bit::new(); MAX_NUMBER_BITS] }
+    }
+}
+
+/// Adaptive models for the coefficients of one kind of plane.
+#[derive(Clone)]
+struct Models {
+    dc: NumberModel,
+    dc_sign: Bit,
+    /// Models for the end of block bit, by zigzag position.
+    end: [Bit; COEFFS],
+    run: NumberModel,
+    level: NumberModel,
+}
+
+impl Models {
+    fn new() -> Models {
+        Models {
+            dc: NumberModel::new(),
+            dc_sign: Bit::new(),
+            end: [Bit::new(); COEFFS],
+            run: NumberModel::new(),
+            level: NumberModel::new(),
+        }
+    }
+}
+
+/// Destination for the bits of the coded blocks.
+trait BitWriter {
+    /// Writes a bit with the probability given by model, and updates the
+    /// model.
+    fn encode(&mut self, bit: bool, model: &mut Bit);
+
+    /// Encodes n, which must be less than 2**MAX_NUMBER_BITS.
+    fn encode_number(&mut self, n: u32, model: &mut NumberModel) {
+        let n = n + 1;
+        let nbits = (31 - n.leading_zeros()) as usize;
+        for i in 0..nbits {
+            self.encode(true, &mut model.nbits[i]);
+        }
+        self.encode(false, &mut model.nbits[nbits]);
+        for i in (0..nbits).rev() {
+            self.encode(n >> i & 1 != 0, &mut model.bits[i]);
+        }
+    }
+
+    fn encode_sign(&mut self, negative: bool) {
+        self.encode(negative, &mut Bit::new());
+    }
+}
+
+/// Writes bits to a Vec<u8> using brc::Encoder.
+struct RangeEncoder {
+    encoder: brc::Encoder,
+    output: Vec<u8>,
+}
+
+impl RangeEncoder {
+    fn new(output: Vec<u8>) -> RangeEncoder {
+        RangeEncoder { encoder: brc::Encoder::new(), output }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.output.push(self.encoder.flush());
+        self.output
+    }
+}
+
+impl BitWriter for RangeEncoder {
+    fn encode(&mut self, bit: bool, model: &mut Bit) {
+        self.encoder.encode_bit(model.p0(), bit);
+        model.update(bit);
+        while self.encoder.needs_normalize() {
+            self.output.push(self.encoder.normalize());
+        }
+    }
+}
+
+/// Adds up the number of bits RangeEncoder would spend on each bit,
+/// without producing any output.
+struct BitCounter {
+    bits: f64,
+}
+
+impl BitWriter for BitCounter {
+    fn encode(&mut self, bit: bool, model: &mut Bit) {
+        let p0 = model.p0() as f64 / 256.0;
+        self.bits -= if bit { 1.0 - p0 } else { p0 }.log2();
+        model.update(bit);
+    }
+}
+
+/// Reads bits written by a RangeEncoder from a slice.
+struct RangeDecoder<'a> {
+    decoder: brc::Decoder,
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl RangeDecoder<'_> {
+    fn new(input: &[u8]) -> RangeDecoder<'_> {
+        RangeDecoder { decoder: brc::Decoder::new(), input, pos: 0 }
+    }
+
+    fn decode(&mut self, model: &mut Bit) -> bool {
+        while self.decoder.needs_normalize() {
+            // The encoder leaves off the bytes the decoder needs to read
+            // ahead, which are 0.
+            self.decoder.normalize(self.input.get(self.pos).copied().unwrap_or(0));
+            self.pos += 1;
+        }
+        let bit = self.decoder.decode_bit(model.p0());
+        model.update(bit);
+        bit
+    }
+
+    fn decode_number(&mut self, model: &mut NumberModel) -> BoxResult<u32> {
+        let mut nbits = 0;
+        while self.decode(&mut model.nbits[nbits]) {
+            nbits += 1;
+            if nbits > MAX_NUMBER_BITS {
+                return Err("number too large in compressed image".into());
+            }
+        }
+        let mut n = 1u32;
+        for i in (0..nbits).rev() {
+            n = n << 1 | self.decode(&mut model.bits[i]) as u32;
+        }
+        Ok(n - 1)
+    }
+
+    fn decode_sign(&mut self) -> bool {
+        self.decode(&mut Bit::new())
+    }
+
+    /// Returns an error if the decoder has read past the end of the
+    /// input by more than the bytes it reads ahead.
+    fn check_end(&self) -> BoxResult<()> {
+        if self.pos > self.input.len() + 3 {
+            return Err("compressed image is truncated".into());
+        }
+        Ok(())
+    }
+}
+
+/// Encodes a block of quantized coefficients in zigzag order.
+fn encode_block<W: BitWriter>(encoder: &mut W, models: &mut Models, block: &[i32; COEFFS],
+                              dc: &mut i32) {
+    let diff = block[0] - *dc;
+    *dc = block[0];
+    encoder.encode_number(diff.unsigned_abs(), &mut models.dc);
+    if diff != 0 {
+        encoder.encode(diff < 0, &mut models.dc_sign);
+    }
+    let mut next = 1;
+    for (i, &level) in block.iter().enumerate().skip(1) {
+        if level == 0 {
+            continue;
+        }
+        encoder.encode(false, &mut models.end[next]);
+        encoder.encode_number((i - next) as u32, &mut models.run);
+        encoder.encode_number(level.unsigned_abs() - 1, &mut models.level);
+        encoder.encode_sign(level < 0);
+        next = i + 1;
+    }
+    if next < COEFFS {
+        encoder.encode(true, &mut models.end[next]);
+    }
+}
+
+/// Decodes a block written by encode_block.
+fn decode_block(decoder: &mut RangeDecoder, models: &mut Models, dc: &mut i32)
+                -> BoxResult<[i32; COEFFS]> {
+    let mut block = [0i32; COEFFS];
+    let diff = decoder.decode_number(&mut models.dc)? as i32;
+    let negative = diff != 0 && decoder.decode(&mut models.dc_sign);
+    *dc = dc.checked_add(if negative { -diff } else { diff })
+        .ok_or("DC overflow in compressed image")?;
+    block[0] = *dc;
+    let mut next = 1;
+    while next < COEFFS && !decoder.decode(&mut models.end[next]) {
+        let i = next + decoder.decode_number(&mut models.run)? as usize;
+        if i >= COEFFS {
+            return Err("run past the end of a block in compressed image".into());
+        }
+        let level = decoder.decode_number(&mut models.level)? as i32 + 1;
+        block[i] = if decoder.decode_sign() { -level } else { level };
+        next = i + 1;
+    }
+    Ok(block)
+}
+
+/// The state of the adaptive models for the blocks of one plane, for
+/// estimating how many bits blocks will take without coding them. Blocks
+/// are quantized coefficients in zigzag order, as the codec stores them.
+#[derive(Clone)]
+pub struct TokenModel {
+    models: Models,
+    dc: i32,
+}
+
+impl TokenModel {
+    /// Creates the model the codec starts each image with.
+    pub fn new() -> TokenModel {
+        TokenModel { models: Models::new(), dc: 0 }
+    }
+
+    /// Returns the number of bits coding block after the blocks the
+    /// model has been updated with would take, not counting the final
+    /// byte of the range coder.
+    pub fn estimate_bits(&self, block: &[i32; COEFFS]) -> f64 {
+        let mut counter = BitCounter { bits: 0.0 };
+        encode_block(&mut counter, &mut self.models.clone(), block, &mut self.dc.clone());
+        counter.bits
+    }
+
+    /// Adapts the model to block, as coding it would.
+    pub fn update(&mut self, block: &[i32; COEFFS]) {
+        encode_block(&mut BitCounter { bits: 0.0 }, &mut self.models, block, &mut self.dc);
+    }
+}
+
+impl Default for TokenModel {
+    fn default() -> TokenModel {
+        TokenModel::new()
+    }
+}
+
+/// Returns the quantization table for each quality from 0 to 100, scaled
+/// from base. Quality 0 is not used.
+fn qtables(base: &[u16; COEFFS]) -> Vec<[u16; COEFFS]> {
+    (0..=100).map(|quality| dct::scale_qtable(base, quality)).collect()
+}
+
+/// Returns the quality of each block of a plane with blocks_wide x
+/// blocks_high blocks, taken from the quality map of the full resolution
+/// image, which has map_wide blocks in each row. Each block of the plane
+/// gets the quality of the block of the image that holds its top left
+/// pixel; the plane is subsampled by factors.0 horizontally and factors.1
+/// vertically.
+fn plane_qualities(map: &[u8], map_wide: usize, blocks_wide: usize, blocks_high: usize,
+                   factors: (usize, usize)) -> Vec<u8> {
+    let map_high = map.len() / map_wide;
+    (0..blocks_wide * blocks_high).map(|i| {
+        let x = (i % blocks_wide * factors.0).min(map_wide - 1);
+        let y = (i / blocks_wide * factors.1).min(map_high - 1);
+        map[y * map_wide + x]
+    }).collect()
+}
+
+/// Transforms, quantizes and encodes a width x height plane, quantizing
+/// each block with the table for its quality.
+fn encode_plane(encoder: &mut RangeEncoder, models: &mut Models, plane: &[f32],
+                width: usize, height: usize, qtables: &[[u16; COEFFS]], qualities: &[u8]) {
+    let shifted: Vec<f32> = plane.iter().map(|&x| x - 128.0).collect();
+    let coeffs = transform::transform_image(&Dct, &shifted, width, height, width,
+                                            Padding::Replicate);
+    let mut dc = 0;
+    for (block, &quality) in coeffs.blocks.iter().zip(qualities.iter()) {
+        let quantized = dct::quantize(block, &qtables[quality as usize]);
+        encode_block(encoder, models, &dct::zigzag(&quantized), &mut dc);
+    }
+}
+
+/// Decodes a plane written by encode_plane.
+fn decode_plane(decoder: &mut RangeDecoder, models: &mut Models, width: usize, height: usize,
+                qtables: &[[u16; COEFFS]], qualities: &[u8]) -> BoxResult<Vec<f32>> {
+    let blocks_wide = width.div_ceil(BLOCK);
+    let blocks_high = height.div_ceil(BLOCK);
+    // Grow blocks as they are decoded, so that a header that claims a
+    // large image does not allocate more than the data can fill.
+    let mut blocks = Vec::new();
+    let mut dc = 0;
+    for &quality in &qualities[..blocks_wide * blocks_high] {
+        let block = decode_block(decoder, models, &mut dc)?;
+        blocks.push(dct::dequantize(&dct::unzigzag(&block), &qtables[quality as usize]));
+        decoder.check_end()?;
+    }
+    let coeffs = ImageCoefficients { width, height, blocks_wide, blocks_high, blocks };
+    Ok(transform::reverse_image(&Dct, &coeffs).into_iter().map(|x| x + 128.0).collect())
+}
+
+/// Returns the horizontal and vertical factors by which subsampling
+/// reduces a width x height plane.
+fn subsampling_factors(subsampling: Subsampling, width: usize, height: usize)
+                       -> (usize, usize) {
+    let (cw, ch) = subsampling.size(width, height);
+    (if cw < width { 2 } else { 1 }, if ch < height { 2 } else { 1 })
+}
+
+fn subsampling_number(subsampling: Subsampling) -> u8 {
+    match subsampling {
+        Subsampling::None => 0,
+        Subsampling::Half => 1,
+        Subsampling::Quarter => 2,
+    }
+}
+
+/// Returns the header for an image, followed by the quality map if
+/// options has one, and the quality of each block of the image.
+fn header(channels: u8, options: &EncodeOptions, width: usize, height: usize)
+          -> BoxResult<(Vec<u8>, Vec<u8>)> {
+    if width == 0 || height == 0 {
+        return Err("image is empty".into());
+    }
+    // decode rejects images of more than MAX_PIXELS pixels.
+    if width > u32::MAX as usize || height > u32::MAX as usize
+        || width.saturating_mul(height) > MAX_PIXELS {
+        return Err("image is too large".into());
+    }
+    let subsampling = if channels == 1 { Subsampling::None } else { options.subsampling };
+    let nblocks = width.div_ceil(BLOCK) * height.div_ceil(BLOCK);
+    let version = if options.quality_map.is_some() { VERSION_MAP } else { VERSION };
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&[version, channels, subsampling_number(subsampling),
+                               options.quality as u8]);
+    header.extend_from_slice(&(width as u32).to_le_bytes());
+    header.extend_from_slice(&(height as u32).to_le_bytes());
+    let qualities = match &options.quality_map {
+        Some(map) => {
+            if map.len() != nblocks {
+                return Err("quality map does not match number of blocks".into());
+            }
+            header.extend_from_slice(map);
+            map.clone()
+        },
+        None => vec![options.quality as u8; nblocks],
+    };
+    Ok((header, qualities))
+}
+
+/// Compresses a grayscale image of width x height pixels, given in
+/// row-major order.
+pub fn encode_gray(pixels: &[u8], width: usize, height: usize, options: &EncodeOptions)
+                   -> BoxResult<Vec<u8>> {
+    let (output, qualities) = header(1, options, width, height)?;
+    if pixels.len() != width * height {
+        return Err("number of pixels does not match size".into());
+    }
+    let plane: Vec<f32> = pixels.iter().map(|&x| x as f32).collect();
+    let qtables = qtables(&dct::LUMINANCE_QTABLE);
+    let mut encoder = RangeEncoder::new(output);
+    encode_plane(&mut encoder, &mut Models::new(), &plane, width, height, &qtables, &qualities);
+    Ok(encoder.finish())
+}
+
+/// Compresses an RGB image of width x height pixels, given in
+/// row-major order with the red, green and blue values of each pixel
+/// interleaved.
+pub fn encode_rgb(pixels: &[u8], width: usize, height: usize, options: &EncodeOptions)
+                  -> BoxResult<Vec<u8>> {
+    let (output, qualities) = header(3, options, width, height)?;
+    if pixels.len() != width * height * 3 {
+        return Err("number of pixels does not match size".into());
+    }
+    let [y, cb, cr] = color::rgb_to_planes(pixels, Matrix::Bt601);
+    let (cw, ch) = options.subsampling.size(width, height);
+    let chroma_qualities = plane_qualities(
+        &qualities, width.div_ceil(BLOCK), cw.div_ceil(BLOCK), ch.div_ceil(BLOCK),
+        subsampling_factors(options.subsampling, width, height));
+    let luma_qtables = qtables(&dct::LUMINANCE_QTABLE);
+    let chroma_qtables = qtables(&dct::CHROMINANCE_QTABLE);
+    let mut encoder = RangeEncoder::new(output);
+    encode_plane(&mut encoder, &mut Models::new(), &y, width, height, &luma_qtables, &qualities);
+    let mut chroma_models = Models::new();
+    for plane in [cb, cr].iter() {
+        let samples = color::subsample(plane, width, height, options.subsampling);
+        encode_plane(&mut encoder, &mut chroma_models, &samples, cw, ch, &chroma_qtables,
+                     &chroma_qualities);
+    }
+    Ok(encoder.finish())
+}
+
+/// Rounds and clamps the values in a plane to pixel values.
+fn to_pixels(plane: &[f32]) -> Vec<u8> {
+    plane.iter().map(|&x| x.round().clamp(0.0, 255.0) as u8).collect()
+}
+
+/// Decompresses an image produced by encode_gray or encode_rgb.
+pub fn decode(data: &[u8]) -> BoxResult<Image> {
+    if data.len() < HEADER_SIZE {
+        return Err("compressed image is truncated".into());
+    }
+    if &data[..4] != MAGIC {
+        return Err("not a compressed image".into());
+    }
+    if data[4] != VERSION && data[4] != VERSION_MAP {
+        return Err(format!("unsupported image format version {}", data[4]).into());
+    }
+    let channels = data[5] as usize;
+    let subsampling = match data[6] {
+        0 => Subsampling::None,
+        1 => Subsampling::Half,
+        2 => Subsampling::Quarter,
+        n => return Err(format!("invalid subsampling {}", n).into()),
+    };
+    let quality = data[7] as u32;
+    let width = u32::from_le_bytes([data[8], data[9], data[10], data[11]]) as usize;
+    let height = u32::from_le_bytes([data[12], data[13], data[14], data[15]]) as usize;
+    if width == 0 || height == 0 || width.saturating_mul(height) > MAX_PIXELS {
+        return Err(format!("invalid image size {}x{}", width, height).into());
+    }
+
+    let nblocks = width.div_ceil(BLOCK) * height.div_ceil(BLOCK);
+    let (qualities, start) = if data[4] == VERSION_MAP {
+        let map = data.get(HEADER_SIZE..HEADER_SIZE + nblocks)
+            .ok_or("compressed image is truncated")?;
+        if map.iter().any(|&q| !(1..=100).contains(&q)) {
+            return Err("invalid quality in quality map".into());
+        }
+        (map.to_vec(), HEADER_SIZE + nblocks)
+    } else {
+        (vec![quality.clamp(1, 100) as u8; nblocks], HEADER_SIZE)
+    };
+
+    let mut decoder = RangeDecoder::new(&data[start..]);
+    let luma_qtables = qtables(&dct::LUMINANCE_QTABLE);
+    let y = decode_plane(&mut decoder, &mut Models::new(), width, height, &luma_qtables,
+                         &qualities)?;
+    let pixels = match channels {
+        1 => to_pixels(&y),
+        3 => {
+            let (cw, ch) = subsampling.size(width, height);
+            let chroma_qualities = plane_qualities(
+                &qualities, width.div_ceil(BLOCK), cw.div_ceil(BLOCK), ch.div_ceil(BLOCK),
+                subsampling_factors(subsampling, width, height));
+            let chroma_qtables = qtables(&dct::CHROMINANCE_QTABLE);
+            let mut chroma_models = Models::new();
+            let cb = decode_plane(&mut decoder, &mut chroma_models, cw, ch, &chroma_qtables,
+                                  &chroma_qualities)?;
+            let cr = decode_plane(&mut decoder, &mut chroma_models, cw, ch, &chroma_qtables,
+                                  &chroma_qualities)?;
+            color::planes_to_rgb(&y,
+                                 &color::upsample(&cb, width, height, subsampling),
+                                 &color::upsample(&cr, width, height, subsampling),
+                                 Matrix::Bt601)
+        },
+        n => return Err(format!("invalid number of channels {}", n).into()),
+    };
+    Ok(Image { width, height, channels, pixels })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::lcg;
+
+    /// Returns a test image: smooth gradients with some texture.
+    fn test_image(width: usize, height: usize, channels: usize) -> Vec<u8> {
+        let mut pixels = Vec::with_capacity(width * height * channels);
+        for y in 0..height {
+            for x in 0..width {
+                for c in 0..channels {
+                    let v = x * 160 / width + y * 60 / height + c * 10;
+                    pixels.push((v + (x * 7 + y * 13) % 5) as u8);
+                }
+            }
+        }
+        pixels
+    }
+
+    /// Returns the largest difference between two images.
+    fn max_error(a: &[u8], b: &[u8]) -> u8 {
+        assert_eq!(a.len(), b.len());
+        a.iter().zip(b.iter()).map(|(&x, &y)| x.abs_diff(y)).max().unwrap()
+    }
+
+    /// Returns the mean absolute difference between two images.
+    fn mean_error(a: &[u8], b: &[u8]) -> f64 {
+        let sum: u64 = a.iter().zip(b.iter()).map(|(&x, &y)| x.abs_diff(y) as u64).sum();
+        sum as f64 / a.len() as f64
+    }
+
+    #[test]
+    fn numbers() {
+        let values = [0, 1, 2, 3, 7, 8, 100, 1000, 65535, (1 << MAX_NUMBER_BITS) - 2];
+        let mut model = NumberModel::new();
+        let mut encoder = RangeEncoder::new(Vec::new());
+        for &n in values.iter().chain(values.iter()) {
+            encoder.encode_number(n, &mut model);
+            encoder.encode_sign(n % 2 == 0);
+        }
+        let output = encoder.finish();
+        let mut model = NumberModel::new();
+        let mut decoder = RangeDecoder::new(&output);
+        for &n in values.iter().chain(values.iter()) {
+            assert_eq!(decoder.decode_number(&mut model).unwrap(), n);
+            assert_eq!(decoder.decode_sign(), n % 2 == 0);
+        }
+        decoder.check_end().unwrap();
+    }
+
+    #[test]
+    fn blocks() {
+        let mut blocks = vec![[0i32; COEFFS]; 4];
+        blocks[0][0] = 500;
+        blocks[1][0] = -20;
+        blocks[1][5] = 3;
+        blocks[1][63] = -1;
+        blocks[2][1] = -7;
+        blocks[3] = [1; COEFFS];
+        let mut encoder = RangeEncoder::new(Vec::new());
+        let mut models = Models::new();
+        let mut dc = 0;
+        for block in &blocks {
+            encode_block(&mut encoder, &mut models, block, &mut dc);
+        }
+        let output = encoder.finish();
+        let mut decoder = RangeDecoder::new(&output);
+        let mut models = Models::new();
+        let mut dc = 0;
+        for block in &blocks {
+            assert_eq!(&decode_block(&mut decoder, &mut models, &mut dc).unwrap(), block);
+        }
+    }
+
+    #[test]
+    fn rate_estimate() {
+        let mut blocks = vec![[0i32; COEFFS]; 200];
+        let mut random = lcg(5);
+        for (n, block) in blocks.iter_mut().enumerate() {
+            block[0] = (n % 13) as i32 * 4 - 20;
+            for x in block[1..1 + n % 20].iter_mut() {
+                *x = (random.next().unwrap() >> 28) as i32 - 8;
+            }
+        }
+        let mut encoder = RangeEncoder::new(Vec::new());
+        let mut models = Models::new();
+        let mut dc = 0;
+        let mut model = TokenModel::new();
+        let mut estimate = 0.0;
+        for block in &blocks {
+            let bits = model.estimate_bits(block);
+            assert_eq!(model.estimate_bits(block), bits);
+            estimate += bits;
+            model.update(block);
+            encode_block(&mut encoder, &mut models, block, &mut dc);
+        }
+        let actual = (encoder.finish().len() * 8) as f64;
+        assert!((estimate - actual).abs() < 0.01 * actual, "{} vs {}", estimate, actual);
+
+        // Coarser quantization gives fewer bits.
+        let model = TokenModel::default();
+        let halved = blocks[19].map(|x| x / 2);
+        assert!(model.estimate_bits(&halved) < model.estimate_bits(&blocks[19]));
+        // An empty block is a zero DC difference and an end of block,
+        // each one bit with fresh models.
+        assert_eq!(model.estimate_bits(&[0; COEFFS]), 2.0);
+    }
+
+    #[test]
+    fn gray_roundtrip() {
+        let (width, height) = (37, 23);
+        let pixels = test_image(width, height, 1);
+        let low = encode_gray(&pixels, width, height, &EncodeOptions::new().quality(30)).unwrap();
+        let high = encode_gray(&pixels, width, height, &EncodeOptions::new().quality(95)).unwrap();
+        assert!(low.len() < high.len());
+        assert!(high.len() < pixels.len());
+
+        let image = decode(&high).unwrap();
+        assert_eq!((image.width, image.height, image.channels), (width, height, 1));
+        assert!(max_error(&image.pixels, &pixels) <= 16);
+        assert!(mean_error(&image.pixels, &pixels) < 2.0);
+        let image = decode(&low).unwrap();
+        assert!(mean_error(&image.pixels, &pixels) < 6.0);
+
+        // A flat image is reproduced exactly.
+        let flat = vec![77; 64 * 64];
+        let compressed = encode_gray(&flat, 64, 64, &EncodeOptions::new()).unwrap();
+        assert!(compressed.len() < 40);
+        assert_eq!(decode(&compressed).unwrap().pixels, flat);
+    }
+
+    #[test]
+    fn rgb_roundtrip() {
+        let (width, height) = (29, 17);
+        let pixels = test_image(width, height, 3);
+        let subsamplings = [Subsampling::None, Subsampling::Half, Subsampling::Quarter];
+        let mut sizes = Vec::new();
+        for &subsampling in subsamplings.iter() {
+            let options = EncodeOptions::new().quality(90).subsampling(subsampling);
+            let compressed = encode_rgb(&pixels, width, height, &options).unwrap();
+            sizes.push(compressed.len());
+            let image = decode(&compressed).unwrap();
+            assert_eq!((image.width, image.height, image.channels), (width, height, 3));
+            assert!(mean_error(&image.pixels, &pixels) < 4.0,
+                    "mean error {} too large", mean_error(&image.pixels, &pixels));
+        }
+        assert!(sizes[2] < sizes[0]);
+        assert!(sizes[0] < pixels.len());
+    }
+
+    #[test]
+    fn quality_map() {
+        let (width, height) = (40, 24);
+        // High quality in the left two columns of blocks, low quality in
+        // the others.
+        let map: Vec<u8> = (0..15).map(|i| if i % 5 < 2 { 95 } else { 10 }).collect();
+        let options = EncodeOptions::new().quality_map(map.clone());
+        // Returns the mean errors left and right of x = 16.
+        let split_error = |a: &[u8], b: &[u8], channels: usize| -> (f64, f64) {
+            let split = 16 * channels;
+            let rows = || a.chunks(width * channels).zip(b.chunks(width * channels));
+            (rows().map(|(a, b)| mean_error(&a[..split], &b[..split])).sum(),
+             rows().map(|(a, b)| mean_error(&a[split..], &b[split..])).sum())
+        };
+
+        let gray = test_image(width, height, 1);
+        let compressed = encode_gray(&gray, width, height, &options).unwrap();
+        assert_eq!(compressed[4], VERSION_MAP);
+        assert_eq!(compressed[HEADER_SIZE..HEADER_SIZE + map.len()], map[..]);
+        let (left, right) = split_error(&gray, &decode(&compressed).unwrap().pixels, 1);
+        assert!(left < right, "{} vs {}", left, right);
+
+        let rgb = test_image(width, height, 3);
+        let image = decode(&encode_rgb(&rgb, width, height, &options).unwrap()).unwrap();
+        let (left, right) = split_error(&rgb, &image.pixels, 3);
+        assert!(left < right, "{} vs {}", left, right);
+
+        // A uniform map decodes like the quality it holds.
+        let plain = encode_gray(&gray, width, height, &EncodeOptions::new().quality(60)).unwrap();
+        assert_eq!(plain[4], VERSION);
+        let uniform = EncodeOptions::new().quality_map(vec![60; 15]);
+        let mapped = encode_gray(&gray, width, height, &uniform).unwrap();
+        assert_eq!(decode(&plain).unwrap().pixels, decode(&mapped).unwrap().pixels);
+
+        // Qualities out of range are clamped by the encoder and rejected
+        // by the decoder.
+        let clamped = EncodeOptions::new().quality_map(vec![0; 15]);
+        assert!(decode(&encode_gray(&gray, width, height, &clamped).unwrap()).is_ok());
+        let mut bad = compressed.clone();
+        bad[HEADER_SIZE + 3] = 101;
+        assert!(decode(&bad).is_err());
+        assert!(decode(&compressed[..HEADER_SIZE + 10]).is_err());
+        let short = EncodeOptions::new().quality_map(vec![50; 14]);
+        assert!(encode_gray(&gray, width, height, &short).is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        let options = EncodeOptions::new();
+        assert!(encode_gray(&[], 0, 0, &options).is_err());
+        assert!(encode_gray(&[0; 10], 3, 3, &options).is_err());
+        assert!(encode_rgb(&[0; 9], 3, 3, &options).is_err());
+
+        let pixels = test_image(40, 40, 1);
+        let compressed = encode_gray(&pixels, 40, 40, &options).unwrap();
+        assert!(decode(&compressed[..10]).is_err());
+        assert!(decode(&compressed[..compressed.len() / 2]).is_err());
+        let mut bad = compressed.clone();
+        bad[0] = b'X';
+        assert!(decode(&bad).is_err());
+        let mut bad = compressed.clone();
+        bad[4] = 3;
+        assert!(decode(&bad).is_err());
+        let mut bad = compressed.clone();
+        bad[5] = 2;
+        assert!(decode(&bad).is_err());
+        let mut bad = compressed;
+        bad[15] = 0xff;
+        assert!(decode(&bad).is_err());
+
+        // A header for the largest image, with no data.
+        let (forged, _) = header(1, &options, 1 << 12, 1 << 12).unwrap();
+        assert!(decode(&forged).is_err());
+    }
+
+    #[test]
+    fn forged_size() {
+        // Headers that claim more than MAX_PIXELS pixels, followed by
+        // enough data for a few blocks.
+        for &(width, height) in &[(1u32 << 14, 1u32 << 14), (1 << 12, (1 << 12) + 1),
+                                  (u32::MAX, u32::MAX)] {
+            let (mut forged, _) = header(3, &EncodeOptions::new(), 8, 8).unwrap();
+            forged[8..12].copy_from_slice(&width.to_le_bytes());
+            forged[12..16].copy_from_slice(&height.to_le_bytes());
+            forged.extend_from_slice(&[0; 64]);
+            let err = decode(&forged).unwrap_err();
+            assert!(err.to_string().contains("invalid image size"), "{}", err);
+        }
+        // The encoder does not write images that decode would reject.
+        assert!(header(1, &EncodeOptions::new(), 1 << 12, (1 << 12) + 1).is_err());
+    }
+
+    #[test]
+    fn dc_overflow() {
+        let mut block = [0i32; COEFFS];
+        block[0] = 10;
+        let mut encoder = RangeEncoder::new(Vec::new());
+        encode_block(&mut encoder, &mut Models::new(), &block, &mut 0);
+        let output = encoder.finish();
+        let mut dc = i32::MAX - 5;
+        let result = decode_block(&mut RangeDecoder::new(&output), &mut Models::new(), &mut dc);
+        assert!(result.unwrap_err().to_string().contains("DC overflow"));
+    }
+}
@@ -0,0 +1,294 @@
+// Intra prediction.
+//
+// Copyright 2023 Robbert Haarman
+//
+// SPDX-License-Identifier: MIT
+
+//! This module implements spatial intra prediction, as used by video
+//! codecs for key frames. Each 8x8 block is predicted from the already
+//! reconstructed pixels above and to the left of it, and only the
+//! difference between the block and the prediction (the residual) is
+//! transformed with the DCT and quantized. In smooth areas, the residual
+//! is much smaller than the block itself, so that more coefficients
+//! quantize to zero.
+//!
+//! The decoder only has the reconstructed pixels, not the original ones,
+//! so the encoder predicts from its own reconstruction too, block by
+//! block in row-major order.
+
+use crate::dct::{self, ImageCoefficients};
+
+/// Blocks are N by N pixels.
+const N: usize = 8;
+
+/// How a block is predicted from its neighbors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Every pixel is the mean of the pixels above and to the left of
+    /// the block, or 0 if there are none.
+    Dc,
+    /// Each row repeats the pixel to the left of it. Falls back to Dc
+    /// for blocks at the left edge.
+    Horizontal,
+    /// Each column repeats the pixel above it. Falls back to Dc for
+    /// blocks at the top edge.
+    Vertical,
+}
+
+/// All modes, in the order choose_mode prefers them when they predict
+/// equally well.
+const MODES: [Mode; 3] = [Mode::Dc, Mode::Horizontal, Mode::Vertical];
+
+/// The reconstructed pixels next to a block.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Neighbors {
+    /// The row of pixels above the block, if the block is not at the
+    /// top edge.
+    pub above: Option<[f32; N]>,
+    /// The column of pixels to the left of the block, from top to
+    /// bottom, if the block is not at the left edge.
+    pub left: Option<[f32; N]>,
+}
+
+impl Neighbors {
+    /// Returns the neighbors of block bx, by of a row-major plane whose
+    /// rows start stride values apart, which must extend at least to
+    /// the end of the block.
+    pub fn from_plane(plane: &[f32], stride: usize, bx: usize, by: usize) -> Neighbors {
+        let (x, y) = (bx * N, by * N);
+        let above = if y > 0 {
+            let mut row = [0.0; N];
+            row.copy_from_slice(&plane[(y - 1) * stride + x..][..N]);
+            Some(row)
+        } else {
+            None
+        };
+        let left = if x > 0 {
+            let mut column = [0.0; N];
+            for (i, c) in column.iter_mut().enumerate() {
+                *c = plane[(y + i) * stride + x - 1];
+            }
+            Some(column)
+        } else {
+            None
+        };
+        Neighbors { above, left }
+    }
+}
+
+/// Returns the prediction for a block with the given neighbors.
+pub fn predict(neighbors: &Neighbors, mode: Mode) -> [f32; N * N] {
+    let mut res = [0.0; N * N];
+    match (mode, neighbors.above, neighbors.left) {
+        (Mode::Horizontal, _, Some(left)) => {
+            for (row, &x) in res.chunks_mut(N).zip(left.iter()) {
+                row.fill(x);
+            }
+        },
+        (Mode::Vertical, Some(above), _) => {
+            for row in res.chunks_mut(N) {
+                row.copy_from_slice(&above);
+            }
+        },
+        (_, above, left) => {
+            let values: Vec<f32> = above.iter().chain(left.iter()).flatten().copied().collect();
+            if !values.is_empty() {
+                res.fill(values.iter().sum::<f32>() / values.len() as f32);
+            }
+        },
+    }
+    res
+}
+
+/// Returns the difference between a block and its prediction.
+pub fn residual(block: &[f32; N * N], prediction: &[f32; N * N]) -> [f32; N * N] {
+    let mut res = *block;
+    for (r, &p) in res.iter_mut().zip(prediction.iter()) {
+        *r -= p;
+    }
+    res
+}
+
+/// Reverses residual, adding the prediction back.
+pub fn reconstruct(residual: &[f32; N * N], prediction: &[f32; N * N]) -> [f32; N * N] {
+    let mut res = *residual;
+    for (r, &p) in res.iter_mut().zip(prediction.iter()) {
+        *r += p;
+    }
+    res
+}
+
+/// Returns the mode whose prediction differs least from block, by the
+/// sum of absolute differences.
+pub fn choose_mode(block: &[f32; N * N], neighbors: &Neighbors) -> Mode {
+    let cost = |mode: Mode| -> f32 {
+        residual(block, &predict(neighbors, mode)).iter().map(|x| x.abs()).sum()
+    };
+    let mut best = MODES[0];
+    let mut best_cost = cost(best);
+    for &mode in &MODES[1..] {
+        let c = cost(mode);
+        if c < best_cost {
+            best = mode;
+            best_cost = c;
+        }
+    }
+    best
+}
+
+/// An image coded with intra prediction: the mode and quantized
+/// residual coefficients of each block.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PredictedImage {
+    /// The mode of each block, in row-major order of blocks.
+    pub modes: Vec<Mode>,
+    /// The quantized DCT coefficients of the residual of each block.
+    pub coeffs: ImageCoefficients<i32>,
+}
+
+/// Reconstructs block i of predicted from its prediction and coefficients
+/// and stores it in the plane.
+fn reconstruct_block(predicted: &PredictedImage, qtable: &[u16; N * N], plane: &mut [f32],
+                     stride: usize, i: usize) {
+    let (bx, by) = (i % predicted.coeffs.blocks_wide, i / predicted.coeffs.blocks_wide);
+    let prediction = predict(&Neighbors::from_plane(plane, stride, bx, by), predicted.modes[i]);
+    let residual = dct::reverse(&dct::dequantize(&predicted.coeffs.blocks[i], qtable));
+    let block = reconstruct(&residual, &prediction);
+    for (y, values) in block.chunks(N).enumerate() {
+        plane[(by * N + y) * stride + bx * N..][..N].copy_from_slice(values);
+    }
+}
+
+/// Splits a row-major image of level shifted samples (centered on 0)
+/// into blocks, chooses a mode for each, and transforms and quantizes
+/// the residuals with qtable. Row y of the image starts at
+/// image[y * stride]. Blocks that extend past the edges of the image
+/// are padded by replicating the last row or column.
+pub fn transform_image(image: &[f32], width: usize, height: usize, stride: usize,
+                       qtable: &[u16; N * N]) -> PredictedImage {
+    assert!(width > 0 && height > 0, "image is empty");
+    assert!(stride >= width, "stride is less than width");
+    assert!(image.len() >= (height - 1) * stride + width, "image is too short");
+    let (blocks_wide, blocks_high) = (width.div_ceil(N), height.div_ceil(N));
+    let recon_stride = blocks_wide * N;
+    let mut recon = vec![0.0f32; recon_stride * blocks_high * N];
+    let mut predicted = PredictedImage {
+        modes: Vec::with_capacity(blocks_wide * blocks_high),
+        coeffs: ImageCoefficients {
+            width,
+            height,
+            blocks_wide,
+            blocks_high,
+            blocks: Vec::with_capacity(blocks_wide * blocks_high),
+        },
+    };
+    for by in 0..blocks_high {
+        for bx in 0..blocks_wide {
+            let mut block = [0.0; N * N];
+            for (i, b) in block.iter_mut().enumerate() {
+                let x = (bx * N + i % N).min(width - 1);
+                let y = (by * N + i / N).min(height - 1);
+                *b = image[y * stride + x];
+            }
+            let neighbors = Neighbors::from_plane(&recon, recon_stride, bx, by);
+            let mode = choose_mode(&block, &neighbors);
+            let residual = residual(&block, &predict(&neighbors, mode));
+            predicted.modes.push(mode);
+            predicted.coeffs.blocks.push(dct::quantize(&dct::transform(&residual), qtable));
+            reconstruct_block(&predicted, qtable, &mut recon, recon_stride,
+                              predicted.modes.len() - 1);
+        }
+    }
+    predicted
+}
+
+/// Reverses transform_image, up to quantization errors, returning the
+/// image with a stride equal to its width.
+pub fn reverse_image(predicted: &PredictedImage, qtable: &[u16; N * N]) -> Vec<f32> {
+    let coeffs = &predicted.coeffs;
+    let nblocks = coeffs.blocks_wide * coeffs.blocks_high;
+    assert_eq!(coeffs.blocks.len(), nblocks, "wrong number of blocks");
+    assert_eq!(predicted.modes.len(), nblocks, "wrong number of modes");
+    let recon_stride = coeffs.blocks_wide * N;
+    let mut recon = vec![0.0f32; recon_stride * coeffs.blocks_high * N];
+    for i in 0..nblocks {
+        reconstruct_block(predicted, qtable, &mut recon, recon_stride, i);
+    }
+    recon.chunks(recon_stride).take(coeffs.height)
+        .flat_map(|row| row[..coeffs.width].iter().copied()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modes() {
+        let above: [f32; N] = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0];
+        let left: [f32; N] = [-1.0, -2.0, -3.0, -4.0, -5.0, -6.0, -7.0, -8.0];
+        let both = Neighbors { above: Some(above), left: Some(left) };
+        assert_eq!(predict(&both, Mode::Dc), [0.0; N * N]);
+        let vertical = predict(&both, Mode::Vertical);
+        assert_eq!(vertical[N * 5..N * 6], above);
+        let horizontal = predict(&both, Mode::Horizontal);
+        assert_eq!(horizontal[N * 3..N * 4], [-4.0; N]);
+
+        // Missing neighbors fall back to Dc.
+        let top = Neighbors { above: None, left: Some(left) };
+        assert_eq!(predict(&top, Mode::Vertical), [-4.5; N * N]);
+        assert_eq!(predict(&Neighbors { above: None, left: None }, Mode::Horizontal),
+                   [0.0; N * N]);
+
+        assert_eq!(choose_mode(&vertical, &both), Mode::Vertical);
+        assert_eq!(choose_mode(&horizontal, &both), Mode::Horizontal);
+        assert_eq!(choose_mode(&[0.0; N * N], &both), Mode::Dc);
+
+        let block = [0.0; N * N].map(|_| 3.5);
+        let r = residual(&block, &vertical);
+        assert_eq!(reconstruct(&r, &vertical), block);
+    }
+
+    #[test]
+    fn neighbors() {
+        let stride = 20;
+        let plane: Vec<f32> = (0..stride * 16).map(|i| i as f32).collect();
+        let n = Neighbors::from_plane(&plane, stride, 1, 1);
+        assert_eq!(n.above.unwrap()[0], (7 * stride + 8) as f32);
+        assert_eq!(n.left.unwrap()[2], (10 * stride + 7) as f32);
+        let n = Neighbors::from_plane(&plane, stride, 0, 1);
+        assert!(n.left.is_none() && n.above.is_some());
+    }
+
+    #[test]
+    fn images() {
+        // A smooth image with a vertical edge.
+        let (width, height, stride) = (45, 30, 48);
+        let image: Vec<f32> = (0..stride * height).map(|i| {
+            let (x, y) = ((i % stride) as f32, (i / stride) as f32);
+            if x < 20.0 { y * 2.0 - 60.0 } else { 50.0 + y }
+        }).collect();
+        let qtable = dct::scale_qtable(&dct::LUMINANCE_QTABLE, 75);
+        let predicted = transform_image(&image, width, height, stride, &qtable);
+        assert_eq!(predicted.modes.len(), 6 * 4);
+        assert!(predicted.modes.contains(&Mode::Vertical));
+        let restored = reverse_image(&predicted, &qtable);
+        assert_eq!(restored.len(), width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let (a, b) = (restored[y * width + x], image[y * stride + x]);
+                assert!((a - b).abs() < 6.0, "{} differs from {} at {}, {}", a, b, x, y);
+            }
+        }
+
+        // Prediction leaves fewer nonzero coefficients than coding the
+        // blocks directly.
+        let direct = dct::quantize_image(
+            &dct::transform_image(&image, width, height, stride, dct::Padding::Replicate),
+            &qtable);
+        let nonzero = |coeffs: &ImageCoefficients<i32>| -> usize {
+            coeffs.blocks.iter().flat_map(|b| b.iter()).filter(|&&q| q != 0).count()
+        };
+        assert!(nonzero(&predicted.coeffs) * 4 < nonzero(&direct) * 3,
+                "{} vs {}", nonzero(&predicted.coeffs), nonzero(&direct));
+    }
+}
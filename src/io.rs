@@ -4,6 +4,8 @@
 //
 // SPDX-License-Identifier: MIT
 
+use std::convert::TryInto;
+
 use crate::result::BoxResult;
 
 pub trait IOTrait {
@@ -16,11 +18,20 @@ pub trait IOTrait {
     /// Attempts to read the next byte of input. Returns Ok(None) if
     /// the end of the input has been reached, Ok(Some(b)) when a byte
     /// b has been read and Err(e) if some error e has occurred.
-
     fn next_byte(&mut self) -> BoxResult<Option<u8>>;
 
     /// Writes a single byte to the output.
     fn write_byte(&mut self, b: u8) -> BoxResult<()>;
+
+    /// Hints that about additional more bytes of output will be written,
+    /// so that IOs that keep their output in memory can allocate it at
+    /// once. Does nothing by default.
+    fn reserve_output(&mut self, _additional: usize) {}
+
+    /// Returns the number of bytes of input left, if known, so that hints
+    /// for reserve_output taken from the input can be checked against
+    /// it. Returns None by default.
+    fn remaining_input(&self) -> Option<u64> { None }
 }
 
 pub trait LookbackInput {
@@ -29,8 +40,183 @@ pub trait LookbackInput {
     fn lookback(&self, pos: u64) -> u8;
 }
 
+/// LookbackInput that can only look back at the most recent input, like
+/// the IOs that keep a window of their input in a ring buffer. Codecs
+/// that need to look back a certain distance use this to check that
+/// they can, instead of failing part way through.
+pub trait WindowedLookback: LookbackInput {
+    /// Returns how many of the most recent bytes of input lookback can
+    /// return: positions from inpos() - lookback_window() on. IOs that
+    /// keep all of their input return u64::MAX.
+    fn lookback_window(&self) -> u64;
+}
+
+/// Input that can be examined ahead of the current position without
+/// consuming it, so that match finders can look at upcoming bytes
+/// instead of reading them one at a time with next_byte. Implemented by
+/// the IOs that have their input in memory.
+pub trait LookaheadInput {
+    /// Returns the next n bytes of input without consuming them. Fewer
+    /// bytes are returned only at the end of the input.
+    fn lookahead(&mut self, n: usize) -> BoxResult<&[u8]>;
+
+    /// Returns the byte offset bytes after the next byte of input, or
+    /// Ok(None) if the input ends before it.
+    fn peek(&mut self, offset: usize) -> BoxResult<Option<u8>> {
+        Ok(self.lookahead(offset + 1)?.get(offset).copied())
+    }
+}
+
+impl<T: IOTrait + ?Sized> IOTrait for &mut T {
+    fn copy_bytes(&mut self, count: usize) -> BoxResult<()> { (**self).copy_bytes(count) }
+    fn inpos(&self) -> u64 { (**self).inpos() }
+    fn next_byte(&mut self) -> BoxResult<Option<u8>> { (**self).next_byte() }
+    fn write_byte(&mut self, b: u8) -> BoxResult<()> { (**self).write_byte(b) }
+    fn reserve_output(&mut self, additional: usize) { (**self).reserve_output(additional) }
+    fn remaining_input(&self) -> Option<u64> { (**self).remaining_input() }
+}
+
+impl<T: LookbackInput + ?Sized> LookbackInput for &mut T {
+    fn lookback(&self, pos: u64) -> u8 { (**self).lookback(pos) }
+}
+
+impl<T: WindowedLookback + ?Sized> WindowedLookback for &mut T {
+    fn lookback_window(&self) -> u64 { (**self).lookback_window() }
+}
+
+impl<T: LookaheadInput + ?Sized> LookaheadInput for &mut T {
+    fn lookahead(&mut self, n: usize) -> BoxResult<&[u8]> { (**self).lookahead(n) }
+    fn peek(&mut self, offset: usize) -> BoxResult<Option<u8>> { (**self).peek(offset) }
+}
+
+impl<T: RepeatOutput + ?Sized> RepeatOutput for &mut T {
+    fn repeat_bytes(&mut self, count: usize, distance: usize) -> BoxResult<()> {
+        (**self).repeat_bytes(count, distance)
+    }
+}
+
+impl<T: IOTrait + ?Sized> IOTrait for Box<T> {
+    fn copy_bytes(&mut self, count: usize) -> BoxResult<()> { (**self).copy_bytes(count) }
+    fn inpos(&self) -> u64 { (**self).inpos() }
+    fn next_byte(&mut self) -> BoxResult<Option<u8>> { (**self).next_byte() }
+    fn write_byte(&mut self, b: u8) -> BoxResult<()> { (**self).write_byte(b) }
+    fn reserve_output(&mut self, additional: usize) { (**self).reserve_output(additional) }
+    fn remaining_input(&self) -> Option<u64> { (**self).remaining_input() }
+}
+
+impl<T: LookbackInput + ?Sized> LookbackInput for Box<T> {
+    fn lookback(&self, pos: u64) -> u8 { (**self).lookback(pos) }
+}
+
+impl<T: WindowedLookback + ?Sized> WindowedLookback for Box<T> {
+    fn lookback_window(&self) -> u64 { (**self).lookback_window() }
+}
+
+impl<T: LookaheadInput + ?Sized> LookaheadInput for Box<T> {
+    fn lookahead(&mut self, n: usize) -> BoxResult<&[u8]> { (**self).lookahead(n) }
+    fn peek(&mut self, offset: usize) -> BoxResult<Option<u8>> { (**self).peek(offset) }
+}
+
+impl<T: RepeatOutput + ?Sized> RepeatOutput for Box<T> {
+    fn repeat_bytes(&mut self, count: usize, distance: usize) -> BoxResult<()> {
+        (**self).repeat_bytes(count, distance)
+    }
+}
+
+/// IO that decoders can use. Implemented for every type that has the
+/// needed traits, so that decoders can be given a Box<dyn DecodeIO> (see
+/// BoxDecodeIO) whose backend is chosen at runtime.
+pub trait DecodeIO: IOTrait + RepeatOutput {}
+
+impl<T: IOTrait + RepeatOutput + ?Sized> DecodeIO for T {}
+
+/// IO that encoders can use, like DecodeIO.
+pub trait EncodeIO: IOTrait + WindowedLookback {}
+
+impl<T: IOTrait + WindowedLookback + ?Sized> EncodeIO for T {}
+
+/// IO that both encoders and decoders can use, like DecodeIO.
+pub trait FullIO: IOTrait + WindowedLookback + RepeatOutput {}
+
+impl<T: IOTrait + WindowedLookback + RepeatOutput + ?Sized> FullIO for T {}
+
+pub type BoxDecodeIO<'a> = Box<dyn DecodeIO + 'a>;
+pub type BoxEncodeIO<'a> = Box<dyn EncodeIO + 'a>;
+pub type BoxFullIO<'a> = Box<dyn FullIO + 'a>;
+
+/// Error returned by ReadBits.
+#[derive(Debug)]
+pub enum BitReadError {
+    /// The input ended bits_needed bits short of what a read needed.
+    UnexpectedEof { bits_needed: u32 },
+    /// Reading from the underlying reader failed.
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for BitReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BitReadError::UnexpectedEof { bits_needed } =>
+                write!(f, "unexpected end of input, {} more bits needed", bits_needed),
+            BitReadError::Io(e) => write!(f, "error reading bits: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BitReadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BitReadError::UnexpectedEof { .. } => None,
+            BitReadError::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<std::io::Error> for BitReadError {
+    fn from(e: std::io::Error) -> BitReadError { BitReadError::Io(e) }
+}
+
+/// Result returned by ReadBits.
+pub type BitResult<T> = Result<T, BitReadError>;
+
 pub trait ReadBits {
-    fn read_bits(&mut self, nbits: u32) -> BoxResult<u32>;
+    /// Reads nbits bits (at most 32), least significant bit first.
+    fn read_bits(&mut self, nbits: u32) -> BitResult<u32>;
+
+    /// Like read_bits, but returns Ok(None) instead of an error if the
+    /// input ends at the current position, which is then always a byte
+    /// boundary. Running out of input after reading part of the bits is
+    /// still an error. Codecs use this to tell the clean end of a stream
+    /// from a truncated one.
+    fn try_read_bits(&mut self, nbits: u32) -> BitResult<Option<u32>>;
+
+    /// Returns the next nbits bits (at most 32) without consuming them.
+    /// Bits past the end of the input are returned as 0, so that
+    /// table-driven decoders can peek at the longest code even near the
+    /// end of the input.
+    fn peek_bits(&mut self, nbits: u32) -> BitResult<u32>;
+
+    /// Returns the number of bits that have been consumed so far.
+    fn bit_position(&self) -> u64;
+
+    /// Reads nbits bits (at most 64), least significant bit first.
+    fn read_bits64(&mut self, nbits: u32) -> BitResult<u64> {
+        assert!(nbits <= 64, "cannot read more than 64 bits at once");
+        if nbits <= 32 {
+            return Ok(self.read_bits(nbits)? as u64);
+        }
+        let low = self.read_bits(32)? as u64;
+        let high = self.read_bits(nbits - 32)? as u64;
+        Ok(low | (high << 32))
+    }
+
+    /// Skips the remaining bits of the current byte, if any, so that the
+    /// next read starts at a byte boundary.
+    fn align_to_byte(&mut self) -> BitResult<()> {
+        let pad = (8 - self.bit_position() % 8) % 8;
+        self.read_bits(pad as u32)?;
+        Ok(())
+    }
 }
 
 pub trait RepeatOutput {
@@ -41,53 +227,374 @@ pub trait RepeatOutput {
 
 pub trait WriteBits {
     fn flush(&mut self) -> BoxResult<()>;
+
+    /// Writes the nbits (at most 32) least significant bits of bits,
+    /// least significant bit first. The other bits of bits are ignored.
     fn write_bits(&mut self, bits: u32, nbits: u8) -> BoxResult<()>;
+
+    /// Writes the nbits (at most 64) least significant bits of bits,
+    /// least significant bit first. The other bits of bits are ignored.
+    fn write_bits64(&mut self, bits: u64, nbits: u8) -> BoxResult<()> {
+        assert!(nbits <= 64, "cannot write more than 64 bits at once");
+        if nbits <= 32 {
+            return self.write_bits(bits as u32, nbits);
+        }
+        self.write_bits(bits as u32, 32)?;
+        self.write_bits((bits >> 32) as u32, nbits - 32)
+    }
+
+    /// Pads the current byte, if any, with zero bits, so that the next
+    /// write starts at a byte boundary.
+    fn align_to_byte(&mut self) -> BoxResult<()>;
 }
 
-pub struct BitReader<'a> {
-    input: &'a mut dyn std::io::Read,
+impl<T: ReadBits + ?Sized> ReadBits for &mut T {
+    fn read_bits(&mut self, nbits: u32) -> BitResult<u32> { (**self).read_bits(nbits) }
+    fn try_read_bits(&mut self, nbits: u32) -> BitResult<Option<u32>> {
+        (**self).try_read_bits(nbits)
+    }
+    fn peek_bits(&mut self, nbits: u32) -> BitResult<u32> { (**self).peek_bits(nbits) }
+    fn bit_position(&self) -> u64 { (**self).bit_position() }
+    fn read_bits64(&mut self, nbits: u32) -> BitResult<u64> { (**self).read_bits64(nbits) }
+    fn align_to_byte(&mut self) -> BitResult<()> { (**self).align_to_byte() }
+}
+
+impl<T: ReadBits + ?Sized> ReadBits for Box<T> {
+    fn read_bits(&mut self, nbits: u32) -> BitResult<u32> { (**self).read_bits(nbits) }
+    fn try_read_bits(&mut self, nbits: u32) -> BitResult<Option<u32>> {
+        (**self).try_read_bits(nbits)
+    }
+    fn peek_bits(&mut self, nbits: u32) -> BitResult<u32> { (**self).peek_bits(nbits) }
+    fn bit_position(&self) -> u64 { (**self).bit_position() }
+    fn read_bits64(&mut self, nbits: u32) -> BitResult<u64> { (**self).read_bits64(nbits) }
+    fn align_to_byte(&mut self) -> BitResult<()> { (**self).align_to_byte() }
+}
+
+impl<T: WriteBits + ?Sized> WriteBits for &mut T {
+    fn flush(&mut self) -> BoxResult<()> { (**self).flush() }
+    fn write_bits(&mut self, bits: u32, nbits: u8) -> BoxResult<()> {
+        (**self).write_bits(bits, nbits)
+    }
+    fn write_bits64(&mut self, bits: u64, nbits: u8) -> BoxResult<()> {
+        (**self).write_bits64(bits, nbits)
+    }
+    fn align_to_byte(&mut self) -> BoxResult<()> { (**self).align_to_byte() }
+}
+
+impl<T: WriteBits + ?Sized> WriteBits for Box<T> {
+    fn flush(&mut self) -> BoxResult<()> { (**self).flush() }
+    fn write_bits(&mut self, bits: u32, nbits: u8) -> BoxResult<()> {
+        (**self).write_bits(bits, nbits)
+    }
+    fn write_bits64(&mut self, bits: u64, nbits: u8) -> BoxResult<()> {
+        (**self).write_bits64(bits, nbits)
+    }
+    fn align_to_byte(&mut self) -> BoxResult<()> { (**self).align_to_byte() }
+}
+
+pub type BoxReadBits<'a> = Box<dyn ReadBits + 'a>;
+pub type BoxWriteBits<'a> = Box<dyn WriteBits + 'a>;
+
+/// Number of bytes BitReader reads from its input at a time.
+const BIT_READER_BUFFER: usize = 0x2000;
+
+/// Buffered bits read from a reader, shared by BitReader and
+/// SeekableBitReader. The reader is passed to each call.
+struct BitBuffer {
+    /// Bytes read from input but not yet moved into bits.
+    buf: Vec<u8>,
+    buf_pos: usize,
+    buf_len: usize,
+    /// Offset in the input of buf[0].
+    buf_offset: u64,
+    /// Bits that have been read from input but not consumed yet. The
+    /// next bit is the least significant one. This holds up to 64 bits
+    /// plus the partial byte read to get them.
+    bits: u128,
+    /// Number of valid bits in bits.
     have_bits: u32,
-    bits: u8,
+    /// Number of bits consumed so far.
+    position: u64,
+    /// Whether the end of the input has been reached.
+    eof: bool,
 }
 
-impl<'a> BitReader<'a> {
-    pub fn new(input: &'a mut dyn std::io::Read) -> BitReader {
-        BitReader {
-            input: input,
+impl BitBuffer {
+    fn new() -> BitBuffer {
+        BitBuffer {
+            buf: vec![0; BIT_READER_BUFFER],
+            buf_pos: 0,
+            buf_len: 0,
+            buf_offset: 0,
             bits: 0,
             have_bits: 0,
+            position: 0,
+            eof: false,
+        }
+    }
+
+    /// Reads bytes from input until at least nbits bits are available
+    /// or the end of the input is reached. If reading fails, the bytes
+    /// read so far are kept, so that fill can be called again once more
+    /// input is available.
+    fn fill(&mut self, input: &mut dyn std::io::Read, nbits: u32) -> BitResult<()> {
+        while self.have_bits < nbits && !self.eof {
+            if self.buf_pos == self.buf_len {
+                self.buf_offset += self.buf_len as u64;
+                self.buf_pos = 0;
+                self.buf_len = 0;
+                self.buf_len = read_some(input, &mut self.buf)?;
+                if self.buf_len == 0 {
+                    self.eof = true;
+                    break;
+                }
+            }
+            self.bits |= (self.buf[self.buf_pos] as u128) << self.have_bits;
+            self.buf_pos += 1;
+            self.have_bits += 8;
+        }
+        Ok(())
+    }
+
+    /// Consumes and returns nbits bits, at most 64.
+    fn take(&mut self, input: &mut dyn std::io::Read, nbits: u32) -> BitResult<u64> {
+        self.fill(input, nbits)?;
+        if self.have_bits < nbits {
+            return Err(BitReadError::UnexpectedEof { bits_needed: nbits - self.have_bits });
         }
+        let bits = self.bits & ((1u128 << nbits) - 1);
+        self.bits >>= nbits;
+        self.have_bits -= nbits;
+        self.position += nbits as u64;
+        Ok(bits as u64)
+    }
+
+    fn read_bits(&mut self, input: &mut dyn std::io::Read, nbits: u32) -> BitResult<u32> {
+        assert!(nbits <= 32, "cannot read more than 32 bits at once");
+        Ok(self.take(input, nbits)? as u32)
+    }
+
+    fn try_read_bits(&mut self, input: &mut dyn std::io::Read, nbits: u32)
+                     -> BitResult<Option<u32>> {
+        self.fill(input, 1)?;
+        if self.have_bits == 0 && nbits > 0 {
+            return Ok(None);
+        }
+        self.read_bits(input, nbits).map(Some)
+    }
+
+    fn peek_bits(&mut self, input: &mut dyn std::io::Read, nbits: u32) -> BitResult<u32> {
+        assert!(nbits <= 32, "cannot peek at more than 32 bits at once");
+        self.fill(input, nbits)?;
+        Ok((self.bits & ((1u128 << nbits) - 1)) as u32)
+    }
+
+    fn read_bits64(&mut self, input: &mut dyn std::io::Read, nbits: u32) -> BitResult<u64> {
+        assert!(nbits <= 64, "cannot read more than 64 bits at once");
+        self.take(input, nbits)
+    }
+
+    /// Discards all buffered bits and continues at byte offset in the
+    /// input, which the caller has positioned the reader at.
+    fn reset(&mut self, offset: u64) {
+        self.buf_pos = 0;
+        self.buf_len = 0;
+        self.buf_offset = offset;
+        self.bits = 0;
+        self.have_bits = 0;
+        self.position = offset * 8;
+        self.eof = false;
+    }
+}
+
+/// Reads bits from a reader, least significant bit of each byte first.
+/// Input is read in blocks, so the reader may have advanced past the
+/// last byte the BitReader has used. Short reads are fine, and reads
+/// that fail, like reads from a nonblocking reader that fail with
+/// WouldBlock, consume no bits: the bytes read so far are kept and the
+/// call can be repeated when more input is available.
+pub struct BitReader<'a> {
+    input: &'a mut dyn std::io::Read,
+    state: BitBuffer,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(input: &'a mut dyn std::io::Read) -> BitReader<'a> {
+        BitReader { input, state: BitBuffer::new() }
     }
 }
 
 impl ReadBits for BitReader<'_> {
-    fn read_bits(&mut self, nbits: u32) -> BoxResult<u32> {
-        let mut bits = 0;
-        let mut shift = 0;
-        let mut need_bits = nbits;
-        while need_bits > 0 {
-            // If we are out of bits, read some.
-            if self.have_bits == 0 {
-                self.input.read_exact(std::slice::from_mut(&mut self.bits))?;
-                self.have_bits = 8;
-            }
-            // Move bits from self.bits into bits.
-            let n = std::cmp::min(need_bits, self.have_bits);
-            bits |= ((self.bits as u32) & ((1 << n) - 1)) << shift;
-            // Shifting an u8 by 8 panics in debug mode. Instead, shift as
-            // an u32, which avoids the panic.
-            self.bits = ((self.bits as u32) >> n) as u8;
-            self.have_bits -= n;
-            need_bits -= n;
-            shift += n;
+    fn read_bits(&mut self, nbits: u32) -> BitResult<u32> {
+        self.state.read_bits(self.input, nbits)
+    }
+
+    fn try_read_bits(&mut self, nbits: u32) -> BitResult<Option<u32>> {
+        self.state.try_read_bits(self.input, nbits)
+    }
+
+    fn peek_bits(&mut self, nbits: u32) -> BitResult<u32> {
+        self.state.peek_bits(self.input, nbits)
+    }
+
+    fn bit_position(&self) -> u64 { self.state.position }
+
+    fn read_bits64(&mut self, nbits: u32) -> BitResult<u64> {
+        self.state.read_bits64(self.input, nbits)
+    }
+}
+
+/// Reads bits from a reader that supports seeking, like BitReader, and
+/// can jump to any bit offset in the input, for formats with an index
+/// of where their blocks start. Bit offsets and bit_position count from
+/// the start of the input, not from where the reader was when the
+/// SeekableBitReader was created. Like with BitReader, reads that fail
+/// can be repeated.
+pub struct SeekableBitReader<R> {
+    input: R,
+    state: BitBuffer,
+}
+
+impl<R: std::io::Read + std::io::Seek> SeekableBitReader<R> {
+    /// Returns a SeekableBitReader that starts reading where input is.
+    pub fn new(mut input: R) -> BoxResult<SeekableBitReader<R>> {
+        let offset = input.stream_position()?;
+        let mut state = BitBuffer::new();
+        state.reset(offset);
+        Ok(SeekableBitReader { input, state })
+    }
+
+    /// Continues reading at bit offset from the start of the input.
+    /// Seeks within the bytes already buffered do not touch the reader.
+    pub fn seek_bits(&mut self, offset: u64) -> BoxResult<()> {
+        let byte = offset / 8;
+        let state = &mut self.state;
+        let buffered = state.buf_offset..state.buf_offset + state.buf_len as u64;
+        if buffered.contains(&byte) {
+            let (buf_offset, buf_len) = (state.buf_offset, state.buf_len);
+            state.reset(byte);
+            state.buf_offset = buf_offset;
+            state.buf_len = buf_len;
+            state.buf_pos = (byte - buf_offset) as usize;
+        } else {
+            self.input.seek(std::io::SeekFrom::Start(byte))?;
+            state.reset(byte);
         }
-        Ok(bits)
+        state.take(&mut self.input, (offset % 8) as u32)?;
+        Ok(())
+    }
+
+    /// Returns the reader. Its position is unspecified.
+    pub fn into_inner(self) -> R { self.input }
+}
+
+impl<R: std::io::Read + std::io::Seek> ReadBits for SeekableBitReader<R> {
+    fn read_bits(&mut self, nbits: u32) -> BitResult<u32> {
+        self.state.read_bits(&mut self.input, nbits)
+    }
+
+    fn try_read_bits(&mut self, nbits: u32) -> BitResult<Option<u32>> {
+        self.state.try_read_bits(&mut self.input, nbits)
+    }
+
+    fn peek_bits(&mut self, nbits: u32) -> BitResult<u32> {
+        self.state.peek_bits(&mut self.input, nbits)
+    }
+
+    fn bit_position(&self) -> u64 { self.state.position }
+
+    fn read_bits64(&mut self, nbits: u32) -> BitResult<u64> {
+        self.state.read_bits64(&mut self.input, nbits)
+    }
+}
+
+/// Reads bits directly from a slice, least significant bit of each byte
+/// first. This produces the same results as BitReader, but is faster,
+/// since it loads up to 8 bytes at a time and does not go through
+/// std::io::Read.
+pub struct SliceBitReader<'a> {
+    input: &'a [u8],
+    /// Index in input of the next byte to load into bits.
+    next: usize,
+    /// Loaded bits that have not been consumed yet, next bit first.
+    /// Bits above have_bits are 0.
+    bits: u64,
+    have_bits: u32,
+    /// Number of bits consumed so far.
+    position: u64,
+}
+
+impl<'a> SliceBitReader<'a> {
+    pub fn new(input: &'a [u8]) -> SliceBitReader<'a> {
+        SliceBitReader { input, next: 0, bits: 0, have_bits: 0, position: 0 }
+    }
+
+    /// Loads as many whole bytes into bits as fit.
+    fn refill(&mut self) {
+        let room = ((64 - self.have_bits) / 8) as usize;
+        if self.next + 8 <= self.input.len() {
+            let word = u64::from_le_bytes(self.input[self.next..self.next + 8].try_into().unwrap());
+            let word = if room == 8 { word } else { word & ((1u64 << (room * 8)) - 1) };
+            self.bits |= word << self.have_bits;
+            self.next += room;
+            self.have_bits += room as u32 * 8;
+        } else {
+            let end = std::cmp::min(self.next + room, self.input.len());
+            for &b in &self.input[self.next..end] {
+                self.bits |= (b as u64) << self.have_bits;
+                self.have_bits += 8;
+            }
+            self.next = end;
+        }
+    }
+}
+
+impl ReadBits for SliceBitReader<'_> {
+    fn read_bits(&mut self, nbits: u32) -> BitResult<u32> {
+        assert!(nbits <= 32, "cannot read more than 32 bits at once");
+        if self.have_bits < nbits {
+            self.refill();
+            if self.have_bits < nbits {
+                return Err(BitReadError::UnexpectedEof { bits_needed: nbits - self.have_bits });
+            }
+        }
+        let bits = self.bits & ((1u64 << nbits) - 1);
+        // Shifting by 64 would overflow, so shift in two steps.
+        self.bits = (self.bits >> (nbits / 2)) >> (nbits - nbits / 2);
+        self.have_bits -= nbits;
+        self.position += nbits as u64;
+        Ok(bits as u32)
+    }
+
+    fn try_read_bits(&mut self, nbits: u32) -> BitResult<Option<u32>> {
+        if self.have_bits == 0 && self.next == self.input.len() && nbits > 0 {
+            return Ok(None);
+        }
+        self.read_bits(nbits).map(Some)
+    }
+
+    fn peek_bits(&mut self, nbits: u32) -> BitResult<u32> {
+        assert!(nbits <= 32, "cannot peek at more than 32 bits at once");
+        if self.have_bits < nbits {
+            self.refill();
+        }
+        Ok((self.bits & ((1u64 << nbits) - 1)) as u32)
     }
+
+    fn bit_position(&self) -> u64 { self.position }
 }
 
+/// Number of bytes BitWriter collects before writing them to its output.
+const BIT_WRITER_BUFFER: usize = 0x2000;
+
+/// Writes bits to a writer, least significant bit of each byte first.
+/// Output is buffered and written in blocks; call flush to write all of
+/// it.
 pub struct BitWriter<'a> {
     /// Bytes will be written to this.
     output: &'a mut dyn std::io::Write,
+    /// Completed bytes that have not been written to output yet.
+    buf: Vec<u8>,
     /// Accummulated bits.
     bits: u8,
     /// Number of accummulated bits.
@@ -97,7 +604,8 @@ pub struct BitWriter<'a> {
 impl<'a> BitWriter<'a> {
     pub fn new(output: &'a mut dyn std::io::Write) -> BitWriter<'a> {
         BitWriter {
-            output: output,
+            output,
+            buf: Vec::with_capacity(BIT_WRITER_BUFFER),
             bits: 0,
             have_bits: 0,
         }
@@ -106,70 +614,197 @@ impl<'a> BitWriter<'a> {
 
 impl WriteBits for BitWriter<'_> {
     fn flush(&mut self) -> BoxResult<()> {
-        if self.have_bits > 0 {
-            self.output.write(std::slice::from_ref(&self.bits))?;
-            self.bits = 0;
-            self.have_bits = 0;
-        }
+        self.align_to_byte()?;
+        self.output.write_all(&self.buf)?;
+        self.buf.clear();
+        self.output.flush()?;
         Ok(())
     }
  
     fn write_bits(&mut self, bits: u32, nbits: u8) -> BoxResult<()> {
-        let mut bits = bits;
-        let mut nbits = nbits;
-        while nbits > 0 {
-            self.bits |= ((bits << self.have_bits) & 0xff) as u8;
-            let need_bits = 8 - self.have_bits;
-            if nbits < need_bits {
-                self.have_bits += nbits;
-                break;
+        assert!(nbits <= 32, "cannot write more than 32 bits at once");
+        self.write_bits64(bits as u64, nbits)
+    }
+
+    fn align_to_byte(&mut self) -> BoxResult<()> {
+        if self.have_bits > 0 {
+            self.write_bits(0, 8 - self.have_bits)?;
+        }
+        Ok(())
+    }
+
+    fn write_bits64(&mut self, bits: u64, nbits: u8) -> BoxResult<()> {
+        assert!(nbits <= 64, "cannot write more than 64 bits at once");
+        // The pending bits and the new ones take up to 71 bits.
+        let mut pending = self.bits as u128
+            | (((bits & low_bits(nbits as u32)) as u128) << self.have_bits);
+        let mut have_bits = self.have_bits + nbits;
+        while have_bits >= 8 {
+            self.buf.push(pending as u8);
+            if self.buf.len() == BIT_WRITER_BUFFER {
+                self.output.write_all(&self.buf)?;
+                self.buf.clear();
             }
-            self.output.write(std::slice::from_ref(&self.bits))?;
-            self.bits = 0;
-            self.have_bits = 0;
-            bits >>= need_bits;
-            nbits -= need_bits;
+            pending >>= 8;
+            have_bits -= 8;
         }
+        self.bits = pending as u8;
+        self.have_bits = have_bits;
         Ok(())
     }
 }
 
+/// Copies nbits bits from reader to writer without interpreting them,
+/// for re-framing or concatenating bit-level streams. Neither side needs
+/// to be at a byte boundary. Bits are moved 64 at a time.
+pub fn copy_bits(reader: &mut dyn ReadBits, writer: &mut dyn WriteBits, nbits: u64)
+                 -> BoxResult<()> {
+    let mut remaining = nbits;
+    while remaining >= 64 {
+        writer.write_bits64(reader.read_bits64(64)?, 64)?;
+        remaining -= 64;
+    }
+    if remaining > 0 {
+        writer.write_bits64(reader.read_bits64(remaining as u32)?, remaining as u8)?;
+    }
+    Ok(())
+}
+
+/// Error returned by IO backends that write to a Vec<u8> when the output
+/// would grow beyond the limit set with set_output_limit.
+#[derive(Debug)]
+pub struct OutputLimitExceeded {
+    pub limit: usize,
+}
+
+impl std::fmt::Display for OutputLimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "output exceeds the limit of {} bytes", self.limit)
+    }
+}
+
+impl std::error::Error for OutputLimitExceeded {}
+
+/// Makes room for additional bytes in output. Returns an error instead
+/// of aborting if the allocation fails, and OutputLimitExceeded if
+/// output would grow beyond limit bytes.
+fn reserve_in_vec(output: &mut Vec<u8>, additional: usize, limit: usize) -> BoxResult<()> {
+    if additional > limit.saturating_sub(output.len()) {
+        return Err(Box::new(OutputLimitExceeded { limit }));
+    }
+    output.try_reserve(additional)?;
+    Ok(())
+}
+
+/// Implements IOTrait::reserve_output for IOs that write to a Vec<u8>
+/// of at most limit bytes. Since this is only a hint, allocation
+/// failures are ignored.
+fn reserve_hint(output: &mut Vec<u8>, additional: usize, limit: usize) {
+    let room = limit.saturating_sub(output.len());
+    let _ = output.try_reserve(std::cmp::min(additional, room));
+}
+
+/// Appends b to output, like reserve_in_vec.
+fn push_output(output: &mut Vec<u8>, b: u8, limit: usize) -> BoxResult<()> {
+    reserve_in_vec(output, 1, limit)?;
+    output.push(b);
+    Ok(())
+}
+
 pub struct SliceToVecIO<'a> {
     input: &'a [u8],
     output: &'a mut Vec::<u8>,
     inpos: usize,
+    output_limit: usize,
 }
 
 impl<'a> SliceToVecIO<'a> {
     pub fn new(input: &'a [u8], output: &'a mut Vec::<u8>) -> SliceToVecIO<'a> {
         SliceToVecIO {
-            input: input,
-            output: output,
+            input,
+            output,
             inpos: 0,
+            output_limit: usize::MAX,
         }
     }
+
+    /// Makes writes that would grow the output beyond limit bytes fail
+    /// with OutputLimitExceeded. There is no limit by default.
+    pub fn set_output_limit(&mut self, limit: usize) {
+        self.output_limit = limit;
+    }
+}
+
+/// Copies count bytes of input, starting at *inpos, to output, which
+/// may grow to at most limit bytes.
+fn copy_slice(input: &[u8], inpos: &mut usize, output: &mut Vec<u8>, count: usize,
+              limit: usize) -> BoxResult<()> {
+    let newpos = *inpos + count;
+    if newpos > input.len() {
+        return Err("end of input while copying bytes".into());
+    }
+    reserve_in_vec(output, count, limit)?;
+    output.extend_from_slice(&input[*inpos..newpos]);
+    *inpos = newpos;
+    Ok(())
+}
+
+/// Returns up to n bytes of input starting at inpos.
+fn lookahead_in_slice(input: &[u8], inpos: usize, n: usize) -> &[u8] {
+    &input[inpos..std::cmp::min(inpos.saturating_add(n), input.len())]
+}
+
+/// Returns the byte of input at *inpos, if any, and advances *inpos.
+fn next_in_slice(input: &[u8], inpos: &mut usize) -> Option<u8> {
+    let b = input.get(*inpos).copied();
+    if b.is_some() {
+        *inpos += 1;
+    }
+    b
+}
+
+/// Appends count bytes to output, starting dist bytes before the last
+/// one. output may grow to at most limit bytes.
+fn repeat_in_vec(output: &mut Vec<u8>, count: usize, dist: usize, limit: usize)
+                 -> BoxResult<()> {
+    if dist >= output.len() {
+        return Err(format!("distance {} is before the start of the output", dist + 1).into());
+    }
+    reserve_in_vec(output, count, limit)?;
+    let start = output.len() - 1 - dist;
+    // If the copy overlaps its source, the bytes from start on repeat
+    // with a period of dist + 1, so copying what is there already keeps
+    // that up, and doubles the amount that can be copied next time.
+    let mut remaining = count;
+    while remaining > 0 {
+        let n = std::cmp::min(remaining, output.len() - start);
+        output.extend_from_within(start..start + n);
+        remaining -= n;
+    }
+    Ok(())
 }
 
 impl IOTrait for SliceToVecIO<'_> {
     fn copy_bytes(&mut self, count: usize) -> BoxResult<()> {
-        let newpos = self.inpos + count;
-        self.output.extend_from_slice(&self.input[self.inpos..newpos]);
-        self.inpos = newpos;
-        Ok(())
+        copy_slice(self.input, &mut self.inpos, self.output, count, self.output_limit)
     }
 
     fn inpos(&self) -> u64 { self.inpos as u64 }
 
     fn next_byte(&mut self) -> BoxResult<Option<u8>> {
-        match self.input.get(self.inpos) {
-            Some(b) => { self.inpos += 1; Ok(Some(*b)) },
-            None => Ok(None),
-        }
+        Ok(next_in_slice(self.input, &mut self.inpos))
     }
 
     fn write_byte(&mut self, b: u8) -> BoxResult<()> {
-        self.output.push(b);
-        Ok(())
+        push_output(self.output, b, self.output_limit)
+    }
+
+    fn reserve_output(&mut self, additional: usize) {
+        reserve_hint(self.output, additional, self.output_limit)
+    }
+
+    fn remaining_input(&self) -> Option<u64> {
+        Some((self.input.len() - self.inpos) as u64)
     }
 }
 
@@ -177,30 +812,2490 @@ impl LookbackInput for SliceToVecIO<'_> {
     fn lookback(&self, pos: u64) -> u8 { self.input[pos as usize] }
 }
 
+impl WindowedLookback for SliceToVecIO<'_> {
+    fn lookback_window(&self) -> u64 { u64::MAX }
+}
+
+impl LookaheadInput for SliceToVecIO<'_> {
+    fn lookahead(&mut self, n: usize) -> BoxResult<&[u8]> {
+        Ok(lookahead_in_slice(self.input, self.inpos, n))
+    }
+}
+
 impl RepeatOutput for SliceToVecIO<'_> {
     fn repeat_bytes(&mut self, count: usize, dist: usize) -> BoxResult<()> {
-        let mut outpos = self.output.len() - 1 - dist;
-        for _ in 0..count {
-            self.output.push(self.output[outpos]);
-            outpos += 1;
+        repeat_in_vec(self.output, count, dist, self.output_limit)
+    }
+}
+
+/// Like SliceToVecIO, but reads input from a sequence of slices, as if
+/// they were concatenated, without concatenating them. This allows data
+/// that is already fragmented, like the contents of a ring buffer or a
+/// rope, to be compressed without copying it.
+pub struct SlicesToVecIO<'a> {
+    /// The non-empty input slices.
+    chunks: Vec<&'a [u8]>,
+    /// Position in the input of the start of each chunk.
+    starts: Vec<u64>,
+    /// Total length of the input.
+    len: u64,
+    /// Index of the chunk that holds the next byte of input.
+    chunk: usize,
+    /// Index of the next byte of input in its chunk.
+    chunk_pos: usize,
+    inpos: u64,
+    /// Holds lookahead that spans more than one chunk.
+    scratch: Vec<u8>,
+    output: &'a mut Vec<u8>,
+    output_limit: usize,
+}
+
+impl<'a> SlicesToVecIO<'a> {
+    /// Creates a SlicesToVecIO that reads the concatenation of chunks.
+    /// Input in a slice of slices can be passed as slices.iter().copied().
+    pub fn new<I>(chunks: I, output: &'a mut Vec<u8>) -> SlicesToVecIO<'a>
+    where I: IntoIterator<Item = &'a [u8]> {
+        let chunks: Vec<&'a [u8]> = chunks.into_iter().filter(|c| !c.is_empty()).collect();
+        let mut starts = Vec::with_capacity(chunks.len());
+        let mut len = 0;
+        for chunk in &chunks {
+            starts.push(len);
+            len += chunk.len() as u64;
+        }
+        SlicesToVecIO {
+            chunks,
+            starts,
+            len,
+            chunk: 0,
+            chunk_pos: 0,
+            inpos: 0,
+            scratch: Vec::new(),
+            output,
+            output_limit: usize::MAX,
+        }
+    }
+
+    /// Makes writes that would grow the output beyond limit bytes fail
+    /// with OutputLimitExceeded. There is no limit by default.
+    pub fn set_output_limit(&mut self, limit: usize) {
+        self.output_limit = limit;
+    }
+
+    /// Moves on to the next chunk if the current one has been read.
+    fn next_chunk(&mut self) {
+        if self.chunk < self.chunks.len() && self.chunk_pos == self.chunks[self.chunk].len() {
+            self.chunk += 1;
+            self.chunk_pos = 0;
         }
-        Ok(())
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl IOTrait for SlicesToVecIO<'_> {
+    fn copy_bytes(&mut self, count: usize) -> BoxResult<()> {
+        if count as u64 > self.len - self.inpos {
+            return Err("end of input while copying bytes".into());
+        }
+        reserve_in_vec(self.output, count, self.output_limit)?;
+        let mut remaining = count;
+        while remaining > 0 {
+            let chunk = &self.chunks[self.chunk][self.chunk_pos..];
+            let n = std::cmp::min(remaining, chunk.len());
+            self.output.extend_from_slice(&chunk[..n]);
+            self.chunk_pos += n;
+            self.next_chunk();
+            remaining -= n;
+        }
+        self.inpos += count as u64;
+        Ok(())
+    }
 
-    #[test]
-    fn bitreader_empty() {
-        let mut input = &b""[..];
-        let mut reader = BitReader::new(&mut input);
-        let r = reader.read_bits(1);
-        assert!(r.is_err());
+    fn inpos(&self) -> u64 { self.inpos }
+
+    fn next_byte(&mut self) -> BoxResult<Option<u8>> {
+        if self.chunk == self.chunks.len() {
+            return Ok(None);
+        }
+        let b = self.chunks[self.chunk][self.chunk_pos];
+        self.chunk_pos += 1;
+        self.next_chunk();
+        self.inpos += 1;
+        Ok(Some(b))
     }
 
-    #[test]
+    fn write_byte(&mut self, b: u8) -> BoxResult<()> {
+        push_output(self.output, b, self.output_limit)
+    }
+
+    fn reserve_output(&mut self, additional: usize) {
+        reserve_hint(self.output, additional, self.output_limit)
+    }
+
+    fn remaining_input(&self) -> Option<u64> { Some(self.len - self.inpos) }
+}
+
+impl LookbackInput for SlicesToVecIO<'_> {
+    fn lookback(&self, pos: u64) -> u8 {
+        // Most lookbacks are close to the current position, so try the
+        // current chunk before searching.
+        let chunk = match self.starts.get(self.chunk) {
+            Some(&start) if start <= pos => self.chunk,
+            _ => self.starts.partition_point(|&start| start <= pos) - 1,
+        };
+        self.chunks[chunk][(pos - self.starts[chunk]) as usize]
+    }
+}
+
+impl WindowedLookback for SlicesToVecIO<'_> {
+    fn lookback_window(&self) -> u64 { u64::MAX }
+}
+
+impl LookaheadInput for SlicesToVecIO<'_> {
+    fn lookahead(&mut self, n: usize) -> BoxResult<&[u8]> {
+        let first = match self.chunks.get(self.chunk) {
+            Some(chunk) => &chunk[self.chunk_pos..],
+            None => return Ok(&[]),
+        };
+        if n <= first.len() {
+            return Ok(&first[..n]);
+        }
+        self.scratch.clear();
+        self.scratch.extend_from_slice(first);
+        for chunk in &self.chunks[self.chunk + 1..] {
+            if self.scratch.len() >= n {
+                break;
+            }
+            let want = std::cmp::min(n - self.scratch.len(), chunk.len());
+            self.scratch.extend_from_slice(&chunk[..want]);
+        }
+        Ok(&self.scratch)
+    }
+}
+
+impl RepeatOutput for SlicesToVecIO<'_> {
+    fn repeat_bytes(&mut self, count: usize, dist: usize) -> BoxResult<()> {
+        repeat_in_vec(self.output, count, dist, self.output_limit)
+    }
+}
+
+/// Error returned by SliceIO when the output slice is full.
+#[derive(Debug)]
+pub struct OutputFull;
+
+impl std::fmt::Display for OutputFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("output buffer is full")
+    }
+}
+
+impl std::error::Error for OutputFull {}
+
+/// Reads input from a slice and writes output to a caller-provided
+/// slice, so that codecs can run without allocating. Writes that do not
+/// fit fail with OutputFull and write nothing.
+pub struct SliceIO<'a> {
+    input: &'a [u8],
+    inpos: usize,
+    output: &'a mut [u8],
+    outpos: usize,
+}
+
+impl<'a> SliceIO<'a> {
+    pub fn new(input: &'a [u8], output: &'a mut [u8]) -> SliceIO<'a> {
+        SliceIO { input, inpos: 0, output, outpos: 0 }
+    }
+
+    /// Returns the number of bytes written to the output.
+    pub fn outpos(&self) -> usize { self.outpos }
+
+    /// Returns an error if count more bytes do not fit in the output.
+    fn check_room(&self, count: usize) -> BoxResult<()> {
+        if count > self.output.len() - self.outpos {
+            return Err(Box::new(OutputFull));
+        }
+        Ok(())
+    }
+}
+
+impl IOTrait for SliceIO<'_> {
+    fn copy_bytes(&mut self, count: usize) -> BoxResult<()> {
+        if count > self.input.len() - self.inpos {
+            return Err("end of input while copying bytes".into());
+        }
+        self.check_room(count)?;
+        self.output[self.outpos..self.outpos + count]
+            .copy_from_slice(&self.input[self.inpos..self.inpos + count]);
+        self.inpos += count;
+        self.outpos += count;
+        Ok(())
+    }
+
+    fn inpos(&self) -> u64 { self.inpos as u64 }
+
+    fn next_byte(&mut self) -> BoxResult<Option<u8>> {
+        Ok(next_in_slice(self.input, &mut self.inpos))
+    }
+
+    fn write_byte(&mut self, b: u8) -> BoxResult<()> {
+        self.check_room(1)?;
+        self.output[self.outpos] = b;
+        self.outpos += 1;
+        Ok(())
+    }
+}
+
+impl LookbackInput for SliceIO<'_> {
+    fn lookback(&self, pos: u64) -> u8 { self.input[pos as usize] }
+}
+
+impl WindowedLookback for SliceIO<'_> {
+    fn lookback_window(&self) -> u64 { u64::MAX }
+}
+
+impl LookaheadInput for SliceIO<'_> {
+    fn lookahead(&mut self, n: usize) -> BoxResult<&[u8]> {
+        Ok(lookahead_in_slice(self.input, self.inpos, n))
+    }
+}
+
+impl RepeatOutput for SliceIO<'_> {
+    fn repeat_bytes(&mut self, count: usize, dist: usize) -> BoxResult<()> {
+        if dist >= self.outpos {
+            return Err(format!("distance {} is before the start of the output", dist + 1).into());
+        }
+        self.check_room(count)?;
+        let start = self.outpos - 1 - dist;
+        for i in 0..count {
+            self.output[self.outpos + i] = self.output[start + i];
+        }
+        self.outpos += count;
+        Ok(())
+    }
+}
+
+/// Reads input from an owned buffer, such as a Vec<u8>, Box<[u8]> or
+/// Arc<[u8]>, and writes output to an owned Vec<u8>. Unlike
+/// SliceToVecIO, this does not borrow anything, so it can be stored and
+/// passed around freely.
+pub struct CursorIO<T> {
+    input: T,
+    inpos: usize,
+    output: Vec<u8>,
+    output_limit: usize,
+}
+
+/// CursorIO over an input Vec<u8>.
+pub type VecIO = CursorIO<Vec<u8>>;
+
+/// CursorIO over a reference-counted input buffer, which can be shared
+/// with other threads without copying it. bytes::Bytes can be used as
+/// input to CursorIO the same way.
+pub type ArcIO = CursorIO<std::sync::Arc<[u8]>>;
+
+impl<T: AsRef<[u8]>> CursorIO<T> {
+    /// Creates a CursorIO that reads input from the start and writes
+    /// output to a new Vec<u8>.
+    pub fn new(input: T) -> CursorIO<T> {
+        CursorIO::with_output(input, Vec::new())
+    }
+
+    /// Creates a CursorIO that reads input from the start and appends
+    /// output to output.
+    pub fn with_output(input: T, output: Vec<u8>) -> CursorIO<T> {
+        CursorIO { input, inpos: 0, output, output_limit: usize::MAX }
+    }
+
+    /// Returns the input.
+    pub fn input(&self) -> &T { &self.input }
+
+    /// Returns the output produced so far.
+    pub fn output(&self) -> &[u8] { &self.output }
+
+    /// Sets the position of the next byte of input to read.
+    pub fn set_inpos(&mut self, pos: usize) {
+        self.inpos = pos;
+    }
+
+    /// Makes writes that would grow the output beyond limit bytes fail
+    /// with OutputLimitExceeded. There is no limit by default.
+    pub fn set_output_limit(&mut self, limit: usize) {
+        self.output_limit = limit;
+    }
+
+    /// Returns the output.
+    pub fn into_output(self) -> Vec<u8> { self.output }
+
+    /// Returns the input and the output.
+    pub fn into_parts(self) -> (T, Vec<u8>) { (self.input, self.output) }
+}
+
+impl<T: AsRef<[u8]>> IOTrait for CursorIO<T> {
+    fn copy_bytes(&mut self, count: usize) -> BoxResult<()> {
+        copy_slice(self.input.as_ref(), &mut self.inpos, &mut self.output, count,
+                   self.output_limit)
+    }
+
+    fn inpos(&self) -> u64 { self.inpos as u64 }
+
+    fn next_byte(&mut self) -> BoxResult<Option<u8>> {
+        Ok(next_in_slice(self.input.as_ref(), &mut self.inpos))
+    }
+
+    fn write_byte(&mut self, b: u8) -> BoxResult<()> {
+        push_output(&mut self.output, b, self.output_limit)
+    }
+
+    fn reserve_output(&mut self, additional: usize) {
+        reserve_hint(&mut self.output, additional, self.output_limit)
+    }
+
+    fn remaining_input(&self) -> Option<u64> {
+        Some((self.input.as_ref().len() - self.inpos) as u64)
+    }
+}
+
+impl<T: AsRef<[u8]>> LookbackInput for CursorIO<T> {
+    fn lookback(&self, pos: u64) -> u8 { self.input.as_ref()[pos as usize] }
+}
+
+impl<T: AsRef<[u8]>> WindowedLookback for CursorIO<T> {
+    fn lookback_window(&self) -> u64 { u64::MAX }
+}
+
+impl<T: AsRef<[u8]>> LookaheadInput for CursorIO<T> {
+    fn lookahead(&mut self, n: usize) -> BoxResult<&[u8]> {
+        Ok(lookahead_in_slice(self.input.as_ref(), self.inpos, n))
+    }
+}
+
+impl<T: AsRef<[u8]>> RepeatOutput for CursorIO<T> {
+    fn repeat_bytes(&mut self, count: usize, dist: usize) -> BoxResult<()> {
+        repeat_in_vec(&mut self.output, count, dist, self.output_limit)
+    }
+}
+
+/// Reads input from a bytes::Bytes and writes output to a
+/// bytes::BytesMut, so that network services can pass reference-counted
+/// buffers through the codecs without copying them. Neither the input
+/// nor the output is copied to get it in or out of a BytesIO.
+#[cfg(feature = "bytes")]
+pub struct BytesIO {
+    input: bytes::Bytes,
+    inpos: usize,
+    output: bytes::BytesMut,
+    output_limit: usize,
+}
+
+#[cfg(feature = "bytes")]
+impl BytesIO {
+    /// Creates a BytesIO that reads input from the start and writes
+    /// output to a new BytesMut.
+    pub fn new(input: bytes::Bytes) -> BytesIO {
+        BytesIO::with_output(input, bytes::BytesMut::new())
+    }
+
+    /// Creates a BytesIO that reads input from the start and appends
+    /// output to output.
+    pub fn with_output(input: bytes::Bytes, output: bytes::BytesMut) -> BytesIO {
+        BytesIO { input, inpos: 0, output, output_limit: usize::MAX }
+    }
+
+    /// Makes writes that would grow the output beyond limit bytes fail
+    /// with OutputLimitExceeded. There is no limit by default.
+    pub fn set_output_limit(&mut self, limit: usize) {
+        self.output_limit = limit;
+    }
+
+    /// Returns the output produced so far.
+    pub fn output(&self) -> &[u8] { &self.output }
+
+    /// Returns the output.
+    pub fn into_output(self) -> bytes::BytesMut { self.output }
+
+    /// Returns the input and the output.
+    pub fn into_parts(self) -> (bytes::Bytes, bytes::BytesMut) { (self.input, self.output) }
+
+    /// Makes room for count more bytes of output.
+    fn reserve(&mut self, count: usize) -> BoxResult<()> {
+        if count > self.output_limit.saturating_sub(self.output.len()) {
+            return Err(Box::new(OutputLimitExceeded { limit: self.output_limit }));
+        }
+        try_reserve_bytes(&mut self.output, count)
+    }
+}
+
+/// Makes room for additional more bytes in output, like
+/// BytesMut::reserve, but returns an error instead of aborting if the
+/// memory cannot be allocated. BytesMut has no try_reserve, so the new
+/// buffer is allocated as a Vec and turned into a BytesMut.
+#[cfg(feature = "bytes")]
+fn try_reserve_bytes(output: &mut bytes::BytesMut, additional: usize) -> BoxResult<()> {
+    if additional <= output.capacity() - output.len() {
+        return Ok(());
+    }
+    let needed = output.len().checked_add(additional).ok_or("output is too large")?;
+    let mut grown = Vec::new();
+    grown.try_reserve_exact(std::cmp::max(needed, 2 * output.capacity()))?;
+    grown.extend_from_slice(output);
+    *output = bytes::Bytes::from(grown).try_into_mut()
+        .expect("a new buffer has no other references");
+    Ok(())
+}
+
+#[cfg(feature = "bytes")]
+impl IOTrait for BytesIO {
+    fn copy_bytes(&mut self, count: usize) -> BoxResult<()> {
+        if count > self.input.len() - self.inpos {
+            return Err("end of input while copying bytes".into());
+        }
+        self.reserve(count)?;
+        self.output.extend_from_slice(&self.input[self.inpos..self.inpos + count]);
+        self.inpos += count;
+        Ok(())
+    }
+
+    fn inpos(&self) -> u64 { self.inpos as u64 }
+
+    fn next_byte(&mut self) -> BoxResult<Option<u8>> {
+        Ok(next_in_slice(&self.input, &mut self.inpos))
+    }
+
+    fn write_byte(&mut self, b: u8) -> BoxResult<()> {
+        self.reserve(1)?;
+        self.output.extend_from_slice(&[b]);
+        Ok(())
+    }
+
+    fn reserve_output(&mut self, additional: usize) {
+        // Since this is only a hint, allocation failures are ignored.
+        let room = self.output_limit.saturating_sub(self.output.len());
+        let _ = try_reserve_bytes(&mut self.output, std::cmp::min(additional, room));
+    }
+
+    fn remaining_input(&self) -> Option<u64> {
+        Some((self.input.len() - self.inpos) as u64)
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl LookbackInput for BytesIO {
+    fn lookback(&self, pos: u64) -> u8 { self.input[pos as usize] }
+}
+
+#[cfg(feature = "bytes")]
+impl WindowedLookback for BytesIO {
+    fn lookback_window(&self) -> u64 { u64::MAX }
+}
+
+#[cfg(feature = "bytes")]
+impl LookaheadInput for BytesIO {
+    fn lookahead(&mut self, n: usize) -> BoxResult<&[u8]> {
+        Ok(lookahead_in_slice(&self.input, self.inpos, n))
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl RepeatOutput for BytesIO {
+    fn repeat_bytes(&mut self, count: usize, dist: usize) -> BoxResult<()> {
+        let len = self.output.len();
+        if dist >= len {
+            return Err(format!("distance {} is before the start of the output", dist + 1).into());
+        }
+        self.reserve(count)?;
+        self.output.resize(len + count, 0);
+        // Copy in chunks that do not overlap their source, like
+        // repeat_in_vec.
+        let start = len - 1 - dist;
+        let mut done = 0;
+        while done < count {
+            let n = std::cmp::min(count - done, len + done - start);
+            self.output.copy_within(start..start + n, len + done);
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+/// Number of bytes read from the input at a time by StreamIO.
+const RING_INPUT_BUFFER: usize = 0x1000;
+
+/// Size of the output buffer used by MmapIO and by StreamIO made with
+/// with_input_history.
+const RING_OUTPUT_BUFFER: usize = 0x1000;
+
+/// Reads up to buf.len() bytes from input, retrying if interrupted.
+/// Returns the number of bytes read, which is 0 at the end of the input.
+fn read_some(input: &mut dyn std::io::Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    loop {
+        match input.read(buf) {
+            Ok(n) => return Ok(n),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Buffers input from a reader, keeping at least the most recent history
+/// bytes so that they can be looked back at.
+struct InputRing {
+    /// The most recent input. The byte at position pos is stored at
+    /// index pos & (ring.len() - 1).
+    ring: Vec<u8>,
+    /// Number of bytes read from input.
+    avail: u64,
+    /// Number of bytes returned by next_byte.
+    inpos: u64,
+}
+
+impl InputRing {
+    /// Creates an InputRing that allows looking back at least history
+    /// bytes. history is rounded up to a power of two.
+    fn new(history: usize) -> InputRing {
+        // Leave room for reading ahead in chunks of RING_INPUT_BUFFER.
+        let size = (history + RING_INPUT_BUFFER).next_power_of_two();
+        InputRing { ring: vec![0; size], avail: 0, inpos: 0 }
+    }
+
+    /// Returns the number of bytes that can be looked back at.
+    fn history(&self) -> u64 {
+        (self.ring.len() - RING_INPUT_BUFFER) as u64
+    }
+
+    fn next_byte(&mut self, input: &mut dyn std::io::Read) -> BoxResult<Option<u8>> {
+        let mask = self.ring.len() - 1;
+        if self.inpos == self.avail {
+            // Read into the part of the ring after avail, which holds the
+            // oldest input, without wrapping around.
+            let start = self.avail as usize & mask;
+            let end = std::cmp::min(start + RING_INPUT_BUFFER, self.ring.len());
+            let n = read_some(input, &mut self.ring[start..end])?;
+            if n == 0 {
+                return Ok(None);
+            }
+            self.avail += n as u64;
+        }
+        let b = self.ring[self.inpos as usize & mask];
+        self.inpos += 1;
+        Ok(Some(b))
+    }
+
+    fn lookback(&self, pos: u64) -> u8 {
+        assert!(pos < self.inpos && self.inpos - pos <= self.history(),
+                "position {} is outside the input window", pos);
+        self.ring[pos as usize & (self.ring.len() - 1)]
+    }
+}
+
+/// Buffers output for a writer, keeping the most recent window bytes so
+/// that they can be repeated.
+struct OutputRing {
+    ring: Vec<u8>,
+    /// Index in ring where the next byte of output will be stored.
+    ringpos: usize,
+    /// Number of bytes of output produced so far.
+    outpos: u64,
+    /// Number of bytes in ring that have not been written to output.
+    pending: usize,
+}
+
+impl OutputRing {
+    /// Creates an OutputRing that allows repeating bytes up to window
+    /// bytes back. window must be at least 1.
+    fn new(window: usize) -> OutputRing {
+        assert!(window > 0, "window must be at least 1 byte");
+        OutputRing { ring: vec![0; window], ringpos: 0, outpos: 0, pending: 0 }
+    }
+
+    /// Writes all output that has not been written yet to output,
+    /// without flushing output.
+    fn drain(&mut self, output: &mut dyn std::io::Write) -> BoxResult<()> {
+        let start = (self.ringpos + self.ring.len() - self.pending) % self.ring.len();
+        if start + self.pending > self.ring.len() {
+            output.write_all(&self.ring[start..])?;
+            output.write_all(&self.ring[..self.ringpos])?;
+        } else {
+            output.write_all(&self.ring[start..start + self.pending])?;
+        }
+        self.pending = 0;
+        Ok(())
+    }
+
+    /// Writes all output that has not been written yet to output and
+    /// flushes it.
+    fn flush(&mut self, output: &mut dyn std::io::Write) -> BoxResult<()> {
+        self.drain(output)?;
+        output.flush()?;
+        Ok(())
+    }
+
+    fn push(&mut self, b: u8, output: &mut dyn std::io::Write) -> BoxResult<()> {
+        if self.pending == self.ring.len() {
+            self.drain(output)?;
+        }
+        self.ring[self.ringpos] = b;
+        self.ringpos += 1;
+        if self.ringpos == self.ring.len() {
+            self.ringpos = 0;
+        }
+        self.outpos += 1;
+        self.pending += 1;
+        Ok(())
+    }
+
+    fn repeat(&mut self, count: usize, dist: usize, output: &mut dyn std::io::Write)
+              -> BoxResult<()> {
+        if dist >= self.ring.len() || dist as u64 >= self.outpos {
+            return Err(format!("distance {} is outside the window", dist + 1).into());
+        }
+        let len = self.ring.len();
+        for _ in 0..count {
+            let src = (self.ringpos + len - 1 - dist) % len;
+            self.push(self.ring[src], output)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads input from a memory-mapped file and writes output to any
+/// std::io::Write. This lets encoders look back at any part of a large
+/// file without first reading all of it into memory. Output is buffered;
+/// call flush or finish when done.
+#[cfg(feature = "memmap")]
+pub struct MmapIO<W> {
+    map: memmap2::Mmap,
+    inpos: usize,
+    output: W,
+    outbuf: OutputRing,
+}
+
+#[cfg(feature = "memmap")]
+impl<W: std::io::Write> MmapIO<W> {
+    /// Maps file for reading as input.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated, by this process or
+    /// any other, while the MmapIO exists. See memmap2::Mmap::map.
+    pub unsafe fn map(file: &std::fs::File, output: W) -> BoxResult<MmapIO<W>> {
+        Ok(MmapIO {
+            map: memmap2::Mmap::map(file)?,
+            inpos: 0,
+            output,
+            outbuf: OutputRing::new(RING_OUTPUT_BUFFER),
+        })
+    }
+
+    /// Writes all buffered output to the writer and flushes it.
+    pub fn flush(&mut self) -> BoxResult<()> {
+        self.outbuf.flush(&mut self.output)
+    }
+
+    /// Flushes the output and returns the writer.
+    pub fn finish(mut self) -> BoxResult<W> {
+        self.flush()?;
+        Ok(self.output)
+    }
+}
+
+#[cfg(feature = "memmap")]
+impl<W: std::io::Write> IOTrait for MmapIO<W> {
+    fn copy_bytes(&mut self, count: usize) -> BoxResult<()> {
+        let newpos = self.inpos + count;
+        if newpos > self.map.len() {
+            return Err("end of input while copying bytes".into());
+        }
+        for i in self.inpos..newpos {
+            self.outbuf.push(self.map[i], &mut self.output)?;
+        }
+        self.inpos = newpos;
+        Ok(())
+    }
+
+    fn inpos(&self) -> u64 { self.inpos as u64 }
+
+    fn next_byte(&mut self) -> BoxResult<Option<u8>> {
+        Ok(next_in_slice(&self.map, &mut self.inpos))
+    }
+
+    fn write_byte(&mut self, b: u8) -> BoxResult<()> {
+        self.outbuf.push(b, &mut self.output)
+    }
+}
+
+#[cfg(feature = "memmap")]
+impl<W: std::io::Write> LookbackInput for MmapIO<W> {
+    fn lookback(&self, pos: u64) -> u8 { self.map[pos as usize] }
+}
+
+#[cfg(feature = "memmap")]
+impl<W: std::io::Write> WindowedLookback for MmapIO<W> {
+    fn lookback_window(&self) -> u64 { u64::MAX }
+}
+
+#[cfg(feature = "memmap")]
+impl<W: std::io::Write> LookaheadInput for MmapIO<W> {
+    fn lookahead(&mut self, n: usize) -> BoxResult<&[u8]> {
+        Ok(lookahead_in_slice(&self.map, self.inpos, n))
+    }
+}
+
+/// Default window size of StreamIO. This is enough for every
+/// backreference the backref format can express.
+pub const DEFAULT_STREAM_WINDOW: usize = 0x10000;
+
+/// Reads input from any std::io::Read and writes output to any
+/// std::io::Write, so that codecs can work on files and sockets as well
+/// as on slices. Input and output are buffered, and the most recent
+/// window bytes of each are kept, so that StreamIO can be used for
+/// encoding (which looks back at the input) as well as for decoding
+/// (which repeats earlier output). Call flush or finish when done to
+/// write any buffered output.
+pub struct StreamIO<R, W> {
+    input: R,
+    inring: InputRing,
+    output: W,
+    outring: OutputRing,
+}
+
+impl<R: std::io::Read, W: std::io::Write> StreamIO<R, W> {
+    /// Creates a StreamIO with a window of DEFAULT_STREAM_WINDOW bytes.
+    pub fn new(input: R, output: W) -> StreamIO<R, W> {
+        StreamIO::with_window(input, output, DEFAULT_STREAM_WINDOW)
+    }
+
+    /// Creates a StreamIO that allows looking back at least window bytes
+    /// of input and repeating up to window bytes of output. window must
+    /// be at least 1. Memory use is fixed by window, no matter how large
+    /// the input and output are: output is written to the writer as the
+    /// window fills up, and input is read in blocks into a ring of about
+    /// the same size.
+    pub fn with_window(input: R, output: W, window: usize) -> StreamIO<R, W> {
+        StreamIO {
+            input,
+            inring: InputRing::new(window),
+            output,
+            outring: OutputRing::new(window),
+        }
+    }
+
+    /// Creates a StreamIO for decoding, which allows repeating bytes up
+    /// to window bytes back but keeps no more input than it reads at a
+    /// time. window must be at least 1.
+    pub fn with_output_window(input: R, output: W, window: usize) -> StreamIO<R, W> {
+        StreamIO {
+            input,
+            inring: InputRing::new(0),
+            output,
+            outring: OutputRing::new(window),
+        }
+    }
+
+    /// Creates a StreamIO for encoding, which allows looking back at
+    /// least history bytes of input, and only buffers output. history is
+    /// rounded up to a power of two. This lets encoders process input of
+    /// any size in bounded memory, as long as they never look back
+    /// further than history bytes.
+    pub fn with_input_history(input: R, output: W, history: usize) -> StreamIO<R, W> {
+        StreamIO {
+            input,
+            inring: InputRing::new(history),
+            output,
+            outring: OutputRing::new(RING_OUTPUT_BUFFER),
+        }
+    }
+
+    /// Writes all buffered output to the writer and flushes it.
+    pub fn flush(&mut self) -> BoxResult<()> {
+        self.outring.flush(&mut self.output)
+    }
+
+    /// Returns the number of bytes of output produced so far.
+    pub fn outpos(&self) -> u64 { self.outring.outpos }
+
+    /// Flushes the output and returns the reader and the writer.
+    pub fn finish(mut self) -> BoxResult<(R, W)> {
+        self.flush()?;
+        Ok((self.input, self.output))
+    }
+}
+
+impl<R: std::io::Read, W: std::io::Write> IOTrait for StreamIO<R, W> {
+    fn copy_bytes(&mut self, count: usize) -> BoxResult<()> {
+        for _ in 0..count {
+            match self.next_byte()? {
+                Some(b) => self.write_byte(b)?,
+                None => return Err("end of input while copying bytes".into()),
+            }
+        }
+        Ok(())
+    }
+
+    fn inpos(&self) -> u64 { self.inring.inpos }
+
+    fn next_byte(&mut self) -> BoxResult<Option<u8>> {
+        self.inring.next_byte(&mut self.input)
+    }
+
+    fn write_byte(&mut self, b: u8) -> BoxResult<()> {
+        self.outring.push(b, &mut self.output)
+    }
+}
+
+impl<R: std::io::Read, W: std::io::Write> LookbackInput for StreamIO<R, W> {
+    fn lookback(&self, pos: u64) -> u8 {
+        self.inring.lookback(pos)
+    }
+}
+
+impl<R: std::io::Read, W: std::io::Write> WindowedLookback for StreamIO<R, W> {
+    fn lookback_window(&self) -> u64 { self.inring.history() }
+}
+
+impl<R: std::io::Read, W: std::io::Write> RepeatOutput for StreamIO<R, W> {
+    fn repeat_bytes(&mut self, count: usize, dist: usize) -> BoxResult<()> {
+        self.outring.repeat(count, dist, &mut self.output)
+    }
+}
+
+/// Error returned by PushIO when a codec reads past the input pushed so
+/// far, before the end of the input has been signaled.
+#[derive(Debug)]
+pub struct NeedsInput;
+
+impl std::fmt::Display for NeedsInput {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("more input is needed")
+    }
+}
+
+impl std::error::Error for NeedsInput {}
+
+/// Result of running a codec with PushIO::run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PushStatus {
+    /// The codec has consumed all input it can; push more input to
+    /// continue.
+    NeedsInput,
+    /// The codec has finished.
+    Done,
+}
+
+/// IO for codecs that are fed input in chunks as it becomes available,
+/// instead of reading it themselves. This allows using codecs with
+/// non-blocking and event-driven sources.
+///
+/// The codec is run as a series of steps by run. A step that runs out of
+/// input fails with NeedsInput; its input and output are rolled back, so
+/// that it can be retried from the start when more input has been
+/// pushed. Steps must therefore only update their own state once they
+/// have read all the input they need. The most recent window bytes of
+/// input and output are kept for LookbackInput and RepeatOutput.
+pub struct PushIO {
+    /// Input from position base onward.
+    input: Vec<u8>,
+    base: u64,
+    inpos: u64,
+    /// Whether finish_input has been called.
+    finished: bool,
+    /// Output, of which the first taken bytes have been returned by
+    /// take_output already.
+    output: Vec<u8>,
+    taken: usize,
+    window: usize,
+    /// Maximum number of bytes of output not taken yet.
+    output_limit: usize,
+}
+
+impl PushIO {
+    /// Creates a PushIO that keeps at least window bytes of input and
+    /// output.
+    pub fn new(window: usize) -> PushIO {
+        PushIO {
+            input: Vec::new(),
+            base: 0,
+            inpos: 0,
+            finished: false,
+            output: Vec::new(),
+            taken: 0,
+            window,
+            output_limit: usize::MAX,
+        }
+    }
+
+    /// Makes writes fail with OutputLimitExceeded if they would leave
+    /// more than limit bytes of output that have not been taken yet.
+    /// There is no limit by default.
+    pub fn set_output_limit(&mut self, limit: usize) {
+        self.output_limit = limit;
+    }
+
+    /// Makes room for count more bytes of output, like reserve_in_vec.
+    fn reserve(&mut self, count: usize) -> BoxResult<()> {
+        let pending = self.output.len() - self.taken;
+        if count > self.output_limit.saturating_sub(pending) {
+            return Err(Box::new(OutputLimitExceeded { limit: self.output_limit }));
+        }
+        self.output.try_reserve(count)?;
+        Ok(())
+    }
+
+    /// Appends data to the input.
+    pub fn push_input(&mut self, data: &[u8]) {
+        assert!(!self.finished, "input pushed after finish_input");
+        self.input.extend_from_slice(data);
+    }
+
+    /// Signals that no more input will be pushed. After this, reading
+    /// past the end of the input returns end of input instead of
+    /// NeedsInput.
+    pub fn finish_input(&mut self) {
+        self.finished = true;
+    }
+
+    /// Returns the output produced since the last call.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        let output = self.output[self.taken..].to_vec();
+        self.taken = self.output.len();
+        self.compact();
+        output
+    }
+
+    /// Calls step until it returns Ok(false), meaning the codec is done,
+    /// or needs more input. Returns any other error step returns.
+    pub fn run<F>(&mut self, mut step: F) -> BoxResult<PushStatus>
+        where F: FnMut(&mut PushIO) -> BoxResult<bool> {
+        loop {
+            let (inpos, outlen) = (self.inpos, self.output.len());
+            match step(self) {
+                Ok(true) => (),
+                Ok(false) => return Ok(PushStatus::Done),
+                Err(e) if e.is::<NeedsInput>() => {
+                    self.inpos = inpos;
+                    self.output.truncate(outlen);
+                    self.compact();
+                    return Ok(PushStatus::NeedsInput);
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Discards input and output that are no longer needed, once there
+    /// is enough of it to be worth moving the rest.
+    fn compact(&mut self) {
+        let consumed = (self.inpos - self.base) as usize;
+        if consumed >= 2 * self.window {
+            let drop = consumed - self.window;
+            self.input.drain(..drop);
+            self.base += drop as u64;
+        }
+        if self.taken >= 2 * self.window {
+            let drop = self.taken - self.window;
+            self.output.drain(..drop);
+            self.taken -= drop;
+        }
+    }
+}
+
+impl IOTrait for PushIO {
+    fn copy_bytes(&mut self, count: usize) -> BoxResult<()> {
+        let start = (self.inpos - self.base) as usize;
+        if start + count > self.input.len() {
+            if self.finished {
+                return Err("end of input while copying bytes".into());
+            }
+            return Err(Box::new(NeedsInput));
+        }
+        self.reserve(count)?;
+        self.output.extend_from_slice(&self.input[start..start + count]);
+        self.inpos += count as u64;
+        Ok(())
+    }
+
+    fn inpos(&self) -> u64 { self.inpos }
+
+    fn next_byte(&mut self) -> BoxResult<Option<u8>> {
+        match self.input.get((self.inpos - self.base) as usize) {
+            Some(&b) => {
+                self.inpos += 1;
+                Ok(Some(b))
+            },
+            None if self.finished => Ok(None),
+            None => Err(Box::new(NeedsInput)),
+        }
+    }
+
+    fn write_byte(&mut self, b: u8) -> BoxResult<()> {
+        self.reserve(1)?;
+        self.output.push(b);
+        Ok(())
+    }
+}
+
+impl LookbackInput for PushIO {
+    fn lookback(&self, pos: u64) -> u8 {
+        assert!(pos >= self.base && pos < self.inpos,
+                "position {} is outside the input window", pos);
+        self.input[(pos - self.base) as usize]
+    }
+}
+
+impl WindowedLookback for PushIO {
+    fn lookback_window(&self) -> u64 { self.window as u64 }
+}
+
+/// Returns NeedsInput if fewer than n bytes have been pushed past the
+/// current position and the end of the input has not been signaled.
+impl LookaheadInput for PushIO {
+    fn lookahead(&mut self, n: usize) -> BoxResult<&[u8]> {
+        let start = (self.inpos - self.base) as usize;
+        if self.input.len() - start < n && !self.finished {
+            return Err(Box::new(NeedsInput));
+        }
+        Ok(lookahead_in_slice(&self.input, start, n))
+    }
+}
+
+impl RepeatOutput for PushIO {
+    fn repeat_bytes(&mut self, count: usize, dist: usize) -> BoxResult<()> {
+        self.reserve(count)?;
+        repeat_in_vec(&mut self.output, count, dist, usize::MAX)
+    }
+}
+
+/// Decoders that are fed their input in chunks, like backref::PushDecoder.
+/// DecodeReader turns any of these into a std::io::Read.
+pub trait PushDecode {
+    /// Decodes as much of the input pushed so far, plus data, as
+    /// possible.
+    fn push_input(&mut self, data: &[u8]) -> BoxResult<PushStatus>;
+
+    /// Signals the end of the input and decodes the rest of it.
+    fn finish(&mut self) -> BoxResult<()>;
+
+    /// Returns the output produced since the last call.
+    fn take_output(&mut self) -> Vec<u8>;
+}
+
+/// Number of bytes of compressed input DecodeReader reads at a time.
+const DECODE_READER_BUFFER: usize = 0x2000;
+
+/// Wraps a compressed source and decompresses it on demand, so that it
+/// can be passed to anything that takes a std::io::Read.
+pub struct DecodeReader<R, D> {
+    input: R,
+    decoder: D,
+    inbuf: Vec<u8>,
+    output: Vec<u8>,
+    output_pos: usize,
+    finished: bool,
+}
+
+impl<R: std::io::Read, D: PushDecode> DecodeReader<R, D> {
+    /// Returns a DecodeReader that decodes input with a default
+    /// constructed decoder.
+    pub fn new(input: R) -> DecodeReader<R, D> where D: Default {
+        DecodeReader::with_decoder(input, D::default())
+    }
+
+    pub fn with_decoder(input: R, decoder: D) -> DecodeReader<R, D> {
+        DecodeReader {
+            input,
+            decoder,
+            inbuf: vec![0; DECODE_READER_BUFFER],
+            output: Vec::new(),
+            output_pos: 0,
+            finished: false,
+        }
+    }
+
+    /// Returns the compressed source.
+    pub fn into_inner(self) -> R { self.input }
+
+    /// Reads input until the decoder produces output or the input ends.
+    fn fill(&mut self) -> BoxResult<()> {
+        while self.output_pos == self.output.len() && !self.finished {
+            let n = read_some(&mut self.input, &mut self.inbuf)?;
+            if n == 0 {
+                self.decoder.finish()?;
+                self.finished = true;
+            } else {
+                self.decoder.push_input(&self.inbuf[..n])?;
+            }
+            self.output = self.decoder.take_output();
+            self.output_pos = 0;
+        }
+        Ok(())
+    }
+}
+
+impl<R: std::io::Read, D: PushDecode> std::io::Read for DecodeReader<R, D> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Err(e) = self.fill() {
+            return Err(match e.downcast::<std::io::Error>() {
+                Ok(e) => *e,
+                Err(e) => std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()),
+            });
+        }
+        let n = std::cmp::min(buf.len(), self.output.len() - self.output_pos);
+        buf[..n].copy_from_slice(&self.output[self.output_pos..self.output_pos + n]);
+        self.output_pos += n;
+        Ok(n)
+    }
+}
+
+/// Writes bits to a caller-provided slice, least significant bit of each
+/// byte first, producing the same bytes as BitWriter. Bits are
+/// collected in a 64-bit accumulator and stored 32 at a time; the final
+/// partial word is stored a byte at a time by flush. Writes that do not
+/// fit fail with OutputFull and write nothing. This is the writing
+/// counterpart of SliceBitReader.
+pub struct SliceBitWriter<'a> {
+    output: &'a mut [u8],
+    /// Number of bytes stored in output.
+    outpos: usize,
+    /// Bits not stored yet, next bit first. Fewer than 32 between calls.
+    bits: u64,
+    have_bits: u32,
+}
+
+impl<'a> SliceBitWriter<'a> {
+    pub fn new(output: &'a mut [u8]) -> SliceBitWriter<'a> {
+        SliceBitWriter { output, outpos: 0, bits: 0, have_bits: 0 }
+    }
+
+    /// Returns the number of bytes stored in the output. Call flush
+    /// first to include the final partial word.
+    pub fn outpos(&self) -> usize { self.outpos }
+
+    /// Returns an error if nbits more bits would not fit in the output.
+    fn check_room(&self, nbits: u32) -> BoxResult<()> {
+        let bytes = (self.have_bits + nbits).div_ceil(8) as usize;
+        if bytes > self.output.len() - self.outpos {
+            return Err(Box::new(OutputFull));
+        }
+        Ok(())
+    }
+
+    /// Stores a word if 32 or more bits are pending.
+    fn store_word(&mut self) {
+        if self.have_bits >= 32 {
+            self.output[self.outpos..self.outpos + 4]
+                .copy_from_slice(&(self.bits as u32).to_le_bytes());
+            self.outpos += 4;
+            self.bits >>= 32;
+            self.have_bits -= 32;
+        }
+    }
+}
+
+impl WriteBits for SliceBitWriter<'_> {
+    fn flush(&mut self) -> BoxResult<()> {
+        self.align_to_byte()?;
+        while self.have_bits > 0 {
+            self.output[self.outpos] = self.bits as u8;
+            self.outpos += 1;
+            self.bits >>= 8;
+            self.have_bits -= 8;
+        }
+        Ok(())
+    }
+
+    fn write_bits(&mut self, bits: u32, nbits: u8) -> BoxResult<()> {
+        assert!(nbits <= 32, "cannot write more than 32 bits at once");
+        self.check_room(nbits as u32)?;
+        self.bits |= (bits as u64 & ((1u64 << nbits) - 1)) << self.have_bits;
+        self.have_bits += nbits as u32;
+        self.store_word();
+        Ok(())
+    }
+
+    fn write_bits64(&mut self, bits: u64, nbits: u8) -> BoxResult<()> {
+        assert!(nbits <= 64, "cannot write more than 64 bits at once");
+        if nbits <= 32 {
+            return self.write_bits(bits as u32, nbits);
+        }
+        // Check first, so that nothing is written if the high half does
+        // not fit.
+        self.check_room(nbits as u32)?;
+        self.write_bits(bits as u32, 32)?;
+        self.write_bits((bits >> 32) as u32, nbits - 32)
+    }
+
+    fn align_to_byte(&mut self) -> BoxResult<()> {
+        self.have_bits = (self.have_bits + 7) & !7;
+        self.store_word();
+        Ok(())
+    }
+}
+
+/// Returns a mask of the nbits (at most 64) least significant bits.
+fn low_bits(nbits: u32) -> u64 {
+    if nbits >= 64 { u64::MAX } else { (1u64 << nbits) - 1 }
+}
+
+/// Bits kept in memory, least significant bit of each byte first, that
+/// can be written with WriteBits and read back with ReadBits. Unlike
+/// the bytes written by a BitWriter, it keeps track of its exact length
+/// in bits, so that bit streams can be produced in one place and
+/// appended to another without padding. Reads start at the first bit
+/// and do not remove bits; see discard_read. Both traits have an
+/// align_to_byte method, so it has to be called as
+/// WriteBits::align_to_byte(&mut bits) or ReadBits::align_to_byte(&mut bits).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BitVec {
+    /// The bits. Bits past len are 0.
+    bytes: Vec<u8>,
+    /// Number of bits in bytes.
+    len: u64,
+    /// Position in bytes of the next bit to read.
+    pos: u64,
+    /// Number of bits that have been read and discarded.
+    discarded: u64,
+}
+
+impl BitVec {
+    pub fn new() -> BitVec {
+        BitVec::default()
+    }
+
+    /// Returns the number of bits held, which does not include discarded
+    /// bits.
+    pub fn len(&self) -> u64 { self.len }
+
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    /// Returns the number of bits that have not been read yet.
+    pub fn remaining_bits(&self) -> u64 { self.len - self.pos }
+
+    /// Returns the bits held, padded with 0 bits to a whole number of
+    /// bytes.
+    pub fn as_bytes(&self) -> &[u8] { &self.bytes }
+
+    /// Continues reading at the first bit held.
+    pub fn rewind(&mut self) {
+        self.pos = 0;
+    }
+
+    /// Removes all bits and resets the read position.
+    pub fn clear(&mut self) {
+        *self = BitVec { bytes: std::mem::take(&mut self.bytes), ..BitVec::default() };
+        self.bytes.clear();
+    }
+
+    /// Frees the bytes whose bits have all been read. bit_position still
+    /// counts the discarded bits.
+    pub fn discard_read(&mut self) {
+        let n = self.pos / 8;
+        self.bytes.drain(..n as usize);
+        self.len -= n * 8;
+        self.pos -= n * 8;
+        self.discarded += n * 8;
+    }
+
+    /// Appends the nbits (at most 64) least significant bits of bits.
+    fn push(&mut self, bits: u64, nbits: u32) {
+        let mut bits = bits & low_bits(nbits);
+        let mut nbits = nbits;
+        while nbits > 0 {
+            let offset = (self.len % 8) as u32;
+            if offset == 0 {
+                self.bytes.push(0);
+            }
+            let take = std::cmp::min(8 - offset, nbits);
+            *self.bytes.last_mut().unwrap() |= ((bits & low_bits(take)) << offset) as u8;
+            bits >>= take;
+            nbits -= take;
+            self.len += take as u64;
+        }
+    }
+
+    /// Returns nbits (at most 64) bits starting at bit pos. Bits past
+    /// the end are 0.
+    fn get(&self, pos: u64, nbits: u32) -> u64 {
+        let mut result = 0;
+        let mut got = 0;
+        while got < nbits {
+            let p = pos + got as u64;
+            let byte = self.bytes.get((p / 8) as usize).copied().unwrap_or(0) as u64;
+            let offset = (p % 8) as u32;
+            let take = std::cmp::min(8 - offset, nbits - got);
+            result |= ((byte >> offset) & low_bits(take)) << got;
+            got += take;
+        }
+        result
+    }
+
+    /// Consumes and returns nbits bits, at most 64.
+    fn take(&mut self, nbits: u32) -> BitResult<u64> {
+        let remaining = self.remaining_bits();
+        if nbits as u64 > remaining {
+            return Err(BitReadError::UnexpectedEof { bits_needed: nbits - remaining as u32 });
+        }
+        let bits = self.get(self.pos, nbits);
+        self.pos += nbits as u64;
+        Ok(bits)
+    }
+}
+
+impl WriteBits for BitVec {
+    /// Does nothing, since the bits are kept in memory.
+    fn flush(&mut self) -> BoxResult<()> { Ok(()) }
+
+    fn write_bits(&mut self, bits: u32, nbits: u8) -> BoxResult<()> {
+        self.push(bits as u64, nbits as u32);
+        Ok(())
+    }
+
+    fn write_bits64(&mut self, bits: u64, nbits: u8) -> BoxResult<()> {
+        self.push(bits, nbits as u32);
+        Ok(())
+    }
+
+    fn align_to_byte(&mut self) -> BoxResult<()> {
+        self.push(0, ((8 - self.len % 8) % 8) as u32);
+        Ok(())
+    }
+}
+
+impl ReadBits for BitVec {
+    fn read_bits(&mut self, nbits: u32) -> BitResult<u32> {
+        assert!(nbits <= 32, "cannot read more than 32 bits at once");
+        Ok(self.take(nbits)? as u32)
+    }
+
+    fn try_read_bits(&mut self, nbits: u32) -> BitResult<Option<u32>> {
+        if self.remaining_bits() == 0 && nbits > 0 {
+            return Ok(None);
+        }
+        self.read_bits(nbits).map(Some)
+    }
+
+    fn peek_bits(&mut self, nbits: u32) -> BitResult<u32> {
+        assert!(nbits <= 32, "cannot peek at more than 32 bits at once");
+        Ok(self.get(self.pos, nbits) as u32)
+    }
+
+    fn bit_position(&self) -> u64 { self.discarded + self.pos }
+
+    fn read_bits64(&mut self, nbits: u32) -> BitResult<u64> {
+        assert!(nbits <= 64, "cannot read more than 64 bits at once");
+        self.take(nbits)
+    }
+}
+
+/// WriteBits implementation that only counts the bits written to it, so
+/// that encoders can compute the exact size of their output without
+/// producing it. Padding added by align_to_byte and flush is counted as
+/// well, so bytes returns what the same calls on a BitWriter would
+/// write.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BitCounter {
+    bits: u64,
+}
+
+impl BitCounter {
+    pub fn new() -> BitCounter {
+        BitCounter { bits: 0 }
+    }
+
+    /// Returns the number of bits written so far.
+    pub fn bits(&self) -> u64 { self.bits }
+
+    /// Returns the number of bytes needed to hold the bits written so far.
+    pub fn bytes(&self) -> u64 { self.bits.div_ceil(8) }
+}
+
+impl WriteBits for BitCounter {
+    fn flush(&mut self) -> BoxResult<()> {
+        self.align_to_byte()
+    }
+
+    fn write_bits(&mut self, _bits: u32, nbits: u8) -> BoxResult<()> {
+        self.bits += nbits as u64;
+        Ok(())
+    }
+
+    fn write_bits64(&mut self, _bits: u64, nbits: u8) -> BoxResult<()> {
+        self.bits += nbits as u64;
+        Ok(())
+    }
+
+    fn align_to_byte(&mut self) -> BoxResult<()> {
+        self.bits = self.bytes() * 8;
+        Ok(())
+    }
+}
+
+/// Error returned by LimitedInput when a codec reads more input than
+/// allowed.
+#[derive(Debug)]
+pub struct InputLimitExceeded {
+    pub limit: u64,
+}
+
+impl std::fmt::Display for InputLimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "input exceeds the limit of {} bytes", self.limit)
+    }
+}
+
+impl std::error::Error for InputLimitExceeded {}
+
+/// Wraps an IO and fails with InputLimitExceeded when the codec tries to
+/// read more than limit bytes of input, counted from where the inner IO
+/// is when wrapped. Reaching the end of the input exactly at the limit
+/// is not an error.
+pub struct LimitedInput<T> {
+    inner: T,
+    start: u64,
+    limit: u64,
+}
+
+impl<T: IOTrait> LimitedInput<T> {
+    pub fn new(inner: T, limit: u64) -> LimitedInput<T> {
+        let start = inner.inpos();
+        LimitedInput { inner, start, limit }
+    }
+
+    pub fn get_ref(&self) -> &T { &self.inner }
+
+    pub fn get_mut(&mut self) -> &mut T { &mut self.inner }
+
+    pub fn into_inner(self) -> T { self.inner }
+
+    /// Returns the number of bytes that may still be read. next_byte
+    /// reads one byte past the limit to tell whether the input ends
+    /// there, so the inner IO can be ahead of the limit.
+    fn remaining(&self) -> u64 {
+        self.limit.saturating_sub(self.inner.inpos() - self.start)
+    }
+
+    fn exceeded(&self) -> Box<dyn std::error::Error> {
+        Box::new(InputLimitExceeded { limit: self.limit })
+    }
+}
+
+impl<T: IOTrait> IOTrait for LimitedInput<T> {
+    fn copy_bytes(&mut self, count: usize) -> BoxResult<()> {
+        if count as u64 > self.remaining() {
+            return Err(self.exceeded());
+        }
+        self.inner.copy_bytes(count)
+    }
+
+    fn inpos(&self) -> u64 { self.inner.inpos() }
+
+    fn next_byte(&mut self) -> BoxResult<Option<u8>> {
+        if self.remaining() == 0 {
+            return match self.inner.next_byte()? {
+                Some(_) => Err(self.exceeded()),
+                None => Ok(None),
+            };
+        }
+        self.inner.next_byte()
+    }
+
+    fn write_byte(&mut self, b: u8) -> BoxResult<()> {
+        self.inner.write_byte(b)
+    }
+
+    fn reserve_output(&mut self, additional: usize) {
+        self.inner.reserve_output(additional)
+    }
+
+    fn remaining_input(&self) -> Option<u64> {
+        let remaining = self.remaining();
+        Some(self.inner.remaining_input().map_or(remaining, |n| n.min(remaining)))
+    }
+}
+
+impl<T: LookbackInput> LookbackInput for LimitedInput<T> {
+    fn lookback(&self, pos: u64) -> u8 { self.inner.lookback(pos) }
+}
+
+impl<T: WindowedLookback> WindowedLookback for LimitedInput<T> {
+    fn lookback_window(&self) -> u64 { self.inner.lookback_window() }
+}
+
+impl<T: RepeatOutput> RepeatOutput for LimitedInput<T> {
+    fn repeat_bytes(&mut self, count: usize, dist: usize) -> BoxResult<()> {
+        self.inner.repeat_bytes(count, dist)
+    }
+}
+
+/// Wraps an IO and fails with OutputLimitExceeded, without writing
+/// anything, when a write would make the codec produce more than limit
+/// bytes of output through it. This protects decoders against input
+/// that expands to huge amounts of output.
+pub struct LimitedOutput<T> {
+    inner: T,
+    written: usize,
+    limit: usize,
+}
+
+impl<T> LimitedOutput<T> {
+    pub fn new(inner: T, limit: usize) -> LimitedOutput<T> {
+        LimitedOutput { inner, written: 0, limit }
+    }
+
+    /// Returns the number of bytes written so far.
+    pub fn written(&self) -> usize { self.written }
+
+    pub fn get_ref(&self) -> &T { &self.inner }
+
+    pub fn get_mut(&mut self) -> &mut T { &mut self.inner }
+
+    pub fn into_inner(self) -> T { self.inner }
+
+    /// Accounts for count more bytes of output, or returns an error if
+    /// they would exceed the limit.
+    fn add(&mut self, count: usize) -> BoxResult<()> {
+        if count > self.limit - self.written {
+            return Err(Box::new(OutputLimitExceeded { limit: self.limit }));
+        }
+        self.written += count;
+        Ok(())
+    }
+}
+
+impl<T: IOTrait> IOTrait for LimitedOutput<T> {
+    fn copy_bytes(&mut self, count: usize) -> BoxResult<()> {
+        self.add(count)?;
+        self.inner.copy_bytes(count)
+    }
+
+    fn inpos(&self) -> u64 { self.inner.inpos() }
+
+    fn next_byte(&mut self) -> BoxResult<Option<u8>> {
+        self.inner.next_byte()
+    }
+
+    fn write_byte(&mut self, b: u8) -> BoxResult<()> {
+        self.add(1)?;
+        self.inner.write_byte(b)
+    }
+
+    fn reserve_output(&mut self, additional: usize) {
+        self.inner.reserve_output(std::cmp::min(additional, self.limit - self.written))
+    }
+
+    fn remaining_input(&self) -> Option<u64> { self.inner.remaining_input() }
+}
+
+impl<T: LookbackInput> LookbackInput for LimitedOutput<T> {
+    fn lookback(&self, pos: u64) -> u8 { self.inner.lookback(pos) }
+}
+
+impl<T: WindowedLookback> WindowedLookback for LimitedOutput<T> {
+    fn lookback_window(&self) -> u64 { self.inner.lookback_window() }
+}
+
+impl<T: RepeatOutput> RepeatOutput for LimitedOutput<T> {
+    fn repeat_bytes(&mut self, count: usize, dist: usize) -> BoxResult<()> {
+        self.add(count)?;
+        self.inner.repeat_bytes(count, dist)
+    }
+}
+
+/// Wraps an IO that does not keep its input, such as a StreamIO made
+/// with with_output_window, and keeps the most recent window bytes of
+/// input read through it in a ring buffer, so that encoders can look
+/// back at them. Input copied with copy_bytes goes through the ring a
+/// byte at a time.
+pub struct LookbackRing<T> {
+    inner: T,
+    /// The byte at position pos is stored at index pos & (ring.len() - 1).
+    ring: Vec<u8>,
+    window: usize,
+}
+
+impl<T: IOTrait> LookbackRing<T> {
+    /// Creates a LookbackRing that allows looking back window bytes.
+    /// Only input read after this can be looked back at.
+    pub fn new(inner: T, window: usize) -> LookbackRing<T> {
+        assert!(window > 0, "window must be at least 1 byte");
+        LookbackRing { inner, ring: vec![0; window.next_power_of_two()], window }
+    }
+
+    pub fn get_ref(&self) -> &T { &self.inner }
+
+    pub fn get_mut(&mut self) -> &mut T { &mut self.inner }
+
+    pub fn into_inner(self) -> T { self.inner }
+}
+
+impl<T: IOTrait> IOTrait for LookbackRing<T> {
+    fn copy_bytes(&mut self, count: usize) -> BoxResult<()> {
+        for _ in 0..count {
+            match self.next_byte()? {
+                Some(b) => self.inner.write_byte(b)?,
+                None => return Err("end of input while copying bytes".into()),
+            }
+        }
+        Ok(())
+    }
+
+    fn inpos(&self) -> u64 { self.inner.inpos() }
+
+    fn next_byte(&mut self) -> BoxResult<Option<u8>> {
+        let b = self.inner.next_byte()?;
+        if let Some(b) = b {
+            let mask = self.ring.len() - 1;
+            self.ring[(self.inner.inpos() - 1) as usize & mask] = b;
+        }
+        Ok(b)
+    }
+
+    fn write_byte(&mut self, b: u8) -> BoxResult<()> {
+        self.inner.write_byte(b)
+    }
+
+    fn reserve_output(&mut self, additional: usize) {
+        self.inner.reserve_output(additional)
+    }
+
+    fn remaining_input(&self) -> Option<u64> { self.inner.remaining_input() }
+}
+
+impl<T: IOTrait> LookbackInput for LookbackRing<T> {
+    fn lookback(&self, pos: u64) -> u8 {
+        let inpos = self.inner.inpos();
+        assert!(pos < inpos && inpos - pos <= self.window as u64,
+                "position {} is outside the input window", pos);
+        self.ring[pos as usize & (self.ring.len() - 1)]
+    }
+}
+
+impl<T: IOTrait> WindowedLookback for LookbackRing<T> {
+    fn lookback_window(&self) -> u64 { self.window as u64 }
+}
+
+impl<T: RepeatOutput> RepeatOutput for LookbackRing<T> {
+    fn repeat_bytes(&mut self, count: usize, dist: usize) -> BoxResult<()> {
+        self.inner.repeat_bytes(count, dist)
+    }
+}
+
+/// Wraps an IO and also writes all output produced through it to a
+/// second sink, for example a file for logging, or a checksum. The last
+/// window bytes of output are kept, so that repeated bytes can be sent to
+/// the sink too. Bytes are written to the sink one at a time; wrap it in
+/// a std::io::BufWriter if that is slow.
+pub struct TeeIO<T, S> {
+    inner: T,
+    sink: S,
+    window: Vec<u8>,
+    outpos: usize,
+}
+
+impl<T, S: std::io::Write> TeeIO<T, S> {
+    /// Creates a TeeIO that allows repeating bytes up to window bytes
+    /// back. window must be at least 1.
+    pub fn new(inner: T, sink: S, window: usize) -> TeeIO<T, S> {
+        assert!(window > 0, "window must be at least 1 byte");
+        TeeIO { inner, sink, window: vec![0; window], outpos: 0 }
+    }
+
+    pub fn sink(&self) -> &S { &self.sink }
+
+    pub fn sink_mut(&mut self) -> &mut S { &mut self.sink }
+
+    /// Returns the wrapped IO and the sink.
+    pub fn into_parts(self) -> (T, S) { (self.inner, self.sink) }
+
+    fn tee(&mut self, b: u8) -> BoxResult<()> {
+        self.sink.write_all(std::slice::from_ref(&b))?;
+        let len = self.window.len();
+        self.window[self.outpos % len] = b;
+        self.outpos += 1;
+        Ok(())
+    }
+}
+
+impl<T: IOTrait, S: std::io::Write> IOTrait for TeeIO<T, S> {
+    fn copy_bytes(&mut self, count: usize) -> BoxResult<()> {
+        for _ in 0..count {
+            let b = self.inner.next_byte()?.ok_or("end of input while copying bytes")?;
+            self.write_byte(b)?;
+        }
+        Ok(())
+    }
+
+    fn inpos(&self) -> u64 { self.inner.inpos() }
+
+    fn next_byte(&mut self) -> BoxResult<Option<u8>> { self.inner.next_byte() }
+
+    fn write_byte(&mut self, b: u8) -> BoxResult<()> {
+        self.inner.write_byte(b)?;
+        self.tee(b)
+    }
+
+    fn reserve_output(&mut self, additional: usize) {
+        self.inner.reserve_output(additional)
+    }
+
+    fn remaining_input(&self) -> Option<u64> { self.inner.remaining_input() }
+}
+
+impl<T: LookbackInput, S> LookbackInput for TeeIO<T, S> {
+    fn lookback(&self, pos: u64) -> u8 { self.inner.lookback(pos) }
+}
+
+impl<T: WindowedLookback, S> WindowedLookback for TeeIO<T, S> {
+    fn lookback_window(&self) -> u64 { self.inner.lookback_window() }
+}
+
+impl<T: RepeatOutput, S: std::io::Write> RepeatOutput for TeeIO<T, S> {
+    fn repeat_bytes(&mut self, count: usize, dist: usize) -> BoxResult<()> {
+        if dist >= self.window.len() {
+            return Err(format!("distance {} is outside the window", dist + 1).into());
+        }
+        // Output the wrapped IO had before it was wrapped never went
+        // through the window, so it cannot be sent to the sink.
+        if dist >= self.outpos {
+            return Err(format!("distance {} is before the start of the teed output",
+                               dist + 1).into());
+        }
+        self.inner.repeat_bytes(count, dist)?;
+        let len = self.window.len();
+        for _ in 0..count {
+            self.tee(self.window[(self.outpos - 1 - dist) % len])?;
+        }
+        Ok(())
+    }
+}
+
+/// Writer that writes everything to two writers. It also implements
+/// WriteBits if both of them do.
+pub struct TeeWriter<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A, B> TeeWriter<A, B> {
+    pub fn new(first: A, second: B) -> TeeWriter<A, B> {
+        TeeWriter { first, second }
+    }
+
+    /// Returns the two writers.
+    pub fn into_parts(self) -> (A, B) { (self.first, self.second) }
+}
+
+impl<A: std::io::Write, B: std::io::Write> std::io::Write for TeeWriter<A, B> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // Write all of buf to both, so that they never disagree about how
+        // much has been written.
+        self.first.write_all(buf)?;
+        self.second.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.first.flush()?;
+        self.second.flush()
+    }
+}
+
+impl<A: WriteBits, B: WriteBits> WriteBits for TeeWriter<A, B> {
+    fn flush(&mut self) -> BoxResult<()> {
+        self.first.flush()?;
+        self.second.flush()
+    }
+
+    fn write_bits(&mut self, bits: u32, nbits: u8) -> BoxResult<()> {
+        self.first.write_bits(bits, nbits)?;
+        self.second.write_bits(bits, nbits)
+    }
+
+    fn write_bits64(&mut self, bits: u64, nbits: u8) -> BoxResult<()> {
+        self.first.write_bits64(bits, nbits)?;
+        self.second.write_bits64(bits, nbits)
+    }
+
+    fn align_to_byte(&mut self) -> BoxResult<()> {
+        self.first.align_to_byte()?;
+        self.second.align_to_byte()
+    }
+}
+
+/// Number of bytes sent through a pipe at a time.
+const PIPE_CHUNK: usize = 0x4000;
+
+/// Number of chunks a pipe holds before the writer has to wait.
+const PIPE_CHUNKS: usize = 4;
+
+/// Writing end of a pipe. Data is sent to the reader in chunks, when a
+/// chunk is full, on flush, and when the PipeWriter is dropped.
+pub struct PipeWriter {
+    sender: std::sync::mpsc::SyncSender<Vec<u8>>,
+    chunk: Vec<u8>,
+}
+
+/// Reading end of a pipe. Reads return end of input once the PipeWriter
+/// has been dropped and everything it sent has been read.
+pub struct PipeReader {
+    receiver: std::sync::mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+/// Creates a pipe through which the output of one codec can be fed to
+/// another as it is produced, with a bounded amount of data in between.
+/// The two ends are meant to be used from different threads; see
+/// pipeline.
+pub fn pipe() -> (PipeWriter, PipeReader) {
+    let (sender, receiver) = std::sync::mpsc::sync_channel(PIPE_CHUNKS);
+    (PipeWriter { sender, chunk: Vec::with_capacity(PIPE_CHUNK) },
+     PipeReader { receiver, chunk: Vec::new(), pos: 0 })
+}
+
+impl PipeWriter {
+    fn send_chunk(&mut self) -> std::io::Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.chunk, Vec::with_capacity(PIPE_CHUNK));
+        self.sender.send(chunk).map_err(|_| std::io::Error::new(
+            std::io::ErrorKind::BrokenPipe, "pipe reader was dropped"))
+    }
+}
+
+impl std::io::Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = std::cmp::min(buf.len(), PIPE_CHUNK - self.chunk.len());
+        self.chunk.extend_from_slice(&buf[..n]);
+        if self.chunk.len() == PIPE_CHUNK {
+            self.send_chunk()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.send_chunk()
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        // There is no one to report an error to; the reader will see the
+        // data end early.
+        let _ = self.send_chunk();
+    }
+}
+
+impl std::io::Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                },
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = std::cmp::min(buf.len(), self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Runs two codec stages, feeding the output of first to the input of
+/// second through a pipe. first runs on a separate thread, so that
+/// neither stage needs to materialize all of its data. Returns the
+/// result of second, or an error if either stage failed. first stops
+/// with a broken pipe if second returns before reading all its input.
+pub fn pipeline<F, G, T>(first: F, second: G) -> BoxResult<T>
+    where F: FnOnce(&mut PipeWriter) -> BoxResult<()> + Send,
+          G: FnOnce(&mut PipeReader) -> BoxResult<T> {
+    let (mut writer, mut reader) = pipe();
+    std::thread::scope(|scope| {
+        let handle = scope.spawn(move || {
+            match first(&mut writer) {
+                Ok(()) => Ok(()),
+                // A broken pipe means second is done and has no use for
+                // more input, so this is not an error of its own.
+                Err(e) if e.downcast_ref::<std::io::Error>()
+                    .is_some_and(|e| e.kind() == std::io::ErrorKind::BrokenPipe) => Ok(()),
+                // Errors are not Send, so pass on their messages instead.
+                Err(e) => Err(e.to_string()),
+            }
+        });
+        let result = second(&mut reader);
+        // Let first finish, with an error if it was still writing.
+        drop(reader);
+        let first_result = handle.join().map_err(|_| "first pipeline stage panicked")?;
+        first_result?;
+        result
+    })
+}
+
+/// Default number of bytes PrefetchReader reads at a time.
+const PREFETCH_BUFFER: usize = 0x10000;
+
+/// Options for PrefetchReader.
+#[derive(Clone, Debug)]
+pub struct PrefetchOptions {
+    buffer_size: usize,
+    background: bool,
+}
+
+impl PrefetchOptions {
+    /// Returns the default options: buffers of 64 KiB, read on a
+    /// background thread.
+    pub fn new() -> PrefetchOptions {
+        PrefetchOptions { buffer_size: PREFETCH_BUFFER, background: true }
+    }
+
+    /// Sets the number of bytes read at a time. This is at least 1.
+    pub fn buffer_size(mut self, buffer_size: usize) -> PrefetchOptions {
+        self.buffer_size = std::cmp::max(buffer_size, 1);
+        self
+    }
+
+    /// Sets whether to read on a background thread. Without one, the
+    /// PrefetchReader works like a std::io::BufReader, which is useful
+    /// where threads are not available or not worth it.
+    pub fn background(mut self, background: bool) -> PrefetchOptions {
+        self.background = background;
+        self
+    }
+}
+
+impl Default for PrefetchOptions {
+    fn default() -> PrefetchOptions {
+        PrefetchOptions::new()
+    }
+}
+
+/// Where a PrefetchReader gets its buffers from.
+enum PrefetchSource<R> {
+    /// The reader, read on the calling thread.
+    Inline(R),
+    /// Filled buffers from the background thread, which gets empty
+    /// buffers back through recycle.
+    Thread {
+        receiver: std::sync::mpsc::Receiver<std::io::Result<Vec<u8>>>,
+        recycle: std::sync::mpsc::SyncSender<Vec<u8>>,
+    },
+}
+
+/// Reads ahead from a reader on a background thread while the previous
+/// buffer is being consumed, so that single-threaded codecs do not have
+/// to wait for the disk. Two buffers are used: one being read from and
+/// one being filled. Errors from the reader are returned after all data
+/// read before them.
+pub struct PrefetchReader<R> {
+    source: PrefetchSource<R>,
+    chunk: Vec<u8>,
+    pos: usize,
+    buffer_size: usize,
+}
+
+impl<R: std::io::Read + Send + 'static> PrefetchReader<R> {
+    /// Creates a PrefetchReader with the default options.
+    pub fn new(input: R) -> PrefetchReader<R> {
+        PrefetchReader::with_options(input, &PrefetchOptions::new())
+    }
+
+    /// Creates a PrefetchReader with the given options.
+    pub fn with_options(mut input: R, options: &PrefetchOptions) -> PrefetchReader<R> {
+        let buffer_size = options.buffer_size;
+        let source = if options.background {
+            let (sender, receiver) = std::sync::mpsc::sync_channel(1);
+            let (recycle, recycled) = std::sync::mpsc::sync_channel::<Vec<u8>>(2);
+            recycle.send(Vec::new()).unwrap();
+            std::thread::spawn(move || {
+                // Stops when the PrefetchReader is dropped, which closes
+                // both channels.
+                for mut buf in recycled {
+                    buf.resize(buffer_size, 0);
+                    let result = read_some(&mut input, &mut buf).map(|n| {
+                        buf.truncate(n);
+                        buf
+                    });
+                    let done = !matches!(&result, Ok(buf) if !buf.is_empty());
+                    if sender.send(result).is_err() || done {
+                        break;
+                    }
+                }
+            });
+            PrefetchSource::Thread { receiver, recycle }
+        } else {
+            PrefetchSource::Inline(input)
+        };
+        PrefetchReader { source, chunk: Vec::new(), pos: 0, buffer_size }
+    }
+}
+
+impl<R: std::io::Read> PrefetchReader<R> {
+    /// Replaces the current buffer, which has been read, with the next
+    /// one. An empty buffer means the end of the input.
+    fn next_chunk(&mut self) -> std::io::Result<()> {
+        self.pos = 0;
+        match &mut self.source {
+            PrefetchSource::Inline(input) => {
+                self.chunk.resize(self.buffer_size, 0);
+                let n = read_some(input, &mut self.chunk)?;
+                self.chunk.truncate(n);
+            },
+            PrefetchSource::Thread { receiver, recycle } => {
+                // The thread may have finished, in which case it has no
+                // use for the buffer, and the channel is closed, which
+                // means the end of the input.
+                let _ = recycle.try_send(std::mem::take(&mut self.chunk));
+                if let Ok(result) = receiver.recv() {
+                    self.chunk = result?;
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+impl<R: std::io::Read> std::io::Read for PrefetchReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos == self.chunk.len() && !buf.is_empty() {
+            self.next_chunk()?;
+        }
+        let n = std::cmp::min(buf.len(), self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Histogram of values with power-of-two buckets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Histogram {
+    /// buckets[0] counts values of 0; buckets[i] for i > 0 counts values
+    /// v such that 2**(i - 1) <= v < 2**i.
+    pub buckets: [u64; 65],
+}
+
+impl Histogram {
+    /// Adds value to the histogram.
+    pub fn record(&mut self, value: u64) {
+        self.buckets[(64 - value.leading_zeros()) as usize] += 1;
+    }
+
+    /// Returns the number of values recorded.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram { buckets: [0; 65] }
+    }
+}
+
+/// Counts collected by CountingIO.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IOCounts {
+    /// Bytes consumed by next_byte and copy_bytes.
+    pub bytes_read: u64,
+    /// Bytes produced by write_byte, copy_bytes and repeat_bytes.
+    pub bytes_written: u64,
+    /// Bits consumed by read_bits and read_bits64.
+    pub bits_read: u64,
+    /// Bits produced by write_bits and write_bits64.
+    pub bits_written: u64,
+    pub next_byte_calls: u64,
+    pub write_byte_calls: u64,
+    pub lookback_calls: u64,
+    /// Counts passed to copy_bytes.
+    pub copy_sizes: Histogram,
+    /// Counts passed to repeat_bytes.
+    pub repeat_sizes: Histogram,
+    /// Distances passed to repeat_bytes, counting the most recent byte as
+    /// distance 1.
+    pub repeat_distances: Histogram,
+    /// Numbers of bits requested from read_bits and read_bits64.
+    pub read_widths: Histogram,
+    /// Numbers of bits passed to write_bits and write_bits64.
+    pub write_widths: Histogram,
+}
+
+/// Wraps any of the IO types in this module and counts how it is used,
+/// to measure the compression ratio and access patterns of a codec
+/// without modifying it. Only successful calls are counted.
+pub struct CountingIO<T> {
+    inner: T,
+    counts: IOCounts,
+    /// LookbackInput::lookback takes &self, so its calls are counted
+    /// separately.
+    lookbacks: std::cell::Cell<u64>,
+}
+
+impl<T> CountingIO<T> {
+    pub fn new(inner: T) -> CountingIO<T> {
+        CountingIO { inner, counts: IOCounts::default(), lookbacks: std::cell::Cell::new(0) }
+    }
+
+    /// Returns the counts collected so far.
+    pub fn counts(&self) -> IOCounts {
+        let mut counts = self.counts.clone();
+        counts.lookback_calls = self.lookbacks.get();
+        counts
+    }
+
+    pub fn get_ref(&self) -> &T { &self.inner }
+
+    pub fn get_mut(&mut self) -> &mut T { &mut self.inner }
+
+    pub fn into_inner(self) -> T { self.inner }
+}
+
+impl<T: IOTrait> IOTrait for CountingIO<T> {
+    fn copy_bytes(&mut self, count: usize) -> BoxResult<()> {
+        self.inner.copy_bytes(count)?;
+        self.counts.copy_sizes.record(count as u64);
+        self.counts.bytes_read += count as u64;
+        self.counts.bytes_written += count as u64;
+        Ok(())
+    }
+
+    fn inpos(&self) -> u64 { self.inner.inpos() }
+
+    fn next_byte(&mut self) -> BoxResult<Option<u8>> {
+        let b = self.inner.next_byte()?;
+        self.counts.next_byte_calls += 1;
+        if b.is_some() {
+            self.counts.bytes_read += 1;
+        }
+        Ok(b)
+    }
+
+    fn write_byte(&mut self, b: u8) -> BoxResult<()> {
+        self.inner.write_byte(b)?;
+        self.counts.write_byte_calls += 1;
+        self.counts.bytes_written += 1;
+        Ok(())
+    }
+
+    fn reserve_output(&mut self, additional: usize) {
+        self.inner.reserve_output(additional)
+    }
+
+    fn remaining_input(&self) -> Option<u64> { self.inner.remaining_input() }
+}
+
+impl<T: LookbackInput> LookbackInput for CountingIO<T> {
+    fn lookback(&self, pos: u64) -> u8 {
+        self.lookbacks.set(self.lookbacks.get() + 1);
+        self.inner.lookback(pos)
+    }
+}
+
+impl<T: WindowedLookback> WindowedLookback for CountingIO<T> {
+    fn lookback_window(&self) -> u64 { self.inner.lookback_window() }
+}
+
+impl<T: RepeatOutput> RepeatOutput for CountingIO<T> {
+    fn repeat_bytes(&mut self, count: usize, distance: usize) -> BoxResult<()> {
+        self.inner.repeat_bytes(count, distance)?;
+        self.counts.repeat_sizes.record(count as u64);
+        self.counts.repeat_distances.record(distance as u64 + 1);
+        self.counts.bytes_written += count as u64;
+        Ok(())
+    }
+}
+
+impl<T: ReadBits> ReadBits for CountingIO<T> {
+    fn read_bits(&mut self, nbits: u32) -> BitResult<u32> {
+        let bits = self.inner.read_bits(nbits)?;
+        self.counts.read_widths.record(nbits as u64);
+        self.counts.bits_read += nbits as u64;
+        Ok(bits)
+    }
+
+    fn try_read_bits(&mut self, nbits: u32) -> BitResult<Option<u32>> {
+        let bits = self.inner.try_read_bits(nbits)?;
+        if bits.is_some() {
+            self.counts.read_widths.record(nbits as u64);
+            self.counts.bits_read += nbits as u64;
+        }
+        Ok(bits)
+    }
+
+    fn peek_bits(&mut self, nbits: u32) -> BitResult<u32> {
+        self.inner.peek_bits(nbits)
+    }
+
+    fn bit_position(&self) -> u64 { self.inner.bit_position() }
+
+    fn read_bits64(&mut self, nbits: u32) -> BitResult<u64> {
+        let bits = self.inner.read_bits64(nbits)?;
+        self.counts.read_widths.record(nbits as u64);
+        self.counts.bits_read += nbits as u64;
+        Ok(bits)
+    }
+
+    fn align_to_byte(&mut self) -> BitResult<()> {
+        self.inner.align_to_byte()
+    }
+}
+
+impl<T: WriteBits> WriteBits for CountingIO<T> {
+    fn flush(&mut self) -> BoxResult<()> {
+        self.inner.flush()
+    }
+
+    fn write_bits(&mut self, bits: u32, nbits: u8) -> BoxResult<()> {
+        self.inner.write_bits(bits, nbits)?;
+        self.counts.write_widths.record(nbits as u64);
+        self.counts.bits_written += nbits as u64;
+        Ok(())
+    }
+
+    fn write_bits64(&mut self, bits: u64, nbits: u8) -> BoxResult<()> {
+        self.inner.write_bits64(bits, nbits)?;
+        self.counts.write_widths.record(nbits as u64);
+        self.counts.bits_written += nbits as u64;
+        Ok(())
+    }
+
+    fn align_to_byte(&mut self) -> BoxResult<()> {
+        self.inner.align_to_byte()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_io_window() {
+        let data: Vec<u8> = (0..50000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut input = &data[..];
+        let mut output = Vec::new();
+        let mut io = StreamIO::with_window(&mut input, &mut output, 1000);
+        io.copy_bytes(1000).unwrap();
+        let mut pos = 1000;
+        while io.next_byte().unwrap().is_some() {
+            assert_eq!(io.lookback(pos - 999), data[pos as usize - 999]);
+            pos += 1;
+        }
+        for _ in 0..20 {
+            io.repeat_bytes(1000, 999).unwrap();
+        }
+        assert!(io.repeat_bytes(1, 1000).is_err());
+        io.flush().unwrap();
+        assert_eq!(output.len(), 21000);
+        assert!(output.chunks(1000).all(|chunk| chunk == &data[..1000]));
+    }
+
+    #[test]
+    fn counting_io() {
+        let mut io = CountingIO::new(VecIO::new(b"abcdef".to_vec()));
+        io.copy_bytes(3).unwrap();
+        io.repeat_bytes(4, 2).unwrap();
+        assert!(io.repeat_bytes(1, 10).is_err());
+        assert_eq!(io.next_byte().unwrap(), Some(b'd'));
+        io.write_byte(b'z').unwrap();
+        assert_eq!(io.lookback(1), b'b');
+        let counts = io.counts();
+        assert_eq!((counts.bytes_read, counts.bytes_written), (4, 8));
+        assert_eq!((counts.next_byte_calls, counts.write_byte_calls), (1, 1));
+        assert_eq!(counts.lookback_calls, 1);
+        assert_eq!(counts.copy_sizes.buckets[2], 1);
+        assert_eq!(counts.repeat_sizes.buckets[3], 1);
+        assert_eq!(counts.repeat_distances.buckets[2], 1);
+        assert_eq!(counts.repeat_distances.count(), 1);
+        assert_eq!(io.into_inner().into_output(), b"abcabcaz");
+
+        let mut output = Vec::new();
+        let mut writer = CountingIO::new(BitWriter::new(&mut output));
+        writer.write_bits(5, 3).unwrap();
+        writer.write_bits64(1, 40).unwrap();
+        writer.align_to_byte().unwrap();
+        writer.flush().unwrap();
+        assert_eq!(writer.counts().bits_written, 43);
+        assert_eq!(writer.counts().write_widths.count(), 2);
+        drop(writer);
+        let mut input = &output[..];
+        let mut reader = CountingIO::new(BitReader::new(&mut input));
+        assert_eq!(reader.read_bits(3).unwrap(), 5);
+        assert_eq!(reader.read_bits64(40).unwrap(), 1);
+        assert_eq!(reader.counts().bits_read, 43);
+        assert_eq!(reader.counts().read_widths.buckets[6], 1);
+    }
+
+    #[cfg(feature = "memmap")]
+    #[test]
+    fn mmap_io() {
+        use std::io::Write;
+        let path = std::env::temp_dir().join(format!("mmap-io-test-{}", std::process::id()));
+        let data: Vec<u8> = (0..20000u32).map(|i| (i * 7 % 251) as u8).collect();
+        std::fs::File::create(&path).unwrap().write_all(&data).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        let mut io = unsafe { MmapIO::map(&file, Vec::new()) }.unwrap();
+        io.copy_bytes(10000).unwrap();
+        assert_eq!(io.next_byte().unwrap(), Some(data[10000]));
+        assert_eq!(io.lookback(5), data[5]);
+        assert_eq!(io.lookahead(3).unwrap(), &data[10001..10004]);
+        io.write_byte(b'x').unwrap();
+        assert!(io.copy_bytes(10000).is_err());
+        io.copy_bytes(9999).unwrap();
+        assert_eq!(io.next_byte().unwrap(), None);
+        let output = io.finish().unwrap();
+        assert_eq!(&output[..10000], &data[..10000]);
+        assert_eq!(output[10000], b'x');
+        assert_eq!(&output[10001..], &data[10001..]);
+
+        std::fs::File::create(&path).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        let mut io = unsafe { MmapIO::map(&file, Vec::new()) }.unwrap();
+        assert_eq!(io.next_byte().unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cursor_io() {
+        let mut io = VecIO::new(b"abcd".to_vec());
+        io.copy_bytes(2).unwrap();
+        io.repeat_bytes(3, 1).unwrap();
+        assert!(io.repeat_bytes(1, 5).is_err());
+        assert_eq!(io.next_byte().unwrap(), Some(b'c'));
+        assert_eq!(io.lookback(0), b'a');
+        io.write_byte(b'z').unwrap();
+        assert!(io.copy_bytes(2).is_err());
+        assert_eq!(io.output(), b"ababaz");
+        io.set_inpos(1);
+        io.copy_bytes(3).unwrap();
+        assert_eq!(io.next_byte().unwrap(), None);
+        assert_eq!(io.into_output(), b"ababazbcd");
+
+        let input: std::sync::Arc<[u8]> = b"xy"[..].into();
+        let mut io = CursorIO::with_output(input, b"w".to_vec());
+        io.copy_bytes(2).unwrap();
+        let (input, output) = io.into_parts();
+        assert_eq!(&input[..], b"xy");
+        assert_eq!(output, b"wxy");
+    }
+
+    #[test]
+    fn push_io() {
+        // A codec that copies two-byte records, reversing each.
+        let step = |io: &mut PushIO| -> BoxResult<bool> {
+            let a = match io.next_byte()? {
+                Some(a) => a,
+                None => return Ok(false),
+            };
+            let b = io.next_byte()?.ok_or("odd input length")?;
+            io.write_byte(b)?;
+            io.write_byte(a)?;
+            Ok(true)
+        };
+        let mut io = PushIO::new(4);
+        assert_eq!(io.run(step).unwrap(), PushStatus::NeedsInput);
+        io.push_input(b"abc");
+        assert_eq!(io.run(step).unwrap(), PushStatus::NeedsInput);
+        assert_eq!(io.take_output(), b"ba");
+        assert_eq!(io.inpos(), 2);
+        io.push_input(b"defghijklmnop");
+        assert_eq!(io.run(step).unwrap(), PushStatus::NeedsInput);
+        assert_eq!(io.lookback(13), b'n');
+        io.repeat_bytes(2, 1).unwrap();
+        assert_eq!(io.take_output(), b"dcfehgjilknmpopo");
+        io.push_input(b"qr");
+        io.finish_input();
+        assert_eq!(io.run(step).unwrap(), PushStatus::Done);
+        assert_eq!(io.take_output(), b"rq");
+
+        let mut io = PushIO::new(4);
+        io.push_input(b"abc");
+        io.finish_input();
+        assert!(io.run(step).is_err());
+        assert!(io.copy_bytes(2).is_err());
+    }
+
+    #[test]
+    fn ring_buffer_repeat() {
+        let mut input = &b"abcdefgh"[..];
+        let mut output = Vec::new();
+        let mut io = StreamIO::with_output_window(&mut input, &mut output, 4);
+        io.copy_bytes(3).unwrap();
+        io.repeat_bytes(5, 1).unwrap();
+        assert!(io.repeat_bytes(1, 4).is_err());
+        io.repeat_bytes(2, 3).unwrap();
+        assert_eq!(io.next_byte().unwrap(), Some(b'd'));
+        assert_eq!(io.inpos(), 4);
+        io.write_byte(b'z').unwrap();
+        io.flush().unwrap();
+        assert_eq!(io.outpos(), 11);
+        assert_eq!(output, b"abcbcbcbcbz");
+    }
+
+    #[test]
+    fn ring_input() {
+        let data: Vec<u8> = (0..20000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut input = &data[..];
+        let mut output = Vec::new();
+        let mut io = StreamIO::with_input_history(&mut input, &mut output, 5000);
+        for (pos, &b) in data.iter().enumerate() {
+            assert_eq!(io.next_byte().unwrap(), Some(b));
+            assert_eq!(io.lookback(pos as u64), b);
+            if pos >= 5000 {
+                assert_eq!(io.lookback((pos - 4999) as u64), data[pos - 4999]);
+            }
+        }
+        assert_eq!(io.next_byte().unwrap(), None);
+        io.write_byte(b'x').unwrap();
+        io.flush().unwrap();
+        assert_eq!(output, b"x");
+    }
+
+    #[test]
+    #[should_panic(expected = "outside the input window")]
+    fn ring_input_evicted() {
+        let data = vec![0u8; 10000];
+        let mut input = &data[..];
+        let mut output = Vec::new();
+        let mut io = StreamIO::with_input_history(&mut input, &mut output, 10);
+        while io.next_byte().unwrap().is_some() {}
+        io.lookback(0);
+    }
+
+    #[test]
+    fn ring_buffer_errors() {
+        let mut input = &b"ab"[..];
+        let mut output = Vec::new();
+        let mut io = StreamIO::with_output_window(&mut input, &mut output, 16);
+        assert!(io.repeat_bytes(1, 0).is_err());
+        assert!(io.copy_bytes(3).is_err());
+    }
+
+    #[test]
+    fn stream_io() {
+        let data: Vec<u8> = (0..20000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut io = StreamIO::with_window(&data[..], Vec::new(), 100);
+        io.copy_bytes(5000).unwrap();
+        assert_eq!(io.lookback(4950), data[4950]);
+        io.repeat_bytes(3, 99).unwrap();
+        assert!(io.repeat_bytes(1, 100).is_err());
+        assert_eq!(io.next_byte().unwrap(), Some(data[5000]));
+        while io.next_byte().unwrap().is_some() {}
+        assert_eq!(io.inpos(), 20000);
+        assert!(io.copy_bytes(1).is_err());
+        assert_eq!(io.outpos(), 5003);
+        let (_, output) = io.finish().unwrap();
+        assert_eq!(&output[..5000], &data[..5000]);
+        assert_eq!(&output[5000..], &data[4900..4903]);
+    }
+
+    #[test]
+    fn bitreader_empty() {
+        let mut input = &b""[..];
+        let mut reader = BitReader::new(&mut input);
+        let r = reader.read_bits(1);
+        assert!(r.is_err());
+    }
+
+    #[test]
     fn bitreader() {
         // From least significant to most significant:
         // 0101 0110 1010 0010 1000 0011 0110 1100
@@ -219,6 +3314,892 @@ mod tests {
         assert!(reader.read_bits(1).is_err());        
     }
 
+    #[test]
+    fn bitreader_peek() {
+        let mut input = &b"jE\xc16"[..];
+        let mut reader = BitReader::new(&mut input);
+        assert_eq!(reader.peek_bits(4).unwrap(), 0xa);
+        assert_eq!(reader.peek_bits(10).unwrap(), 0x16a);
+        assert_eq!(reader.bit_position(), 0);
+        assert_eq!(reader.read_bits(7).unwrap(), 0x6a);
+        assert_eq!(reader.bit_position(), 7);
+        assert_eq!(reader.peek_bits(25).unwrap(), 0x36c1456a >> 7);
+        assert_eq!(reader.peek_bits(32).unwrap(), 0x36c1456a >> 7);
+        assert_eq!(reader.read_bits(25).unwrap(), 0x36c1456a >> 7);
+        assert_eq!(reader.bit_position(), 32);
+        assert_eq!(reader.peek_bits(8).unwrap(), 0);
+        assert!(reader.read_bits(1).is_err());
+        assert_eq!(reader.bit_position(), 32);
+    }
+
+    #[test]
+    fn bits64() {
+        let values: &[(u64, u8)] = &[
+            (0x5, 3), (0xfedc_ba98_7654_3210, 64), (0x1_2345_6789, 33), (0, 0),
+            (0x7f, 7), (u64::MAX, 64), (0xabcd, 16),
+        ];
+        let mut output = Vec::new();
+        let mut writer = BitWriter::new(&mut output);
+        for &(bits, nbits) in values {
+            writer.write_bits64(bits, nbits).unwrap();
+        }
+        writer.flush().unwrap();
+        assert_eq!(output.len(), 24);
+        let mut input = &output[..];
+        let mut reader = BitReader::new(&mut input);
+        for &(bits, nbits) in values {
+            assert_eq!(reader.read_bits64(nbits as u32).unwrap(), bits);
+        }
+        assert_eq!(reader.bit_position(), 187);
+        assert!(reader.read_bits64(64).is_err());
+    }
+
+    /// Reader that returns one byte per read, after an interruption,
+    /// and then fails with the given error kind instead of returning
+    /// end of input.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        interrupt: bool,
+        error: std::io::ErrorKind,
+    }
+
+    impl std::io::Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                return Err(std::io::ErrorKind::Interrupted.into());
+            }
+            match self.data.split_first() {
+                Some((&b, rest)) => {
+                    buf[0] = b;
+                    self.data = rest;
+                    Ok(1)
+                },
+                None => Err(self.error.into()),
+            }
+        }
+    }
+
+    /// Nonblocking reader that fails with WouldBlock before each read,
+    /// which then returns at most 3 bytes.
+    struct Nonblocking {
+        input: std::io::Cursor<Vec<u8>>,
+        ready: bool,
+    }
+
+    impl std::io::Read for Nonblocking {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.ready = !self.ready;
+            if !self.ready {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+            let n = std::cmp::min(buf.len(), 3);
+            self.input.read(&mut buf[..n])
+        }
+    }
+
+    impl std::io::Seek for Nonblocking {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.input.seek(pos)
+        }
+    }
+
+    /// Repeats read until it does not fail with WouldBlock.
+    fn retry<T>(mut read: impl FnMut() -> BitResult<T>) -> BitResult<T> {
+        loop {
+            match read() {
+                Err(BitReadError::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock => {},
+                result => return result,
+            }
+        }
+    }
+
+    #[test]
+    fn bitreader_would_block() {
+        let data: Vec<u8> = (0..100u32).map(|i| (i * 37 % 251) as u8).collect();
+        let mut input = Nonblocking { input: std::io::Cursor::new(data.clone()), ready: false };
+        let mut reader = BitReader::new(&mut input);
+        assert_eq!(retry(|| reader.read_bits(4)).unwrap(), (data[0] & 0xf) as u32);
+        assert_eq!(retry(|| reader.read_bits64(60)).unwrap(),
+                   u64::from_le_bytes(data[..8].try_into().unwrap()) >> 4);
+        for &b in &data[8..] {
+            assert_eq!(retry(|| reader.peek_bits(8)).unwrap(), b as u32);
+            assert_eq!(retry(|| reader.read_bits(8)).unwrap(), b as u32);
+        }
+        assert_eq!(retry(|| reader.try_read_bits(8)).unwrap(), None);
+
+        // Seeking just past the bytes buffered after a failed read must
+        // not reuse the buffer.
+        let mut input = Nonblocking { input: std::io::Cursor::new(data.clone()), ready: true };
+        let mut reader = SeekableBitReader::new(&mut input).unwrap();
+        let mut pos = 0;
+        while pos + 6 < data.len() {
+            for &b in &data[pos..pos + 4] {
+                assert_eq!(retry(|| reader.read_bits(8)).unwrap(), b as u32);
+            }
+            pos += 6;
+            retry(|| reader.seek_bits(pos as u64 * 8).map_err(|e| match e.downcast() {
+                Ok(e) => *e,
+                Err(e) => panic!("{}", e),
+            })).unwrap();
+            assert_eq!(retry(|| reader.read_bits(8)).unwrap(), data[pos] as u32);
+            pos += 1;
+        }
+    }
+
+    #[test]
+    fn bitreader_short_reads() {
+        let mut input = Trickle {
+            data: b"jE\xc16", interrupt: false, error: std::io::ErrorKind::BrokenPipe,
+        };
+        let mut reader = BitReader::new(&mut input);
+        assert_eq!(reader.read_bits(7).unwrap(), 0x6a);
+        assert_eq!(reader.read_bits(25).unwrap(), 0x36c1456a >> 7);
+        match reader.read_bits(1).unwrap_err() {
+            BitReadError::Io(e) => assert_eq!(e.kind(), std::io::ErrorKind::BrokenPipe),
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert_eq!(reader.bit_position(), 32);
+    }
+
+    #[test]
+    fn bitreader_large() {
+        let data: Vec<u8> = (0..3 * BIT_READER_BUFFER as u32).map(|i| (i * 7 % 251) as u8)
+            .collect();
+        let mut input = &data[..];
+        let mut reader = BitReader::new(&mut input);
+        for &b in data.iter() {
+            assert_eq!(reader.read_bits(8).unwrap(), b as u32);
+        }
+        assert!(matches!(reader.read_bits(1).unwrap_err(),
+                         BitReadError::UnexpectedEof { bits_needed: 1 }));
+    }
+
+    #[test]
+    fn bitwriter_buffered() {
+        let data: Vec<u8> = (0..3 * BIT_WRITER_BUFFER as u32 + 5).map(|i| (i * 7 % 251) as u8)
+            .collect();
+        let mut output = Vec::new();
+        let mut writer = BitWriter::new(&mut output);
+        writer.write_bits(1, 1).unwrap();
+        for &b in data.iter() {
+            writer.write_bits(b as u32, 8).unwrap();
+        }
+        writer.flush().unwrap();
+        // Flushing twice does not write the partial byte again.
+        writer.flush().unwrap();
+        assert_eq!(output.len(), data.len() + 1);
+        let mut input = &output[..];
+        let mut reader = BitReader::new(&mut input);
+        assert_eq!(reader.read_bits(1).unwrap(), 1);
+        for &b in data.iter() {
+            assert_eq!(reader.read_bits(8).unwrap(), b as u32);
+        }
+        assert_eq!(reader.read_bits(7).unwrap(), 0);
+    }
+
+    #[test]
+    fn align_to_byte() {
+        let mut output = Vec::new();
+        let mut writer = BitWriter::new(&mut output);
+        writer.align_to_byte().unwrap();
+        writer.write_bits(5, 3).unwrap();
+        writer.align_to_byte().unwrap();
+        writer.write_bits(0xab, 8).unwrap();
+        writer.align_to_byte().unwrap();
+        writer.write_bits(0x1ff, 9).unwrap();
+        writer.align_to_byte().unwrap();
+        writer.flush().unwrap();
+        assert_eq!(output, [0x05, 0xab, 0xff, 0x01]);
+
+        let mut input = &output[..];
+        let mut reader = BitReader::new(&mut input);
+        reader.align_to_byte().unwrap();
+        assert_eq!(reader.bit_position(), 0);
+        assert_eq!(reader.read_bits(3).unwrap(), 5);
+        reader.align_to_byte().unwrap();
+        assert_eq!(reader.bit_position(), 8);
+        assert_eq!(reader.read_bits(8).unwrap(), 0xab);
+        reader.align_to_byte().unwrap();
+        assert_eq!(reader.read_bits(1).unwrap(), 1);
+        reader.align_to_byte().unwrap();
+        assert_eq!(reader.read_bits(8).unwrap(), 0x01);
+        reader.align_to_byte().unwrap();
+        assert!(reader.read_bits(1).is_err());
+    }
+
+    #[test]
+    fn seekable_bitreader() {
+        let data: Vec<u8> = (0..20000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut expected = SliceBitReader::new(&data);
+        let mut bits = Vec::new();
+        for _ in 0..data.len() * 8 / 13 {
+            bits.push(expected.read_bits(13).unwrap());
+        }
+        let mut reader = SeekableBitReader::new(std::io::Cursor::new(&data)).unwrap();
+        // Seeks backward and forward, within and outside the buffer.
+        for &i in &[5000, 3, 0, 12000, 11999, 1, 4000] {
+            reader.seek_bits(i as u64 * 13).unwrap();
+            assert_eq!(reader.bit_position(), i as u64 * 13);
+            for &b in &bits[i..i + 10] {
+                assert_eq!(reader.read_bits(13).unwrap(), b);
+            }
+        }
+        reader.seek_bits(data.len() as u64 * 8 - 3).unwrap();
+        assert_eq!(reader.read_bits(3).unwrap(), (data[data.len() - 1] >> 5) as u32);
+        assert_eq!(reader.try_read_bits(1).unwrap(), None);
+        assert!(reader.seek_bits(data.len() as u64 * 8 + 1).is_err());
+
+        // Offsets count from the start of the input.
+        let mut cursor = std::io::Cursor::new(&data);
+        cursor.set_position(100);
+        let mut reader = SeekableBitReader::new(cursor).unwrap();
+        assert_eq!(reader.bit_position(), 800);
+        assert_eq!(reader.read_bits(8).unwrap(), data[100] as u32);
+        reader.seek_bits(13).unwrap();
+        assert_eq!(reader.read_bits(13).unwrap(), bits[1]);
+    }
+
+    #[test]
+    fn copy_bits_unaligned() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut output = Vec::new();
+        {
+            let mut writer = BitWriter::new(&mut output);
+            writer.write_bits(5, 3).unwrap();
+            let mut reader = SliceBitReader::new(&data);
+            reader.read_bits(2).unwrap();
+            copy_bits(&mut reader, &mut writer, 7000).unwrap();
+            assert_eq!(reader.bit_position(), 7002);
+            assert!(copy_bits(&mut reader, &mut writer, 1000).is_err());
+            writer.flush().unwrap();
+        }
+        let mut copied = SliceBitReader::new(&output);
+        let mut original = SliceBitReader::new(&data);
+        assert_eq!(copied.read_bits(3).unwrap(), 5);
+        original.read_bits(2).unwrap();
+        for _ in 0..7000 {
+            assert_eq!(copied.read_bits(1).unwrap(), original.read_bits(1).unwrap());
+        }
+    }
+
+    #[test]
+    fn output_limit() {
+        fn limit_of(e: Box<dyn std::error::Error>) -> usize {
+            e.downcast_ref::<OutputLimitExceeded>().expect("not OutputLimitExceeded").limit
+        }
+
+        let mut output = b"xy".to_vec();
+        let mut io = SliceToVecIO::new(b"abcdef", &mut output);
+        io.set_output_limit(6);
+        io.copy_bytes(2).unwrap();
+        io.repeat_bytes(1, 0).unwrap();
+        io.write_byte(b'z').unwrap();
+        assert_eq!(limit_of(io.write_byte(b'z').unwrap_err()), 6);
+        assert_eq!(limit_of(io.copy_bytes(1).unwrap_err()), 6);
+        assert_eq!(limit_of(io.repeat_bytes(1, 0).unwrap_err()), 6);
+        assert_eq!(output, b"xyabbz");
+
+        let mut io = VecIO::new(b"abcdef".to_vec());
+        io.set_output_limit(4);
+        io.copy_bytes(3).unwrap();
+        assert_eq!(limit_of(io.repeat_bytes(2, 0).unwrap_err()), 4);
+        io.repeat_bytes(1, 0).unwrap();
+        assert_eq!(io.output(), b"abcc");
+
+        // PushIO limits the output that has not been taken.
+        let mut io = PushIO::new(4);
+        io.set_output_limit(3);
+        io.push_input(b"abcdef");
+        io.copy_bytes(3).unwrap();
+        assert_eq!(limit_of(io.write_byte(b'x').unwrap_err()), 3);
+        assert_eq!(io.take_output(), b"abc");
+        io.copy_bytes(1).unwrap();
+        io.repeat_bytes(2, 1).unwrap();
+        assert_eq!(limit_of(io.repeat_bytes(1, 0).unwrap_err()), 3);
+        assert_eq!(io.take_output(), b"dcd");
+    }
+
+    #[test]
+    fn repeat_in_vec_overlap() {
+        let prefix: Vec<u8> = (0..50u8).collect();
+        for dist in 0..20 {
+            for count in 0..100 {
+                let mut expected = prefix.clone();
+                for _ in 0..count {
+                    expected.push(expected[expected.len() - 1 - dist]);
+                }
+                let mut output = prefix.clone();
+                repeat_in_vec(&mut output, count, dist, usize::MAX).unwrap();
+                assert_eq!(output, expected, "count {} dist {}", count, dist);
+            }
+        }
+    }
+
+    #[test]
+    fn boxed_bits() {
+        let mut output = Vec::new();
+        {
+            let mut writer: BoxWriteBits = Box::new(BitWriter::new(&mut output));
+            writer.write_bits(5, 3).unwrap();
+            writer.write_bits64(0x1234_5678_9abc, 48).unwrap();
+            writer.flush().unwrap();
+        }
+        // Boxes can be used where &mut dyn ReadBits and WriteBits are
+        // expected.
+        let mut reader: BoxReadBits = Box::new(SliceBitReader::new(&output));
+        let mut copy = [0u8; 7];
+        let mut writer: BoxWriteBits = Box::new(SliceBitWriter::new(&mut copy));
+        copy_bits(&mut reader, &mut writer, 51).unwrap();
+        assert_eq!(reader.bit_position(), 51);
+        writer.flush().unwrap();
+        drop(writer);
+        assert_eq!(copy[..], output[..]);
+    }
+
+    #[test]
+    fn lookahead() {
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(b"abcdef", &mut output);
+        io.next_byte().unwrap();
+        assert_eq!(io.lookahead(3).unwrap(), b"bcd");
+        assert_eq!(io.lookahead(10).unwrap(), b"bcdef");
+        assert_eq!(io.peek(0).unwrap(), Some(b'b'));
+        assert_eq!(io.peek(4).unwrap(), Some(b'f'));
+        assert_eq!(io.peek(5).unwrap(), None);
+        assert_eq!(io.inpos(), 1);
+        io.copy_bytes(5).unwrap();
+        assert_eq!(io.lookahead(usize::MAX).unwrap(), b"");
+
+        let mut io = VecIO::new(b"xyz".to_vec());
+        let io: &mut dyn LookaheadInput = &mut io;
+        assert_eq!(io.lookahead(2).unwrap(), b"xy");
+
+        let mut io = PushIO::new(4);
+        io.push_input(b"ab");
+        assert_eq!(io.lookahead(2).unwrap(), b"ab");
+        assert!(io.lookahead(3).unwrap_err().is::<NeedsInput>());
+        assert!(io.peek(2).unwrap_err().is::<NeedsInput>());
+        io.finish_input();
+        assert_eq!(io.lookahead(3).unwrap(), b"ab");
+        assert_eq!(io.peek(2).unwrap(), None);
+    }
+
+    #[test]
+    fn lookback_ring() {
+        let data: Vec<u8> = (0..10000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut input = &data[..];
+        let mut output = Vec::new();
+        {
+            let inner = StreamIO::with_output_window(&mut input, &mut output, 16);
+            let mut io = LookbackRing::new(inner, 100);
+            assert_eq!(io.lookback_window(), 100);
+            io.copy_bytes(5000).unwrap();
+            assert_eq!(io.next_byte().unwrap(), Some(data[5000]));
+            assert_eq!(io.lookback(5000), data[5000]);
+            assert_eq!(io.lookback(4901), data[4901]);
+            io.repeat_bytes(3, 0).unwrap();
+            io.copy_bytes(4999).unwrap();
+            assert!(io.copy_bytes(1).is_err());
+            assert_eq!(io.lookback(9999), data[9999]);
+            assert_eq!(io.lookback(9900), data[9900]);
+            io.get_mut().flush().unwrap();
+        }
+        assert_eq!(output.len(), 10002);
+        assert_eq!(&output[..5000], &data[..5000]);
+        assert_eq!(output[5000..5003], [data[4999]; 3]);
+        assert_eq!(&output[5003..], &data[5001..]);
+    }
+
+    #[test]
+    #[should_panic(expected = "outside the input window")]
+    fn lookback_ring_outside_window() {
+        let mut output = Vec::new();
+        let mut io = LookbackRing::new(SliceToVecIO::new(&[0; 200], &mut output), 100);
+        io.copy_bytes(200).unwrap();
+        io.lookback(99);
+    }
+
+    #[test]
+    fn arc_io() {
+        let input: std::sync::Arc<[u8]> = b"abcdef".to_vec().into();
+        let mut io = ArcIO::new(input.clone());
+        io.copy_bytes(3).unwrap();
+        io.repeat_bytes(4, 1).unwrap();
+        assert_eq!(io.output(), b"abcbcbc");
+        assert!(std::sync::Arc::ptr_eq(io.input(), &input));
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn bytes_io() {
+        let input = bytes::Bytes::from_static(b"abcdef");
+        let mut io = BytesIO::with_output(input.clone(), bytes::BytesMut::from(&b"xy"[..]));
+        io.copy_bytes(3).unwrap();
+        io.repeat_bytes(5, 1).unwrap();
+        io.repeat_bytes(2, 9).unwrap();
+        assert!(io.repeat_bytes(1, 12).is_err());
+        assert_eq!(io.next_byte().unwrap(), Some(b'd'));
+        assert_eq!(io.lookback(1), b'b');
+        assert_eq!(io.lookahead(5).unwrap(), b"ef");
+        io.write_byte(b'z').unwrap();
+        assert!(io.copy_bytes(3).is_err());
+        io.set_output_limit(14);
+        assert!(io.copy_bytes(2).unwrap_err().is::<OutputLimitExceeded>());
+        io.copy_bytes(1).unwrap();
+        let (rest, output) = io.into_parts();
+        assert_eq!(rest, input);
+        assert_eq!(&output[..], b"xyabcbcbcbxyze");
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn bytes_io_reserve() {
+        let mut io = BytesIO::new(bytes::Bytes::from_static(b"abc"));
+        assert_eq!(io.remaining_input(), Some(3));
+        // Hints that cannot be allocated are ignored instead of aborting.
+        io.reserve_output(usize::MAX);
+        io.reserve_output(usize::MAX / 2);
+        io.reserve_output(100);
+        io.copy_bytes(2).unwrap();
+        assert_eq!(io.remaining_input(), Some(1));
+        let mut output = io.into_output();
+        assert!(output.capacity() >= 100);
+        assert!(try_reserve_bytes(&mut output, usize::MAX / 2).is_err());
+        assert!(try_reserve_bytes(&mut output, usize::MAX).is_err());
+        try_reserve_bytes(&mut output, 1000).unwrap();
+        assert!(output.capacity() >= 1002);
+        assert_eq!(&output[..], b"ab");
+    }
+
+    #[test]
+    fn slices_to_vec_io() {
+        let mut output = Vec::new();
+        let chunks: &[&[u8]] = &[b"ab", b"", b"cde", b"f"];
+        let mut io = SlicesToVecIO::new(chunks.iter().copied(), &mut output);
+        assert_eq!(io.lookahead(1).unwrap(), b"a");
+        assert_eq!(io.lookahead(5).unwrap(), b"abcde");
+        io.copy_bytes(3).unwrap();
+        assert_eq!(io.next_byte().unwrap(), Some(b'd'));
+        assert_eq!((0..4).map(|i| io.lookback(i)).collect::<Vec<_>>(), b"abcd");
+        assert_eq!(io.peek(1).unwrap(), Some(b'f'));
+        assert_eq!(io.peek(2).unwrap(), None);
+        assert!(io.copy_bytes(3).is_err());
+        io.copy_bytes(2).unwrap();
+        assert_eq!(io.inpos(), 6);
+        assert_eq!(io.next_byte().unwrap(), None);
+        assert_eq!(io.lookahead(4).unwrap(), b"");
+        io.repeat_bytes(3, 1).unwrap();
+        assert_eq!(output, b"abcefefe");
+    }
+
+    #[test]
+    fn slice_io() {
+        let mut output = [0u8; 8];
+        let mut io = SliceIO::new(b"abcdef", &mut output);
+        io.copy_bytes(2).unwrap();
+        io.repeat_bytes(3, 1).unwrap();
+        assert_eq!(io.next_byte().unwrap(), Some(b'c'));
+        io.write_byte(b'x').unwrap();
+        assert!(io.copy_bytes(4).is_err());
+        assert!(io.repeat_bytes(1, 6).is_err());
+        assert!(io.repeat_bytes(3, 0).unwrap_err().is::<OutputFull>());
+        assert!(io.copy_bytes(3).unwrap_err().is::<OutputFull>());
+        io.copy_bytes(2).unwrap();
+        assert!(io.write_byte(b'y').unwrap_err().is::<OutputFull>());
+        assert_eq!(io.outpos(), 8);
+        assert_eq!(io.inpos(), 5);
+        assert_eq!(&output, b"ababaxde");
+    }
+
+    #[test]
+    fn slice_bitwriter() {
+        fn write_fields(writer: &mut dyn WriteBits) -> BoxResult<()> {
+            for i in 0..1000u32 {
+                let nbits = i % 33;
+                let mask = ((1u64 << nbits) - 1) as u32;
+                writer.write_bits(i.wrapping_mul(0x9e3779b9) & mask, nbits as u8)?;
+                if i % 100 == 0 {
+                    writer.align_to_byte()?;
+                }
+            }
+            writer.write_bits64(0x0123_4567_89ab_cdef, 64)?;
+            writer.write_bits(1, 3)?;
+            writer.flush()
+        }
+
+        let mut expected = Vec::new();
+        write_fields(&mut BitWriter::new(&mut expected)).unwrap();
+        let mut output = vec![0xffu8; expected.len()];
+        let mut writer = SliceBitWriter::new(&mut output);
+        write_fields(&mut writer).unwrap();
+        assert_eq!(writer.outpos(), expected.len());
+        assert_eq!(output, expected);
+
+        // The final partial word only needs as many bytes as it has bits.
+        let mut output = [0u8; 6];
+        let mut writer = SliceBitWriter::new(&mut output);
+        writer.write_bits(0xdeadbeef, 32).unwrap();
+        writer.write_bits(0x1ff, 9).unwrap();
+        assert!(writer.write_bits(0, 8).unwrap_err().is::<OutputFull>());
+        writer.write_bits(0x7f, 7).unwrap();
+        assert!(writer.write_bits(0, 1).unwrap_err().is::<OutputFull>());
+        writer.flush().unwrap();
+        assert_eq!(writer.outpos(), 6);
+        assert_eq!(output, [0xef, 0xbe, 0xad, 0xde, 0xff, 0xff]);
+
+        // A 64-bit write with only 5 bytes left stores nothing, not even
+        // the low half.
+        let mut output = [0u8; 9];
+        let mut writer = SliceBitWriter::new(&mut output);
+        writer.write_bits(0xdeadbeef, 32).unwrap();
+        assert!(writer.write_bits64(u64::MAX, 64).unwrap_err().is::<OutputFull>());
+        writer.flush().unwrap();
+        assert_eq!(writer.outpos(), 4);
+        assert_eq!(output, [0xef, 0xbe, 0xad, 0xde, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn limited_input() {
+        let mut output = Vec::new();
+        let mut inner = SliceToVecIO::new(b"abcdefgh", &mut output);
+        inner.next_byte().unwrap();
+        let mut io = LimitedInput::new(inner, 4);
+        io.copy_bytes(2).unwrap();
+        assert!(io.copy_bytes(3).unwrap_err().is::<InputLimitExceeded>());
+        io.copy_bytes(1).unwrap();
+        assert_eq!(io.next_byte().unwrap(), Some(b'e'));
+        let err = io.next_byte().unwrap_err();
+        assert_eq!(err.downcast_ref::<InputLimitExceeded>().unwrap().limit, 4);
+        // The limit stays exceeded.
+        assert!(io.next_byte().unwrap_err().is::<InputLimitExceeded>());
+        assert!(io.copy_bytes(1).unwrap_err().is::<InputLimitExceeded>());
+        assert_eq!(io.remaining_input(), Some(0));
+        assert_eq!(output, b"bcd");
+
+        // The end of the input at the limit is not an error.
+        let mut output = Vec::new();
+        let mut io = LimitedInput::new(SliceToVecIO::new(b"ab", &mut output), 2);
+        io.copy_bytes(2).unwrap();
+        assert_eq!(io.next_byte().unwrap(), None);
+    }
+
+    #[test]
+    fn reserve_output_hint() {
+        let mut output = Vec::new();
+        let mut io = SliceToVecIO::new(b"", &mut output);
+        io.set_output_limit(100);
+        io.reserve_output(1000);
+        io.reserve_output(usize::MAX);
+        assert!(output.capacity() >= 100 && output.capacity() < 1000);
+
+        // Through &mut, as generic codecs see it.
+        fn hint<IO: IOTrait>(mut io: IO) { io.reserve_output(1000) }
+        let mut io = LimitedOutput::new(VecIO::new(Vec::new()), 50);
+        hint(&mut io);
+        assert!(io.get_ref().output.capacity() >= 50);
+        assert!(io.get_ref().output.capacity() < 100);
+    }
+
+    #[test]
+    fn limited_output() {
+        let mut io = LimitedOutput::new(VecIO::new(b"abcdef".to_vec()), 5);
+        io.copy_bytes(2).unwrap();
+        io.repeat_bytes(2, 1).unwrap();
+        assert!(io.repeat_bytes(2, 1).unwrap_err().is::<OutputLimitExceeded>());
+        assert!(io.copy_bytes(2).unwrap_err().is::<OutputLimitExceeded>());
+        io.write_byte(b'x').unwrap();
+        assert!(io.write_byte(b'x').unwrap_err().is::<OutputLimitExceeded>());
+        assert_eq!(io.written(), 5);
+        assert_eq!(io.into_inner().into_output(), b"ababx");
+    }
+
+    #[test]
+    fn slice_bitreader() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+            .collect();
+        let mut input = &data[..];
+        let mut expected = BitReader::new(&mut input);
+        let mut reader = SliceBitReader::new(&data);
+        let mut nbits = 0;
+        loop {
+            nbits = (nbits * 7 + 3) % 33;
+            assert_eq!(reader.peek_bits(nbits).unwrap(), expected.peek_bits(nbits).unwrap());
+            match expected.read_bits(nbits) {
+                Ok(bits) => assert_eq!(reader.read_bits(nbits).unwrap(), bits),
+                Err(_) => {
+                    assert!(reader.read_bits(nbits).is_err());
+                    break;
+                },
+            }
+            assert_eq!(reader.bit_position(), expected.bit_position());
+        }
+        let rest = 8000 - reader.bit_position();
+        assert_eq!(reader.read_bits64(rest as u32).unwrap(),
+                   expected.read_bits64(rest as u32).unwrap());
+        assert!(reader.read_bits(1).is_err());
+
+        let mut reader = SliceBitReader::new(b"jE\xc16");
+        assert_eq!(reader.read_bits(7).unwrap(), 0x6a);
+        reader.align_to_byte().unwrap();
+        assert_eq!(reader.read_bits(24).unwrap(), 0x36c145);
+        assert_eq!(reader.peek_bits(8).unwrap(), 0);
+    }
+
+    #[test]
+    fn try_read_bits() {
+        let mut input = &b"jE"[..];
+        let mut reader = BitReader::new(&mut input);
+        assert_eq!(reader.try_read_bits(12).unwrap(), Some(0x56a));
+        assert!(matches!(reader.try_read_bits(5).unwrap_err(),
+                         BitReadError::UnexpectedEof { bits_needed: 1 }));
+        assert_eq!(reader.try_read_bits(4).unwrap(), Some(0x4));
+        assert_eq!(reader.try_read_bits(1).unwrap(), None);
+        assert_eq!(reader.try_read_bits(0).unwrap(), Some(0));
+
+        let mut reader = SliceBitReader::new(b"jE");
+        assert_eq!(reader.try_read_bits(12).unwrap(), Some(0x56a));
+        assert!(matches!(reader.try_read_bits(5).unwrap_err(),
+                         BitReadError::UnexpectedEof { bits_needed: 1 }));
+        assert_eq!(reader.try_read_bits(4).unwrap(), Some(0x4));
+        assert_eq!(reader.try_read_bits(1).unwrap(), None);
+
+        let mut reader = SliceBitReader::new(b"");
+        assert_eq!(reader.try_read_bits(8).unwrap(), None);
+    }
+
+    #[test]
+    fn pipeline_stages() {
+        let data: Vec<u8> = (0..100000u32).map(|i| (i * 7 % 251) as u8).collect();
+        // Stage 1 writes bit fields, stage 2 reads them back through
+        // StreamIO.
+        let result = pipeline(|writer| {
+            let mut bits = BitWriter::new(writer);
+            for &b in data.iter() {
+                bits.write_bits(b as u32, 8)?;
+            }
+            bits.flush()
+        }, |reader| {
+            let mut io = StreamIO::new(reader, Vec::new());
+            io.copy_bytes(data.len())?;
+            assert_eq!(io.next_byte()?, None);
+            Ok(io.finish()?.1)
+        }).unwrap();
+        assert!(result == data);
+
+        // Errors in either stage are reported.
+        let err = pipeline(|_| Err("first failed".into()), |_| Ok(())).unwrap_err();
+        assert_eq!(err.to_string(), "first failed");
+        let err = pipeline(|writer| {
+            std::io::Write::write_all(writer, &data)?;
+            std::io::Write::write_all(writer, &data)?;
+            Ok(())
+        }, |_| -> BoxResult<()> { Err("second failed".into()) }).unwrap_err();
+        assert_eq!(err.to_string(), "second failed");
+    }
+
+    #[test]
+    fn prefetch_reader() {
+        use std::io::Read;
+        let data: Vec<u8> = (0..100000u32).map(|i| (i * 7 % 251) as u8).collect();
+        for background in [false, true] {
+            let options = PrefetchOptions::new().buffer_size(1000).background(background);
+            let mut reader = PrefetchReader::with_options(
+                std::io::Cursor::new(data.clone()), &options);
+            let mut output = Vec::new();
+            reader.read_to_end(&mut output).unwrap();
+            assert!(output == data);
+            assert_eq!(reader.read(&mut [0; 10]).unwrap(), 0);
+        }
+
+        // Errors come after the data read before them.
+        struct Failing(std::io::Cursor<Vec<u8>>);
+
+        impl std::io::Read for Failing {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                match self.0.read(buf)? {
+                    0 => Err(std::io::ErrorKind::BrokenPipe.into()),
+                    n => Ok(n),
+                }
+            }
+        }
+
+        for background in [false, true] {
+            let options = PrefetchOptions::new().buffer_size(1000).background(background);
+            let input = Failing(std::io::Cursor::new(data[..2500].to_vec()));
+            let mut reader = PrefetchReader::with_options(input, &options);
+            let mut output = vec![0; 2500];
+            reader.read_exact(&mut output).unwrap();
+            assert!(output == data[..2500]);
+            let err = reader.read(&mut [0; 10]).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        }
+    }
+
+    #[test]
+    fn tee_io() {
+        let mut output = Vec::new();
+        let mut io = TeeIO::new(SliceToVecIO::new(b"abcd", &mut output), Vec::new(), 4);
+        io.copy_bytes(2).unwrap();
+        io.repeat_bytes(3, 1).unwrap();
+        assert!(io.repeat_bytes(1, 4).is_err());
+        assert!(io.repeat_bytes(1, 3).is_ok());
+        assert_eq!(io.next_byte().unwrap(), Some(b'c'));
+        assert_eq!(io.lookback(0), b'a');
+        io.write_byte(b'z').unwrap();
+        assert!(io.copy_bytes(2).is_err());
+        let (_, sink) = io.into_parts();
+        assert_eq!(output, b"abababzd");
+        assert_eq!(sink, output);
+
+        // The wrapped IO already has output, which was not teed.
+        let mut output = b"xyz".to_vec();
+        let mut io = TeeIO::new(SliceToVecIO::new(b"ab", &mut output), Vec::new(), 8);
+        assert!(io.repeat_bytes(1, 0).is_err());
+        io.copy_bytes(2).unwrap();
+        assert!(io.repeat_bytes(1, 2).is_err());
+        io.repeat_bytes(3, 1).unwrap();
+        let (_, sink) = io.into_parts();
+        assert_eq!(sink, b"ababa");
+        assert_eq!(output, b"xyzababa");
+    }
+
+    #[test]
+    fn tee_writer() {
+        use std::io::Write;
+        let mut tee = TeeWriter::new(Vec::new(), crate::crc32::Crc32::new());
+        tee.write_all(b"123456789").unwrap();
+        tee.flush().unwrap();
+        let (data, crc) = tee.into_parts();
+        assert_eq!(data, b"123456789");
+        assert_eq!(crc.value(), 0xcbf43926);
+
+        let mut a = Vec::new();
+        let mut tee = TeeWriter::new(BitWriter::new(&mut a), BitCounter::new());
+        tee.write_bits(0x1ff, 9).unwrap();
+        WriteBits::flush(&mut tee).unwrap();
+        let (_, counter) = tee.into_parts();
+        assert_eq!(a, [0xff, 0x01]);
+        assert_eq!(counter.bytes(), 2);
+    }
+
+    #[test]
+    fn bit_vec() {
+        let mut bits = BitVec::new();
+        bits.write_bits(0x5, 3).unwrap();
+        bits.write_bits64(u64::MAX, 64).unwrap();
+        bits.write_bits(0x1234, 13).unwrap();
+        assert_eq!(bits.len(), 80);
+        assert_eq!(bits.read_bits(3).unwrap(), 0x5);
+        assert_eq!(bits.read_bits64(64).unwrap(), u64::MAX);
+        assert_eq!(bits.peek_bits(16).unwrap(), 0x1234 & 0x1fff);
+        assert_eq!(bits.read_bits(13).unwrap(), 0x1234 & 0x1fff);
+        assert_eq!(bits.try_read_bits(1).unwrap(), None);
+        bits.write_bits(1, 1).unwrap();
+        assert!(matches!(bits.read_bits(3).unwrap_err(),
+                         BitReadError::UnexpectedEof { bits_needed: 2 }));
+        bits.discard_read();
+        assert_eq!((bits.len(), bits.bit_position()), (1, 80));
+        WriteBits::align_to_byte(&mut bits).unwrap();
+        assert_eq!(bits.as_bytes(), [1]);
+
+        // Round trip through BitWriter.
+        let mut output = Vec::new();
+        let mut writer = BitWriter::new(&mut output);
+        bits.rewind();
+        copy_bits(&mut bits, &mut writer, 8).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(output, [1]);
+        bits.clear();
+        assert_eq!((bits.len(), bits.bit_position()), (0, 0));
+    }
+
+    /// Writes every width from 0 to 64 bits at every bit offset, with
+    /// bits above the width set, and checks the result against BitVec.
+    fn check_write_widths(writer: &mut dyn WriteBits) -> BitVec {
+        let mut expected = BitVec::new();
+        let mut x = 0x9e3779b97f4a7c15u64;
+        for offset in 0..8u8 {
+            for nbits in 0..=64u8 {
+                for w in [&mut *writer, &mut expected as &mut dyn WriteBits] {
+                    w.write_bits(0x55, offset).unwrap();
+                    w.write_bits64(x, nbits).unwrap();
+                    if nbits <= 32 {
+                        w.write_bits(!x as u32, nbits).unwrap();
+                    }
+                    w.align_to_byte().unwrap();
+                }
+                x = x.rotate_left(7) ^ nbits as u64;
+            }
+        }
+        expected
+    }
+
+    #[test]
+    fn write_bits_widths() {
+        let mut output = Vec::new();
+        let mut writer = BitWriter::new(&mut output);
+        let expected = check_write_widths(&mut writer);
+        writer.flush().unwrap();
+        assert_eq!(output, expected.as_bytes());
+
+        let mut buf = vec![0; expected.as_bytes().len()];
+        let mut writer = SliceBitWriter::new(&mut buf);
+        check_write_widths(&mut writer);
+        writer.flush().unwrap();
+        assert_eq!(buf, expected.as_bytes());
+
+        // A 64-bit write that does not fit writes nothing.
+        let mut buf = [0u8; 8];
+        let mut writer = SliceBitWriter::new(&mut buf);
+        writer.write_bits(1, 1).unwrap();
+        assert!(writer.write_bits64(u64::MAX, 64).unwrap_err().is::<OutputFull>());
+        writer.write_bits64(u64::MAX, 63).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(writer.outpos(), 8);
+        assert_eq!(buf, [0xff; 8]);
+    }
+
+    #[test]
+    fn bitwriter_wide() {
+        // Writes of 64 bits that span 9 bytes and cross the buffer size.
+        let mut output = Vec::new();
+        let mut writer = BitWriter::new(&mut output);
+        writer.write_bits(0, 0).unwrap();
+        writer.write_bits(1, 1).unwrap();
+        let words = BIT_WRITER_BUFFER as u64 / 8 + 2;
+        for i in 0..words {
+            writer.write_bits64(i.wrapping_mul(0x9e3779b97f4a7c15), 64).unwrap();
+        }
+        writer.write_bits(0x7f, 7).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(output.len() as u64, words * 8 + 1);
+        let mut reader = SliceBitReader::new(&output);
+        assert_eq!(reader.read_bits(1).unwrap(), 1);
+        for i in 0..words {
+            assert_eq!(reader.read_bits64(64).unwrap(), i.wrapping_mul(0x9e3779b97f4a7c15));
+        }
+        assert_eq!(reader.read_bits(7).unwrap(), 0x7f);
+    }
+
+    #[test]
+    fn bit_counter() {
+        let mut output = Vec::new();
+        let mut writer = BitWriter::new(&mut output);
+        let mut counter = BitCounter::new();
+        for w in [&mut writer as &mut dyn WriteBits, &mut counter] {
+            w.write_bits(5, 3).unwrap();
+            w.write_bits64(1, 40).unwrap();
+            w.align_to_byte().unwrap();
+            w.write_bits(3, 2).unwrap();
+            w.flush().unwrap();
+        }
+        assert_eq!(counter.bits(), 56);
+        assert_eq!(counter.bytes(), output.len() as u64);
+        counter.write_bits(1, 1).unwrap();
+        assert_eq!((counter.bits(), counter.bytes()), (57, 8));
+    }
+
     #[test]
     fn bitwriter_5bits() {
         let mut output = Vec::new();
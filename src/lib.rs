@@ -2,9 +2,34 @@
 //
 // SPDX-License-Identifier: MIT
 
+#[cfg(feature = "async")]
+pub mod async_io;
 pub mod backref;
 pub mod brc;
+pub mod color;
+pub mod crc32;
 pub mod dct;
+pub mod file;
+pub mod filters;
+#[cfg(feature = "gpu")]
+pub mod gpu;
+pub mod haar;
+pub mod image;
+pub mod intra;
 pub mod io;
+pub mod lapped;
+pub mod lz78;
+pub mod lzw;
+pub mod mdct;
+pub mod metrics;
+pub mod mux;
+pub mod ppm;
+pub mod progressive;
+pub mod rans;
 pub mod result;
+pub mod rle;
 pub mod tans;
+#[cfg(test)]
+mod testutil;
+pub mod transform;
+pub mod wavelet;
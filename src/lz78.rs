@@ -0,0 +1,257 @@
+// LZ78 compression.
+//
+// Copyright 2023 Robbert Haarman
+//
+// SPDX-License-Identifier: MIT
+
+//! This module implements LZ78, which splits the input into phrases that
+//! each consist of the longest earlier phrase that matches the input,
+//! followed by one more byte. Each phrase is coded as a token: the index
+//! of the earlier phrase in the dictionary (0 for the empty phrase),
+//! followed by the byte. Each token then adds its phrase to the
+//! dictionary. Unlike backref, which refers to earlier data by distance
+//! and length, LZ78 only refers to whole phrases, which makes it simpler
+//! but slower to adapt.
+//!
+//! The dictionary holds at most max_entries phrases. When it is full,
+//! the policy decides whether it stays as it is or starts over empty.
+//!
+//! A stream starts with the length of the input as a 64-bit number,
+//! followed by the tokens. Indices are written with just enough bits for
+//! the largest index the dictionary can have at that point, and bytes
+//! with 8 bits. If the input ends in the middle of a phrase, the last
+//! token is that of the phrase's own earlier phrase and last byte. Bits
+//! are written least significant bit first, as by io::BitWriter.
+
+use std::collections::HashMap;
+
+use crate::io::{BitWriter, ReadBits, SliceBitReader, WriteBits};
+use crate::result::BoxResult;
+
+/// What happens when the dictionary is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    /// Keep the dictionary and stop adding phrases.
+    Freeze,
+    /// Empty the dictionary and start adding phrases again.
+    Reset,
+}
+
+/// Options for encoding and decoding. The same options must be used for
+/// both.
+#[derive(Clone, Debug)]
+pub struct Options {
+    max_entries: u32,
+    policy: Policy,
+}
+
+impl Options {
+    /// Returns the default options: a dictionary of 4096 phrases that is
+    /// reset when full.
+    pub fn new() -> Options {
+        Options { max_entries: 4096, policy: Policy::Reset }
+    }
+
+    /// Sets the largest number of phrases in the dictionary, from 1 to
+    /// 2**24.
+    pub fn max_entries(mut self, max_entries: u32) -> Options {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// Sets what happens when the dictionary is full.
+    pub fn policy(mut self, policy: Policy) -> Options {
+        self.policy = policy;
+        self
+    }
+
+    fn check(&self) -> BoxResult<()> {
+        if !(1..=1 << 24).contains(&self.max_entries) {
+            return Err(format!("invalid LZ78 dictionary size {}", self.max_entries).into());
+        }
+        Ok(())
+    }
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options::new()
+    }
+}
+
+/// Returns the number of bits needed for the indices of a dictionary
+/// with the given number of phrases.
+fn index_bits(entries: u32) -> u32 {
+    32 - entries.leading_zeros()
+}
+
+/// Tracks the number of phrases in the dictionary the same way in the
+/// encoder and the decoder. Returns whether the phrase of a token is
+/// added, and empties the dictionary if the policy says so.
+fn add_phrase(entries: &mut u32, options: &Options) -> bool {
+    if *entries < options.max_entries {
+        *entries += 1;
+        true
+    } else {
+        if options.policy == Policy::Reset {
+            *entries = 0;
+        }
+        false
+    }
+}
+
+/// Encodes input, writing the tokens to output.
+pub fn encode(input: &[u8], output: &mut dyn WriteBits, options: &Options) -> BoxResult<()> {
+    options.check()?;
+    output.write_bits64(input.len() as u64, 64)?;
+    // Maps an earlier phrase and a byte to the index of the phrase that
+    // extends it with the byte.
+    let mut dictionary: HashMap<(u32, u8), u32> = HashMap::new();
+    let mut entries = 0;
+    // The index of the phrase matched so far and its own earlier phrase.
+    let mut current = 0;
+    let mut parent = 0;
+    for &b in input {
+        if let Some(&index) = dictionary.get(&(current, b)) {
+            parent = current;
+            current = index;
+            continue;
+        }
+        output.write_bits(current, index_bits(entries) as u8)?;
+        output.write_bits(b as u32, 8)?;
+        if add_phrase(&mut entries, options) {
+            dictionary.insert((current, b), entries);
+        } else if entries == 0 {
+            dictionary.clear();
+        }
+        current = 0;
+    }
+    if current != 0 {
+        // The input ends with the phrase current. Code it as its earlier
+        // phrase and last byte, which is the last byte of the input.
+        output.write_bits(parent, index_bits(entries) as u8)?;
+        output.write_bits(input[input.len() - 1] as u32, 8)?;
+    }
+    Ok(())
+}
+
+/// Decodes tokens produced by encode with the same options.
+pub fn decode(input: &mut dyn ReadBits, options: &Options) -> BoxResult<Vec<u8>> {
+    options.check()?;
+    let len = input.read_bits64(64)?;
+    let mut output = Vec::with_capacity(len.min(1 << 24) as usize);
+    // Where in the output each phrase starts, and its length.
+    let mut phrases: Vec<(usize, usize)> = Vec::new();
+    while (output.len() as u64) < len {
+        let index = input.read_bits(index_bits(phrases.len() as u32))?;
+        let b = input.read_bits(8)? as u8;
+        let start = output.len();
+        if index > 0 {
+            let (s, n) = *phrases.get(index as usize - 1)
+                .ok_or_else(|| format!("invalid LZ78 phrase index {}", index))?;
+            output.extend_from_within(s..s + n);
+        }
+        output.push(b);
+        if output.len() as u64 > len {
+            return Err("LZ78 token extends past end of data".into());
+        }
+        let mut entries = phrases.len() as u32;
+        if add_phrase(&mut entries, options) {
+            phrases.push((start, output.len() - start));
+        } else if entries == 0 {
+            phrases.clear();
+        }
+    }
+    Ok(output)
+}
+
+/// Compresses data in memory.
+pub fn compress(input: &[u8], options: &Options) -> BoxResult<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut writer = BitWriter::new(&mut output);
+    encode(input, &mut writer, options)?;
+    writer.flush()?;
+    drop(writer);
+    Ok(output)
+}
+
+/// Decompresses data produced by compress with the same options.
+pub fn decompress(input: &[u8], options: &Options) -> BoxResult<Vec<u8>> {
+    decode(&mut SliceBitReader::new(input), options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::lcg;
+
+    #[test]
+    fn tokens() {
+        // The phrases are a, b, ab, aba, ba and b, which is a whole
+        // phrase at the end of the input, coded as phrase 0 followed by b.
+        // The indices take 0, 1, 2, 2, 3 and 3 bits.
+        let data = compress(b"ababababab", &Options::new()).unwrap();
+        let mut expected = 10u128;
+        let mut shift = 64;
+        for &(index, bits, b) in &[(0, 0, b'a'), (0, 1, b'b'), (1, 2, b'b'), (3, 2, b'a'),
+                                   (2, 3, b'a'), (0, 3, b'b')] {
+            expected |= (index as u128 | (b as u128) << bits) << shift;
+            shift += bits + 8;
+        }
+        assert_eq!(data, expected.to_le_bytes()[..(shift as usize).div_ceil(8)]);
+        assert_eq!(decompress(&data, &Options::new()).unwrap(), b"ababababab");
+    }
+
+    #[test]
+    fn roundtrip() {
+        let text = b"it was the best of times, it was the worst of times, ".repeat(200);
+        let compressed = compress(&text, &Options::new()).unwrap();
+        assert!(compressed.len() < text.len() / 4);
+        assert_eq!(decompress(&compressed, &Options::new()).unwrap(), text);
+
+        let data: Vec<u8> = (0..20000).zip(lcg(5)).map(|(i, state)| {
+            if i % 5 < 2 { (state >> 30) as u8 } else { text[i % 97] }
+        }).collect();
+        for &max in &[1, 2, 100, 1 << 20] {
+            for &policy in &[Policy::Freeze, Policy::Reset] {
+                let options = Options::new().max_entries(max).policy(policy);
+                let compressed = compress(&data, &options).unwrap();
+                assert_eq!(decompress(&compressed, &options).unwrap(), data);
+            }
+        }
+        for len in 0..4 {
+            let compressed = compress(&data[..len], &Options::new()).unwrap();
+            assert_eq!(decompress(&compressed, &Options::new()).unwrap(), &data[..len]);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(compress(b"", &Options::new().max_entries(0)).is_err());
+        assert!(compress(b"", &Options::new().max_entries((1 << 24) + 1)).is_err());
+        let compressed = compress(b"abcabcabcabc", &Options::new()).unwrap();
+        assert!(decompress(&compressed[..compressed.len() - 2], &Options::new()).is_err());
+        let mut bad = compressed.clone();
+        bad[0] = 11;
+        assert!(decompress(&bad, &Options::new()).is_err());
+        // Streams of the tokens a, 1b and the given index followed by c.
+        let stream = |len: u64, index: u32| {
+            let mut output = Vec::new();
+            let mut writer = BitWriter::new(&mut output);
+            writer.write_bits64(len, 64).unwrap();
+            writer.write_bits(b'a' as u32, 8).unwrap();
+            writer.write_bits(1, 1).unwrap();
+            writer.write_bits(b'b' as u32, 8).unwrap();
+            writer.write_bits(index, 2).unwrap();
+            writer.write_bits(b'c' as u32, 8).unwrap();
+            writer.flush().unwrap();
+            drop(writer);
+            output
+        };
+        assert_eq!(decompress(&stream(6, 2), &Options::new()).unwrap(), b"aababc");
+        // Phrase 3 does not exist yet.
+        assert!(decompress(&stream(6, 3), &Options::new()).is_err());
+        // The last token is longer than the rest of the data.
+        assert!(decompress(&stream(5, 2), &Options::new()).is_err());
+    }
+}
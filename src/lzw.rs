@@ -0,0 +1,294 @@
+// Lempel-Ziv-Welch compression.
+//
+// Copyright 2023 Robbert Haarman
+//
+// SPDX-License-Identifier: MIT
+
+//! This module implements LZW with variable-width codes, in the variant
+//! used by GIF. The dictionary starts out with one code for each symbol
+//! that fits in initial_width - 1 bits, followed by a clear code, which
+//! resets the dictionary, and an end code, which ends the stream. Every
+//! code after the first adds the previous string followed by the first
+//! symbol of the current one to the dictionary. Codes start out
+//! initial_width bits wide and get one bit wider each time the next
+//! code to be added would not fit, up to max_width bits. When the
+//! dictionary is full, the encoder emits a clear code and starts over.
+//!
+//! The stream starts with a clear code and ends with an end code. Codes
+//! are written least significant bit first, as by io::BitWriter.
+
+use std::collections::HashMap;
+
+use crate::io::{BitWriter, ReadBits, SliceBitReader, WriteBits};
+use crate::result::BoxResult;
+
+/// Widths of the codes of a stream. The same options must be used for
+/// encoding and decoding.
+#[derive(Clone, Debug)]
+pub struct Options {
+    initial_width: u8,
+    max_width: u8,
+}
+
+impl Options {
+    /// Returns the default options: 9 to 12 bit codes, for 8-bit
+    /// symbols.
+    pub fn new() -> Options {
+        Options { initial_width: 9, max_width: 12 }
+    }
+
+    /// Sets the width of the first codes, from 3 to 9. Symbols must fit
+    /// in one bit less: 9 allows all bytes, and GIF images with 4 colors
+    /// use 3.
+    pub fn initial_width(mut self, width: u8) -> Options {
+        self.initial_width = width;
+        self
+    }
+
+    /// Sets the largest width of codes, from initial_width to 16.
+    pub fn max_width(mut self, width: u8) -> Options {
+        self.max_width = width;
+        self
+    }
+
+    fn check(&self) -> BoxResult<()> {
+        if !(3..=9).contains(&self.initial_width) {
+            return Err(format!("invalid initial LZW code width {}", self.initial_width).into());
+        }
+        if !(self.initial_width..=16).contains(&self.max_width) {
+            return Err(format!("invalid maximum LZW code width {}", self.max_width).into());
+        }
+        Ok(())
+    }
+
+    fn clear_code(&self) -> u32 {
+        1 << (self.initial_width - 1)
+    }
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options::new()
+    }
+}
+
+/// Writes codes with the current width and keeps track of the number of
+/// codes in the dictionary, as the decoder will see it.
+struct CodeWriter<'a> {
+    writer: &'a mut dyn WriteBits,
+    options: &'a Options,
+    width: u8,
+    next: u32,
+}
+
+impl CodeWriter<'_> {
+    fn write(&mut self, code: u32) -> BoxResult<()> {
+        self.writer.write_bits(code, self.width)
+    }
+
+    /// Resets the dictionary after a clear code.
+    fn reset(&mut self) {
+        self.width = self.options.initial_width;
+        self.next = self.options.clear_code() + 2;
+    }
+
+    /// Returns whether the dictionary has room for another code.
+    fn has_room(&self) -> bool {
+        self.next < 1 << self.options.max_width
+    }
+
+    /// Adds a code, widening codes once the last code added needs it.
+    fn add(&mut self) {
+        self.next += 1;
+        if self.next > 1 << self.width && self.width < self.options.max_width {
+            self.width += 1;
+        }
+    }
+}
+
+/// Encodes input, writing the codes to output. Every symbol must be
+/// less than 2**(initial_width - 1).
+pub fn encode(input: &[u8], output: &mut dyn WriteBits, options: &Options) -> BoxResult<()> {
+    options.check()?;
+    let clear = options.clear_code();
+    if let Some(&b) = input.iter().find(|&&b| b as u32 >= clear) {
+        return Err(format!("symbol {} does not fit in LZW code width", b).into());
+    }
+    let mut writer = CodeWriter { writer: output, options, width: 0, next: 0 };
+    let mut dictionary: HashMap<(u32, u8), u32> = HashMap::new();
+    writer.reset();
+    writer.write(clear)?;
+    // The code of the string matched so far, and whether it is the first
+    // code since the last clear code.
+    let mut current: Option<u32> = None;
+    let mut first = true;
+    for &b in input {
+        let code = match current {
+            None => {
+                current = Some(b as u32);
+                continue;
+            },
+            Some(code) => code,
+        };
+        if let Some(&longer) = dictionary.get(&(code, b)) {
+            current = Some(longer);
+            continue;
+        }
+        writer.write(code)?;
+        first = false;
+        if writer.has_room() {
+            dictionary.insert((code, b), writer.next);
+            writer.add();
+        } else {
+            writer.write(clear)?;
+            writer.reset();
+            dictionary.clear();
+            first = true;
+        }
+        current = Some(b as u32);
+    }
+    if let Some(code) = current {
+        writer.write(code)?;
+        // The decoder adds an entry for this code, which may widen the
+        // end code.
+        if !first && writer.has_room() {
+            writer.add();
+        }
+    }
+    writer.write(clear + 1)?;
+    Ok(())
+}
+
+/// Decodes codes produced by encode with the same options.
+pub fn decode(input: &mut dyn ReadBits, options: &Options) -> BoxResult<Vec<u8>> {
+    options.check()?;
+    let clear = options.clear_code();
+    let mut output = Vec::new();
+    // Where in the output the string of each code after the end code
+    // starts, and its length.
+    let mut entries: Vec<(usize, usize)> = Vec::new();
+    let mut width = options.initial_width;
+    // The start and length of the string of the previous code.
+    let mut previous: Option<(usize, usize)> = None;
+    loop {
+        let code = input.read_bits(width as u32)?;
+        if code == clear {
+            entries.clear();
+            width = options.initial_width;
+            previous = None;
+            continue;
+        }
+        if code == clear + 1 {
+            return Ok(output);
+        }
+        let next = clear + 2 + entries.len() as u32;
+        let start = output.len();
+        if code < clear {
+            output.push(code as u8);
+        } else if code < next {
+            let (s, len) = entries[(code - clear - 2) as usize];
+            output.extend_from_within(s..s + len);
+        } else {
+            match previous {
+                // The string of the code being defined: the previous string
+                // followed by its first symbol.
+                Some((s, len)) if code == next => {
+                    output.extend_from_within(s..s + len);
+                    output.push(output[s]);
+                },
+                _ => return Err(format!("invalid LZW code {}", code).into()),
+            }
+        }
+        if let Some((s, len)) = previous {
+            if next < 1 << options.max_width {
+                entries.push((s, len + 1));
+                if next + 1 >= 1 << width && width < options.max_width {
+                    width += 1;
+                }
+            }
+        }
+        previous = Some((start, output.len() - start));
+    }
+}
+
+/// Compresses data in memory.
+pub fn compress(input: &[u8], options: &Options) -> BoxResult<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut writer = BitWriter::new(&mut output);
+    encode(input, &mut writer, options)?;
+    writer.flush()?;
+    drop(writer);
+    Ok(output)
+}
+
+/// Decompresses data produced by compress with the same options.
+pub fn decompress(input: &[u8], options: &Options) -> BoxResult<Vec<u8>> {
+    decode(&mut SliceBitReader::new(input), options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::lcg;
+
+    #[test]
+    fn codes() {
+        // With 3-bit codes, clear is 4, end is 5, and the first new code
+        // is 6. The codes are clear, 1 (adding 6 = 11), 6 (adding 7 =
+        // 111), 6 (adding 8 = 110, which needs 4 bits), 0 and end.
+        let options = Options::new().initial_width(3);
+        let data = compress(&[1, 1, 1, 1, 1, 0], &options).unwrap();
+        let expected: u32 = 4 | 1 << 3 | 6 << 6 | 6 << 9 | 5 << 16;
+        assert_eq!(data, expected.to_le_bytes()[..3]);
+        assert_eq!(decompress(&data, &options).unwrap(), [1, 1, 1, 1, 1, 0]);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let text = b"TOBEORNOTTOBEORTOBEORNOT#".repeat(300);
+        let options = Options::new();
+        let compressed = compress(&text, &options).unwrap();
+        assert!(compressed.len() < text.len() / 8);
+        assert_eq!(decompress(&compressed, &options).unwrap(), text);
+
+        // Small maximum widths fill the dictionary and clear it often.
+        let data: Vec<u8> = (0..20000).zip(lcg(1)).map(|(i, state)| {
+            if i % 7 < 4 { (state >> 29) as u8 } else { b'a' + (i % 5) as u8 }
+        }).collect();
+        for &(initial, max) in &[(9, 9), (9, 10), (9, 12), (9, 16), (8, 12)] {
+            let options = Options::new().initial_width(initial).max_width(max);
+            let compressed = compress(&data, &options).unwrap();
+            assert_eq!(decompress(&compressed, &options).unwrap(), data);
+        }
+        let options = Options::new().initial_width(3).max_width(5);
+        let colors: Vec<u8> = data.iter().map(|&b| b & 3).collect();
+        assert_eq!(decompress(&compress(&colors, &options).unwrap(), &options).unwrap(), colors);
+
+        let options = Options::new();
+        for len in 0..4 {
+            let compressed = compress(&text[..len], &options).unwrap();
+            assert_eq!(decompress(&compressed, &options).unwrap(), &text[..len]);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        let options = Options::new().initial_width(4);
+        assert!(compress(&[8], &options).is_err());
+        assert!(compress(b"", &Options::new().initial_width(10)).is_err());
+        assert!(compress(b"", &Options::new().initial_width(2)).is_err());
+        assert!(compress(b"", &Options::new().max_width(8)).is_err());
+        assert!(compress(b"", &Options::new().max_width(17)).is_err());
+
+        let compressed = compress(b"abcabcabc", &Options::new()).unwrap();
+        assert!(decompress(&compressed[..compressed.len() - 2], &Options::new()).is_err());
+        // A code that is not yet defined.
+        let mut output = Vec::new();
+        let mut writer = BitWriter::new(&mut output);
+        writer.write_bits(256, 9).unwrap();
+        writer.write_bits(300, 9).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+        assert!(decompress(&output, &Options::new()).is_err());
+    }
+}
@@ -0,0 +1,146 @@
+// Image quality metrics.
+//
+// Copyright 2023 Robbert Haarman
+//
+// SPDX-License-Identifier: MIT
+
+//! Metrics that compare an image reconstructed by a lossy codec with the
+//! original, to weigh quality against size. Images are planes of 8-bit
+//! values, except for psnr_u16, which takes wider samples; compare color
+//! images one channel at a time.
+
+/// Side of the square windows ssim compares.
+const SSIM_WINDOW: usize = 8;
+
+/// Distance between the starts of consecutive windows ssim compares.
+const SSIM_STEP: usize = 4;
+
+/// Returns the mean squared error between two images of the same size.
+pub fn mse(a: &[u8], b: &[u8]) -> f64 {
+    assert_eq!(a.len(), b.len(), "images differ in size");
+    assert!(!a.is_empty(), "images are empty");
+    let sum: u64 = a.iter().zip(b.iter()).map(|(&x, &y)| (x.abs_diff(y) as u64).pow(2)).sum();
+    sum as f64 / a.len() as f64
+}
+
+/// Returns the peak signal-to-noise ratio of b relative to a, in
+/// decibels. Higher is better; identical images give infinity.
+pub fn psnr(a: &[u8], b: &[u8]) -> f64 {
+    10.0 * (255.0 * 255.0 / mse(a, b)).log10()
+}
+
+/// Like psnr, but for samples of bits bits, from 8 to 16, relative to
+/// the largest value such samples can hold.
+pub fn psnr_u16(a: &[u16], b: &[u16], bits: u32) -> f64 {
+    assert!((8..=16).contains(&bits), "bit depth must be from 8 to 16");
+    assert_eq!(a.len(), b.len(), "images differ in size");
+    assert!(!a.is_empty(), "images are empty");
+    let sum: f64 = a.iter().zip(b.iter()).map(|(&x, &y)| (x.abs_diff(y) as f64).powi(2)).sum();
+    let peak = ((1u32 << bits) - 1) as f64;
+    10.0 * (peak * peak * a.len() as f64 / sum).log10()
+}
+
+/// Returns the structural similarity of two width x height images, from
+/// -1 to 1, where 1 means they are identical. This is the mean of the
+/// SSIM of 8x8 windows, 4 pixels apart, with the constants from the
+/// original paper. Images smaller than a window are compared as a
+/// whole.
+pub fn ssim(a: &[u8], b: &[u8], width: usize, height: usize) -> f64 {
+    assert_eq!(a.len(), width * height, "first image does not match size");
+    assert_eq!(b.len(), width * height, "second image does not match size");
+    assert!(!a.is_empty(), "images are empty");
+    let positions = |len: usize| -> Vec<usize> {
+        if len <= SSIM_WINDOW {
+            vec![0]
+        } else {
+            let mut starts: Vec<usize> = (0..=len - SSIM_WINDOW).step_by(SSIM_STEP).collect();
+            if starts.last() != Some(&(len - SSIM_WINDOW)) {
+                starts.push(len - SSIM_WINDOW);
+            }
+            starts
+        }
+    };
+    let (window_width, window_height) = (width.min(SSIM_WINDOW), height.min(SSIM_WINDOW));
+    let mut total = 0.0;
+    let mut count = 0;
+    for &y in &positions(height) {
+        for &x in &positions(width) {
+            let pixels = (y..y + window_height)
+                .flat_map(|row| (x..x + window_width).map(move |col| row * width + col));
+            total += window_ssim(pixels.map(|i| (a[i] as f64, b[i] as f64)));
+            count += 1;
+        }
+    }
+    total / count as f64
+}
+
+/// Returns the SSIM of a window, given as pairs of corresponding pixels.
+fn window_ssim<I: Iterator<Item = (f64, f64)>>(pixels: I) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+    let (mut n, mut sa, mut sb, mut saa, mut sbb, mut sab) = (0.0, 0.0, 0.0, 0.0, 0.0, 0.0);
+    for (x, y) in pixels {
+        n += 1.0;
+        sa += x;
+        sb += y;
+        saa += x * x;
+        sbb += y * y;
+        sab += x * y;
+    }
+    let (ma, mb) = (sa / n, sb / n);
+    let va = saa / n - ma * ma;
+    let vb = sbb / n - mb * mb;
+    let cov = sab / n - ma * mb;
+    (2.0 * ma * mb + C1) * (2.0 * cov + C2) / ((ma * ma + mb * mb + C1) * (va + vb + C2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_image(width: usize, height: usize) -> Vec<u8> {
+        (0..width * height).map(|i| ((i % width) * 7 + (i / width) * 3) as u8).collect()
+    }
+
+    #[test]
+    fn psnr() {
+        let a = test_image(16, 16);
+        assert_eq!(mse(&a, &a), 0.0);
+        assert_eq!(super::psnr(&a, &a), f64::INFINITY);
+        let b: Vec<u8> = a.iter().map(|&x| x ^ 1).collect();
+        assert_eq!(mse(&a, &b), 1.0);
+        assert!((super::psnr(&a, &b) - 48.1308).abs() < 1e-4);
+        let c: Vec<u8> = a.iter().map(|&x| x ^ 4).collect();
+        assert!(super::psnr(&a, &c) < super::psnr(&a, &b));
+
+        // The same error is smaller relative to a larger peak.
+        let wide: Vec<u16> = a.iter().map(|&x| x as u16 * 4).collect();
+        let narrow: Vec<u16> = b.iter().map(|&x| x as u16 * 4).collect();
+        assert_eq!(psnr_u16(&wide, &wide, 10), f64::INFINITY);
+        let expected = 10.0 * (1023.0f64 * 1023.0 / 16.0).log10();
+        assert!((psnr_u16(&wide, &narrow, 10) - expected).abs() < 1e-9);
+        let bytes: Vec<u16> = a.iter().map(|&x| x as u16).collect();
+        let flipped: Vec<u16> = b.iter().map(|&x| x as u16).collect();
+        assert!((psnr_u16(&bytes, &flipped, 8) - super::psnr(&a, &b)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn ssim() {
+        let (width, height) = (21, 13);
+        let a = test_image(width, height);
+        assert!((super::ssim(&a, &a, width, height) - 1.0).abs() < 1e-12);
+        let slightly: Vec<u8> = a.iter().enumerate().map(|(i, &x)| x ^ (i % 2) as u8).collect();
+        let very: Vec<u8> = a.iter().enumerate().map(|(i, &x)| x ^ (i % 7 * 9) as u8).collect();
+        let s1 = super::ssim(&a, &slightly, width, height);
+        let s2 = super::ssim(&a, &very, width, height);
+        assert!(s1 < 1.0 && s1 > 0.9, "{}", s1);
+        assert!(s2 < s1, "{} not below {}", s2, s1);
+
+        // An inverted image is structurally the opposite.
+        let inverted: Vec<u8> = a.iter().map(|&x| 255 - x).collect();
+        assert!(super::ssim(&a, &inverted, width, height) < 0.0);
+
+        // Images smaller than a window are compared as a whole.
+        assert!((super::ssim(&[1, 2, 3], &[1, 2, 3], 3, 1) - 1.0).abs() < 1e-12);
+    }
+}
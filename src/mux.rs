@@ -0,0 +1,198 @@
+// Multiplexing of several bit streams over one WriteBits.
+//
+// Copyright 2021 Robbert Haarman
+//
+// SPDX-License-Identifier: MIT
+//
+// Formats that code literals, lengths and distances as separate streams
+// can write each stream to its own BitVec in a Muxer and then
+// have the Muxer write them to a single output as segments. A segment
+// consists of the tag of its stream (8 bits), its length in bits (32
+// bits) and its bits. Segments are written in groups, each preceded by
+// the number of segments in it (8 bits). A group of 0 segments marks
+// the end of the streams. A Demuxer reads the groups back and appends
+// the bits of each segment to the BitVec for its tag, so that a
+// stream can span any number of segments.
+
+use crate::io::{BitVec, ReadBits, WriteBits};
+use crate::result::BoxResult;
+
+/// Maximum number of streams in a Muxer or Demuxer, so that the number
+/// of segments in a group fits in 8 bits.
+pub const MAX_STREAMS: usize = 255;
+
+/// Writes several bit streams to one WriteBits as tagged segments.
+pub struct Muxer {
+    streams: Vec<BitVec>,
+}
+
+impl Muxer {
+    /// Creates a Muxer for nstreams streams, tagged 0 to nstreams - 1.
+    pub fn new(nstreams: usize) -> Muxer {
+        assert!(nstreams <= MAX_STREAMS, "too many streams");
+        Muxer { streams: (0..nstreams).map(|_| BitVec::new()).collect() }
+    }
+
+    /// Returns the writer for the stream with the given tag.
+    pub fn stream(&mut self, tag: usize) -> &mut BitVec {
+        &mut self.streams[tag]
+    }
+
+    /// Writes the bits written to each stream since the last call as a
+    /// group of segments. Writes nothing if no bits have been written.
+    pub fn write_segments(&mut self, output: &mut dyn WriteBits) -> BoxResult<()> {
+        let count = self.streams.iter().filter(|s| !s.is_empty()).count();
+        if count == 0 {
+            return Ok(());
+        }
+        // Check every segment before writing anything, so that an error
+        // does not leave a group that is missing segments in output.
+        if let Some(stream) = self.streams.iter().find(|s| s.len() > u32::MAX as u64) {
+            return Err(format!("segment of {} bits is too long", stream.len()).into());
+        }
+        output.write_bits(count as u32, 8)?;
+        for (tag, stream) in self.streams.iter_mut().enumerate() {
+            let len = stream.len();
+            if len == 0 {
+                continue;
+            }
+            output.write_bits(tag as u32, 8)?;
+            output.write_bits(len as u32, 32)?;
+            stream.rewind();
+            while stream.remaining_bits() > 0 {
+                let n = std::cmp::min(64, stream.remaining_bits()) as u32;
+                output.write_bits64(stream.read_bits64(n)?, n as u8)?;
+            }
+            stream.clear();
+        }
+        Ok(())
+    }
+
+    /// Writes the remaining bits and the end marker. Does not flush
+    /// output.
+    pub fn finish(&mut self, output: &mut dyn WriteBits) -> BoxResult<()> {
+        self.write_segments(output)?;
+        output.write_bits(0, 8)
+    }
+}
+
+/// Reads segments written by a Muxer and splits them into streams.
+pub struct Demuxer {
+    streams: Vec<BitVec>,
+}
+
+impl Demuxer {
+    /// Creates a Demuxer for nstreams streams, tagged 0 to nstreams - 1.
+    pub fn new(nstreams: usize) -> Demuxer {
+        assert!(nstreams <= MAX_STREAMS, "too many streams");
+        Demuxer { streams: (0..nstreams).map(|_| BitVec::new()).collect() }
+    }
+
+    /// Returns the reader for the stream with the given tag.
+    pub fn stream(&mut self, tag: usize) -> &mut BitVec {
+        &mut self.streams[tag]
+    }
+
+    /// Reads the next group of segments from input and appends their
+    /// bits to their streams. Returns Ok(false) if the end marker was
+    /// read instead.
+    pub fn read_segments(&mut self, input: &mut dyn ReadBits) -> BoxResult<bool> {
+        let count = input.read_bits(8)?;
+        if count == 0 {
+            return Ok(false);
+        }
+        for stream in self.streams.iter_mut() {
+            stream.discard_read();
+        }
+        for _ in 0..count {
+            let tag = input.read_bits(8)? as usize;
+            let stream = match self.streams.get_mut(tag) {
+                Some(stream) => stream,
+                None => return Err(format!("segment for unknown stream {}", tag).into()),
+            };
+            let len = input.read_bits(32)? as u64;
+            let mut pos = 0;
+            while pos < len {
+                let n = std::cmp::min(64, len - pos) as u32;
+                stream.write_bits64(input.read_bits64(n)?, n as u8)?;
+                pos += n as u64;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Reads all remaining groups of segments from input, up to and
+    /// including the end marker.
+    pub fn read_all(&mut self, input: &mut dyn ReadBits) -> BoxResult<()> {
+        while self.read_segments(input)? {}
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::{BitReadError, BitWriter, SliceBitReader};
+
+    #[test]
+    fn mux_roundtrip() {
+        let mut output = Vec::new();
+        {
+            let mut writer = BitWriter::new(&mut output);
+            writer.write_bits(0x3, 3).unwrap();
+            let mut mux = Muxer::new(3);
+            for i in 0..1000u32 {
+                mux.stream(0).write_bits(i & 0xff, 8).unwrap();
+                if i % 3 == 0 {
+                    mux.stream(2).write_bits(i, 17).unwrap();
+                }
+                if i % 400 == 399 {
+                    mux.write_segments(&mut writer).unwrap();
+                }
+            }
+            mux.stream(1).write_bits64(0x123456789abcdef, 60).unwrap();
+            assert_eq!(mux.stream(1).len(), 60);
+            mux.finish(&mut writer).unwrap();
+            writer.flush().unwrap();
+        }
+
+        let mut reader = SliceBitReader::new(&output);
+        assert_eq!(reader.read_bits(3).unwrap(), 0x3);
+        let mut demux = Demuxer::new(3);
+        assert!(demux.read_segments(&mut reader).unwrap());
+        assert_eq!(demux.stream(0).remaining_bits(), 400 * 8);
+        assert_eq!(demux.stream(1).try_read_bits(1).unwrap(), None);
+        for i in 0..300u32 {
+            assert_eq!(demux.stream(0).read_bits(8).unwrap(), i & 0xff);
+        }
+        demux.read_all(&mut reader).unwrap();
+        for i in 300..1000u32 {
+            assert_eq!(demux.stream(0).read_bits(8).unwrap(), i & 0xff);
+        }
+        assert_eq!(demux.stream(0).bit_position(), 8000);
+        for i in (0..1000u32).step_by(3) {
+            assert_eq!(demux.stream(2).read_bits(17).unwrap(), i);
+        }
+        assert_eq!(demux.stream(1).read_bits64(60).unwrap(), 0x123456789abcdef);
+        assert!(matches!(demux.stream(1).read_bits(2).unwrap_err(),
+                         BitReadError::UnexpectedEof { bits_needed: 2 }));
+    }
+
+    #[test]
+    fn demux_errors() {
+        let mut output = Vec::new();
+        {
+            let mut writer = BitWriter::new(&mut output);
+            let mut mux = Muxer::new(3);
+            mux.stream(2).write_bits(0x7f, 7).unwrap();
+            mux.finish(&mut writer).unwrap();
+            writer.flush().unwrap();
+        }
+        let mut demux = Demuxer::new(2);
+        assert!(demux.read_all(&mut SliceBitReader::new(&output)).is_err());
+
+        let mut demux = Demuxer::new(3);
+        let err = demux.read_all(&mut SliceBitReader::new(&output[..4])).unwrap_err();
+        assert!(err.is::<BitReadError>());
+    }
+}
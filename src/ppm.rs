@@ -0,0 +1,362 @@
+// Prediction by partial matching.
+//
+// Copyright 2023 Robbert Haarman
+//
+// SPDX-License-Identifier: MIT
+
+//! This module implements a PPM compressor in the style of PPMC. Each
+//! byte is predicted from the contexts formed by the bytes before it,
+//! starting with the longest context, of order bytes, and falling back
+//! to shorter ones. A context predicts the bytes that have followed it
+//! before, in proportion to how often they did. If the byte has not
+//! followed the context before, an escape is coded and the next shorter
+//! context is tried, leaving out the bytes the longer contexts already
+//! predicted. After the empty context of order 0, bytes are coded as 8
+//! bits with equal probabilities. As in PPMC, the probability of an
+//! escape is the number of different bytes that have followed the
+//! context divided by that number plus the number of times the context
+//! occurred. After a byte is coded, it is added to the context that
+//! predicted it and the longer contexts.
+//!
+//! All decisions are coded as bits with the binary range coder from brc.
+//! A byte is coded as a bit for each candidate, from most to least
+//! frequent, that says whether it is that candidate.
+//!
+//! A stream starts with the order as 1 byte and the length of the data
+//! as 8 bytes, least significant byte first, followed by the range
+//! coded data.
+
+use std::collections::HashMap;
+
+use crate::brc;
+use crate::result::BoxResult;
+
+/// Largest order supported.
+pub const MAX_ORDER: usize = 7;
+
+const HEADER_SIZE: usize = 9;
+
+/// Count at which the counts of a context are halved, so that it keeps
+/// adapting to the data.
+const MAX_COUNT: u32 = 1 << 10;
+
+/// Options for compression.
+#[derive(Clone, Debug)]
+pub struct Options {
+    order: usize,
+}
+
+impl Options {
+    /// Returns the default options: order 4.
+    pub fn new() -> Options {
+        Options { order: 4 }
+    }
+
+    /// Sets the length of the longest context, from 0 to MAX_ORDER.
+    /// Longer contexts predict text better, but use more memory and
+    /// take longer to learn.
+    pub fn order(mut self, order: usize) -> Options {
+        self.order = order;
+        self
+    }
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options::new()
+    }
+}
+
+/// The bytes that have followed a context, with the number of times
+/// each did, from most to least frequent.
+#[derive(Default)]
+struct Context {
+    symbols: Vec<(u8, u32)>,
+}
+
+impl Context {
+    /// Adds an occurrence of sym.
+    fn update(&mut self, sym: u8) {
+        let mut i = match self.symbols.iter().position(|&(s, _)| s == sym) {
+            Some(i) => i,
+            None => {
+                self.symbols.push((sym, 0));
+                self.symbols.len() - 1
+            },
+        };
+        self.symbols[i].1 += 1;
+        while i > 0 && self.symbols[i - 1].1 < self.symbols[i].1 {
+            self.symbols.swap(i - 1, i);
+            i -= 1;
+        }
+        if self.symbols[i].1 >= MAX_COUNT {
+            for (_, count) in &mut self.symbols {
+                *count = count.div_ceil(2);
+            }
+        }
+    }
+}
+
+/// Returns the probability of a 0 bit in the form brc uses, for a
+/// decision between outcomes with weights zero and one.
+fn p0(zero: u32, one: u32) -> u8 {
+    let p = (zero as u64 * 256 + (zero + one) as u64 / 2) / (zero + one) as u64;
+    p.clamp(1, 255) as u8
+}
+
+/// The contexts seen so far, and the bytes before the current one.
+struct Model {
+    order: usize,
+    contexts: HashMap<u64, Context>,
+    /// The last MAX_ORDER bytes, the most recent in the low bits.
+    history: u64,
+    /// Number of bytes seen, up to order.
+    seen: usize,
+}
+
+impl Model {
+    fn new(order: usize) -> Model {
+        Model { order, contexts: HashMap::new(), history: 0, seen: 0 }
+    }
+
+    /// Returns the key of the context of order k, for k up to seen.
+    fn key(&self, k: usize) -> u64 {
+        (k as u64) << 56 | (self.history & ((1u64 << (8 * k)) - 1))
+    }
+
+    /// Adds sym to the contexts from order k up, and makes it part of
+    /// the history.
+    fn update(&mut self, sym: u8, k: usize) {
+        for k in k..=self.seen {
+            let key = self.key(k);
+            self.contexts.entry(key).or_default().update(sym);
+        }
+        self.history = self.history << 8 | sym as u64;
+        self.seen = (self.seen + 1).min(self.order);
+    }
+}
+
+/// The candidates of a context: the bytes that have followed it and
+/// were not excluded by longer contexts, and the sum of their counts.
+fn candidates(context: &Context, excluded: &[bool; 256]) -> (Vec<(u8, u32)>, u32) {
+    let candidates: Vec<(u8, u32)> = context.symbols.iter()
+        .filter(|&&(s, _)| !excluded[s as usize]).copied().collect();
+    let total = candidates.iter().map(|&(_, c)| c).sum();
+    (candidates, total)
+}
+
+/// Writes bits to a Vec<u8> using brc::Encoder.
+struct RangeEncoder {
+    encoder: brc::Encoder,
+    output: Vec<u8>,
+}
+
+impl RangeEncoder {
+    fn encode(&mut self, bit: bool, p0: u8) {
+        self.encoder.encode_bit(p0, bit);
+        while self.encoder.needs_normalize() {
+            self.output.push(self.encoder.normalize());
+        }
+    }
+}
+
+/// Reads bits written by a RangeEncoder from a slice.
+struct RangeDecoder<'a> {
+    decoder: brc::Decoder,
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl RangeDecoder<'_> {
+    fn decode(&mut self, p0: u8) -> bool {
+        while self.decoder.needs_normalize() {
+            // The encoder leaves off the bytes the decoder needs to read
+            // ahead, which are 0.
+            self.decoder.normalize(self.input.get(self.pos).copied().unwrap_or(0));
+            self.pos += 1;
+        }
+        self.decoder.decode_bit(p0)
+    }
+}
+
+/// Compresses data.
+pub fn compress(input: &[u8], options: &Options) -> BoxResult<Vec<u8>> {
+    if options.order > MAX_ORDER {
+        return Err(format!("invalid PPM order {}", options.order).into());
+    }
+    let mut output = Vec::with_capacity(HEADER_SIZE + input.len() / 2);
+    output.push(options.order as u8);
+    output.extend_from_slice(&(input.len() as u64).to_le_bytes());
+    let mut encoder = RangeEncoder { encoder: brc::Encoder::new(), output };
+    let mut model = Model::new(options.order);
+    for &sym in input {
+        let mut excluded = [false; 256];
+        let mut found = None;
+        for k in (0..=model.seen).rev() {
+            let context = match model.contexts.get(&model.key(k)) {
+                Some(context) => context,
+                None => continue,
+            };
+            let (candidates, total) = candidates(context, &excluded);
+            if candidates.is_empty() {
+                continue;
+            }
+            let hit = candidates.iter().any(|&(s, _)| s == sym);
+            encoder.encode(!hit, p0(total, candidates.len() as u32));
+            if hit {
+                let mut remaining = total;
+                for &(s, count) in &candidates[..candidates.len() - 1] {
+                    encoder.encode(s != sym, p0(count, remaining - count));
+                    if s == sym {
+                        break;
+                    }
+                    remaining -= count;
+                }
+                found = Some(k);
+                break;
+            }
+            for &(s, _) in &candidates {
+                excluded[s as usize] = true;
+            }
+        }
+        if found.is_none() {
+            for i in (0..8).rev() {
+                encoder.encode(sym >> i & 1 != 0, 128);
+            }
+        }
+        model.update(sym, found.unwrap_or(0));
+    }
+    let mut output = encoder.output;
+    output.push(encoder.encoder.flush());
+    Ok(output)
+}
+
+/// Decompresses data produced by compress.
+pub fn decompress(input: &[u8]) -> BoxResult<Vec<u8>> {
+    if input.len() < HEADER_SIZE {
+        return Err("PPM data is too short for header".into());
+    }
+    let order = input[0] as usize;
+    if order > MAX_ORDER {
+        return Err(format!("invalid PPM order {}", order).into());
+    }
+    let mut len_bytes = [0; 8];
+    len_bytes.copy_from_slice(&input[1..HEADER_SIZE]);
+    let len = u64::from_le_bytes(len_bytes);
+    let mut decoder = RangeDecoder {
+        decoder: brc::Decoder::new(), input: &input[HEADER_SIZE..], pos: 0,
+    };
+    let mut model = Model::new(order);
+    let mut output = Vec::with_capacity(len.min(1 << 24) as usize);
+    while (output.len() as u64) < len {
+        let mut excluded = [false; 256];
+        let mut found = None;
+        for k in (0..=model.seen).rev() {
+            let context = match model.contexts.get(&model.key(k)) {
+                Some(context) => context,
+                None => continue,
+            };
+            let (candidates, total) = candidates(context, &excluded);
+            if candidates.is_empty() {
+                continue;
+            }
+            if !decoder.decode(p0(total, candidates.len() as u32)) {
+                let mut remaining = total;
+                let mut sym = candidates[candidates.len() - 1].0;
+                for &(s, count) in &candidates[..candidates.len() - 1] {
+                    if !decoder.decode(p0(count, remaining - count)) {
+                        sym = s;
+                        break;
+                    }
+                    remaining -= count;
+                }
+                found = Some((sym, k));
+                break;
+            }
+            for &(s, _) in &candidates {
+                excluded[s as usize] = true;
+            }
+        }
+        let (sym, k) = match found {
+            Some(found) => found,
+            None => {
+                let mut sym = 0;
+                for _ in 0..8 {
+                    sym = sym << 1 | decoder.decode(128) as u8;
+                }
+                (sym, 0)
+            },
+        };
+        output.push(sym);
+        model.update(sym, k);
+        // The decoder reads ahead at most 3 bytes past the input.
+        if decoder.pos > decoder.input.len() + 3 {
+            return Err("PPM data is truncated".into());
+        }
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lz78;
+    use crate::testutil::lcg_bytes;
+
+    const TEXT: &[u8] = b"It is a truth universally acknowledged, that a single man in \
+        possession of a good fortune, must be in want of a wife. However little known \
+        the feelings or views of such a man may be on his first entering a neighbourhood, \
+        this truth is so well fixed in the minds of the surrounding families, that he is \
+        considered the rightful property of some one or other of their daughters.\n";
+
+    #[test]
+    fn contexts() {
+        let mut context = Context::default();
+        for &b in b"abbcbb" {
+            context.update(b);
+        }
+        assert_eq!(context.symbols, [(b'b', 4), (b'a', 1), (b'c', 1)]);
+        for _ in 0..MAX_COUNT {
+            context.update(b'c');
+        }
+        assert_eq!(context.symbols, [(b'c', 513), (b'b', 2), (b'a', 1)]);
+        assert_eq!(p0(3, 1), 192);
+        assert_eq!(p0(1, 0), 255);
+        assert_eq!(p0(0, 5), 1);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let text = TEXT.repeat(20);
+        let compressed = compress(&text, &Options::new()).unwrap();
+        assert!(compressed.len() < text.len() / 10, "{} bytes", compressed.len());
+        assert_eq!(decompress(&compressed).unwrap(), text);
+        // PPM predicts text better than dictionary coding does.
+        let once = compress(TEXT, &Options::new()).unwrap();
+        assert!(once.len() < lz78::compress(TEXT, &lz78::Options::new()).unwrap().len());
+
+        let data: Vec<u8> = lcg_bytes(11, 5000).into_iter().enumerate().map(|(i, b)| {
+            if i % 3 == 0 { b } else { TEXT[i % 101] }
+        }).collect();
+        for order in 0..=MAX_ORDER {
+            let options = Options::new().order(order);
+            let compressed = compress(&data, &options).unwrap();
+            assert_eq!(decompress(&compressed).unwrap(), data);
+        }
+        for len in 0..4 {
+            let compressed = compress(&TEXT[..len], &Options::new()).unwrap();
+            assert_eq!(decompress(&compressed).unwrap(), &TEXT[..len]);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(compress(b"", &Options::new().order(MAX_ORDER + 1)).is_err());
+        let compressed = compress(TEXT, &Options::new()).unwrap();
+        assert!(decompress(&compressed[..HEADER_SIZE - 1]).is_err());
+        assert!(decompress(&compressed[..compressed.len() / 2]).is_err());
+        let mut bad = compressed.clone();
+        bad[0] = MAX_ORDER as u8 + 1;
+        assert!(decompress(&bad).is_err());
+    }
+}
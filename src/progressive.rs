@@ -0,0 +1,224 @@
+// Progressive coding of quantized coefficients.
+//
+// Copyright 2023 Robbert Haarman
+//
+// SPDX-License-Identifier: MIT
+
+//! This module stores the quantized DCT coefficients of an image in an
+//! order that allows decoding any prefix of the stream. Coefficients are
+//! sent one bit plane at a time, most significant plane first, and
+//! within each plane by frequency band, lowest frequencies first. A
+//! truncated stream therefore decodes to a coarser approximation of the
+//! image, which improves as more of the stream arrives.
+//!
+//! The stream starts with the width and height of the image as 32-bit
+//! numbers and the number of bit planes as a 6-bit number. For each bit
+//! plane, each band and each block, it then contains a bit for each
+//! coefficient of the band in the block. For a coefficient that was 0 in
+//! all previous planes, the bit tells whether it has this bit set, and
+//! if so, is followed by its sign. For other coefficients, it is the
+//! next bit of the magnitude. Bits are written least significant bit of
+//! each byte first, as by io::BitWriter.
+
+use crate::dct::ImageCoefficients;
+use crate::io::{BitReadError, BitWriter, ReadBits, SliceBitReader, WriteBits};
+use crate::result::BoxResult;
+
+/// Blocks are N by N coefficients.
+const N: usize = 8;
+
+/// Number of frequency bands. Band u + v holds the coefficients in row
+/// v and column u of a block.
+const BANDS: usize = 2 * N - 1;
+
+/// Largest number of pixels decode accepts. Even a stream that is just
+/// a header decodes to coefficients for the whole image, so this limits
+/// what a forged header can make decode allocate.
+const MAX_PIXELS: usize = 1 << 24;
+
+/// Returns the indices of the coefficients in band b.
+fn band(b: usize) -> impl Iterator<Item = usize> {
+    (0..N * N).filter(move |i| i % N + i / N == b)
+}
+
+/// Encodes the quantized coefficients of an image progressively. The
+/// coefficients must not be i32::MIN.
+pub fn encode(coeffs: &ImageCoefficients<i32>) -> Vec<u8> {
+    assert_eq!(coeffs.blocks.len(), coeffs.blocks_wide * coeffs.blocks_high,
+               "wrong number of blocks");
+    let max = coeffs.blocks.iter().flat_map(|b| b.iter()).map(|q| q.unsigned_abs()).max()
+        .unwrap_or(0);
+    assert!(max <= i32::MAX as u32, "coefficient out of range");
+    let planes = 32 - max.leading_zeros();
+    let mut output = Vec::new();
+    let mut writer = BitWriter::new(&mut output);
+    // Writing to a Vec cannot fail.
+    writer.write_bits(coeffs.width as u32, 32).unwrap();
+    writer.write_bits(coeffs.height as u32, 32).unwrap();
+    writer.write_bits(planes, 6).unwrap();
+    for plane in (0..planes).rev() {
+        for b in 0..BANDS {
+            for block in &coeffs.blocks {
+                for i in band(b) {
+                    let magnitude = block[i].unsigned_abs();
+                    writer.write_bits(magnitude >> plane & 1, 1).unwrap();
+                    if magnitude >> plane == 1 {
+                        writer.write_bits((block[i] < 0) as u32, 1).unwrap();
+                    }
+                }
+            }
+        }
+    }
+    writer.flush().unwrap();
+    drop(writer);
+    output
+}
+
+/// Decodes the planes of a progressive stream into blocks, recording in
+/// known the lowest plane read for each coefficient. Stops with an error
+/// where the input ends.
+fn decode_planes(reader: &mut SliceBitReader, planes: u32, blocks: &mut [[i32; N * N]],
+                 known: &mut [[u8; N * N]]) -> Result<(), BitReadError> {
+    for plane in (0..planes).rev() {
+        for b in 0..BANDS {
+            for (block, known) in blocks.iter_mut().zip(known.iter_mut()) {
+                for i in band(b) {
+                    let bit = reader.read_bits(1)? as i32;
+                    if block[i] != 0 {
+                        block[i] += block[i].signum() * (bit << plane);
+                    } else if bit != 0 {
+                        let negative = reader.read_bits(1)? != 0;
+                        block[i] = if negative { -1 << plane } else { 1 << plane };
+                    }
+                    known[i] = plane as u8;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Decodes a stream produced by encode, or any prefix of it that
+/// includes the header. Coefficients whose lower bits are missing are
+/// reconstructed in the middle of the range of values they could have.
+pub fn decode(data: &[u8]) -> BoxResult<ImageCoefficients<i32>> {
+    let mut reader = SliceBitReader::new(data);
+    let width = reader.read_bits(32)? as usize;
+    let height = reader.read_bits(32)? as usize;
+    let planes = reader.read_bits(6)?;
+    if width == 0 || height == 0 || width.saturating_mul(height) > MAX_PIXELS {
+        return Err("invalid image size in progressive stream".into());
+    }
+    if planes > 31 {
+        return Err("invalid number of bit planes in progressive stream".into());
+    }
+    let (blocks_wide, blocks_high) = (width.div_ceil(N), height.div_ceil(N));
+    let mut blocks = vec![[0i32; N * N]; blocks_wide * blocks_high];
+    let mut known = vec![[planes as u8; N * N]; blocks.len()];
+    match decode_planes(&mut reader, planes, &mut blocks, &mut known) {
+        Ok(()) | Err(BitReadError::UnexpectedEof { .. }) => (),
+        Err(e) => return Err(e.into()),
+    }
+    for (block, known) in blocks.iter_mut().zip(known.iter()) {
+        for (q, &k) in block.iter_mut().zip(known.iter()) {
+            if *q != 0 && k > 0 {
+                *q += q.signum() * (((1 << k) - 1) / 2);
+            }
+        }
+    }
+    Ok(ImageCoefficients { width, height, blocks_wide, blocks_high, blocks })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dct::{self, Padding};
+
+    fn test_coeffs() -> ImageCoefficients<i32> {
+        let (width, height) = (37, 21);
+        let image: Vec<f32> = (0..width * height).map(|i| {
+            let (x, y) = ((i % width) as f32, (i / width) as f32);
+            ((x * 0.4).sin() * 60.0 + (y * 0.3).cos() * 40.0 + x * y * 0.1) - 60.0
+        }).collect();
+        let qtable = dct::scale_qtable(&dct::LUMINANCE_QTABLE, 90);
+        dct::quantize_image(&dct::transform_image(&image, width, height, width,
+                                                  Padding::Replicate), &qtable)
+    }
+
+    #[test]
+    fn bands() {
+        assert_eq!(band(0).collect::<Vec<usize>>(), [0]);
+        assert_eq!(band(2).collect::<Vec<usize>>(), [2, 9, 16]);
+        assert_eq!((0..BANDS).map(|b| band(b).count()).sum::<usize>(), N * N);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let coeffs = test_coeffs();
+        let data = encode(&coeffs);
+        assert_eq!(decode(&data).unwrap(), coeffs);
+
+        let empty = ImageCoefficients { width: 3, height: 3, blocks_wide: 1, blocks_high: 1,
+                                        blocks: vec![[0; N * N]] };
+        assert_eq!(decode(&encode(&empty)).unwrap(), empty);
+    }
+
+    #[test]
+    fn truncated() {
+        let coeffs = test_coeffs();
+        let data = encode(&coeffs);
+        let error = |decoded: &ImageCoefficients<i32>| -> i64 {
+            decoded.blocks.iter().zip(coeffs.blocks.iter())
+                .flat_map(|(a, b)| a.iter().zip(b.iter()))
+                .map(|(&a, &b)| ((a - b) as i64).pow(2)).sum()
+        };
+        // Longer prefixes give better approximations.
+        let mut last = i64::MAX;
+        for &len in &[9, data.len() / 8, data.len() / 4, data.len() / 2, data.len()] {
+            let decoded = decode(&data[..len]).unwrap();
+            assert_eq!((decoded.blocks_wide, decoded.blocks_high), (5, 3));
+            let e = error(&decoded);
+            assert!(e <= last, "{} bytes give error {}, more than {}", len, e, last);
+            last = e;
+        }
+        assert_eq!(last, 0);
+
+        // The low frequencies arrive before the high ones, so they are
+        // more accurate in a prefix.
+        let decoded = decode(&data[..data.len() / 3]).unwrap();
+        let relative_error = |positions: &[usize]| -> f64 {
+            let (mut error, mut total) = (0, 0);
+            for (a, b) in decoded.blocks.iter().zip(coeffs.blocks.iter()) {
+                for &i in positions {
+                    error += (a[i] - b[i]).abs();
+                    total += b[i].abs();
+                }
+            }
+            error as f64 / total as f64
+        };
+        let low = relative_error(&[0, 1, 8]);
+        let high: Vec<usize> = (0..N * N).filter(|i| i % N + i / N >= 4).collect();
+        assert!(low < relative_error(&high), "{} vs {}", low, relative_error(&high));
+
+        assert!(decode(&data[..8]).is_err());
+        let mut invalid = data.clone();
+        invalid[0..4].copy_from_slice(&[0, 0, 0, 0]);
+        assert!(decode(&invalid).is_err());
+    }
+
+    #[test]
+    fn forged_size() {
+        // Headers that claim more than MAX_PIXELS pixels, with no data.
+        for &(width, height) in &[(1 << 14, 1 << 14), (1 << 12, (1 << 12) + 1),
+                                  (u32::MAX, u32::MAX)] {
+            let mut header = Vec::new();
+            let mut writer = BitWriter::new(&mut header);
+            writer.write_bits(width, 32).unwrap();
+            writer.write_bits(height, 32).unwrap();
+            writer.write_bits(31, 6).unwrap();
+            writer.flush().unwrap();
+            drop(writer);
+            assert!(decode(&header).is_err());
+        }
+    }
+}
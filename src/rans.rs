@@ -0,0 +1,237 @@
+// Range asymmetric numeral system compression.
+//
+// Copyright 2023 Robbert Haarman
+//
+// SPDX-License-Identifier: MIT
+
+//! This module implements rANS, the range variant of asymmetric numeral
+//! systems, for alphabets of many symbols with static frequencies. The
+//! state is a 32-bit number that is kept between RANS_L and 256 times
+//! RANS_L by moving whole bytes in and out of it, which is faster than
+//! the bitwise renormalization in tans, and needs no table of states,
+//! so that precise frequencies can be used.
+//!
+//! rANS decodes symbols in the reverse of the order in which they were
+//! encoded. The encoder works backwards through the symbols and reverses
+//! its output, so that the decoder produces them in their original
+//! order. The interleaved variants alternate between two states, one
+//! for the symbols at even positions and one for those at odd positions.
+//! The two states do not depend on each other, which lets the processor
+//! work on both at the same time.
+//!
+//! A stream starts with the final state of each encoder, as 32-bit
+//! numbers, least significant byte first, followed by the bytes the
+//! encoders shifted out of the states.
+
+use crate::result::BoxResult;
+use crate::tans;
+
+/// Lower bound of the normalized state.
+const RANS_L: u32 = 1 << 23;
+
+/// Largest number of bits for the sum of the frequencies.
+pub const MAX_SCALE_BITS: u32 = 16;
+
+/// Static frequencies of the symbols 0 to n - 1, which add up to
+/// 2**scale_bits.
+#[derive(Clone, Debug)]
+pub struct Model {
+    scale_bits: u32,
+    /// Frequency of each symbol.
+    freqs: Vec<u32>,
+    /// Sum of the frequencies of the symbols before each symbol.
+    starts: Vec<u32>,
+    /// The symbol each of the 2**scale_bits slots belongs to.
+    slots: Vec<u32>,
+}
+
+impl Model {
+    /// Creates a model from frequencies that add up to 2**scale_bits.
+    pub fn new(scale_bits: u32, freqs: &[u32]) -> BoxResult<Model> {
+        if scale_bits == 0 || scale_bits > MAX_SCALE_BITS {
+            return Err(format!("invalid number of scale bits {}", scale_bits).into());
+        }
+        if freqs.iter().map(|&f| f as u64).sum::<u64>() != 1 << scale_bits {
+            return Err("frequencies do not add up to 2**scale_bits".into());
+        }
+        let mut starts = Vec::with_capacity(freqs.len());
+        let mut slots = Vec::with_capacity(1 << scale_bits);
+        for (sym, &freq) in freqs.iter().enumerate() {
+            starts.push(slots.len() as u32);
+            slots.resize(slots.len() + freq as usize, sym as u32);
+        }
+        Ok(Model { scale_bits, freqs: freqs.to_vec(), starts, slots })
+    }
+
+    /// Creates a model from counts of how often each symbol occurs,
+    /// normalized with tans::normalize_freqs.
+    pub fn from_counts(scale_bits: u32, counts: &[u32]) -> BoxResult<Model> {
+        if counts.iter().all(|&c| c == 0) {
+            return Err("no symbol occurs".into());
+        }
+        if scale_bits == 0 || scale_bits > MAX_SCALE_BITS
+            || counts.iter().filter(|&&c| c > 0).count() > 1 << scale_bits {
+            return Err(format!("invalid number of scale bits {}", scale_bits).into());
+        }
+        Model::new(scale_bits, &tans::normalize_freqs(scale_bits, counts))
+    }
+
+    /// Returns the frequency of each symbol.
+    pub fn freqs(&self) -> &[u32] {
+        &self.freqs
+    }
+}
+
+/// Encodes sym into state, pushing the bytes shifted out to output.
+fn encode_sym(model: &Model, state: &mut u32, sym: u32, output: &mut Vec<u8>) {
+    let freq = model.freqs.get(sym as usize).copied().unwrap_or(0);
+    assert!(freq > 0, "symbol {} has frequency 0", sym);
+    let max = ((RANS_L >> model.scale_bits) << 8) * freq;
+    while *state >= max {
+        output.push(*state as u8);
+        *state >>= 8;
+    }
+    *state = ((*state / freq) << model.scale_bits) + *state % freq + model.starts[sym as usize];
+}
+
+/// Decodes a symbol from state, reading bytes from input at pos as
+/// needed.
+fn decode_sym(model: &Model, state: &mut u32, input: &[u8], pos: &mut usize) -> BoxResult<u32> {
+    let slot = *state & ((1 << model.scale_bits) - 1);
+    let sym = model.slots[slot as usize];
+    *state = model.freqs[sym as usize] * (*state >> model.scale_bits) + slot
+        - model.starts[sym as usize];
+    while *state < RANS_L {
+        let byte = *input.get(*pos).ok_or("rANS stream is truncated")?;
+        *state = *state << 8 | byte as u32;
+        *pos += 1;
+    }
+    Ok(sym)
+}
+
+/// Encodes symbols with W states, symbol i using state i % W.
+fn encode_ways<const W: usize>(model: &Model, symbols: &[u32]) -> Vec<u8> {
+    let mut states = [RANS_L; W];
+    let mut output = Vec::with_capacity(symbols.len() / 2 + 4 * W);
+    for (i, &sym) in symbols.iter().enumerate().rev() {
+        encode_sym(model, &mut states[i % W], sym, &mut output);
+    }
+    for state in states.iter().rev() {
+        output.extend_from_slice(&state.to_be_bytes());
+    }
+    output.reverse();
+    output
+}
+
+/// Reverses encode_ways, checking that the input is used up and that
+/// the states return to their initial values.
+fn decode_ways<const W: usize>(model: &Model, input: &[u8], count: usize)
+                               -> BoxResult<Vec<u32>> {
+    if input.len() < 4 * W {
+        return Err("rANS stream is truncated".into());
+    }
+    let mut states = [0; W];
+    for (state, bytes) in states.iter_mut().zip(input.chunks_exact(4)) {
+        *state = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        if *state < RANS_L {
+            return Err("invalid rANS state".into());
+        }
+    }
+    let mut pos = 4 * W;
+    let mut symbols = Vec::with_capacity(count.min(input.len() * 8));
+    for i in 0..count {
+        symbols.push(decode_sym(model, &mut states[i % W], input, &mut pos)?);
+    }
+    if pos != input.len() || states.iter().any(|&s| s != RANS_L) {
+        return Err("corrupt rANS stream".into());
+    }
+    Ok(symbols)
+}
+
+/// Encodes symbols with a single state. Every symbol must have a
+/// nonzero frequency in model.
+pub fn encode(model: &Model, symbols: &[u32]) -> Vec<u8> {
+    encode_ways::<1>(model, symbols)
+}
+
+/// Decodes count symbols encoded by encode.
+pub fn decode(model: &Model, input: &[u8], count: usize) -> BoxResult<Vec<u32>> {
+    decode_ways::<1>(model, input, count)
+}
+
+/// Like encode, but interleaves two states.
+pub fn encode_interleaved(model: &Model, symbols: &[u32]) -> Vec<u8> {
+    encode_ways::<2>(model, symbols)
+}
+
+/// Decodes count symbols encoded by encode_interleaved.
+pub fn decode_interleaved(model: &Model, input: &[u8], count: usize) -> BoxResult<Vec<u32>> {
+    decode_ways::<2>(model, input, count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::lcg;
+
+    /// Returns symbols from a skewed distribution over 0 to 19, and the
+    /// number of times each occurs.
+    fn test_symbols(n: usize) -> (Vec<u32>, Vec<u32>) {
+        let symbols: Vec<u32> = lcg(3).take(n).map(|state| {
+            (state >> 24).leading_zeros().min(5) * 3 + (state & 1) * (state >> 8 & 3)
+        }).collect();
+        let mut counts = vec![0; 20];
+        for &s in &symbols {
+            counts[s as usize] += 1;
+        }
+        (symbols, counts)
+    }
+
+    #[test]
+    fn models() {
+        let model = Model::new(3, &[2, 5, 0, 1]).unwrap();
+        assert_eq!(model.starts, [0, 2, 7, 7]);
+        assert_eq!(model.slots, [0, 0, 1, 1, 1, 1, 1, 3]);
+        assert!(Model::new(3, &[2, 5]).is_err());
+        assert!(Model::new(17, &[1 << 17]).is_err());
+        assert_eq!(Model::from_counts(4, &[300, 100, 0]).unwrap().freqs(), [12, 4, 0]);
+        assert!(Model::from_counts(4, &[0, 0]).is_err());
+        assert!(Model::from_counts(1, &[1, 1, 1]).is_err());
+    }
+
+    #[test]
+    fn roundtrip() {
+        let (symbols, counts) = test_symbols(10000);
+        let model = Model::from_counts(12, &counts).unwrap();
+        let encoded = encode(&model, &symbols);
+        assert_eq!(decode(&model, &encoded, symbols.len()).unwrap(), symbols);
+        let interleaved = encode_interleaved(&model, &symbols);
+        assert_eq!(decode_interleaved(&model, &interleaved, symbols.len()).unwrap(), symbols);
+        assert!(interleaved.len() <= encoded.len() + 4);
+
+        // The output is close to the entropy of the symbols.
+        let costs = tans::symbol_costs(12, model.freqs());
+        let bits: f64 = symbols.iter().map(|&s| costs[s as usize]).sum();
+        assert!(((encoded.len() * 8) as f64) < bits * 1.01 + 64.0,
+                "{} bytes for {} bits", encoded.len(), bits);
+
+        for &n in &[0, 1, 2, 3] {
+            let encoded = encode_interleaved(&model, &symbols[..n]);
+            assert_eq!(decode_interleaved(&model, &encoded, n).unwrap(), &symbols[..n]);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        let (symbols, counts) = test_symbols(1000);
+        let model = Model::from_counts(10, &counts).unwrap();
+        let encoded = encode_interleaved(&model, &symbols);
+        assert!(decode_interleaved(&model, &encoded[..encoded.len() - 1], 1000).is_err());
+        assert!(decode_interleaved(&model, &encoded[..7], 0).is_err());
+        assert!(decode_interleaved(&model, &encoded, 999).is_err());
+        assert!(decode(&model, &encoded, 1000).is_err());
+        let mut bad = encoded.clone();
+        bad[1] ^= 0x55;
+        assert!(decode_interleaved(&model, &bad, 1000).is_err());
+    }
+}
@@ -0,0 +1,149 @@
+// Run-length encoding.
+//
+// Copyright 2023 Robbert Haarman
+//
+// SPDX-License-Identifier: MIT
+
+//! This module implements a simple byte-oriented run-length encoding.
+//! Bytes are copied from the input to the output, except that runs of
+//! MIN_RUN or more equal bytes and the escape byte ESCAPE are coded as
+//! escape sequences:
+//!
+//!   ESCAPE 0        a single ESCAPE byte
+//!   ESCAPE n b      n + MIN_RUN - 1 copies of byte b, for n from 1 to 255
+//!
+//! Longer runs are split into several escape sequences. Data without
+//! runs grows only by the escape bytes it contains, and long runs, such
+//! as the zeros left by BWT and move-to-front or by quantizing DCT
+//! coefficients, shrink to 3 bytes per MAX_RUN bytes.
+
+use crate::io::{IOTrait, RepeatOutput, SliceToVecIO};
+use crate::result::BoxResult;
+
+/// Byte that starts escape sequences. It rarely occurs in text, or in
+/// the small values produced by move-to-front.
+pub const ESCAPE: u8 = 0x90;
+
+/// Shortest run coded as an escape sequence. Shorter runs take no more
+/// space as literal bytes.
+pub const MIN_RUN: usize = 4;
+
+/// Longest run coded as a single escape sequence.
+pub const MAX_RUN: usize = 255 + MIN_RUN - 1;
+
+/// Writes count copies of b.
+fn write_run<IO: IOTrait>(io: &mut IO, b: u8, mut count: usize) -> BoxResult<()> {
+    while count >= MIN_RUN {
+        let n = count.min(MAX_RUN);
+        io.write_byte(ESCAPE)?;
+        io.write_byte((n + 1 - MIN_RUN) as u8)?;
+        io.write_byte(b)?;
+        count -= n;
+    }
+    for _ in 0..count {
+        io.write_byte(b)?;
+        if b == ESCAPE {
+            io.write_byte(0)?;
+        }
+    }
+    Ok(())
+}
+
+/// Encodes the input.
+pub fn encode<IO: IOTrait>(io: &mut IO) -> BoxResult<()> {
+    let mut run: Option<(u8, usize)> = None;
+    while let Some(b) = io.next_byte()? {
+        run = match run {
+            Some((last, count)) if last == b => Some((b, count + 1)),
+            Some((last, count)) => {
+                write_run(io, last, count)?;
+                Some((b, 1))
+            },
+            None => Some((b, 1)),
+        };
+    }
+    if let Some((last, count)) = run {
+        write_run(io, last, count)?;
+    }
+    Ok(())
+}
+
+/// Decodes a stream produced by encode.
+pub fn decode<IO: IOTrait + RepeatOutput>(io: &mut IO) -> BoxResult<()> {
+    while let Some(b) = io.next_byte()? {
+        if b != ESCAPE {
+            io.write_byte(b)?;
+            continue;
+        }
+        match io.next_byte()?.ok_or("run-length stream ends in escape sequence")? {
+            0 => io.write_byte(ESCAPE)?,
+            n => {
+                let b = io.next_byte()?.ok_or("run-length stream ends in escape sequence")?;
+                io.write_byte(b)?;
+                io.repeat_bytes(n as usize + MIN_RUN - 2, 0)?;
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Returns the largest size encode can produce for len bytes of input.
+pub fn compress_bound(len: usize) -> usize {
+    2 * len
+}
+
+/// Compresses data in memory.
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len());
+    encode(&mut SliceToVecIO::new(input, &mut output)).expect("encoding to memory cannot fail");
+    output
+}
+
+/// Decompresses data produced by compress, or by encode.
+pub fn decompress(input: &[u8]) -> BoxResult<Vec<u8>> {
+    let mut output = Vec::new();
+    decode(&mut SliceToVecIO::new(input, &mut output))?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format() {
+        assert_eq!(compress(b""), b"");
+        assert_eq!(compress(b"abbbc"), b"abbbc");
+        assert_eq!(compress(b"abbbbc"), b"a\x90\x01bc");
+        assert_eq!(compress(&[ESCAPE, 7, ESCAPE]), [ESCAPE, 0, 7, ESCAPE, 0]);
+        assert_eq!(compress(&[ESCAPE; 5]), [ESCAPE, 2, ESCAPE]);
+        let mut long = vec![0; MAX_RUN + 2];
+        long.push(1);
+        assert_eq!(compress(&long), [ESCAPE, 255, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut data = Vec::new();
+        for i in 0..2000u32 {
+            let b = (i.wrapping_mul(2654435761) >> 24) as u8;
+            let len = if b < 64 { (b as usize) * 11 } else { 1 + (b % 3) as usize };
+            data.extend(std::iter::repeat_n(if b < 32 { ESCAPE } else { b }, len));
+        }
+        let compressed = compress(&data);
+        assert!(compressed.len() < data.len() / 4);
+        assert!(compressed.len() <= compress_bound(data.len()));
+        assert_eq!(decompress(&compressed).unwrap(), data);
+
+        let escapes = [ESCAPE; 3];
+        assert_eq!(compress(&escapes).len(), compress_bound(escapes.len()));
+        assert_eq!(decompress(&compress(&escapes)).unwrap(), escapes);
+    }
+
+    #[test]
+    fn truncated() {
+        assert!(decompress(&[1, ESCAPE]).is_err());
+        assert!(decompress(&[1, ESCAPE, 3]).is_err());
+        assert_eq!(decompress(&[ESCAPE, 1, 5]).unwrap(), [5; MIN_RUN]);
+    }
+}
@@ -25,6 +25,20 @@ impl<S: Copy> Decoder<'_, S> {
         self.state = &self.table[s as usize];
         Ok(self.state.0)
     }
+
+    /// Like decode_first, but returns Ok(None) if the input ends cleanly
+    /// instead, so that a sequence of streams can be decoded until the
+    /// end of the input.
+    pub fn try_decode_first(&mut self, input: &mut dyn ReadBits) -> BoxResult<Option<S>> {
+        let sbits = 32 - ((self.table.len() - 1) as u32).leading_zeros();
+        match input.try_read_bits(sbits)? {
+            Some(s) => {
+                self.state = &self.table[s as usize];
+                Ok(Some(self.state.0))
+            },
+            None => Ok(None),
+        }
+    }
     
     pub fn decode_sym(&mut self, input: &mut dyn ReadBits) -> BoxResult<S> {
         let (_, nbits, base) = self.state;
@@ -69,26 +83,26 @@ impl Encoder {
             output: Vec::new(),
             bits: 0,
             state: 0,
-            nstates: nstates,
+            nstates,
             need_bits: 32,
         };
         encoder.origin.resize(nstates as usize, 0);
         let mut o : u32 = 0;
 	
         // Populate symbol table with coded_nbits and offset.
-        for s in 0..nsyms {
-            let coded_nbits = compute_coded_nbits(freqs[s], sbits);
-            let offset = o.wrapping_sub(freqs[s]) & mask;
+        for &freq in freqs {
+            let coded_nbits = compute_coded_nbits(freq, sbits);
+            let offset = o.wrapping_sub(freq) & mask;
             encoder.symtab.push((coded_nbits, offset));
-            o += freqs[s];
+            o += freq;
         }
 	
         // Populate origin table.
         let stride = compute_stride(nstates);
         let mut o = 0;               // index into origin table
         let mut s = stride & mask;   // state number
-        for sym in 0..nsyms {
-            for _ in 0..freqs[sym] {
+        for &freq in freqs {
+            for _ in 0..freq {
                 encoder.origin[o] = s;
                 o += 1;
                 s = (s + stride) & mask;
@@ -146,6 +160,48 @@ impl Encoder {
     }
 }
 
+/// Returns the average number of bits needed to encode each symbol
+/// with a table of 2**sbits states and the given frequencies. Symbols
+/// with a frequency of 0 cannot be encoded and get infinite cost.
+pub fn symbol_costs(sbits: u32, freqs: &[u32]) -> Vec<f64> {
+    freqs.iter().map(|&freq| {
+        if freq == 0 { f64::INFINITY } else { sbits as f64 - (freq as f64).log2() }
+    }).collect()
+}
+
+/// Scales counts of symbol occurrences to frequencies that add up to
+/// 2**sbits, as Encoder::new and rans::Model::new require. Symbols that
+/// occur get a frequency of at least 1. At least one count must be
+/// nonzero, and at most 2**sbits can be.
+pub fn normalize_freqs(sbits: u32, counts: &[u32]) -> Vec<u32> {
+    let target = 1u64 << sbits;
+    let total: u64 = counts.iter().map(|&c| c as u64).sum();
+    assert!(total > 0, "no symbol occurs");
+    assert!(counts.iter().filter(|&&c| c > 0).count() as u64 <= target,
+            "more symbols occur than there are states");
+    let mut freqs: Vec<u32> = counts.iter().map(|&c| {
+        if c == 0 { 0 } else { ((c as u64 * target / total) as u32).max(1) }
+    }).collect();
+    // Rounding down and raising small frequencies to 1 leave the sum off
+    // by a little. Correct it on the most frequent symbols, where it
+    // changes the cost of coding least.
+    let mut sum: u64 = freqs.iter().map(|&f| f as u64).sum();
+    while sum != target {
+        let (i, _) = freqs.iter().enumerate().filter(|&(_, &f)| sum < target || f > 1)
+            .max_by_key(|&(_, &f)| f).unwrap();
+        if sum < target {
+            let add = (target - sum) as u32;
+            freqs[i] += add;
+            sum += add as u64;
+        } else {
+            let sub = ((sum - target) as u32).min(freqs[i] - 1).min(freqs[i] / 2).max(1);
+            freqs[i] -= sub;
+            sum -= sub as u64;
+        }
+    }
+    freqs
+}
+
 /// Computes a value x such that (x + s) >> 24 gives the number
 /// of bits to read in state s.
 fn compute_coded_nbits(freq: u32, sbits: u32) -> u32 {
@@ -193,9 +249,9 @@ fn compute_coded_nbits(freq: u32, sbits: u32) -> u32 {
 /// be spread roughly evenly across the state space.
 fn compute_stride(nstates: u32) -> u32 {
   if nstates <= 8 {
-    return 5;
+    5
   } else {
-    return (nstates >> 1) + (nstates >> 3) + 3;
+    (nstates >> 1) + (nstates >> 3) + 3
   }
 }
 
@@ -219,8 +275,7 @@ mod tests {
     fn tans_prev_state<S: Copy + Eq>(successor: u32,
                                      sym: S,
                                      table: &[(S, u8, u32)]) -> u32 {
-        for i in 0..table.len() {
-            let (s, nbits, base) = table[i];
+        for (i, &(s, nbits, base)) in table.iter().enumerate() {
             if s == sym && base <= successor && (base + (1 << nbits)) > successor {
                 return i as u32;
             }
@@ -244,6 +299,24 @@ mod tests {
         assert_eq!((coded_one_two + 7) >> 24, 2);
     }
     
+    #[test]
+    fn test_symbol_costs() {
+        assert_eq!(symbol_costs(3, &[2, 5, 1, 0]),
+                   [2.0, 3.0 - 5f64.log2(), 3.0, f64::INFINITY]);
+    }
+
+    #[test]
+    fn test_normalize_freqs() {
+        assert_eq!(normalize_freqs(3, &[20, 50, 10]), [2, 5, 1]);
+        assert_eq!(normalize_freqs(4, &[1000, 0, 1, 1]), [14, 0, 1, 1]);
+        let freqs = normalize_freqs(12, &[7, 1, 0, 3000, 999, 2]);
+        assert_eq!(freqs.iter().sum::<u32>(), 1 << 12);
+        assert_eq!(freqs[2], 0);
+        assert!(freqs.iter().enumerate().all(|(i, &f)| i == 2 || f > 0));
+        // The result can be used to build an encoder.
+        let _ = Encoder::new(3, &normalize_freqs(3, &[1, 1, 1, 1, 1, 1, 1, 1]));
+    }
+
     #[test]
     fn decode_abbc() {
         let mut input = &[0xd][..];
@@ -258,6 +331,19 @@ mod tests {
         assert_eq!(decoder.decode_sym(&mut reader).unwrap(), 'c');
     }
 
+    #[test]
+    fn try_decode_first() {
+        let mut decoder = Decoder {
+            table: EXAMPLE_TABLE,
+            state: &EXAMPLE_TABLE[0],
+        };
+        let mut input = &[0xd][..];
+        let mut reader = BitReader::new(&mut input);
+        assert_eq!(decoder.try_decode_first(&mut reader).unwrap(), Some('a'));
+        reader.align_to_byte().unwrap();
+        assert_eq!(decoder.try_decode_first(&mut reader).unwrap(), None);
+    }
+
     #[test]
     fn encode_abbac_slow() {
         let table = EXAMPLE_TABLE;
@@ -0,0 +1,21 @@
+// Helpers shared by the tests of several modules.
+//
+// Copyright 2023 Robbert Haarman
+//
+// SPDX-License-Identifier: MIT
+
+/// Returns the successive states of a linear congruential generator
+/// started from seed, for tests that need reproducible pseudo-random
+/// values. The high bits are more random than the low ones.
+pub(crate) fn lcg(seed: u32) -> impl Iterator<Item = u32> {
+    let mut state = seed;
+    std::iter::repeat_with(move || {
+        state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+        state
+    })
+}
+
+/// Returns len pseudo-random bytes, taken from the high bits of lcg.
+pub(crate) fn lcg_bytes(seed: u32, len: usize) -> Vec<u8> {
+    lcg(seed).take(len).map(|state| (state >> 24) as u8).collect()
+}
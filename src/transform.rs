@@ -0,0 +1,99 @@
+// Block transforms.
+//
+// Copyright 2023 Robbert Haarman
+//
+// SPDX-License-Identifier: MIT
+
+//! This module defines BlockTransform, a trait implemented by the
+//! transforms in this crate that operate on 8x8 blocks: the DCT
+//! (dct::Dct and dct::FixedDct), the Haar transform (haar::Haar) and the
+//! CDF wavelets (wavelet::Cdf53 and wavelet::Cdf97). Code written against
+//! the trait, such as transform_image and reverse_image here, works with
+//! any of them.
+
+use crate::dct::{self, ImageCoefficients, Padding};
+
+/// Blocks are N by N values.
+const N: usize = 8;
+
+/// A transform of an 8x8 block of samples into 8x8 coefficients, with
+/// the lowest frequency coefficient first.
+pub trait BlockTransform {
+    /// Type of the samples.
+    type Sample: Copy + Default;
+    /// Type of the coefficients.
+    type Coeff: Copy;
+
+    /// Transforms a block of samples in row-major order.
+    fn forward(&self, block: &[Self::Sample; N * N]) -> [Self::Coeff; N * N];
+
+    /// Reverses forward, exactly or up to rounding errors, depending on
+    /// the transform.
+    fn inverse(&self, coeffs: &[Self::Coeff; N * N]) -> [Self::Sample; N * N];
+}
+
+/// Splits a row-major image into 8x8 blocks and transforms each of them
+/// with transform, like dct::transform_image.
+pub fn transform_image<T: BlockTransform>(transform: &T, image: &[T::Sample], width: usize,
+                                          height: usize, stride: usize, padding: Padding)
+                                          -> ImageCoefficients<T::Coeff> {
+    dct::transform_blocks(image, width, height, stride, padding, |block| transform.forward(block))
+}
+
+/// Reverses transform_image, returning the image with a stride equal to
+/// its width.
+pub fn reverse_image<T: BlockTransform>(transform: &T, coeffs: &ImageCoefficients<T::Coeff>)
+                                        -> Vec<T::Sample> {
+    dct::reverse_blocks(coeffs, |block| transform.inverse(block))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dct::{Dct, FixedDct};
+    use crate::haar::Haar;
+    use crate::wavelet::{Cdf53, Cdf97};
+
+    /// Transforms and reverses a smooth image with transform, and checks
+    /// that the result is within tolerance of the image and that the
+    /// first coefficient of each block holds most of its energy.
+    fn check<T>(transform: &T, tolerance: f64)
+    where T: BlockTransform, T::Sample: From<i16> + Into<f64>, T::Coeff: Into<f64> {
+        let (width, height, stride) = (21, 13, 24);
+        let image: Vec<T::Sample> = (0..stride * height).map(|i| {
+            T::Sample::from((i % stride * 5 + i / stride * 3) as i16 + 100)
+        }).collect();
+        let coeffs = transform_image(transform, &image, width, height, stride,
+                                     Padding::Replicate);
+        assert_eq!((coeffs.blocks_wide, coeffs.blocks_high), (3, 2));
+        for block in &coeffs.blocks {
+            let energy: Vec<f64> = block.iter().map(|&x| x.into().powi(2)).collect();
+            assert!(energy[0] > 0.5 * energy.iter().sum::<f64>());
+        }
+        let restored = reverse_image(transform, &coeffs);
+        for y in 0..height {
+            for x in 0..width {
+                let (a, b) = (restored[y * width + x].into(), image[y * stride + x].into());
+                assert!((a - b).abs() <= tolerance, "{} differs from {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn transforms() {
+        check(&Dct, 1e-3);
+        check(&FixedDct, 0.0);
+        check(&Haar, 0.0);
+        check(&Cdf53, 0.0);
+        check(&Cdf97, 1e-3);
+    }
+
+    #[test]
+    fn matches_functions() {
+        let block: [f32; N * N] = std::array::from_fn(|i| (i * 37 % 19) as f32 - 9.0);
+        assert_eq!(Dct.forward(&block), dct::transform(&block));
+        let ints = block.map(|x| x as i32);
+        assert_eq!(FixedDct.forward(&ints), dct::transform_fixed(&ints));
+        assert_eq!(Haar.forward(&ints), crate::haar::transform(&ints));
+    }
+}
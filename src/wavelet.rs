@@ -0,0 +1,321 @@
+// Discrete wavelet transforms.
+//
+// Copyright 2023 Robbert Haarman
+//
+// SPDX-License-Identifier: MIT
+
+//! This module implements the two-dimensional Cohen-Daubechies-Feauveau
+//! 5/3 and 9/7 wavelet transforms used by JPEG 2000, using lifting. The
+//! 5/3 transform operates on integers and is exactly reversible. The 9/7
+//! transform operates on floating point values and compacts energy
+//! better, but is only reversible up to rounding errors.
+//!
+//! Each level of decomposition transforms the rows and then the columns
+//! of the top left region of a plane, storing the low-pass half of each
+//! row or column before the high-pass half. This leaves the low-pass
+//! subband of the level in the top left corner, where the next level
+//! decomposes it further. Signals are extended symmetrically at the
+//! edges, so planes can have any size.
+//!
+//! As BlockTransforms, the wavelets apply BLOCK_LEVELS levels of
+//! decomposition to 8x8 blocks.
+
+use std::ops::AddAssign;
+
+use crate::transform::BlockTransform;
+
+/// Blocks are N by N values.
+const N: usize = 8;
+
+/// Number of levels of decomposition the BlockTransform implementations
+/// apply to a block, which leaves a single low-pass coefficient.
+pub const BLOCK_LEVELS: usize = 3;
+
+/// Lifting steps of a wavelet. The even samples of a signal are in s and
+/// the odd samples in d. The forward transform turns them into the
+/// low-pass and high-pass coefficients, respectively.
+pub(crate) trait Lifting {
+    type T: Copy;
+
+    fn forward(s: &mut [Self::T], d: &mut [Self::T]);
+
+    fn inverse(s: &mut [Self::T], d: &mut [Self::T]);
+}
+
+/// Adds f(s[i], s[i + 1]) to each d[i], extending s symmetrically.
+fn predict<T: Copy + AddAssign, F: Fn(T, T) -> T>(s: &[T], d: &mut [T], f: F) {
+    for i in 0..d.len() {
+        let right = if i + 1 < s.len() { s[i + 1] } else { s[i] };
+        d[i] += f(s[i], right);
+    }
+}
+
+/// Adds f(d[i - 1], d[i]) to each s[i], extending d symmetrically.
+fn update<T: Copy + AddAssign, F: Fn(T, T) -> T>(s: &mut [T], d: &[T], f: F) {
+    if d.is_empty() {
+        return;
+    }
+    for i in 0..s.len() {
+        let left = d[i.saturating_sub(1)];
+        let right = if i < d.len() { d[i] } else { d[i - 1] };
+        s[i] += f(left, right);
+    }
+}
+
+/// The reversible CDF 5/3 wavelet.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cdf53;
+
+impl Lifting for Cdf53 {
+    type T = i32;
+
+    fn forward(s: &mut [i32], d: &mut [i32]) {
+        predict(s, d, |a, b| -((a + b) >> 1));
+        update(s, d, |a, b| (a + b + 2) >> 2);
+    }
+
+    fn inverse(s: &mut [i32], d: &mut [i32]) {
+        update(s, d, |a, b| -((a + b + 2) >> 2));
+        predict(s, d, |a, b| (a + b) >> 1);
+    }
+}
+
+/// The irreversible CDF 9/7 wavelet.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cdf97;
+
+const ALPHA: f32 = -1.586_134_3;
+const BETA: f32 = -0.052_980_117;
+const GAMMA: f32 = 0.882_911_1;
+const DELTA: f32 = 0.443_506_87;
+const KAPPA: f32 = 1.149_604_4;
+
+impl Lifting for Cdf97 {
+    type T = f32;
+
+    fn forward(s: &mut [f32], d: &mut [f32]) {
+        predict(s, d, |a, b| ALPHA * (a + b));
+        update(s, d, |a, b| BETA * (a + b));
+        predict(s, d, |a, b| GAMMA * (a + b));
+        update(s, d, |a, b| DELTA * (a + b));
+        s.iter_mut().for_each(|x| *x *= KAPPA);
+        d.iter_mut().for_each(|x| *x /= KAPPA);
+    }
+
+    fn inverse(s: &mut [f32], d: &mut [f32]) {
+        s.iter_mut().for_each(|x| *x /= KAPPA);
+        d.iter_mut().for_each(|x| *x *= KAPPA);
+        update(s, d, |a, b| -DELTA * (a + b));
+        predict(s, d, |a, b| -GAMMA * (a + b));
+        update(s, d, |a, b| -BETA * (a + b));
+        predict(s, d, |a, b| -ALPHA * (a + b));
+    }
+}
+
+/// Transforms len values of data, starting at start and step apart.
+fn forward_line<L: Lifting>(data: &mut [L::T], start: usize, step: usize, len: usize,
+                            line: &mut Vec<L::T>) {
+    line.clear();
+    line.extend((0..len).step_by(2).map(|i| data[start + i * step]));
+    line.extend((1..len).step_by(2).map(|i| data[start + i * step]));
+    let (s, d) = line.split_at_mut(len.div_ceil(2));
+    L::forward(s, d);
+    for (i, &x) in line.iter().enumerate() {
+        data[start + i * step] = x;
+    }
+}
+
+/// Reverses forward_line.
+fn inverse_line<L: Lifting>(data: &mut [L::T], start: usize, step: usize, len: usize,
+                            line: &mut Vec<L::T>) {
+    line.clear();
+    line.extend((0..len).map(|i| data[start + i * step]));
+    let half = len.div_ceil(2);
+    let (s, d) = line.split_at_mut(half);
+    L::inverse(s, d);
+    for (i, &x) in line.iter().enumerate() {
+        let pos = if i < half { 2 * i } else { 2 * (i - half) + 1 };
+        data[start + pos * step] = x;
+    }
+}
+
+/// Returns the size of the region a level of decomposition transforms.
+fn level_size(width: usize, height: usize, level: usize) -> (usize, usize) {
+    let mut size = (width, height);
+    for _ in 0..level {
+        size = (size.0.div_ceil(2), size.1.div_ceil(2));
+    }
+    size
+}
+
+/// Applies levels levels of decomposition with L to a width x height
+/// plane in row-major order, in place.
+pub(crate) fn forward<L: Lifting>(data: &mut [L::T], width: usize, height: usize,
+                                  levels: usize) {
+    assert_eq!(data.len(), width * height, "data does not match size");
+    let mut line = Vec::new();
+    for level in 0..levels {
+        let (w, h) = level_size(width, height, level);
+        for y in 0..h {
+            forward_line::<L>(data, y * width, 1, w, &mut line);
+        }
+        for x in 0..w {
+            forward_line::<L>(data, x, width, h, &mut line);
+        }
+    }
+}
+
+/// Reverses forward.
+pub(crate) fn inverse<L: Lifting>(data: &mut [L::T], width: usize, height: usize,
+                                  levels: usize) {
+    assert_eq!(data.len(), width * height, "data does not match size");
+    let mut line = Vec::new();
+    for level in (0..levels).rev() {
+        let (w, h) = level_size(width, height, level);
+        for x in 0..w {
+            inverse_line::<L>(data, x, width, h, &mut line);
+        }
+        for y in 0..h {
+            inverse_line::<L>(data, y * width, 1, w, &mut line);
+        }
+    }
+}
+
+/// Applies levels levels of the reversible CDF 5/3 transform to a
+/// width x height plane in row-major order, in place.
+pub fn forward_53(data: &mut [i32], width: usize, height: usize, levels: usize) {
+    forward::<Cdf53>(data, width, height, levels)
+}
+
+/// Reverses forward_53 exactly.
+pub fn inverse_53(data: &mut [i32], width: usize, height: usize, levels: usize) {
+    inverse::<Cdf53>(data, width, height, levels)
+}
+
+/// Applies levels levels of the CDF 9/7 transform to a width x height
+/// plane in row-major order, in place.
+pub fn forward_97(data: &mut [f32], width: usize, height: usize, levels: usize) {
+    forward::<Cdf97>(data, width, height, levels)
+}
+
+/// Reverses forward_97, up to rounding errors.
+pub fn inverse_97(data: &mut [f32], width: usize, height: usize, levels: usize) {
+    inverse::<Cdf97>(data, width, height, levels)
+}
+
+impl BlockTransform for Cdf53 {
+    type Sample = i32;
+    type Coeff = i32;
+
+    fn forward(&self, block: &[i32; N * N]) -> [i32; N * N] {
+        let mut res = *block;
+        forward_53(&mut res, N, N, BLOCK_LEVELS);
+        res
+    }
+
+    fn inverse(&self, coeffs: &[i32; N * N]) -> [i32; N * N] {
+        let mut res = *coeffs;
+        inverse_53(&mut res, N, N, BLOCK_LEVELS);
+        res
+    }
+}
+
+impl BlockTransform for Cdf97 {
+    type Sample = f32;
+    type Coeff = f32;
+
+    fn forward(&self, block: &[f32; N * N]) -> [f32; N * N] {
+        let mut res = *block;
+        forward_97(&mut res, N, N, BLOCK_LEVELS);
+        res
+    }
+
+    fn inverse(&self, coeffs: &[f32; N * N]) -> [f32; N * N] {
+        let mut res = *coeffs;
+        inverse_97(&mut res, N, N, BLOCK_LEVELS);
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::lcg;
+
+    /// Returns a plane of smooth values with some noise.
+    fn test_plane(width: usize, height: usize) -> Vec<i32> {
+        (0..width * height).zip(lcg(1)).map(|(i, state)| {
+            ((i % width) * 3 + (i / width) * 2) as i32 + (state >> 29) as i32
+        }).collect()
+    }
+
+    #[test]
+    fn lines() {
+        // A linear signal has no high-pass content with either wavelet,
+        // except where the symmetric extension bends it.
+        let mut data: Vec<i32> = (0..9).map(|i| i * 4).collect();
+        forward_line::<Cdf53>(&mut data, 0, 1, 9, &mut Vec::new());
+        assert_eq!(data[5..], [0, 0, 0, 0]);
+        inverse_line::<Cdf53>(&mut data, 0, 1, 9, &mut Vec::new());
+        assert_eq!(data, (0..9).map(|i| i * 4).collect::<Vec<i32>>());
+
+        let mut data: Vec<f32> = (0..16).map(|i| i as f32).collect();
+        forward_line::<Cdf97>(&mut data, 0, 1, 16, &mut Vec::new());
+        assert!(data[9..14].iter().all(|x| x.abs() < 1e-4));
+
+        for len in 1..6 {
+            let mut data: Vec<i32> = (0..len).map(|i| i * i - 7).collect();
+            let original = data.clone();
+            forward_line::<Cdf53>(&mut data, 0, 1, len as usize, &mut Vec::new());
+            inverse_line::<Cdf53>(&mut data, 0, 1, len as usize, &mut Vec::new());
+            assert_eq!(data, original);
+        }
+    }
+
+    #[test]
+    fn reversible_53() {
+        for &(width, height, levels) in &[(32, 32, 3), (37, 21, 4), (1, 9, 2), (5, 1, 6)] {
+            let original = test_plane(width, height);
+            let mut data = original.clone();
+            forward_53(&mut data, width, height, levels);
+            assert_ne!(data, original);
+            inverse_53(&mut data, width, height, levels);
+            assert_eq!(data, original);
+        }
+    }
+
+    #[test]
+    fn reversible_97() {
+        for &(width, height, levels) in &[(32, 32, 3), (37, 21, 4), (1, 9, 2)] {
+            let original: Vec<f32> = test_plane(width, height).iter().map(|&x| x as f32).collect();
+            let mut data = original.clone();
+            forward_97(&mut data, width, height, levels);
+            inverse_97(&mut data, width, height, levels);
+            for (a, b) in data.iter().zip(original.iter()) {
+                assert!((a - b).abs() < 1e-3, "{} differs from {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn energy_compaction() {
+        // Most of the energy of a smooth plane ends up in the low-pass
+        // subband of the last level.
+        let (width, height, levels) = (64, 64, 3);
+        let original: Vec<f32> = test_plane(width, height).iter().map(|&x| x as f32).collect();
+        let mut data = original.clone();
+        forward_97(&mut data, width, height, levels);
+        let (w, h) = level_size(width, height, levels);
+        assert_eq!((w, h), (8, 8));
+        let energy = |data: &[f32]| -> f32 { data.iter().map(|x| x * x).sum() };
+        let low: f32 = (0..h).map(|y| energy(&data[y * width..y * width + w])).sum();
+        assert!(low > 0.99 * energy(&data));
+
+        let mut data = test_plane(width, height);
+        forward_53(&mut data, width, height, levels);
+        let small = data.iter().enumerate()
+            .filter(|&(i, _)| i % width >= w || i / width >= h)
+            .filter(|&(_, &x)| x.abs() <= 8).count();
+        assert!(small > (width * height - w * h) * 95 / 100);
+    }
+}